@@ -1,6 +1,11 @@
 use std::borrow::Cow;
 use std::fmt::Display;
 use std::pin::Pin;
+use std::sync::Arc;
+
+use base64::Engine;
+use base64::display::Base64Display;
+use base64::engine::general_purpose::STANDARD;
 
 use bytes::Bytes;
 use futures_util::StreamExt;
@@ -110,33 +115,85 @@ pub enum ContentBlock {
         #[serde(default)]
         is_error: Option<bool>,
     },
+    Thinking {
+        thinking: String,
+        signature: String,
+    },
+    RedactedThinking {
+        data: String,
+    },
+    /// A call of a tool on a server passed in `mcp_servers`, executed by the API.
+    McpToolUse {
+        id: String,
+        name: String,
+        server_name: String,
+        input: serde_json::Value,
+    },
+    McpToolResult {
+        tool_use_id: String,
+        #[serde(default)]
+        content: Option<Vec<ToolResultContentBlock>>,
+        #[serde(default)]
+        is_error: Option<bool>,
+    },
+    /// A block of a type this crate doesn't model yet, serialized back exactly as received.
+    ///
+    /// Blocks of a known type that fail to deserialize end up here as well.
+    #[serde(untagged)]
+    Unknown(serde_json::Value),
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum ToolResultContentBlock {
-    Text { text: String },
-    Image { source: ImageSource },
+    Text {
+        text: String,
+    },
+    Image {
+        source: ImageSource,
+    },
+    #[serde(untagged)]
+    Unknown(serde_json::Value),
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum ContentBlockDelta {
-    TextDelta { text: String },
-    InputJsonDelta { partial_json: String },
+    TextDelta {
+        text: String,
+    },
+    InputJsonDelta {
+        partial_json: String,
+    },
+    ThinkingDelta {
+        thinking: String,
+    },
+    SignatureDelta {
+        signature: String,
+    },
+    #[serde(untagged)]
+    Unknown(serde_json::Value),
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
 pub enum CacheControl {
     /// The time-to-live for the cache control breakpoint.
-    Ephemeral { ttl: Ttl },
+    Ephemeral {
+        #[serde(default)]
+        ttl: Ttl,
+    },
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
 pub enum Ttl {
+    #[default]
+    #[serde(rename = "5m")]
     FiveMinutes,
+    #[serde(rename = "1h")]
     OneHour,
 }
 
@@ -184,7 +241,7 @@ pub enum Citation {
 #[serde(rename_all = "snake_case")]
 pub enum ImageSource {
     Base64 {
-        data: String,
+        data: Base64Data,
         media_type: ImageMediaType,
     },
     Url {
@@ -195,30 +252,59 @@ pub enum ImageSource {
     },
 }
 
+/// Binary data that is base64 encoded while the request body is serialized, so large images and
+/// documents don't need a separate encoded copy.
+#[derive(Clone)]
+pub struct Base64Data(pub Arc<[u8]>);
+
+impl std::fmt::Debug for Base64Data {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Base64Data({} bytes)", self.0.len())
+    }
+}
+
+impl Serialize for Base64Data {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&Base64Display::new(&self.0, &STANDARD))
+    }
+}
+
+impl<'de> Deserialize<'de> for Base64Data {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = String::deserialize(deserializer)?;
+        STANDARD
+            .decode(data)
+            .map(|data| Self(data.into()))
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
 pub enum DocumentSource {
+    #[serde(rename = "base64")]
     PdfBase64 {
-        data: String,
+        data: Base64Data,
         media_type: DocumentMediaType,
     },
+    #[serde(rename = "text")]
     PlainText {
         data: String,
         media_type: DocumentMediaType,
     },
-    ContentBlock {
-        content: Content,
-    },
-    PdfUrl {
-        url: String,
-    },
-    FileDocument {
-        file_id: String,
-    },
+    #[serde(rename = "content")]
+    ContentBlock { content: Content },
+    #[serde(rename = "url")]
+    PdfUrl { url: String },
+    #[serde(rename = "file")]
+    FileDocument { file_id: String },
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 pub enum DocumentMediaType {
+    #[serde(rename = "application/pdf")]
     Pdf,
+    #[serde(rename = "text/plain")]
     Plain,
 }
 
@@ -249,24 +335,32 @@ impl Default for InputMessage {
     }
 }
 
+/// A remote MCP server whose tools the API calls on the model's behalf.
+///
+/// Requires the `mcp-client-2025-04-04` beta.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "url")]
 #[allow(clippy::manual_non_exhaustive)]
 pub struct McpServer {
     pub name: String,
     pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub authorization_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_configuration: Option<ToolConfiguration>,
     #[serde(skip)]
-    _ne: (),
+    pub _ne: (),
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[allow(clippy::manual_non_exhaustive)]
 pub struct ToolConfiguration {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub allowed_tools: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub enabled: Option<bool>,
     #[serde(skip)]
-    _ne: (),
+    pub _ne: (),
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -275,16 +369,19 @@ pub struct Metadata<'a> {
     /// An external identifier for the user who is associated with the request.
     pub user_id: Option<Cow<'a, str>>,
     #[serde(skip)]
-    _ne: (),
+    pub _ne: (),
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ServiceTier {
     Auto,
     StandardOnly,
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
 pub enum Thinking {
     Enabled {
         /// Determines how many tokens Claude can use for its internal reasoning process.
@@ -347,9 +444,13 @@ pub struct MessagesRequest<'a> {
     /// The version of the Anthropic API you want to use.
     #[serde(skip)]
     pub anthropic_version: ApiVersion,
-    /// Your unique API key for authentication.
+    /// Your unique API key for authentication, or the token when authenticating with
+    /// [`AuthScheme::Bearer`].
     #[serde(skip)]
     pub x_api_key: Cow<'a, str>,
+    /// Overrides the auth scheme of the client for this request.
+    #[serde(skip)]
+    pub auth_scheme: Option<AuthScheme>,
     /// The body of the request.
     pub body: MessagesRequestBody<'a>,
     #[serde(skip)]
@@ -362,6 +463,7 @@ impl Default for MessagesRequest<'_> {
             anthropic_beta: None,
             anthropic_version: ApiVersion::Latest,
             x_api_key: "".into(),
+            auth_scheme: None,
             body: MessagesRequestBody::default(),
             _ne: (),
         }
@@ -396,7 +498,7 @@ pub struct MessagesRequestBody<'a> {
     pub stream: bool,
     /// System prompt.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub system: Option<Cow<'a, str>>,
+    pub system: Option<System<'a>>,
     /// Amount of randomness injected into the response.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f32>,
@@ -443,6 +545,139 @@ impl Default for MessagesRequestBody<'_> {
     }
 }
 
+/// A system prompt, given as text blocks to place cache breakpoints in it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum System<'a> {
+    Text(Cow<'a, str>),
+    Blocks(Vec<ContentBlock>),
+}
+
+/// Places cache breakpoints on the parts of a request that repeat across turns.
+///
+/// A request may contain at most [`CacheStrategy::MAX_BREAKPOINTS`] breakpoints, including
+/// those that were placed by hand. The tools and the system prompt get one each, the rest mark
+/// the most recent messages so the next turn reads the conversation up to them from the cache.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStrategy {
+    pub ttl: Ttl,
+    /// Whether to cache the tool definitions.
+    pub tools: bool,
+    /// Whether to cache the system prompt, along with the tools before it.
+    pub system: bool,
+    /// The number of most recent messages to place a breakpoint on.
+    pub messages: usize,
+}
+
+impl Default for CacheStrategy {
+    fn default() -> Self {
+        Self {
+            ttl: Ttl::FiveMinutes,
+            tools: true,
+            system: true,
+            messages: 2,
+        }
+    }
+}
+
+impl CacheStrategy {
+    pub const MAX_BREAKPOINTS: usize = 4;
+
+    pub fn apply(&self, body: &mut MessagesRequestBody<'_>) {
+        let mut remaining = Self::MAX_BREAKPOINTS.saturating_sub(count_breakpoints(body));
+        let mut place = |cache_control: &mut Option<CacheControl>| {
+            if remaining == 0 || cache_control.is_some() {
+                return;
+            }
+
+            *cache_control = Some(CacheControl::Ephemeral { ttl: self.ttl });
+            remaining -= 1;
+        };
+
+        if self.tools
+            && let Some(tool) = body.tools.as_mut().and_then(|tools| tools.last_mut())
+        {
+            place(&mut tool.cache_control);
+        }
+
+        if self.system {
+            let blocks = match body.system.take() {
+                Some(System::Text(text)) => vec![ContentBlock::Text {
+                    text: text.into_owned(),
+                    cache_control: None,
+                    citations: None,
+                }],
+                Some(System::Blocks(blocks)) => blocks,
+                None => Vec::new(),
+            };
+
+            if !blocks.is_empty() {
+                let system = body.system.insert(System::Blocks(blocks));
+                if let System::Blocks(blocks) = system
+                    && let Some(cache_control) = blocks.last_mut().and_then(cache_control_mut)
+                {
+                    place(cache_control);
+                }
+            }
+        }
+
+        for message in body.messages.iter_mut().rev().take(self.messages) {
+            if let Content::String(text) = &mut message.content {
+                message.content = Content::Blocks(vec![ContentBlock::Text {
+                    text: std::mem::take(text),
+                    cache_control: None,
+                    citations: None,
+                }]);
+            }
+
+            if let Content::Blocks(blocks) = &mut message.content
+                && let Some(cache_control) = blocks.iter_mut().rev().find_map(cache_control_mut)
+            {
+                place(cache_control);
+            }
+        }
+    }
+}
+
+/// The cache control of a block, `None` for blocks that can't be cached.
+fn cache_control_mut(block: &mut ContentBlock) -> Option<&mut Option<CacheControl>> {
+    match block {
+        ContentBlock::Text { cache_control, .. }
+        | ContentBlock::Image { cache_control, .. }
+        | ContentBlock::Document { cache_control, .. }
+        | ContentBlock::ToolUse { cache_control, .. }
+        | ContentBlock::ToolResult { cache_control, .. } => Some(cache_control),
+        ContentBlock::Thinking { .. }
+        | ContentBlock::RedactedThinking { .. }
+        | ContentBlock::McpToolUse { .. }
+        | ContentBlock::McpToolResult { .. }
+        | ContentBlock::Unknown(_) => None,
+    }
+}
+
+fn count_breakpoints(body: &mut MessagesRequestBody<'_>) -> usize {
+    let tools = body.tools.iter_mut().flatten();
+    let system = match &mut body.system {
+        Some(System::Blocks(blocks)) => blocks.as_mut_slice(),
+        _ => &mut [],
+    };
+    let messages = body
+        .messages
+        .iter_mut()
+        .flat_map(|message| match &mut message.content {
+            Content::Blocks(blocks) => blocks.as_mut_slice(),
+            Content::String(_) => &mut [],
+        });
+
+    tools.filter(|tool| tool.cache_control.is_some()).count()
+        + system
+            .iter_mut()
+            .chain(messages)
+            .filter_map(cache_control_mut)
+            .filter(|cache_control| cache_control.is_some())
+            .count()
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -466,7 +701,10 @@ pub struct Message {
     pub stop_reason: Option<StopReason>,
     /// Which custom stop sequence was generated, if any.
     pub stop_sequence: Option<String>,
-    // TODO: usage
+    /// Billing and rate-limit usage, the output tokens of a streamed message are reported in
+    /// its [`MessagesResponseEvent::MessageDelta`] events.
+    #[serde(default)]
+    pub usage: Option<Usage>,
     // TODO: container
     #[serde(skip)]
     _ne: (),
@@ -481,11 +719,27 @@ impl Default for Message {
             model: Model::ClaudeSonnet3_5,
             stop_reason: None,
             stop_sequence: None,
+            usage: None,
             _ne: (),
         }
     }
 }
 
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Usage {
+    /// The number of input tokens which were used, excluding those read from or written to
+    /// the cache.
+    #[serde(default)]
+    pub input_tokens: u32,
+    /// The number of output tokens which were used, a running total in streamed deltas.
+    #[serde(default)]
+    pub output_tokens: u32,
+    /// The number of input tokens used to create the cache entry.
+    pub cache_creation_input_tokens: Option<u32>,
+    /// The number of input tokens read from the cache.
+    pub cache_read_input_tokens: Option<u32>,
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[allow(clippy::manual_non_exhaustive)]
 pub struct MessageDelta {
@@ -497,7 +751,7 @@ pub struct MessageDelta {
     _ne: (),
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum StopReason {
     /// The model reached a natural stopping point
@@ -512,6 +766,9 @@ pub enum StopReason {
     PauseTurn,
     /// When streaming classifiers intervene to handle potential policy violations
     Refusal,
+    /// A stop reason this crate doesn't model yet.
+    #[serde(untagged)]
+    Other(String),
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -524,6 +781,9 @@ pub enum MessagesResponseEvent {
     },
     MessageDelta {
         delta: MessageDelta,
+        /// The usage of the message so far, reported next to the delta rather than in it.
+        #[serde(default)]
+        usage: Option<Usage>,
     },
     MessageStop,
     ContentBlockStart {
@@ -537,6 +797,15 @@ pub enum MessagesResponseEvent {
     ContentBlockStop {
         index: usize,
     },
+    /// An error that occurred after the response started, such as `overloaded_error`.
+    ///
+    /// [`MessageStream`] returns these as [`AnthropicError::Api`], the stream ends after them.
+    Error {
+        error: ApiErrorDetails,
+    },
+    /// An event of a type this crate doesn't model yet, such as one of a new beta.
+    #[serde(untagged)]
+    Unknown(serde_json::Value),
 }
 
 #[derive(Debug, Error)]
@@ -578,30 +847,34 @@ pub struct ApiErrorDetails {
 
 impl std::error::Error for ApiError {}
 
-pub struct MessageStream {
+/// Reads the `event` and `data` fields of server-sent events from a response body.
+struct EventSource {
     stream: Pin<Box<dyn futures_core::Stream<Item = reqwest::Result<Bytes>> + Send>>,
     buf: Vec<u8>,
 }
 
-impl MessageStream {
-    pub async fn recv(&mut self) -> Result<Option<MessagesResponseEvent>, AnthropicError> {
-        let mut lines_parsed = 0;
-        let mut data = None;
+impl EventSource {
+    async fn next(&mut self) -> Result<Option<(String, String)>, AnthropicError> {
+        let mut event = String::new();
+        let mut data = String::new();
         loop {
             while let Some(at) = self.buf.iter().position(|&b| b == b'\n') {
                 let line = self.buf.drain(..=at).collect::<Vec<u8>>();
                 let line = String::from_utf8_lossy(&line);
                 let line = line.trim();
 
-                match lines_parsed {
-                    0 => assert!(line.strip_prefix("event: ").is_some()),
-                    1 => data = Some(serde_json::from_str(line.strip_prefix("data: ").unwrap())?),
-                    2 => return Ok(data.unwrap()),
-                    _ => unreachable!(),
+                if line.is_empty() {
+                    if !data.is_empty() {
+                        return Ok(Some((event, data)));
+                    }
+                } else if let Some(name) = line.strip_prefix("event:") {
+                    event = name.trim_start().to_string();
+                } else if let Some(line) = line.strip_prefix("data:") {
+                    if !data.is_empty() {
+                        data.push('\n');
+                    }
+                    data.push_str(line.trim_start());
                 }
-
-                lines_parsed += 1;
-                lines_parsed %= 3;
             }
 
             match self.stream.next().await {
@@ -613,36 +886,109 @@ impl MessageStream {
     }
 }
 
+pub struct MessageStream {
+    events: EventSource,
+}
+
+impl MessageStream {
+    pub async fn recv(&mut self) -> Result<Option<MessagesResponseEvent>, AnthropicError> {
+        let Some((_, data)) = self.events.next().await? else {
+            return Ok(None);
+        };
+
+        match serde_json::from_str(&data)? {
+            MessagesResponseEvent::Error { error } => Err(AnthropicError::Api(ApiError {
+                error,
+                ..Default::default()
+            })),
+            event => Ok(Some(event)),
+        }
+    }
+}
+
+/// A server-sent event whose data was parsed as JSON but not into a [`MessagesResponseEvent`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RawEvent {
+    /// The name of the event, such as `content_block_delta`.
+    pub event: String,
+    pub data: serde_json::Value,
+}
+
+/// Streams events as [`RawEvent`]s, including event types this crate doesn't model yet.
+pub struct RawMessageStream {
+    events: EventSource,
+}
+
+impl RawMessageStream {
+    pub async fn recv(&mut self) -> Result<Option<RawEvent>, AnthropicError> {
+        match self.events.next().await? {
+            Some((event, data)) => Ok(Some(RawEvent {
+                event,
+                data: serde_json::from_str(&data)?,
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+/// How requests present their credentials.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AuthScheme {
+    /// Sends the key in the `x-api-key` header.
+    #[default]
+    ApiKey,
+    /// Sends the key as an `Authorization: Bearer` token, for gateways and OAuth-derived
+    /// credentials. Many corporate proxies reject the `x-api-key` header.
+    Bearer,
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct AnthropicClient {
     client: reqwest::Client,
+    auth_scheme: AuthScheme,
 }
 
 impl AnthropicClient {
     pub fn new() -> Self {
         AnthropicClient {
             client: reqwest::Client::new(),
+            auth_scheme: AuthScheme::default(),
         }
     }
 
-    /// Send a structured list of input messages with text and/or image content, and the model will generate the next message in the conversation.
-    pub async fn messages(&self, request: &MessagesRequest<'_>) -> Result<Message, AnthropicError> {
-        if request.body.stream {
-            return Err(AnthropicError::StreamEnabled);
-        }
+    /// Sets the auth scheme of requests that don't specify their own.
+    pub fn with_auth_scheme(mut self, auth_scheme: AuthScheme) -> Self {
+        self.auth_scheme = auth_scheme;
+        self
+    }
 
+    fn post(
+        &self,
+        request: &MessagesRequest<'_>,
+    ) -> Result<reqwest::RequestBuilder, AnthropicError> {
         let mut post = self.client.post("https://api.anthropic.com/v1/messages");
 
         if let Some(beta) = &request.anthropic_beta {
             post = post.header("anthropic-beta", beta.join(","));
         }
 
-        let response = post
+        post = match request.auth_scheme.unwrap_or(self.auth_scheme) {
+            AuthScheme::ApiKey => post.header("x-api-key", request.x_api_key.as_ref()),
+            AuthScheme::Bearer => post.bearer_auth(request.x_api_key.as_ref()),
+        };
+
+        Ok(post
             .header("anthropic-version", request.anthropic_version.as_ref())
-            .header("x-api-key", request.x_api_key.as_ref())
-            .body(serde_json::to_string(&request.body)?)
-            .send()
-            .await?;
+            .body(serde_json::to_string(&request.body)?))
+    }
+
+    /// Send a structured list of input messages with text and/or image content, and the model will generate the next message in the conversation.
+    pub async fn messages(&self, request: &MessagesRequest<'_>) -> Result<Message, AnthropicError> {
+        if request.body.stream {
+            return Err(AnthropicError::StreamEnabled);
+        }
+
+        let response = self.post(request)?.send().await?;
 
         match serde_json::from_str::<MessagesResponse>(&response.text().await?)? {
             MessagesResponse::Message(messages_response) => Ok(messages_response),
@@ -655,22 +1001,31 @@ impl AnthropicClient {
         &self,
         request: &MessagesRequest<'_>,
     ) -> Result<MessageStream, AnthropicError> {
-        if !request.body.stream {
-            return Err(AnthropicError::StreamNotEnabled);
-        }
+        Ok(MessageStream {
+            events: self.event_source(request).await?,
+        })
+    }
 
-        let mut post = self.client.post("https://api.anthropic.com/v1/messages");
+    /// Like [`messages_stream`](Self::messages_stream), but yields every event untyped so that
+    /// events and fields added to the API can be used before this crate models them.
+    pub async fn messages_stream_raw(
+        &self,
+        request: &MessagesRequest<'_>,
+    ) -> Result<RawMessageStream, AnthropicError> {
+        Ok(RawMessageStream {
+            events: self.event_source(request).await?,
+        })
+    }
 
-        if let Some(beta) = &request.anthropic_beta {
-            post = post.header("anthropic-beta", beta.join(","));
+    async fn event_source(
+        &self,
+        request: &MessagesRequest<'_>,
+    ) -> Result<EventSource, AnthropicError> {
+        if !request.body.stream {
+            return Err(AnthropicError::StreamNotEnabled);
         }
 
-        let response = post
-            .header("anthropic-version", request.anthropic_version.as_ref())
-            .header("x-api-key", request.x_api_key.as_ref())
-            .body(serde_json::to_string(&request.body)?)
-            .send()
-            .await?;
+        let response = self.post(request)?.send().await?;
 
         let status = response.status();
         if !status.is_success() {
@@ -689,7 +1044,7 @@ impl AnthropicClient {
             }));
         }
 
-        Ok(MessageStream {
+        Ok(EventSource {
             stream: Box::pin(response.bytes_stream()),
             buf: vec![],
         })
@@ -700,6 +1055,35 @@ impl AnthropicClient {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_deserialize_usage() {
+        let event: MessagesResponseEvent = serde_json::from_str(
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn","stop_sequence":null},"usage":{"output_tokens":15}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            event,
+            MessagesResponseEvent::MessageDelta {
+                usage: Some(Usage {
+                    output_tokens: 15,
+                    ..
+                }),
+                ..
+            }
+        ));
+
+        let event: MessagesResponseEvent = serde_json::from_str(
+            r#"{"type":"message_start","message":{"id":"msg_1","type":"message","role":"assistant","content":[],"model":"claude-opus-4-20250514","stop_reason":null,"stop_sequence":null,"usage":{"input_tokens":25,"cache_read_input_tokens":100,"output_tokens":1}}}"#,
+        )
+        .unwrap();
+        let MessagesResponseEvent::MessageStart { message } = event else {
+            panic!("Expected message start");
+        };
+        let usage = message.usage.unwrap();
+        assert_eq!(usage.input_tokens, 25);
+        assert_eq!(usage.cache_read_input_tokens, Some(100));
+    }
+
     #[ignore]
     #[tokio::test]
     async fn test_messages() {
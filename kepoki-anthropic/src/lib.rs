@@ -3,73 +3,150 @@ use std::borrow::Cow;
 use anthropoki::AnthropicClient;
 pub use anthropoki::AnthropicError;
 pub use anthropoki::ApiVersion;
+pub use anthropoki::AuthScheme;
+pub use anthropoki::CacheStrategy;
+pub use anthropoki::McpServer;
 use anthropoki::MessagesRequestBody;
+use anthropoki::Metadata;
 use anthropoki::Model;
+use anthropoki::ServiceTier;
+use anthropoki::System;
+use anthropoki::Thinking;
 use anthropoki::ToolChoice as AnthropicToolChoice;
+pub use anthropoki::ToolConfiguration;
+use kepoki::backend::AttachmentLimits;
 use kepoki::backend::MessageStream;
+use kepoki::backend::MessagesFuture;
+use kepoki::backend::RecvFuture;
 use kepoki::backend::ToolChoice;
 use kepoki::error::KepokiError;
+use kepoki::error::ProviderErrorKind;
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use serde::de::value::StrDeserializer;
 
 pub struct AnthropicMessageStream(anthropoki::MessageStream);
 
 impl MessageStream for AnthropicMessageStream {
-    fn recv(&mut self) -> Result<Option<kepoki::backend::MessagesResponseEvent>, KepokiError> {
-        match futures::executor::block_on(self.0.recv()) {
-            Ok(Some(event)) => Ok(Some(match event {
-                anthropoki::MessagesResponseEvent::Ping => {
-                    kepoki::backend::MessagesResponseEvent::Ping
-                }
-                anthropoki::MessagesResponseEvent::MessageStart { message } => {
-                    kepoki::backend::MessagesResponseEvent::MessageStart(reverse_convert_message(
-                        message,
-                    ))
+    fn recv(&mut self) -> RecvFuture<'_> {
+        Box::pin(async move {
+            match self.0.recv().await {
+                Ok(Some(event)) => reverse_convert_event(event).map(Some),
+                Ok(None) => Ok(None),
+                Err(err) => Err(convert_error(err)),
+            }
+        })
+    }
+}
+
+/// Converts an event of the stream, failing with the error of an `error` event.
+fn reverse_convert_event(
+    event: anthropoki::MessagesResponseEvent,
+) -> Result<kepoki::backend::MessagesResponseEvent, KepokiError> {
+    Ok(match event {
+        anthropoki::MessagesResponseEvent::Ping => kepoki::backend::MessagesResponseEvent::Ping,
+        anthropoki::MessagesResponseEvent::MessageStart { message } => {
+            kepoki::backend::MessagesResponseEvent::MessageStart(reverse_convert_message(message))
+        }
+        anthropoki::MessagesResponseEvent::MessageDelta { delta, usage } => {
+            kepoki::backend::MessagesResponseEvent::MessageDelta(reverse_convert_message_delta(
+                delta, usage,
+            ))
+        }
+        anthropoki::MessagesResponseEvent::MessageStop => {
+            kepoki::backend::MessagesResponseEvent::MessageStop
+        }
+        anthropoki::MessagesResponseEvent::ContentBlockStart {
+            index,
+            content_block,
+        } => kepoki::backend::MessagesResponseEvent::ContentBlockStart(
+            kepoki::backend::ContentBlockStart {
+                index,
+                content_block: reverse_convert_content_block(content_block),
+            },
+        ),
+        anthropoki::MessagesResponseEvent::ContentBlockDelta { index, delta } => {
+            kepoki::backend::MessagesResponseEvent::ContentBlockDelta(match delta {
+                anthropoki::ContentBlockDelta::TextDelta { text } => {
+                    kepoki::backend::ContentBlockDelta::Text { index, text }
                 }
-                anthropoki::MessagesResponseEvent::MessageDelta { delta } => {
-                    kepoki::backend::MessagesResponseEvent::MessageDelta(
-                        reverse_convert_message_delta(delta),
-                    )
+                anthropoki::ContentBlockDelta::InputJsonDelta { partial_json } => {
+                    kepoki::backend::ContentBlockDelta::InputJson {
+                        index,
+                        partial_json,
+                    }
                 }
-                anthropoki::MessagesResponseEvent::MessageStop => {
-                    kepoki::backend::MessagesResponseEvent::MessageStop
+                anthropoki::ContentBlockDelta::ThinkingDelta { thinking } => {
+                    kepoki::backend::ContentBlockDelta::Thinking { index, thinking }
                 }
-                anthropoki::MessagesResponseEvent::ContentBlockStart {
-                    index,
-                    content_block,
-                } => kepoki::backend::MessagesResponseEvent::ContentBlockStart(
-                    kepoki::backend::ContentBlockStart {
-                        index,
-                        content_block: reverse_convert_content_block(content_block),
-                    },
-                ),
-                anthropoki::MessagesResponseEvent::ContentBlockDelta { index, delta } => {
-                    kepoki::backend::MessagesResponseEvent::ContentBlockDelta(match delta {
-                        anthropoki::ContentBlockDelta::TextDelta { text } => {
-                            kepoki::backend::ContentBlockDelta::Text { index, text }
-                        }
-                        anthropoki::ContentBlockDelta::InputJsonDelta { partial_json } => {
-                            kepoki::backend::ContentBlockDelta::InputJson {
-                                index,
-                                partial_json,
-                            }
-                        }
-                    })
+                anthropoki::ContentBlockDelta::SignatureDelta { signature } => {
+                    kepoki::backend::ContentBlockDelta::Signature { index, signature }
                 }
-                anthropoki::MessagesResponseEvent::ContentBlockStop { index } => {
-                    kepoki::backend::MessagesResponseEvent::ContentBlockStop(
-                        kepoki::backend::ContentBlockStop { index },
-                    )
+                anthropoki::ContentBlockDelta::Unknown(data) => {
+                    kepoki::backend::ContentBlockDelta::Unknown { index, data }
                 }
-            })),
-            Ok(None) => Ok(None),
-            Err(err) => Err(KepokiError::CustomError(Box::new(err))),
+            })
+        }
+        anthropoki::MessagesResponseEvent::ContentBlockStop { index } => {
+            kepoki::backend::MessagesResponseEvent::ContentBlockStop(
+                kepoki::backend::ContentBlockStop { index },
+            )
         }
+        anthropoki::MessagesResponseEvent::Error { error } => {
+            return Err(KepokiError::Provider {
+                kind: error_kind(&error.r#type),
+                message: error.message,
+            });
+        }
+        anthropoki::MessagesResponseEvent::Unknown(event) => {
+            kepoki::backend::MessagesResponseEvent::Unknown(event)
+        }
+    })
+}
+
+/// Classifies errors reported by the API, keeping transport and parsing errors as they are.
+fn convert_error(err: AnthropicError) -> KepokiError {
+    match err {
+        AnthropicError::Api(api_error) => KepokiError::Provider {
+            kind: error_kind(&api_error.error.r#type),
+            message: api_error.error.message,
+        },
+        err => KepokiError::CustomError(Box::new(err)),
     }
 }
 
+/// The kind of an error by its type, see <https://docs.anthropic.com/en/api/errors>.
+fn error_kind(r#type: &str) -> ProviderErrorKind {
+    match r#type {
+        "overloaded_error" => ProviderErrorKind::Overloaded,
+        "rate_limit_error" => ProviderErrorKind::RateLimited,
+        "api_error" => ProviderErrorKind::Server,
+        "invalid_request_error" => ProviderErrorKind::InvalidRequest,
+        "request_too_large" => ProviderErrorKind::RequestTooLarge,
+        "authentication_error" => ProviderErrorKind::Authentication,
+        "permission_error" => ProviderErrorKind::PermissionDenied,
+        "not_found_error" => ProviderErrorKind::NotFound,
+        other => other
+            .strip_prefix("http_error_")
+            .and_then(|status| status.parse().ok())
+            .map_or(ProviderErrorKind::Other, ProviderErrorKind::from_status),
+    }
+}
+
+/// Enables the `mcp_servers` request parameter.
+const MCP_CLIENT_BETA: &str = "mcp-client-2025-04-04";
+
+/// Sends requests to the Anthropic API.
+///
+/// Supports the extension parameters `top_k`, `top_p`, `service_tier`, and `container` of the
+/// API, and `betas`, a list of betas enabled in addition to those of the backend.
+#[derive(Clone)]
 pub struct AnthropicBackend {
     betas: Option<Vec<String>>,
     version: ApiVersion,
     api_key: String,
+    cache_strategy: Option<CacheStrategy>,
+    mcp_servers: Vec<McpServer>,
 
     client: AnthropicClient,
 }
@@ -80,46 +157,188 @@ impl AnthropicBackend {
             betas,
             version,
             api_key,
+            cache_strategy: None,
+            mcp_servers: Vec::new(),
             client: AnthropicClient::new(),
         }
     }
+
+    /// Sends the API key as an `Authorization: Bearer` token or in the `x-api-key` header.
+    pub fn with_auth_scheme(mut self, auth_scheme: AuthScheme) -> Self {
+        self.client = self.client.with_auth_scheme(auth_scheme);
+        self
+    }
+
+    /// Places prompt cache breakpoints on every request, `None` leaves caching to the API's
+    /// defaults.
+    pub fn with_cache_strategy(mut self, cache_strategy: Option<CacheStrategy>) -> Self {
+        self.cache_strategy = cache_strategy;
+        self
+    }
+
+    /// Remote MCP servers whose tools Anthropic calls on the model's behalf, instead of agents
+    /// calling them through their own MCP clients.
+    ///
+    /// Their calls and results appear in responses as server tool blocks.
+    pub fn with_mcp_servers(mut self, mcp_servers: Vec<McpServer>) -> Self {
+        self.mcp_servers = mcp_servers;
+        self
+    }
+
+    fn betas(&self) -> Option<Vec<Cow<'_, str>>> {
+        let mut betas = self
+            .betas
+            .iter()
+            .flatten()
+            .map(|beta| Cow::Borrowed(beta.as_str()))
+            .collect::<Vec<_>>();
+        if !self.mcp_servers.is_empty() && !betas.iter().any(|beta| beta == MCP_CLIENT_BETA) {
+            betas.push(Cow::Borrowed(MCP_CLIENT_BETA));
+        }
+
+        (!betas.is_empty()).then_some(betas)
+    }
+
+    /// Converts a request to one of the API, streaming the response if `stream`.
+    fn request<'a>(
+        &'a self,
+        request: kepoki::backend::MessagesRequest<'a, Self>,
+        stream: bool,
+    ) -> Result<anthropoki::MessagesRequest<'a>, KepokiError> {
+        let extensions = Extensions::of(&request)?;
+
+        // Thinking counts towards max tokens and can't be combined with a custom temperature.
+        let (thinking, max_tokens, temperature) = match request.reasoning {
+            Some(reasoning) => (
+                Some(Thinking::Enabled {
+                    budget_tokens: reasoning.budget_tokens(),
+                }),
+                request.max_tokens + reasoning.budget_tokens(),
+                None,
+            ),
+            None => (None, request.max_tokens, request.temperature),
+        };
+
+        let mut body = MessagesRequestBody {
+            model: request.model,
+            messages: request.messages.into_iter().map(convert_message).collect(),
+            max_tokens,
+            metadata: request.user_id.map(|user_id| Metadata {
+                user_id: Some(user_id),
+                ..Default::default()
+            }),
+            stream,
+            system: request.system.map(System::Text),
+            stop_sequences: request.stop_sequences,
+            temperature,
+            thinking,
+            tool_choice: request.tool_choice.map(convert_tool_choice),
+            tools: request
+                .tools
+                .map(|tools| tools.into_iter().map(convert_tool).collect()),
+            mcp_servers: (!self.mcp_servers.is_empty()).then(|| self.mcp_servers.clone()),
+            top_k: extensions.top_k,
+            top_p: extensions.top_p,
+            service_tier: extensions.service_tier,
+            container: extensions.container.map(Cow::Owned),
+            ..Default::default()
+        };
+        if let Some(cache_strategy) = &self.cache_strategy {
+            cache_strategy.apply(&mut body);
+        }
+
+        let mut betas = self.betas().unwrap_or_default();
+        betas.extend(extensions.betas.into_iter().flatten().map(Cow::Owned));
+        Ok(anthropoki::MessagesRequest {
+            anthropic_beta: (!betas.is_empty()).then_some(betas),
+            anthropic_version: self.version,
+            x_api_key: self.api_key.clone().into(),
+            body,
+            ..Default::default()
+        })
+    }
 }
 
 impl kepoki::backend::Backend for AnthropicBackend {
     type Model = Model;
     type MessagesEventStream = AnthropicMessageStream;
 
-    fn messages(
-        &self,
-        request: kepoki::backend::MessagesRequest<Self>,
-    ) -> Result<Self::MessagesEventStream, KepokiError> {
-        Ok(AnthropicMessageStream(
-            futures::executor::block_on(
-                self.client.messages_stream(&anthropoki::MessagesRequest {
-                    anthropic_beta: self
-                        .betas
-                        .as_ref()
-                        .map(|b| b.iter().map(|s| Cow::Borrowed(s.as_str())).collect()),
-                    anthropic_version: self.version,
-                    x_api_key: self.api_key.clone().into(),
-                    body: MessagesRequestBody {
-                        model: request.model,
-                        messages: request.messages.into_iter().map(convert_message).collect(),
-                        max_tokens: request.max_tokens,
-                        stream: true,
-                        system: request.system,
-                        temperature: request.temperature,
-                        tool_choice: request.tool_choice.map(convert_tool_choice),
-                        tools: request
-                            .tools
-                            .map(|tools| tools.into_iter().map(convert_tool).collect()),
-                        ..Default::default()
-                    },
-                }),
-            )
-            .map_err(|err| KepokiError::CustomError(Box::new(err)))
-            .unwrap(),
-        ))
+    fn parse_model(&self, name: &str) -> Option<Self::Model> {
+        let deserializer: StrDeserializer<'_, serde::de::value::Error> = name.into_deserializer();
+        Model::deserialize(deserializer).ok()
+    }
+
+    fn model_name(&self, model: &Self::Model) -> Option<String> {
+        serde_json::to_value(model)
+            .ok()?
+            .as_str()
+            .map(str::to_string)
+    }
+
+    fn attachment_limits(&self) -> AttachmentLimits {
+        AttachmentLimits {
+            max_image_bytes: Some(5 * 1024 * 1024),
+            // PDFs are limited by the 32MB request size.
+            max_document_bytes: Some(32 * 1024 * 1024),
+            ..Default::default()
+        }
+    }
+
+    fn messages<'a>(
+        &'a self,
+        request: kepoki::backend::MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, Self::MessagesEventStream> {
+        let request = match self.request(request, true) {
+            Ok(request) => request,
+            Err(err) => return Box::pin(async move { Err(err) }),
+        };
+        Box::pin(async move {
+            let stream = self
+                .client
+                .messages_stream(&request)
+                .await
+                .map_err(convert_error)?;
+            Ok(AnthropicMessageStream(stream))
+        })
+    }
+
+    /// Requests the whole response from the non-streaming endpoint.
+    fn complete<'a>(
+        &'a self,
+        request: kepoki::backend::MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, kepoki::backend::Message> {
+        Box::pin(async move {
+            let request = self.request(request, false)?;
+            let message = self
+                .client
+                .messages(&request)
+                .await
+                .map_err(convert_error)?;
+            Ok(reverse_convert_message(message))
+        })
+    }
+}
+
+/// The extension parameters of a request, see [`AnthropicBackend`].
+struct Extensions {
+    betas: Option<Vec<String>>,
+    top_k: Option<u32>,
+    top_p: Option<f32>,
+    service_tier: Option<ServiceTier>,
+    container: Option<String>,
+}
+
+impl Extensions {
+    fn of(
+        request: &kepoki::backend::MessagesRequest<'_, AnthropicBackend>,
+    ) -> Result<Self, KepokiError> {
+        Ok(Self {
+            betas: request.extension("betas")?,
+            top_k: request.extension("top_k")?,
+            top_p: request.extension("top_p")?,
+            service_tier: request.extension("service_tier")?,
+            container: request.extension("container")?,
+        })
     }
 }
 
@@ -127,6 +346,7 @@ fn convert_message(message: kepoki::backend::InputMessage) -> anthropoki::InputM
     anthropoki::InputMessage {
         role: convert_role(message.role),
         content: convert_content(message.content),
+        ..Default::default()
     }
 }
 
@@ -136,7 +356,7 @@ fn reverse_convert_message(message: anthropoki::Message) -> kepoki::backend::Mes
         content: reverse_convert_content(message.content),
         stop_reason: message.stop_reason.map(reverse_convert_stop_reason),
         stop_sequence: message.stop_sequence,
-        usage: None,
+        usage: message.usage.map(reverse_convert_usage),
     }
 }
 
@@ -172,6 +392,15 @@ fn convert_content_block(block: kepoki::backend::ContentBlock) -> anthropoki::Co
             source: convert_source(source),
             cache_control: None,
         },
+        kepoki::backend::ContentBlock::Document { source, title } => {
+            anthropoki::ContentBlock::Document {
+                source: convert_document_source(source),
+                cache_control: None,
+                citations: None,
+                context: None,
+                title,
+            }
+        }
         kepoki::backend::ContentBlock::ToolUse { id, input, name } => {
             anthropoki::ContentBlock::ToolUse {
                 id,
@@ -194,6 +423,45 @@ fn convert_content_block(block: kepoki::backend::ContentBlock) -> anthropoki::Co
             is_error,
             cache_control: None,
         },
+        kepoki::backend::ContentBlock::Thinking {
+            thinking,
+            signature,
+        } => anthropoki::ContentBlock::Thinking {
+            thinking,
+            signature: signature.unwrap_or_default(),
+        },
+        kepoki::backend::ContentBlock::RedactedThinking { data } => {
+            anthropoki::ContentBlock::RedactedThinking { data }
+        }
+        kepoki::backend::ContentBlock::ServerToolUse {
+            id,
+            name,
+            input,
+            server_name,
+        } => anthropoki::ContentBlock::McpToolUse {
+            id,
+            name,
+            server_name: server_name.unwrap_or_default(),
+            input: match input.trim() {
+                "" => serde_json::Value::Object(Default::default()),
+                input => serde_json::from_str(input)
+                    .unwrap_or_else(|_| serde_json::Value::String(input.to_string())),
+            },
+        },
+        kepoki::backend::ContentBlock::ServerToolResult {
+            tool_use_id,
+            content,
+            is_error,
+        } => anthropoki::ContentBlock::McpToolResult {
+            tool_use_id,
+            content: content.map(|c| {
+                c.into_iter()
+                    .map(convert_tool_result_content_block)
+                    .collect()
+            }),
+            is_error,
+        },
+        kepoki::backend::ContentBlock::Unknown { data } => anthropoki::ContentBlock::Unknown(data),
     }
 }
 
@@ -220,7 +488,49 @@ fn reverse_convert_content_block(block: anthropoki::ContentBlock) -> kepoki::bac
             }),
             is_error,
         },
-        _ => todo!("Unsupported content block type: {:?}", block),
+        anthropoki::ContentBlock::Thinking {
+            thinking,
+            signature,
+        } => kepoki::backend::ContentBlock::Thinking {
+            thinking,
+            signature: Some(signature),
+        },
+        anthropoki::ContentBlock::RedactedThinking { data } => {
+            kepoki::backend::ContentBlock::RedactedThinking { data }
+        }
+        anthropoki::ContentBlock::McpToolUse {
+            id,
+            name,
+            server_name,
+            input,
+        } => kepoki::backend::ContentBlock::ServerToolUse {
+            id,
+            name,
+            // Streamed input starts empty and arrives as deltas.
+            input: match input.as_object() {
+                Some(object) if object.is_empty() => String::new(),
+                _ => input.to_string(),
+            },
+            server_name: Some(server_name),
+        },
+        anthropoki::ContentBlock::McpToolResult {
+            tool_use_id,
+            content,
+            is_error,
+        } => kepoki::backend::ContentBlock::ServerToolResult {
+            tool_use_id,
+            content: content.map(|c| {
+                c.into_iter()
+                    .map(reverse_convert_tool_result_content_block)
+                    .collect()
+            }),
+            is_error,
+        },
+        anthropoki::ContentBlock::Unknown(data) => kepoki::backend::ContentBlock::Unknown { data },
+        // Blocks only sent in requests, such as documents, are kept as they are.
+        block => kepoki::backend::ContentBlock::Unknown {
+            data: serde_json::to_value(&block).unwrap_or_default(),
+        },
     }
 }
 
@@ -228,18 +538,37 @@ fn convert_source(source: kepoki::backend::ImageSource) -> anthropoki::ImageSour
     match source {
         kepoki::backend::ImageSource::Base64 { data, media_type } => {
             anthropoki::ImageSource::Base64 {
-                data,
+                data: anthropoki::Base64Data(data.shared()),
                 media_type: convert_media_type(media_type),
             }
         }
     }
 }
 
+fn convert_document_source(source: kepoki::backend::DocumentSource) -> anthropoki::DocumentSource {
+    match source {
+        kepoki::backend::DocumentSource::Base64 {
+            data,
+            media_type: kepoki::backend::DocumentMediaType::Pdf,
+        } => anthropoki::DocumentSource::PdfBase64 {
+            data: anthropoki::Base64Data(data.shared()),
+            media_type: anthropoki::DocumentMediaType::Pdf,
+        },
+        kepoki::backend::DocumentSource::Base64 {
+            data,
+            media_type: kepoki::backend::DocumentMediaType::PlainText,
+        } => anthropoki::DocumentSource::PlainText {
+            data: String::from_utf8_lossy(&data).into_owned(),
+            media_type: anthropoki::DocumentMediaType::Plain,
+        },
+    }
+}
+
 fn reverse_convert_source(source: anthropoki::ImageSource) -> kepoki::backend::ImageSource {
     match source {
         anthropoki::ImageSource::Base64 { data, media_type } => {
             kepoki::backend::ImageSource::Base64 {
-                data,
+                data: data.0.into(),
                 media_type: reverse_convert_media_type(media_type),
             }
         }
@@ -279,6 +608,9 @@ fn convert_tool_result_content_block(
                 source: convert_source(source),
             }
         }
+        kepoki::backend::ToolResultContentBlock::Unknown { data } => {
+            anthropoki::ToolResultContentBlock::Unknown(data)
+        }
     }
 }
 
@@ -294,6 +626,9 @@ fn reverse_convert_tool_result_content_block(
                 source: reverse_convert_source(source),
             }
         }
+        anthropoki::ToolResultContentBlock::Unknown(data) => {
+            kepoki::backend::ToolResultContentBlock::Unknown { data }
+        }
     }
 }
 
@@ -325,6 +660,7 @@ fn convert_tool<'a>(tool: kepoki::backend::Tool<'a>) -> anthropoki::Tool<'a> {
         description: tool.description,
         input_schema: tool.input_schema,
         cache_control: None,
+        ..Default::default()
     }
 }
 
@@ -336,24 +672,204 @@ fn reverse_convert_stop_reason(stop_reason: anthropoki::StopReason) -> kepoki::b
         anthropoki::StopReason::ToolUse => kepoki::backend::StopReason::ToolUse,
         anthropoki::StopReason::PauseTurn => kepoki::backend::StopReason::PauseTurn,
         anthropoki::StopReason::Refusal => kepoki::backend::StopReason::Refusal,
+        anthropoki::StopReason::Other(stop_reason) => {
+            kepoki::backend::StopReason::Other(stop_reason)
+        }
     }
 }
 
-fn reverse_convert_message_delta(delta: anthropoki::MessageDelta) -> kepoki::backend::MessageDelta {
+fn reverse_convert_message_delta(
+    delta: anthropoki::MessageDelta,
+    usage: Option<anthropoki::Usage>,
+) -> kepoki::backend::MessageDelta {
     kepoki::backend::MessageDelta {
         stop_reason: delta.stop_reason.map(reverse_convert_stop_reason),
         stop_sequence: delta.stop_sequence,
-        usage: None,
+        usage: usage.map(reverse_convert_usage),
+    }
+}
+
+/// Counts tokens read from and written to the cache as input tokens, which quotas and rate
+/// limits count them as.
+fn reverse_convert_usage(usage: anthropoki::Usage) -> kepoki::backend::Usage {
+    kepoki::backend::Usage {
+        input_tokens: usage.input_tokens
+            + usage.cache_creation_input_tokens.unwrap_or(0)
+            + usage.cache_read_input_tokens.unwrap_or(0),
+        output_tokens: usage.output_tokens,
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use kepoki::backend::Backend;
+    use kepoki::backend::MessageStream;
+    use kepoki::backend::metering::MeteredBackend;
+    use kepoki::backend::rate_limit::RateLimitMode;
+    use kepoki::backend::rate_limit::RateLimitedBackend;
+    use kepoki::backend::rate_limit::RateLimits;
+    use kepoki::mock::MockBackend;
+    use kepoki::mock::MockResponse;
+    use kepoki::mock::request;
+    use kepoki::runtime::Runtime;
     use kepoki::runtime::agent::AgentCommand;
     use kepoki::runtime::agent::AgentEvent;
+    use kepoki::runtime::quotas::Quota;
+    use kepoki::runtime::quotas::QuotaMetric;
+    use kepoki::runtime::quotas::QuotaScope;
+    use serde_json::json;
 
     use super::*;
 
+    /// A message of `text` streamed the way the Messages API streams it, converted like the
+    /// events of [`AnthropicMessageStream`].
+    fn anthropic_response(text: &str, input_tokens: u32, output_tokens: u32) -> MockResponse {
+        let events = [
+            json!({
+                "type": "message_start",
+                "message": {
+                    "id": "msg_1",
+                    "type": "message",
+                    "role": "assistant",
+                    "content": [],
+                    "model": "claude-sonnet-4-5-20250929",
+                    "stop_reason": null,
+                    "stop_sequence": null,
+                    "usage": {
+                        "input_tokens": input_tokens - 1,
+                        "cache_read_input_tokens": 1,
+                        "output_tokens": 1,
+                    },
+                },
+            }),
+            json!({
+                "type": "content_block_start",
+                "index": 0,
+                "content_block": { "type": "text", "text": "" },
+            }),
+            json!({
+                "type": "content_block_delta",
+                "index": 0,
+                "delta": { "type": "text_delta", "text": text },
+            }),
+            json!({ "type": "content_block_stop", "index": 0 }),
+            json!({
+                "type": "message_delta",
+                "delta": { "stop_reason": "end_turn", "stop_sequence": null },
+                "usage": { "output_tokens": output_tokens },
+            }),
+            json!({ "type": "message_stop" }),
+        ];
+        MockResponse::events(
+            events.into_iter().map(|event| {
+                reverse_convert_event(serde_json::from_value(event).unwrap()).unwrap()
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_meters_anthropic_usage() {
+        let backend = MeteredBackend::new(
+            MockBackend::new().with_response(anthropic_response("Hello", 1000, 200)),
+        );
+        let mut stream = backend
+            .messages(request("claude-sonnet-4-5-20250929".to_string()))
+            .await
+            .unwrap();
+        while stream.recv().await.unwrap().is_some() {}
+
+        let usage = backend.usage_report().total;
+        assert_eq!(usage.input_tokens, 1000);
+        assert_eq!(usage.output_tokens, 200);
+        assert!(usage.cost > 0.0);
+        assert_eq!(usage.unpriced_requests, 0);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits_charge_anthropic_usage() {
+        let backend = RateLimitedBackend::new(
+            MockBackend::new()
+                .with_response(anthropic_response("One", 1000, 200))
+                .with_response(anthropic_response("Two", 1000, 200)),
+            RateLimits {
+                requests_per_minute: None,
+                tokens_per_minute: Some(1000),
+            },
+        )
+        .with_mode(RateLimitMode::Fail);
+        let mut stream = backend
+            .messages(request("claude-sonnet-4-5-20250929".to_string()))
+            .await
+            .unwrap();
+        while stream.recv().await.unwrap().is_some() {}
+
+        // The response took 1200 tokens of the budget of 1000, which waits to be refilled.
+        let Err(KepokiError::Provider { kind, .. }) = backend
+            .messages(request("claude-sonnet-4-5-20250929".to_string()))
+            .await
+        else {
+            panic!("Expected the budget to be exhausted");
+        };
+        assert_eq!(kind, ProviderErrorKind::RateLimited);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_quotas_count_anthropic_usage() {
+        // 1200 tokens costing $0.006 a turn.
+        let backend = MockBackend::new()
+            .with_response(anthropic_response("One", 1000, 200))
+            .with_response(anthropic_response("Two", 1000, 200));
+        let scope = QuotaScope::Tenant("acme".to_string());
+        let mut runtime = Runtime::builder()
+            .with_tenant("acme")
+            .with_quota(
+                scope.clone(),
+                Quota {
+                    daily_tokens: Some(2000),
+                    daily_dollars: Some(0.01),
+                    ..Default::default()
+                },
+            )
+            .build();
+        let agent = runtime.spawn_agent(
+            backend.clone(),
+            "claude-sonnet-4-5-20250929".to_string(),
+            kepoki::agent::Agent::default(),
+        );
+
+        let mut warnings = Vec::new();
+        for message in ["One", "Two"] {
+            runtime
+                .send_as_owner(&agent, AgentCommand::UserMessage(message.to_string()))
+                .unwrap();
+            loop {
+                match runtime.recv().await.unwrap() {
+                    AgentEvent::QuotaWarning(warning) => warnings.push(warning.metric),
+                    AgentEvent::Message(_) => break,
+                    _ => (),
+                }
+            }
+        }
+        warnings.sort_by_key(|metric| format!("{metric}"));
+        assert_eq!(warnings, [QuotaMetric::Dollars, QuotaMetric::Tokens]);
+
+        // The limits were reached, the next request fails before it is sent and the agent
+        // terminates without an error handler.
+        runtime
+            .send_as_owner(&agent, AgentCommand::UserMessage("Three".to_string()))
+            .unwrap();
+        let error = loop {
+            match runtime.recv().await {
+                Ok(AgentEvent::Terminated(error)) => break error,
+                Ok(_) | Err(KepokiError::AgentNotFound(_)) => (),
+                Err(err) => panic!("{err}"),
+            }
+        };
+        assert!(error.contains("quota"), "{error}");
+        assert_eq!(backend.requests().len(), 2);
+        assert!(runtime.quota_usage()[&scope].tokens >= 2400);
+    }
+
     #[ignore]
     #[tokio::test]
     async fn test_message_stream() {
@@ -361,7 +877,7 @@ mod tests {
 
         let api_key = std::env::var("ANTHROPIC_API_KEY").unwrap();
         let backend = AnthropicBackend::new(api_key, ApiVersion::Latest, None);
-        let mut runtime = kepoki::runtime::Runtime::new();
+        let mut runtime = kepoki::runtime::Runtime::builder().build();
         let agent = runtime.spawn_agent(
             backend,
             Model::ClaudeSonnet3_5,
@@ -372,7 +888,7 @@ mod tests {
         );
 
         runtime
-            .send(
+            .send_as_owner(
                 &agent,
                 AgentCommand::UserMessage("Hello! Who are you?".to_string()),
             )
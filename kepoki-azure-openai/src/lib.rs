@@ -0,0 +1,229 @@
+//! Runs agents on models deployed to an Azure OpenAI resource.
+//!
+//! Azure addresses models by the name of their deployment rather than the name of the model,
+//! so [`AzureOpenAiBackend::Model`](kepoki::backend::Backend::Model) is a deployment name.
+//! Requests authenticate with a key of the resource or a Microsoft Entra ID (AAD) token.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use kepoki::backend::AttachmentLimits;
+use kepoki::backend::Backend;
+use kepoki::backend::DocumentMediaType;
+use kepoki::backend::EmbedFuture;
+use kepoki::backend::EmbeddingBackend;
+use kepoki::backend::MessagesFuture;
+use kepoki::backend::MessagesRequest;
+use kepoki_openai_compat::chat;
+use kepoki_openai_compat::chat::ChatCompletionStream;
+use kepoki_openai_compat::embeddings;
+use serde_json::Value;
+
+/// The API version requests use unless another is given.
+pub const DEFAULT_API_VERSION: &str = "2024-10-21";
+
+#[derive(Clone)]
+pub enum AzureCredential {
+    /// A key of the resource, sent in the `api-key` header.
+    ApiKey(String),
+    /// A Microsoft Entra ID access token, sent as a bearer token.
+    Token(String),
+    /// Returns a current Microsoft Entra ID access token for every request, for tokens that
+    /// are refreshed before they expire.
+    TokenProvider(Arc<dyn Fn() -> String + Send + Sync>),
+}
+
+impl Debug for AzureCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::ApiKey(_) => "ApiKey(..)",
+            Self::Token(_) => "Token(..)",
+            Self::TokenProvider(_) => "TokenProvider(..)",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AzureOpenAiBackend {
+    endpoint: String,
+    api_version: String,
+    credential: AzureCredential,
+    /// Deployments by the names of the models they serve.
+    deployments: HashMap<String, String>,
+    client: reqwest::Client,
+}
+
+impl AzureOpenAiBackend {
+    /// A backend for the resource at `endpoint`, such as `https://contoso.openai.azure.com`.
+    pub fn new(
+        endpoint: impl Into<String>,
+        api_version: impl Into<String>,
+        credential: AzureCredential,
+    ) -> Self {
+        Self {
+            endpoint: endpoint.into().trim_end_matches('/').to_string(),
+            api_version: api_version.into(),
+            credential,
+            deployments: HashMap::new(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Routes requests for `model`, such as a per-turn model override, to `deployment`.
+    ///
+    /// Names without a route are used as deployment names as is.
+    pub fn with_deployment(
+        mut self,
+        model: impl Into<String>,
+        deployment: impl Into<String>,
+    ) -> Self {
+        self.deployments.insert(model.into(), deployment.into());
+        self
+    }
+
+    /// The deployment requests for `model` are routed to.
+    pub fn deployment<'a>(&'a self, model: &'a str) -> &'a str {
+        self.deployments.get(model).map_or(model, String::as_str)
+    }
+
+    /// An authenticated request posting `body` to `path` of `deployment`, such as
+    /// `chat/completions`.
+    pub fn post(&self, deployment: &str, path: &str, body: Value) -> reqwest::RequestBuilder {
+        let builder = self
+            .client
+            .post(format!(
+                "{}/openai/deployments/{deployment}/{path}",
+                self.endpoint
+            ))
+            .query(&[("api-version", &self.api_version)])
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        match &self.credential {
+            AzureCredential::ApiKey(key) => builder.header("api-key", key),
+            AzureCredential::Token(token) => builder.bearer_auth(token),
+            AzureCredential::TokenProvider(provider) => builder.bearer_auth(provider()),
+        }
+    }
+}
+
+impl Backend for AzureOpenAiBackend {
+    /// The name of a deployment of the resource.
+    type Model = String;
+    type MessagesEventStream = ChatCompletionStream;
+
+    fn parse_model(&self, name: &str) -> Option<Self::Model> {
+        Some(self.deployment(name).to_string())
+    }
+
+    /// The model a deployment was routed to with [`AzureOpenAiBackend::with_deployment`],
+    /// deployments without a route are assumed to be named after their model.
+    fn model_name(&self, model: &Self::Model) -> Option<String> {
+        let routed = self
+            .deployments
+            .iter()
+            .find(|(_, deployment)| *deployment == model)
+            .map(|(name, _)| name.clone());
+        routed.or_else(|| Some(model.clone()))
+    }
+
+    fn attachment_limits(&self) -> AttachmentLimits {
+        AttachmentLimits {
+            max_image_bytes: Some(20 * 1024 * 1024),
+            image_media_types: Some(chat::IMAGE_MEDIA_TYPES),
+            document_media_types: Some(&[DocumentMediaType::PlainText]),
+            ..Default::default()
+        }
+    }
+
+    fn messages<'a>(
+        &'a self,
+        request: MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, Self::MessagesEventStream> {
+        let deployment = request.model.clone();
+        // The deployment determines the model, the body doesn't name it.
+        let body = chat::request_body(request, None);
+        Box::pin(chat::send(self.post(&deployment, "chat/completions", body)))
+    }
+}
+
+impl EmbeddingBackend for AzureOpenAiBackend {
+    /// Embeds with the deployment routed to `model`, or the deployment named `model`.
+    fn embed<'a>(&'a self, model: &'a str, texts: &'a [String]) -> EmbedFuture<'a> {
+        let body = embeddings::request_body(texts, None);
+        Box::pin(embeddings::send(self.post(
+            self.deployment(model),
+            "embeddings",
+            body,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+
+    const ENDPOINT: &str = "https://contoso.openai.azure.com/";
+
+    #[test]
+    fn test_deployment_routes() {
+        let backend = AzureOpenAiBackend::new(
+            ENDPOINT,
+            DEFAULT_API_VERSION,
+            AzureCredential::ApiKey("key".to_string()),
+        )
+        .with_deployment("gpt-4o", "chat-prod");
+
+        assert_eq!(backend.parse_model("gpt-4o").as_deref(), Some("chat-prod"));
+        assert_eq!(
+            backend.parse_model("gpt-4o-mini").as_deref(),
+            Some("gpt-4o-mini")
+        );
+        assert_eq!(
+            backend.model_name(&"chat-prod".to_string()).as_deref(),
+            Some("gpt-4o")
+        );
+        assert_eq!(
+            backend.model_name(&"gpt-4o-mini".to_string()).as_deref(),
+            Some("gpt-4o-mini")
+        );
+    }
+
+    #[test]
+    fn test_post_authenticates() {
+        let request = |credential| {
+            AzureOpenAiBackend::new(ENDPOINT, "2025-01-01", credential)
+                .post("chat-prod", "chat/completions", Value::Null)
+                .build()
+                .unwrap()
+        };
+
+        let with_key = request(AzureCredential::ApiKey("key".to_string()));
+        assert_eq!(
+            with_key.url().as_str(),
+            "https://contoso.openai.azure.com/openai/deployments/chat-prod/chat/completions?api-version=2025-01-01"
+        );
+        assert_eq!(with_key.headers()["api-key"], "key");
+        assert!(with_key.headers().get("authorization").is_none());
+
+        let with_token = request(AzureCredential::Token("token".to_string()));
+        assert_eq!(with_token.headers()["authorization"], "Bearer token");
+
+        let refreshed = Arc::new(AtomicU32::new(0));
+        let credential = AzureCredential::TokenProvider(Arc::new({
+            let refreshed = refreshed.clone();
+            move || format!("token-{}", refreshed.fetch_add(1, Ordering::Relaxed))
+        }));
+        let backend = AzureOpenAiBackend::new(ENDPOINT, "2025-01-01", credential);
+        for expected in ["Bearer token-0", "Bearer token-1"] {
+            let request = backend
+                .post("embed", "embeddings", Value::Null)
+                .build()
+                .unwrap();
+            assert_eq!(request.headers()["authorization"], expected);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Embeddings with the models Bedrock serves through `InvokeModel`.
+
+use aws_smithy_types::Blob;
+use kepoki::backend::EmbedFuture;
+use kepoki::backend::EmbeddingBackend;
+use kepoki::error::KepokiError;
+use serde_json::Value;
+use serde_json::json;
+
+use crate::BedrockBackend;
+use crate::convert_error;
+
+/// Embeds with Amazon Titan Text Embeddings models, or with Cohere Embed models such as
+/// `cohere.embed-english-v3`.
+impl EmbeddingBackend for BedrockBackend {
+    fn embed<'a>(&'a self, model: &'a str, texts: &'a [String]) -> EmbedFuture<'a> {
+        Box::pin(async move {
+            if model.contains("cohere.embed") {
+                let body = json!({ "texts": texts, "input_type": "search_document" });
+                let response = self.invoke(model, body).await?;
+                return response["embeddings"]
+                    .as_array()
+                    .map(|embeddings| embeddings.iter().map(parse_embedding).collect())
+                    .unwrap_or_else(|| Err(malformed()));
+            }
+
+            // Titan models embed one text per request.
+            let mut embeddings = Vec::with_capacity(texts.len());
+            for text in texts {
+                let response = self.invoke(model, json!({ "inputText": text })).await?;
+                embeddings.push(parse_embedding(&response["embedding"])?);
+            }
+            Ok(embeddings)
+        })
+    }
+}
+
+impl BedrockBackend {
+    async fn invoke(&self, model: &str, body: Value) -> Result<Value, KepokiError> {
+        let output = self
+            .client
+            .invoke_model()
+            .model_id(model)
+            .content_type("application/json")
+            .accept("application/json")
+            .body(Blob::new(body.to_string()))
+            .send()
+            .await
+            .map_err(convert_error)?;
+
+        serde_json::from_slice(output.body.as_ref())
+            .map_err(|err| KepokiError::CustomError(Box::new(err)))
+    }
+}
+
+fn parse_embedding(embedding: &Value) -> Result<Vec<f32>, KepokiError> {
+    embedding
+        .as_array()
+        .map(|values| {
+            values
+                .iter()
+                .map(|value| value.as_f64().unwrap_or_default() as f32)
+                .collect()
+        })
+        .ok_or_else(malformed)
+}
+
+fn malformed() -> KepokiError {
+    KepokiError::MalformedResponse("An embedding isn't an array of numbers".to_string())
+}
@@ -0,0 +1,144 @@
+//! Image generation with the models Bedrock serves through `InvokeModel`.
+
+use aws_sdk_bedrockruntime::Client;
+use aws_sdk_bedrockruntime::Config;
+use aws_smithy_types::Blob;
+use kepoki::backend::ImageMediaType;
+use kepoki::backend::Payload;
+use kepoki::error::KepokiError;
+use kepoki::tools::images::GeneratedImage;
+use kepoki::tools::images::ImageGenerationBackend;
+use kepoki::tools::images::ImageGenerationFuture;
+use kepoki::tools::images::ImageGenerationRequest;
+use serde_json::Value;
+use serde_json::json;
+
+/// Generates images with Amazon Titan Image Generator and Nova Canvas models, or with Stability
+/// AI models such as Stable Image Core and SD3.
+#[derive(Clone)]
+pub struct BedrockImageBackend {
+    client: Client,
+    model_id: String,
+}
+
+impl BedrockImageBackend {
+    pub fn new(config: Config, model_id: impl Into<String>) -> Self {
+        Self {
+            client: Client::from_conf(config),
+            model_id: model_id.into(),
+        }
+    }
+
+    async fn invoke(&self, body: Value) -> Result<Value, KepokiError> {
+        let output = self
+            .client
+            .invoke_model()
+            .model_id(&self.model_id)
+            .content_type("application/json")
+            .accept("application/json")
+            .body(Blob::new(body.to_string()))
+            .send()
+            .await
+            .map_err(|err| KepokiError::CustomError(Box::new(err)))?;
+
+        serde_json::from_slice(output.body.as_ref())
+            .map_err(|err| KepokiError::CustomError(Box::new(err)))
+    }
+}
+
+impl ImageGenerationBackend for BedrockImageBackend {
+    fn generate<'a>(&'a self, request: &'a ImageGenerationRequest) -> ImageGenerationFuture<'a> {
+        Box::pin(async move {
+            let model = self.model_id.rsplit('.').next().unwrap_or_default();
+            let images = if model.starts_with("titan-image") || model.starts_with("nova-canvas") {
+                let response = self.invoke(titan_body(request)).await?;
+                decode_images(&response["images"])?
+            } else if self.model_id.contains("stability.") {
+                // Stability models generate one image per request.
+                let mut images = Vec::new();
+                for _ in 0..request.count {
+                    let response = self.invoke(stability_body(request)).await?;
+                    images.extend(decode_images(&response["images"])?);
+                }
+                images
+            } else {
+                return Err(KepokiError::CustomError(
+                    format!("Unsupported image generation model: {}", self.model_id).into(),
+                ));
+            };
+
+            Ok(images)
+        })
+    }
+}
+
+fn titan_body(request: &ImageGenerationRequest) -> Value {
+    let mut text_to_image = json!({ "text": request.prompt });
+    if let Some(negative_prompt) = &request.negative_prompt {
+        text_to_image["negativeText"] = json!(negative_prompt);
+    }
+
+    let mut config = json!({ "numberOfImages": request.count });
+    if let (Some(width), Some(height)) = (request.width, request.height) {
+        config["width"] = json!(width);
+        config["height"] = json!(height);
+    }
+
+    json!({
+        "taskType": "TEXT_IMAGE",
+        "textToImageParams": text_to_image,
+        "imageGenerationConfig": config,
+    })
+}
+
+fn stability_body(request: &ImageGenerationRequest) -> Value {
+    let mut body = json!({ "prompt": request.prompt, "output_format": "png" });
+    if let Some(negative_prompt) = &request.negative_prompt {
+        body["negative_prompt"] = json!(negative_prompt);
+    }
+
+    // Stability models take an aspect ratio instead of a size.
+    if let (Some(width), Some(height)) = (request.width, request.height) {
+        body["aspect_ratio"] = json!(aspect_ratio(width, height));
+    }
+
+    body
+}
+
+/// The aspect ratio supported by Stability models closest to `width` by `height`.
+fn aspect_ratio(width: u32, height: u32) -> &'static str {
+    const RATIOS: [(&str, f64); 9] = [
+        ("21:9", 21.0 / 9.0),
+        ("16:9", 16.0 / 9.0),
+        ("3:2", 3.0 / 2.0),
+        ("5:4", 5.0 / 4.0),
+        ("1:1", 1.0),
+        ("4:5", 4.0 / 5.0),
+        ("2:3", 2.0 / 3.0),
+        ("9:16", 9.0 / 16.0),
+        ("9:21", 9.0 / 21.0),
+    ];
+
+    let ratio = f64::from(width) / f64::from(height.max(1));
+    RATIOS
+        .iter()
+        .min_by(|(_, a), (_, b)| (a - ratio).abs().total_cmp(&(b - ratio).abs()))
+        .map_or("1:1", |(name, _)| name)
+}
+
+/// Decodes the base64 encoded PNG images of a response.
+fn decode_images(images: &Value) -> Result<Vec<GeneratedImage>, KepokiError> {
+    images
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(|image| {
+            Ok(GeneratedImage {
+                data: Payload::from_base64(image)
+                    .map_err(|err| KepokiError::CustomError(Box::new(err)))?,
+                media_type: ImageMediaType::Png,
+            })
+        })
+        .collect()
+}
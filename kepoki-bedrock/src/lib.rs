@@ -1,5 +1,14 @@
+mod embeddings;
+pub mod images;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
 use aws_sdk_bedrockruntime::Client;
 use aws_sdk_bedrockruntime::Config;
+use aws_sdk_bedrockruntime::error::ProvideErrorMetadata;
+use aws_sdk_bedrockruntime::error::SdkError;
 use aws_sdk_bedrockruntime::primitives::event_stream::EventReceiver;
 use aws_sdk_bedrockruntime::types::AnyToolChoice;
 use aws_sdk_bedrockruntime::types::AutoToolChoice;
@@ -8,11 +17,20 @@ use aws_sdk_bedrockruntime::types::ContentBlockDelta;
 use aws_sdk_bedrockruntime::types::ContentBlockStart;
 use aws_sdk_bedrockruntime::types::ConversationRole;
 use aws_sdk_bedrockruntime::types::ConverseStreamOutput;
+use aws_sdk_bedrockruntime::types::DocumentBlock;
+use aws_sdk_bedrockruntime::types::DocumentFormat;
+use aws_sdk_bedrockruntime::types::DocumentSource;
+use aws_sdk_bedrockruntime::types::GuardrailStreamConfiguration;
+use aws_sdk_bedrockruntime::types::GuardrailTrace;
 use aws_sdk_bedrockruntime::types::ImageBlock;
 use aws_sdk_bedrockruntime::types::ImageFormat;
 use aws_sdk_bedrockruntime::types::ImageSource;
 use aws_sdk_bedrockruntime::types::InferenceConfiguration;
+use aws_sdk_bedrockruntime::types::ReasoningContentBlock;
+use aws_sdk_bedrockruntime::types::ReasoningContentBlockDelta;
+use aws_sdk_bedrockruntime::types::ReasoningTextBlock;
 use aws_sdk_bedrockruntime::types::SpecificToolChoice;
+use aws_sdk_bedrockruntime::types::StopReason;
 use aws_sdk_bedrockruntime::types::SystemContentBlock;
 use aws_sdk_bedrockruntime::types::ToolConfiguration;
 use aws_sdk_bedrockruntime::types::ToolResultBlock;
@@ -24,62 +42,215 @@ use aws_sdk_bedrockruntime::types::ToolUseBlockDelta;
 use aws_sdk_bedrockruntime::types::error::ConverseStreamOutputError;
 use aws_smithy_types::Blob;
 use aws_smithy_types::Document;
+use aws_smithy_types::Number;
+use kepoki::agent::Reasoning;
+use kepoki::backend::AttachmentLimits;
 use kepoki::backend::Backend;
 use kepoki::backend::Message;
+use kepoki::backend::MessageDelta;
 use kepoki::backend::MessageStream;
+use kepoki::backend::MessagesFuture;
+use kepoki::backend::MessagesResponseEvent;
+use kepoki::backend::RecvFuture;
+use kepoki::backend::Usage;
 use kepoki::error::KepokiError;
+use kepoki::error::ProviderErrorKind;
+use serde::Deserialize;
+use serde_json::Value;
 
 pub struct BedrockMessagesEventStream {
     stream: EventReceiver<ConverseStreamOutput, ConverseStreamOutputError>,
+    /// Converted events that haven't been returned yet.
+    pending: VecDeque<MessagesResponseEvent>,
+    /// Bedrock only announces tool use blocks, the other blocks start with their first delta.
+    started: HashSet<usize>,
+    /// Whether the message stopped, the stop is emitted after the usage in the metadata event.
+    stopped: bool,
+}
+
+impl BedrockMessagesEventStream {
+    fn start_block(&mut self, index: usize, content_block: kepoki::backend::ContentBlock) {
+        if self.started.insert(index) {
+            self.pending
+                .push_back(MessagesResponseEvent::ContentBlockStart(
+                    kepoki::backend::ContentBlockStart {
+                        index,
+                        content_block,
+                    },
+                ));
+        }
+    }
+}
+
+/// Classifies the errors Bedrock responds with, keeping others such as connection errors as
+/// they are.
+fn convert_error<E, R>(err: SdkError<E, R>) -> KepokiError
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+    R: std::fmt::Debug + Send + Sync + 'static,
+{
+    let Some(code) = err.as_service_error().and_then(ProvideErrorMetadata::code) else {
+        return KepokiError::CustomError(Box::new(err));
+    };
+    let kind = match code {
+        "ThrottlingException" => ProviderErrorKind::RateLimited,
+        "ServiceUnavailableException" | "ModelNotReadyException" => ProviderErrorKind::Overloaded,
+        "InternalServerException"
+        | "ModelStreamErrorException"
+        | "ModelErrorException"
+        | "ModelTimeoutException" => ProviderErrorKind::Server,
+        "ValidationException" => ProviderErrorKind::InvalidRequest,
+        "AccessDeniedException" => ProviderErrorKind::PermissionDenied,
+        "ResourceNotFoundException" => ProviderErrorKind::NotFound,
+        _ => ProviderErrorKind::Other,
+    };
+    let message = err
+        .as_service_error()
+        .and_then(ProvideErrorMetadata::message)
+        .unwrap_or(code)
+        .to_string();
+    KepokiError::Provider { kind, message }
 }
 
 impl MessageStream for BedrockMessagesEventStream {
-    fn recv(&mut self) -> Result<Option<Message>, KepokiError> {
-        loop {
-            let Some(output) = smol::block_on(self.stream.recv())
-                .map_err(|err| KepokiError::CustomError(Box::new(err)))?
-            else {
-                return Ok(None);
-            };
+    fn recv(&mut self) -> RecvFuture<'_> {
+        Box::pin(async move {
+            loop {
+                if let Some(event) = self.pending.pop_front() {
+                    return Ok(Some(event));
+                }
 
-            return Ok(Some(match output {
-                ConverseStreamOutput::ContentBlockDelta(content_block_delta_event) => {
-                    if let Some(content_block_delta_event) = content_block_delta_event.delta {
-                        Message::ContentBlockDelta(content_block_delta_event)
-                    } else {
-                        continue;
+                let Some(output) = self.stream.recv().await.map_err(convert_error)? else {
+                    if std::mem::take(&mut self.stopped) {
+                        return Ok(Some(MessagesResponseEvent::MessageStop));
                     }
-                    Message::ContentBlockDelta(content_block_delta_event)
-                }
-                ConverseStreamOutput::ContentBlockStart(content_block_start_event) => {
-                    if let Some(content_block_start_event) = content_block_start_event.start {
-                        match content_block_start_event {
-                            ContentBlockStart::ToolUse(tool_use_block_start) => {
-                                Message::ContentBlockStart(kepoki::backend::ContentBlock::ToolUse {
-                                    id: tool_use_block_start.tool_use_id,
-                                    name: tool_use_block_start.name,
+
+                    return Ok(None);
+                };
+
+                match output {
+                    ConverseStreamOutput::MessageStart(_) => {
+                        self.pending
+                            .push_back(MessagesResponseEvent::MessageStart(Message {
+                                id: String::new(),
+                                content: Vec::new(),
+                                stop_reason: None,
+                                stop_sequence: None,
+                                usage: None,
+                            }));
+                    }
+                    ConverseStreamOutput::ContentBlockStart(event) => {
+                        let index = event.content_block_index as usize;
+                        match event.start {
+                            Some(ContentBlockStart::ToolUse(start)) => self.start_block(
+                                index,
+                                kepoki::backend::ContentBlock::ToolUse {
+                                    id: start.tool_use_id,
                                     input: String::new(),
-                                })
+                                    name: start.name,
+                                },
+                            ),
+                            start => {
+                                tracing::warn!("Received unhandled content block start: {start:?}");
                             }
-                            _ => todo!(),
                         }
-                    } else {
-                        continue;
+                    }
+                    ConverseStreamOutput::ContentBlockDelta(event) => {
+                        let index = event.content_block_index as usize;
+                        let Some(delta) = event.delta else {
+                            continue;
+                        };
+
+                        let delta = match delta {
+                            ContentBlockDelta::Text(text) => {
+                                self.start_block(
+                                    index,
+                                    kepoki::backend::ContentBlock::Text {
+                                        text: String::new(),
+                                    },
+                                );
+                                kepoki::backend::ContentBlockDelta::Text { index, text }
+                            }
+                            ContentBlockDelta::ToolUse(ToolUseBlockDelta { input, .. }) => {
+                                kepoki::backend::ContentBlockDelta::InputJson {
+                                    index,
+                                    partial_json: input,
+                                }
+                            }
+                            ContentBlockDelta::ReasoningContent(
+                                ReasoningContentBlockDelta::Text(thinking),
+                            ) => {
+                                self.start_block(index, empty_thinking_block());
+                                kepoki::backend::ContentBlockDelta::Thinking { index, thinking }
+                            }
+                            ContentBlockDelta::ReasoningContent(
+                                ReasoningContentBlockDelta::Signature(signature),
+                            ) => {
+                                self.start_block(index, empty_thinking_block());
+                                kepoki::backend::ContentBlockDelta::Signature { index, signature }
+                            }
+                            delta => {
+                                tracing::warn!(
+                                    "Received unhandled content block delta type from Bedrock: {delta:?}"
+                                );
+                                continue;
+                            }
+                        };
+
+                        self.pending
+                            .push_back(MessagesResponseEvent::ContentBlockDelta(delta));
+                    }
+                    ConverseStreamOutput::ContentBlockStop(event) => {
+                        let index = event.content_block_index as usize;
+                        if self.started.contains(&index) {
+                            self.pending
+                                .push_back(MessagesResponseEvent::ContentBlockStop(
+                                    kepoki::backend::ContentBlockStop { index },
+                                ));
+                        }
+                    }
+                    ConverseStreamOutput::MessageStop(event) => {
+                        self.pending
+                            .push_back(MessagesResponseEvent::MessageDelta(MessageDelta {
+                                stop_reason: Some(convert_stop_reason(event.stop_reason)),
+                                stop_sequence: None,
+                                usage: None,
+                            }));
+                        self.stopped = true;
+                    }
+                    ConverseStreamOutput::Metadata(event) => {
+                        if let Some(usage) = event.usage {
+                            self.pending.push_back(MessagesResponseEvent::MessageDelta(
+                                MessageDelta {
+                                    stop_reason: None,
+                                    stop_sequence: None,
+                                    usage: Some(Usage {
+                                        input_tokens: usage.input_tokens.max(0) as u32,
+                                        output_tokens: usage.output_tokens.max(0) as u32,
+                                    }),
+                                },
+                            ));
+                        }
+
+                        if std::mem::take(&mut self.stopped) {
+                            self.pending.push_back(MessagesResponseEvent::MessageStop);
+                        }
+                    }
+                    _ => {
+                        tracing::warn!("Received unexpected event type from Bedrock: {:?}", output);
                     }
                 }
-                ConverseStreamOutput::ContentBlockStop(content_block_stop_event) => todo!(),
-                ConverseStreamOutput::MessageStart(message_start_event) => todo!(),
-                ConverseStreamOutput::MessageStop(message_stop_event) => todo!(),
-                ConverseStreamOutput::Metadata(converse_stream_metadata_event) => todo!(),
-                _ => {
-                    tracing::warn!("Received unexpected event type from Bedrock: {:?}", output);
-                    return Ok(None);
-                }
-            }));
-        }
+            }
+        })
     }
 }
 
+/// Sends requests to the Converse API of Amazon Bedrock.
+///
+/// Supports the extension parameters `guardrail_config`, an object of a `guardrail_identifier`,
+/// a `guardrail_version`, and optionally `trace`, and `additional_model_request_fields`, an
+/// object sent to the model as it is.
+#[derive(Clone)]
 pub struct BedrockBackend {
     client: Client,
 }
@@ -96,30 +267,74 @@ impl Backend for BedrockBackend {
     type Model = String;
     type MessagesEventStream = BedrockMessagesEventStream;
 
-    fn messages(
-        &self,
-        request: kepoki::backend::MessagesRequest<Self>,
-    ) -> Result<Self::MessagesEventStream, KepokiError> {
-        let mut request_builder = self
-            .client
-            .converse_stream()
-            .model_id(request.model.clone())
-            .inference_config(build_inference_config(&request)?)
-            .tool_config(build_tool_config(&request)?);
-
-        for message in &request.messages {
-            request_builder = request_builder.messages(build_message(message)?);
-        }
+    fn parse_model(&self, name: &str) -> Option<Self::Model> {
+        Some(name.to_string())
+    }
+
+    fn model_name(&self, model: &Self::Model) -> Option<String> {
+        Some(model.clone())
+    }
 
-        if let Some(system) = &request.system {
-            request_builder = request_builder.system(SystemContentBlock::Text(system.to_string()));
+    fn attachment_limits(&self) -> AttachmentLimits {
+        AttachmentLimits {
+            max_image_bytes: Some(3_750_000),
+            max_document_bytes: Some(4_500_000),
+            ..Default::default()
         }
+    }
 
-        let stream = smol::block_on(request_builder.send())
-            .map_err(|err| KepokiError::CustomError(Box::new(err)))?
-            .stream;
+    fn messages<'a>(
+        &'a self,
+        request: kepoki::backend::MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, Self::MessagesEventStream> {
+        Box::pin(async move {
+            let mut request_builder = self
+                .client
+                .converse_stream()
+                .model_id(request.model.clone())
+                .inference_config(build_inference_config(&request)?)
+                .tool_config(build_tool_config(&request)?);
 
-        Ok(BedrockMessagesEventStream { stream })
+            for message in &request.messages {
+                request_builder = request_builder.messages(build_message(message)?);
+            }
+
+            if let Some(system) = &request.system {
+                request_builder =
+                    request_builder.system(SystemContentBlock::Text(system.to_string()));
+            }
+
+            if let Some(user_id) = &request.user_id {
+                request_builder = request_builder.request_metadata("user_id", user_id.to_string());
+            }
+
+            if let Some(guardrail) = request.extension::<GuardrailConfig>("guardrail_config")? {
+                request_builder = request_builder.guardrail_config(guardrail.build()?);
+            }
+
+            let mut fields = request
+                .extension::<serde_json::Map<String, Value>>("additional_model_request_fields")?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, value)| (name, build_document(value)))
+                .collect::<HashMap<_, _>>();
+            if let Some(reasoning) = &request.reasoning {
+                fields.extend(build_reasoning_config(reasoning));
+            }
+            if !fields.is_empty() {
+                request_builder =
+                    request_builder.additional_model_request_fields(Document::Object(fields));
+            }
+
+            let stream = request_builder.send().await.map_err(convert_error)?.stream;
+
+            Ok(BedrockMessagesEventStream {
+                stream,
+                pending: VecDeque::new(),
+                started: HashSet::new(),
+                stopped: false,
+            })
+        })
     }
 }
 
@@ -127,15 +342,81 @@ fn build_inference_config(
     request: &kepoki::backend::MessagesRequest<BedrockBackend>,
 ) -> Result<InferenceConfiguration, KepokiError> {
     let mut inference = InferenceConfiguration::builder();
-    if let Ok(max_tokens) = i32::try_from(request.max_tokens) {
+    // Reasoning counts towards max tokens and can't be combined with a custom temperature.
+    let reasoning_tokens = request
+        .reasoning
+        .map(|reasoning| reasoning.budget_tokens())
+        .unwrap_or_default();
+    if let Ok(max_tokens) = i32::try_from(request.max_tokens + reasoning_tokens) {
         inference = inference.max_tokens(max_tokens);
     }
-    if let Some(temperature) = request.temperature {
+    if let (Some(temperature), None) = (request.temperature, request.reasoning) {
         inference = inference.temperature(temperature);
     }
+    if let Some(stop_sequences) = &request.stop_sequences {
+        for stop_sequence in stop_sequences {
+            inference = inference.stop_sequences(stop_sequence.to_string());
+        }
+    }
     Ok(inference.build())
 }
 
+fn build_reasoning_config(reasoning: &Reasoning) -> HashMap<String, Document> {
+    HashMap::from([(
+        "thinking".to_string(),
+        Document::Object(HashMap::from([
+            ("type".to_string(), Document::String("enabled".to_string())),
+            (
+                "budget_tokens".to_string(),
+                Document::Number(Number::PosInt(reasoning.budget_tokens().into())),
+            ),
+        ])),
+    )])
+}
+
+/// The `guardrail_config` extension parameter.
+#[derive(Deserialize)]
+struct GuardrailConfig {
+    guardrail_identifier: String,
+    guardrail_version: String,
+    #[serde(default)]
+    trace: bool,
+}
+
+impl GuardrailConfig {
+    fn build(self) -> Result<GuardrailStreamConfiguration, KepokiError> {
+        GuardrailStreamConfiguration::builder()
+            .guardrail_identifier(self.guardrail_identifier)
+            .guardrail_version(self.guardrail_version)
+            .trace(match self.trace {
+                true => GuardrailTrace::Enabled,
+                false => GuardrailTrace::Disabled,
+            })
+            .build()
+            .map_err(|err| KepokiError::CustomError(Box::new(err)))
+    }
+}
+
+fn build_document(value: Value) -> Document {
+    match value {
+        Value::Null => Document::Null,
+        Value::Bool(value) => Document::Bool(value),
+        Value::Number(number) => Document::Number(match (number.as_u64(), number.as_i64()) {
+            (Some(value), _) => Number::PosInt(value),
+            (None, Some(value)) => Number::NegInt(value),
+            (None, None) => Number::Float(number.as_f64().unwrap_or_default()),
+        }),
+        Value::String(value) => Document::String(value),
+        Value::Array(values) => Document::Array(values.into_iter().map(build_document).collect()),
+        Value::Object(fields) => Document::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name, build_document(value)))
+                .collect(),
+        ),
+    }
+}
+
 fn build_tool_config(
     request: &kepoki::backend::MessagesRequest<BedrockBackend>,
 ) -> Result<ToolConfiguration, KepokiError> {
@@ -187,11 +468,14 @@ fn build_message(
     });
 
     for content in &message.content {
-        builder = builder.content(match content {
+        let block = match content {
             kepoki::backend::ContentBlock::Text { text } => ContentBlock::Text(text.to_owned()),
             kepoki::backend::ContentBlock::Image { source } => {
                 ContentBlock::Image(build_image_block(source)?)
             }
+            kepoki::backend::ContentBlock::Document { source, title } => {
+                ContentBlock::Document(build_document_block(source, title.as_deref())?)
+            }
             kepoki::backend::ContentBlock::ToolUse { id, input, name } => {
                 ContentBlock::ToolUse(build_tool_use(id, input, name)?)
             }
@@ -200,7 +484,27 @@ fn build_message(
                 content,
                 is_error,
             } => ContentBlock::ToolResult(build_tool_result(tool_use_id, content, *is_error)?),
-        });
+            kepoki::backend::ContentBlock::Thinking {
+                thinking,
+                signature,
+            } => ContentBlock::ReasoningContent(ReasoningContentBlock::ReasoningText(
+                ReasoningTextBlock::builder()
+                    .text(thinking.to_owned())
+                    .set_signature(signature.clone())
+                    .build()
+                    .map_err(|err| KepokiError::CustomError(Box::new(err)))?,
+            )),
+            kepoki::backend::ContentBlock::RedactedThinking { data } => {
+                ContentBlock::ReasoningContent(ReasoningContentBlock::RedactedContent(Blob::new(
+                    data.as_bytes(),
+                )))
+            }
+            // Produced by another provider, Bedrock has no equivalent to send them back as.
+            kepoki::backend::ContentBlock::ServerToolUse { .. }
+            | kepoki::backend::ContentBlock::ServerToolResult { .. }
+            | kepoki::backend::ContentBlock::Unknown { .. } => continue,
+        };
+        builder = builder.content(block);
     }
 
     builder
@@ -219,7 +523,43 @@ fn build_image_block(source: &kepoki::backend::ImageSource) -> Result<ImageBlock
                 kepoki::backend::ImageMediaType::Webp => ImageFormat::Webp,
             });
 
-            builder = builder.source(ImageSource::Bytes(Blob::new(data.as_bytes())))
+            builder = builder.source(ImageSource::Bytes(Blob::new(data.to_vec())))
+        }
+    }
+
+    builder
+        .build()
+        .map_err(|err| KepokiError::CustomError(Box::new(err)))
+}
+
+fn build_document_block(
+    source: &kepoki::backend::DocumentSource,
+    title: Option<&str>,
+) -> Result<DocumentBlock, KepokiError> {
+    // Document names may only contain alphanumerics, single spaces, hyphens, parentheses, and
+    // square brackets.
+    let name = title
+        .map(|title| {
+            title
+                .chars()
+                .map(|c| match c.is_ascii_alphanumeric() || "-()[]".contains(c) {
+                    true => c,
+                    false => '-',
+                })
+                .collect::<String>()
+        })
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "document".to_string());
+
+    let mut builder = DocumentBlock::builder().name(name);
+    match source {
+        kepoki::backend::DocumentSource::Base64 { data, media_type } => {
+            builder = builder
+                .format(match media_type {
+                    kepoki::backend::DocumentMediaType::Pdf => DocumentFormat::Pdf,
+                    kepoki::backend::DocumentMediaType::PlainText => DocumentFormat::Txt,
+                })
+                .source(DocumentSource::Bytes(Blob::new(data.to_vec())));
         }
     }
 
@@ -250,14 +590,16 @@ fn build_tool_result(
 
     if let Some(content) = content {
         for content in content.iter() {
-            builder = builder.content(match content {
+            let content = match content {
                 kepoki::backend::ToolResultContentBlock::Text { text } => {
                     ToolResultContentBlock::Text(text.to_owned())
                 }
                 kepoki::backend::ToolResultContentBlock::Image { source } => {
                     ToolResultContentBlock::Image(build_image_block(source)?)
                 }
-            });
+                kepoki::backend::ToolResultContentBlock::Unknown { .. } => continue,
+            };
+            builder = builder.content(content);
         }
     }
 
@@ -273,25 +615,22 @@ fn build_tool_result(
         .map_err(|err| KepokiError::CustomError(Box::new(err)))
 }
 
-fn convert_content_block_delta(
-    content_block_delta: ContentBlockDelta,
-) -> Option<kepoki::backend::ContentBlock> {
-    Some(match content_block_delta {
-        ContentBlockDelta::Text(text) => kepoki::backend::ContentBlock::Text { text },
-        ContentBlockDelta::ToolUse(ToolUseBlockDelta { input, .. }) => {
-            kepoki::backend::ContentBlock::ToolUse {
-                id: (),
-                input,
-                name: (),
-            }
-        }
-        _ => {
-            tracing::warn!(
-                "Received unhandled content block delta type from Bedrock: {:?}",
-                content_block_delta
-            );
+fn empty_thinking_block() -> kepoki::backend::ContentBlock {
+    kepoki::backend::ContentBlock::Thinking {
+        thinking: String::new(),
+        signature: None,
+    }
+}
 
-            return None;
+fn convert_stop_reason(stop_reason: StopReason) -> kepoki::backend::StopReason {
+    match stop_reason {
+        StopReason::EndTurn => kepoki::backend::StopReason::EndTurn,
+        StopReason::MaxTokens => kepoki::backend::StopReason::MaxTokens,
+        StopReason::StopSequence => kepoki::backend::StopReason::StopSequence,
+        StopReason::ToolUse => kepoki::backend::StopReason::ToolUse,
+        StopReason::ContentFiltered | StopReason::GuardrailIntervened => {
+            kepoki::backend::StopReason::Refusal
         }
-    })
+        stop_reason => kepoki::backend::StopReason::Other(stop_reason.as_str().to_string()),
+    }
 }
@@ -0,0 +1,180 @@
+//! Edits proposed by agents through the `propose_edit` tool, applied only once the user
+//! accepted them in the editor.
+
+use std::collections::HashMap;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use kepoki::backend::Tool;
+use kepoki::tools::BuiltinTool;
+use kepoki::tools::ToolContext;
+use kepoki::tools::ToolFuture;
+use kepoki::tools::ToolOutput;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+use crate::notification;
+
+/// A change to a file, sent to the editor to preview as a diff.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProposedEdit {
+    pub id: String,
+    pub session: String,
+    pub path: PathBuf,
+    /// The content of the file when the edit was proposed, empty for new files.
+    pub original: String,
+    pub proposed: String,
+}
+
+/// The user applying an edit, or the reason it wasn't applied.
+type Decision = oneshot::Sender<Result<(), String>>;
+
+/// Edits waiting for the decision of the user.
+#[derive(Clone, Default)]
+pub(crate) struct PendingEdits(Arc<Mutex<HashMap<String, (ProposedEdit, Decision)>>>);
+
+impl PendingEdits {
+    /// Writes the proposed content of an edit, unless the file changed since it was proposed.
+    pub(crate) fn apply(&self, id: &str) -> Result<PathBuf, String> {
+        let (edit, decision) = self.take(id)?;
+        let current = read_file(&edit.path).map_err(|err| err.to_string())?;
+        let result = match current == edit.original {
+            true => std::fs::write(&edit.path, &edit.proposed).map_err(|err| err.to_string()),
+            false => Err(format!(
+                "{} changed since the edit was proposed",
+                edit.path.display()
+            )),
+        };
+
+        let _ = decision.send(result.clone());
+        result.map(|()| edit.path)
+    }
+
+    pub(crate) fn reject(&self, id: &str, reason: Option<String>) -> Result<(), String> {
+        let (_, decision) = self.take(id)?;
+        let _ = decision.send(Err(
+            reason.unwrap_or_else(|| "The user rejected the edit".to_string())
+        ));
+        Ok(())
+    }
+
+    fn take(&self, id: &str) -> Result<(ProposedEdit, Decision), String> {
+        self.0
+            .lock()
+            .unwrap()
+            .remove(id)
+            .ok_or_else(|| format!("No pending edit with id {id}"))
+    }
+}
+
+/// Proposes replacing text in a file, waiting until the user applied or rejected the edit.
+pub(crate) struct ProposeEditTool {
+    pub(crate) session: String,
+    pub(crate) working_directory: PathBuf,
+    pub(crate) pending: PendingEdits,
+    pub(crate) notifications: UnboundedSender<Value>,
+}
+
+impl BuiltinTool for ProposeEditTool {
+    fn definition(&self) -> Tool<'static> {
+        Tool {
+            name: "propose_edit".into(),
+            input_schema: Some(
+                r#"{"type":"object","properties":{"path":{"type":"string"},"old_text":{"type":"string"},"new_text":{"type":"string"}},"required":["path","old_text","new_text"]}"#.into(),
+            ),
+            description: Some(
+                "Proposes replacing `old_text` with `new_text` in the file at `path`, relative to the working directory. `old_text` must occur exactly once in the file, leave it empty to create a new file. The user reviews the edit in their editor and either applies or rejects it.".into(),
+            ),
+        }
+    }
+
+    fn call(&self, _context: ToolContext, input: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let field = |name| input.get(name).and_then(Value::as_str);
+            let (Some(path), Some(old_text), Some(new_text)) =
+                (field("path"), field("old_text"), field("new_text"))
+            else {
+                return ToolOutput::error("`path`, `old_text`, and `new_text` are required");
+            };
+
+            if Path::new(path).components().any(|component| {
+                matches!(
+                    component,
+                    Component::ParentDir | Component::RootDir | Component::Prefix(_)
+                )
+            }) {
+                return ToolOutput::error("Paths may not leave the working directory");
+            }
+
+            let path = self.working_directory.join(path);
+            let original = match read_file(&path) {
+                Ok(original) => original,
+                Err(err) => return ToolOutput::error(format!("Failed to read file: {err}")),
+            };
+            let proposed = match (old_text.is_empty(), original.matches(old_text).count()) {
+                (true, _) if original.is_empty() => new_text.to_string(),
+                (true, _) => return ToolOutput::error("The file exists, `old_text` is required"),
+                (false, 1) => original.replacen(old_text, new_text, 1),
+                (false, 0) => return ToolOutput::error("`old_text` does not occur in the file"),
+                (false, _) => {
+                    return ToolOutput::error(
+                        "`old_text` occurs more than once in the file, include more context",
+                    );
+                }
+            };
+
+            let edit = ProposedEdit {
+                id: uuid::Uuid::new_v4().to_string(),
+                session: self.session.clone(),
+                path,
+                original,
+                proposed,
+            };
+            let (sender, decision) = oneshot::channel();
+            self.pending
+                .0
+                .lock()
+                .unwrap()
+                .insert(edit.id.clone(), (edit.clone(), sender));
+            // Withdraws the edit if the call is cancelled, such as by its timeout.
+            let _withdraw = Withdraw {
+                pending: &self.pending,
+                id: &edit.id,
+            };
+            let _ = self
+                .notifications
+                .send(notification("edits/proposed", &edit));
+
+            match decision.await {
+                Ok(Ok(())) => ToolOutput::text("The user applied the edit"),
+                Ok(Err(reason)) => ToolOutput::error(format!("The edit was not applied: {reason}")),
+                Err(_) => ToolOutput::error("The editor disconnected before deciding on the edit"),
+            }
+        })
+    }
+}
+
+struct Withdraw<'a> {
+    pending: &'a PendingEdits,
+    id: &'a str,
+}
+
+impl Drop for Withdraw<'_> {
+    fn drop(&mut self) {
+        self.pending.0.lock().unwrap().remove(self.id);
+    }
+}
+
+/// The content of a file, empty if it doesn't exist.
+fn read_file(path: &Path) -> std::io::Result<String> {
+    match std::fs::read_to_string(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        result => result,
+    }
+}
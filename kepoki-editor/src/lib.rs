@@ -0,0 +1,510 @@
+//! Lets editor plugins embed kepoki agents, speaking JSON-RPC 2.0 over stdio with one message
+//! per line.
+//!
+//! Requests from the editor:
+//!
+//! * `session/start` `{ "workingDirectory"? }` starts an agent, returning `{ "session" }`.
+//! * `session/message` `{ "session", "text" }` sends a message to the agent.
+//! * `session/sendSelection` `{ "session", "path", "startLine", "endLine", "text", "message"? }`
+//!   sends a selection in a file as context, with an optional message about it.
+//! * `session/stop` `{ "session" }` stops the agent.
+//! * `edits/apply` `{ "id" }` writes a proposed edit to disk, returning `{ "path" }`.
+//! * `edits/reject` `{ "id", "reason"? }` discards a proposed edit.
+//!
+//! Notifications to the editor:
+//!
+//! * `session/textDelta` `{ "session", "text" }` as the agent responds.
+//! * `session/message` `{ "session", "text" }` once a response is complete.
+//! * `edits/proposed` with a [`ProposedEdit`], for the editor to preview as a diff.
+//! * `session/ended` `{ "session", "error"? }` once the agent stopped.
+//!
+//! Agents never write files themselves, they propose edits with the `propose_edit` tool and
+//! wait until the user applied or rejected them.
+
+mod edits;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use kepoki::agent::Agent;
+use kepoki::backend::Backend;
+use kepoki::backend::ContentBlock;
+use kepoki::backend::ContentBlockDelta;
+use kepoki::backend::Message;
+use kepoki::error::KepokiError;
+use kepoki::runtime::Runtime;
+use kepoki::runtime::agent::AgentCommand;
+use kepoki::runtime::agent::AgentEvent;
+use serde::Deserialize;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use serde_json::json;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::unbounded_channel;
+
+use crate::edits::PendingEdits;
+use crate::edits::ProposeEditTool;
+pub use crate::edits::ProposedEdit;
+
+/// How long an agent waits for the user to decide on a proposed edit.
+const EDIT_REVIEW_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StartParams {
+    working_directory: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct MessageParams {
+    session: String,
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SelectionParams {
+    session: String,
+    path: String,
+    start_line: u32,
+    end_line: u32,
+    text: String,
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SessionParams {
+    session: String,
+}
+
+#[derive(Deserialize)]
+struct EditParams {
+    id: String,
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SessionText<'a> {
+    session: &'a str,
+    text: &'a str,
+}
+
+pub struct EditorServer<B: Backend + Clone> {
+    backend: B,
+    model: B::Model,
+    agent: Agent,
+    sessions: HashMap<String, UnboundedSender<AgentCommand>>,
+    pending: PendingEdits,
+}
+
+impl<B: Backend + Clone> EditorServer<B> {
+    /// A server starting a copy of `agent` for every session.
+    ///
+    /// The agent is given the `propose_edit` tool, and the working directory of its session.
+    pub fn new(backend: B, model: B::Model, agent: Agent) -> Self {
+        Self {
+            backend,
+            model,
+            agent,
+            sessions: HashMap::new(),
+            pending: PendingEdits::default(),
+        }
+    }
+
+    /// Serves the editor on stdin and stdout until stdin is closed.
+    pub async fn serve_stdio(self) -> Result<(), KepokiError> {
+        self.serve(tokio::io::stdin(), tokio::io::stdout()).await
+    }
+
+    pub async fn serve(
+        mut self,
+        input: impl AsyncRead + Unpin,
+        mut output: impl AsyncWrite + Unpin + Send + 'static,
+    ) -> Result<(), KepokiError> {
+        let (messages, mut outgoing) = unbounded_channel::<Value>();
+        let writer = tokio::spawn(async move {
+            while let Some(message) = outgoing.recv().await {
+                let line = format!("{message}\n");
+                if output.write_all(line.as_bytes()).await.is_err() || output.flush().await.is_err()
+                {
+                    return;
+                }
+            }
+        });
+
+        let mut lines = BufReader::new(input).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let request = match serde_json::from_str::<Value>(&line) {
+                Ok(request) => request,
+                Err(err) => {
+                    let _ = messages.send(error(Value::Null, PARSE_ERROR, err.to_string()));
+                    continue;
+                }
+            };
+            let id = request.get("id").cloned();
+            let method = request.get("method").and_then(Value::as_str).unwrap_or("");
+            let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+            let response = self.handle(method, params, &messages);
+            // Requests without an id are notifications, which get no response.
+            if let Some(id) = id {
+                let _ = messages.send(match response {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, message)) => error(id, code, message),
+                });
+            }
+        }
+
+        self.sessions.clear();
+        drop(messages);
+        let _ = writer.await;
+        Ok(())
+    }
+
+    fn handle(
+        &mut self,
+        method: &str,
+        params: Value,
+        messages: &UnboundedSender<Value>,
+    ) -> Result<Value, (i64, String)> {
+        match method {
+            "session/start" => {
+                let params = parse::<StartParams>(params)?;
+                let session = uuid::Uuid::new_v4().to_string();
+                let working_directory = params
+                    .working_directory
+                    .or_else(|| self.agent.working_directory.clone())
+                    .or_else(|| std::env::current_dir().ok())
+                    .unwrap_or_default();
+
+                let mut agent = self.agent.clone();
+                agent.working_directory = Some(working_directory.clone());
+                let tool = "propose_edit".parse().unwrap();
+                if !agent.tools.contains(&tool) {
+                    agent.tools.push(tool.clone());
+                }
+                agent
+                    .tool_timeouts
+                    .tools
+                    .entry(tool)
+                    .or_insert(EDIT_REVIEW_TIMEOUT.as_secs());
+
+                let runtime = Runtime::builder()
+                    .with_tool(ProposeEditTool {
+                        session: session.clone(),
+                        working_directory,
+                        pending: self.pending.clone(),
+                        notifications: messages.clone(),
+                    })
+                    .build();
+                let (commands, receiver) = unbounded_channel();
+                tokio::spawn(run_session(
+                    runtime,
+                    self.backend.clone(),
+                    self.model.clone(),
+                    agent,
+                    session.clone(),
+                    receiver,
+                    messages.clone(),
+                ));
+
+                self.sessions.insert(session.clone(), commands);
+                Ok(json!({ "session": session }))
+            }
+            "session/message" => {
+                let params = parse::<MessageParams>(params)?;
+                self.send(&params.session, AgentCommand::UserMessage(params.text))
+            }
+            "session/sendSelection" => {
+                let params = parse::<SelectionParams>(params)?;
+                let mut text = format!(
+                    "<selection path=\"{}\" lines=\"{}-{}\">\n{}\n</selection>",
+                    params.path, params.start_line, params.end_line, params.text
+                );
+                if let Some(message) = params.message {
+                    text.push_str(&format!("\n\n{message}"));
+                }
+                self.send(&params.session, AgentCommand::UserMessage(text))
+            }
+            "session/stop" => {
+                let params = parse::<SessionParams>(params)?;
+                match self.sessions.remove(&params.session) {
+                    // The session exits once its command channel is closed.
+                    Some(_) => Ok(Value::Null),
+                    None => Err(unknown_session(&params.session)),
+                }
+            }
+            "edits/apply" => {
+                let params = parse::<EditParams>(params)?;
+                let path = self
+                    .pending
+                    .apply(&params.id)
+                    .map_err(|err| (INVALID_PARAMS, err))?;
+                Ok(json!({ "path": path }))
+            }
+            "edits/reject" => {
+                let params = parse::<EditParams>(params)?;
+                self.pending
+                    .reject(&params.id, params.reason)
+                    .map_err(|err| (INVALID_PARAMS, err))?;
+                Ok(Value::Null)
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method `{method}`"))),
+        }
+    }
+
+    fn send(&self, session: &str, command: AgentCommand) -> Result<Value, (i64, String)> {
+        match self.sessions.get(session) {
+            Some(commands) if commands.send(command).is_ok() => Ok(Value::Null),
+            _ => Err(unknown_session(session)),
+        }
+    }
+}
+
+/// Runs the agent of a session, forwarding commands to it and its responses to the editor.
+async fn run_session<B: Backend>(
+    mut runtime: Runtime,
+    backend: B,
+    model: B::Model,
+    agent: Agent,
+    session: String,
+    mut commands: UnboundedReceiver<AgentCommand>,
+    messages: UnboundedSender<Value>,
+) {
+    let handle = runtime.spawn_agent(backend, model, agent);
+    let mut stopping = false;
+    let error = loop {
+        tokio::select! {
+            command = commands.recv(), if !stopping => {
+                let command = command.unwrap_or_else(|| {
+                    stopping = true;
+                    AgentCommand::Exit
+                });
+                if let Err(err) = runtime.send_as_owner(&handle, command) {
+                    break Some(err.to_string());
+                }
+            }
+            event = runtime.recv() => match event {
+                Ok(AgentEvent::ContentBlockDelta(ContentBlockDelta::Text { text, .. })) => {
+                    let text = SessionText { session: &session, text: &text };
+                    let _ = messages.send(notification("session/textDelta", &text));
+                }
+                Ok(AgentEvent::Message(message)) => {
+                    let text = SessionText { session: &session, text: &message_text(&message) };
+                    let _ = messages.send(notification("session/message", &text));
+                }
+                Ok(AgentEvent::Completed(_)) => break None,
+                Ok(AgentEvent::Terminated(error)) => break Some(error),
+                Ok(_) => {}
+                Err(err) => break Some(err.to_string()),
+            },
+        }
+    };
+
+    let _ = messages.send(notification(
+        "session/ended",
+        &json!({ "session": session, "error": error }),
+    ));
+}
+
+fn message_text(message: &Message) -> String {
+    message
+        .content
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::Text { text } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn parse<T: DeserializeOwned>(params: Value) -> Result<T, (i64, String)> {
+    serde_json::from_value(params).map_err(|err| (INVALID_PARAMS, err.to_string()))
+}
+
+fn unknown_session(session: &str) -> (i64, String) {
+    (INVALID_PARAMS, format!("Unknown session `{session}`"))
+}
+
+fn error(id: Value, code: i64, message: String) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+pub(crate) fn notification(method: &str, params: &impl Serialize) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+#[cfg(test)]
+mod tests {
+    use kepoki::mock::MockBackend;
+    use kepoki::mock::MockResponse;
+    use tokio::io::DuplexStream;
+    use tokio::io::Lines;
+
+    use super::*;
+
+    struct Editor {
+        input: DuplexStream,
+        output: Lines<BufReader<DuplexStream>>,
+    }
+
+    impl Editor {
+        async fn send(&mut self, message: Value) {
+            let line = format!("{message}\n");
+            self.input.write_all(line.as_bytes()).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Value {
+            let line = self.output.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        /// Skips notifications until the one of `method`.
+        async fn notification(&mut self, method: &str) -> Value {
+            loop {
+                let message = self.recv().await;
+                if message["method"] == method {
+                    return message["params"].clone();
+                }
+            }
+        }
+    }
+
+    fn start(backend: MockBackend) -> Editor {
+        let (input, server_input) = tokio::io::duplex(4096);
+        let (server_output, output) = tokio::io::duplex(4096);
+        let server = EditorServer::new(backend, "mock".to_string(), Agent::default());
+        tokio::spawn(server.serve(server_input, server_output));
+        Editor {
+            input,
+            output: BufReader::new(output).lines(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_session_applies_proposed_edit() {
+        let directory =
+            std::env::temp_dir().join(format!("kepoki-editor-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("notes.txt"), "Hello wrld").unwrap();
+        let backend = MockBackend::new()
+            .with_response(MockResponse::tool_use(
+                "call_1",
+                "propose_edit",
+                json!({ "path": "notes.txt", "old_text": "wrld", "new_text": "world" }),
+            ))
+            .with_response(MockResponse::text("Fixed the typo."));
+        let mut editor = start(backend);
+
+        editor
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "session/start",
+                "params": { "workingDirectory": directory },
+            }))
+            .await;
+        let response = editor.recv().await;
+        assert_eq!(response["id"], 1);
+        let session = response["result"]["session"].clone();
+
+        editor
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": "session/sendSelection",
+                "params": {
+                    "session": session,
+                    "path": "notes.txt",
+                    "startLine": 1,
+                    "endLine": 1,
+                    "text": "Hello wrld",
+                    "message": "Fix the typo",
+                },
+            }))
+            .await;
+        let edit = editor.notification("edits/proposed").await;
+        assert_eq!(edit["original"], "Hello wrld");
+        assert_eq!(edit["proposed"], "Hello world");
+
+        editor
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": 3,
+                "method": "edits/apply",
+                "params": { "id": edit["id"] },
+            }))
+            .await;
+        // The message calling the tool has no text.
+        assert_eq!(editor.notification("session/message").await["text"], "");
+        let message = editor.notification("session/message").await;
+        assert_eq!(message["text"], "Fixed the typo.");
+        assert_eq!(
+            std::fs::read_to_string(directory.join("notes.txt")).unwrap(),
+            "Hello world"
+        );
+
+        editor
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": 4,
+                "method": "session/stop",
+                "params": { "session": session },
+            }))
+            .await;
+        let ended = editor.notification("session/ended").await;
+        assert_eq!(ended, json!({ "session": session, "error": null }));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_errors() {
+        let mut editor = start(MockBackend::new());
+
+        // Notifications get no response, even for unknown methods.
+        editor
+            .send(json!({ "jsonrpc": "2.0", "method": "session/unknown" }))
+            .await;
+        editor.input.write_all(b"{\n").await.unwrap();
+        assert_eq!(editor.recv().await["error"]["code"], PARSE_ERROR);
+
+        editor
+            .send(json!({ "jsonrpc": "2.0", "id": 0, "method": "session/unknown" }))
+            .await;
+        assert_eq!(editor.recv().await["error"]["code"], METHOD_NOT_FOUND);
+
+        editor
+            .send(json!({ "jsonrpc": "2.0", "id": 1, "method": "session/message", "params": {} }))
+            .await;
+        assert_eq!(editor.recv().await["error"]["code"], INVALID_PARAMS);
+
+        editor
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": "session/message",
+                "params": { "session": "missing", "text": "Hi" },
+            }))
+            .await;
+        let response = editor.recv().await;
+        assert_eq!(response["id"], 2);
+        assert_eq!(response["error"]["message"], "Unknown session `missing`");
+    }
+}
@@ -0,0 +1,792 @@
+//! Runs agents on models served by [Ollama](https://ollama.com), fully offline.
+//!
+//! Requests go to the `/api/chat` endpoint of the server and are streamed back as they are
+//! generated. Tools are supported for models that support tool calling, Ollama returns each
+//! call whole rather than streaming its input.
+//!
+//! Extension parameters of requests are sent as model options, such as `top_k`, `seed`, or
+//! `num_ctx`. Embeddings are requested from the `/api/embed` endpoint.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use kepoki::backend::AttachmentLimits;
+use kepoki::backend::Backend;
+use kepoki::backend::ContentBlock;
+use kepoki::backend::ContentBlockDelta;
+use kepoki::backend::ContentBlockStart;
+use kepoki::backend::ContentBlockStop;
+use kepoki::backend::DocumentMediaType;
+use kepoki::backend::DocumentSource;
+use kepoki::backend::EmbedFuture;
+use kepoki::backend::EmbeddingBackend;
+use kepoki::backend::ImageMediaType;
+use kepoki::backend::ImageSource;
+use kepoki::backend::InputMessage;
+use kepoki::backend::Message;
+use kepoki::backend::MessageDelta;
+use kepoki::backend::MessageStream;
+use kepoki::backend::MessagesFuture;
+use kepoki::backend::MessagesRequest;
+use kepoki::backend::MessagesResponseEvent;
+use kepoki::backend::RecvFuture;
+use kepoki::backend::Role;
+use kepoki::backend::StopReason;
+use kepoki::backend::ToolResultContentBlock;
+use kepoki::backend::Usage;
+use kepoki::error::KepokiError;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Numbers tool calls, which Ollama doesn't identify, uniquely across responses.
+static NEXT_CALL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The address Ollama listens on by default.
+pub const DEFAULT_BASE_URL: &str = "http://localhost:11434";
+
+#[derive(Debug, thiserror::Error)]
+pub enum OllamaError {
+    #[error("Request to Ollama failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Ollama returned an error: {0}")]
+    Api(String),
+    #[error("Invalid response from Ollama: {0}")]
+    InvalidResponse(#[from] serde_json::Error),
+}
+
+impl From<OllamaError> for KepokiError {
+    fn from(err: OllamaError) -> Self {
+        KepokiError::CustomError(Box::new(err))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OllamaBackend {
+    base_url: String,
+    keep_alive: Option<String>,
+    client: reqwest::Client,
+}
+
+impl OllamaBackend {
+    /// A backend for the Ollama server at [`DEFAULT_BASE_URL`].
+    pub fn new() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            keep_alive: None,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Sends requests to the Ollama server at `base_url`, such as `http://gpu-box:11434`.
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// How long the server keeps the model loaded after a request, such as `10m`, `None`
+    /// leaves it to the server's defaults.
+    pub fn with_keep_alive(mut self, keep_alive: Option<String>) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+}
+
+impl Default for OllamaBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for OllamaBackend {
+    /// The name of a model pulled into the server, such as `llama3.1:8b`.
+    type Model = String;
+    type MessagesEventStream = OllamaMessageStream;
+
+    fn parse_model(&self, name: &str) -> Option<Self::Model> {
+        Some(name.to_string())
+    }
+
+    fn model_name(&self, model: &Self::Model) -> Option<String> {
+        Some(model.clone())
+    }
+
+    fn attachment_limits(&self) -> AttachmentLimits {
+        AttachmentLimits {
+            image_media_types: Some(&[ImageMediaType::Jpeg, ImageMediaType::Png]),
+            // Text documents are sent inline, Ollama has no document input.
+            document_media_types: Some(&[DocumentMediaType::PlainText]),
+            ..Default::default()
+        }
+    }
+
+    fn messages<'a>(
+        &'a self,
+        request: MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, Self::MessagesEventStream> {
+        Box::pin(async move {
+            let body = build_request(request, self.keep_alive.clone());
+            let response = self
+                .client
+                .post(format!("{}/api/chat", self.base_url))
+                .json(&body)
+                .send()
+                .await
+                .map_err(OllamaError::from)?;
+
+            if !response.status().is_success() {
+                return Err(api_error(response).await.into());
+            }
+
+            Ok(OllamaMessageStream::new(response))
+        })
+    }
+}
+
+impl EmbeddingBackend for OllamaBackend {
+    fn embed<'a>(&'a self, model: &'a str, texts: &'a [String]) -> EmbedFuture<'a> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .post(format!("{}/api/embed", self.base_url))
+                .json(&EmbedRequest {
+                    model,
+                    input: texts,
+                    keep_alive: self.keep_alive.as_deref(),
+                })
+                .send()
+                .await
+                .map_err(OllamaError::from)?;
+
+            if !response.status().is_success() {
+                return Err(api_error(response).await.into());
+            }
+
+            let text = response.text().await.map_err(OllamaError::from)?;
+            let response =
+                serde_json::from_str::<EmbedResponse>(&text).map_err(OllamaError::from)?;
+            Ok(response.embeddings)
+        })
+    }
+}
+
+/// The error of a response with an error status, with the message of its body if it has one.
+async fn api_error(response: reqwest::Response) -> OllamaError {
+    let status = response.status();
+    let text = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<ChatChunk>(&text)
+        .ok()
+        .and_then(|chunk| chunk.error)
+        .unwrap_or(text);
+    OllamaError::Api(format!("{status}: {message}"))
+}
+
+#[derive(Debug, Serialize)]
+struct EmbedRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<&'a str>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbedResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Value>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    think: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<String>,
+    options: Options,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Options {
+    num_predict: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(flatten)]
+    extensions: HashMap<String, Value>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ChatMessage {
+    #[serde(default)]
+    role: String,
+    #[serde(default)]
+    content: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    thinking: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    /// The tool a `tool` message holds the result of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ToolCall {
+    function: FunctionCall,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct FunctionCall {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatChunk {
+    #[serde(default)]
+    message: Option<ChatMessage>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    done_reason: Option<String>,
+    #[serde(default)]
+    prompt_eval_count: Option<u32>,
+    #[serde(default)]
+    eval_count: Option<u32>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+fn build_request(
+    request: MessagesRequest<OllamaBackend>,
+    keep_alive: Option<String>,
+) -> ChatRequest {
+    let mut messages = Vec::new();
+    if let Some(system) = request.system {
+        messages.push(ChatMessage {
+            role: "system".to_string(),
+            content: system.into_owned(),
+            ..Default::default()
+        });
+    }
+
+    // Tool results only name the call they answer, Ollama identifies them by the tool's name.
+    let mut tool_names = HashMap::new();
+    for message in request.messages {
+        for block in &message.content {
+            if let ContentBlock::ToolUse { id, name, .. } = block {
+                tool_names.insert(id.clone(), name.clone());
+            }
+        }
+        messages.extend(build_messages(message, &tool_names));
+    }
+
+    let tools = request
+        .tools
+        .into_iter()
+        .flatten()
+        .map(|tool| {
+            let parameters = tool
+                .input_schema
+                .and_then(|schema| serde_json::from_str::<Value>(&schema).ok())
+                .unwrap_or_else(|| serde_json::json!({ "type": "object", "properties": {} }));
+            serde_json::json!({
+                "type": "function",
+                "function": {
+                    "name": tool.name,
+                    "description": tool.description.unwrap_or_default(),
+                    "parameters": parameters,
+                },
+            })
+        })
+        .collect();
+
+    // Options set by the request take precedence over extensions of the same name.
+    let mut extensions = request.extensions;
+    extensions.retain(|name, _| !matches!(name.as_str(), "num_predict" | "temperature" | "stop"));
+
+    ChatRequest {
+        model: request.model,
+        messages,
+        tools,
+        stream: true,
+        think: request.reasoning.map(|_| true),
+        keep_alive,
+        options: Options {
+            num_predict: request.max_tokens,
+            temperature: request.temperature,
+            stop: request
+                .stop_sequences
+                .into_iter()
+                .flatten()
+                .map(|stop| stop.into_owned())
+                .collect(),
+            extensions,
+        },
+    }
+}
+
+/// Converts a message into Ollama messages, tool results become messages of their own.
+fn build_messages(message: InputMessage, tool_names: &HashMap<String, String>) -> Vec<ChatMessage> {
+    let mut messages = Vec::new();
+    let mut chat_message = ChatMessage {
+        role: match message.role {
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        }
+        .to_string(),
+        ..Default::default()
+    };
+
+    for block in message.content {
+        match block {
+            ContentBlock::Text { text } => push_text(&mut chat_message.content, &text),
+            ContentBlock::Image {
+                source: ImageSource::Base64 { data, .. },
+            } => chat_message.images.push(data.to_base64()),
+            ContentBlock::Document {
+                source: DocumentSource::Base64 { data, media_type },
+                title,
+            } => match media_type {
+                DocumentMediaType::PlainText => {
+                    let text = String::from_utf8_lossy(&data);
+                    let title = title.as_deref().unwrap_or("document");
+                    push_text(
+                        &mut chat_message.content,
+                        &format!("<document title=\"{title}\">\n{text}\n</document>"),
+                    );
+                }
+                DocumentMediaType::Pdf => {
+                    tracing::warn!("Ollama doesn't support PDF documents, skipping");
+                }
+            },
+            ContentBlock::ToolUse { input, name, .. } => {
+                chat_message.tool_calls.push(ToolCall {
+                    function: FunctionCall {
+                        name,
+                        arguments: serde_json::from_str(&input)
+                            .unwrap_or_else(|_| Value::Object(Default::default())),
+                    },
+                });
+            }
+            ContentBlock::ToolResult {
+                tool_use_id,
+                content,
+                is_error,
+            } => {
+                let mut result = ChatMessage {
+                    role: "tool".to_string(),
+                    tool_name: tool_names.get(&tool_use_id).cloned(),
+                    ..Default::default()
+                };
+                if is_error == Some(true) {
+                    result.content.push_str("Error: ");
+                }
+                for block in content.into_iter().flatten() {
+                    match block {
+                        ToolResultContentBlock::Text { text } => {
+                            push_text(&mut result.content, &text)
+                        }
+                        ToolResultContentBlock::Image {
+                            source: ImageSource::Base64 { data, .. },
+                        } => result.images.push(data.to_base64()),
+                        ToolResultContentBlock::Unknown { .. } => {}
+                    }
+                }
+                messages.push(result);
+            }
+            ContentBlock::Thinking { thinking, .. } => {
+                chat_message.thinking.push_str(&thinking);
+            }
+            // Produced by another provider, Ollama has no equivalent to send them back as.
+            ContentBlock::RedactedThinking { .. }
+            | ContentBlock::ServerToolUse { .. }
+            | ContentBlock::ServerToolResult { .. }
+            | ContentBlock::Unknown { .. } => {}
+        }
+    }
+
+    let is_empty = chat_message.content.is_empty()
+        && chat_message.images.is_empty()
+        && chat_message.tool_calls.is_empty();
+    if !is_empty {
+        messages.push(chat_message);
+    }
+    messages
+}
+
+fn push_text(content: &mut String, text: &str) {
+    if !content.is_empty() {
+        content.push_str("\n\n");
+    }
+    content.push_str(text);
+}
+
+/// The kind of block a streamed response is currently adding to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum OpenBlock {
+    Text,
+    Thinking,
+}
+
+/// Converts the newline delimited JSON Ollama streams into response events.
+pub struct OllamaMessageStream {
+    response: reqwest::Response,
+    /// Bytes received after the last complete line.
+    buffer: Vec<u8>,
+    pending: VecDeque<MessagesResponseEvent>,
+    open: Option<(OpenBlock, usize)>,
+    next_index: usize,
+    called_tools: bool,
+    started: bool,
+    finished: bool,
+}
+
+impl OllamaMessageStream {
+    fn new(response: reqwest::Response) -> Self {
+        Self {
+            response,
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+            open: None,
+            next_index: 0,
+            called_tools: false,
+            started: false,
+            finished: false,
+        }
+    }
+
+    async fn next_line(&mut self) -> Result<Option<String>, OllamaError> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+                return Ok(Some(String::from_utf8_lossy(&line).trim().to_string()));
+            }
+
+            match self.response.chunk().await? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None if self.buffer.is_empty() => return Ok(None),
+                None => {
+                    let line = std::mem::take(&mut self.buffer);
+                    return Ok(Some(String::from_utf8_lossy(&line).trim().to_string()));
+                }
+            }
+        }
+    }
+
+    fn close_block(&mut self) {
+        if let Some((_, index)) = self.open.take() {
+            self.pending
+                .push_back(MessagesResponseEvent::ContentBlockStop(ContentBlockStop {
+                    index,
+                }));
+        }
+    }
+
+    /// The index of the open block of `kind`, closing any other block and starting a new one.
+    fn open_block(&mut self, kind: OpenBlock) -> usize {
+        if let Some((open, index)) = self.open {
+            if open == kind {
+                return index;
+            }
+            self.close_block();
+        }
+
+        let index = self.next_index;
+        self.next_index += 1;
+        self.open = Some((kind, index));
+        self.pending
+            .push_back(MessagesResponseEvent::ContentBlockStart(
+                ContentBlockStart {
+                    index,
+                    content_block: match kind {
+                        OpenBlock::Text => ContentBlock::Text {
+                            text: String::new(),
+                        },
+                        OpenBlock::Thinking => ContentBlock::Thinking {
+                            thinking: String::new(),
+                            signature: None,
+                        },
+                    },
+                },
+            ));
+        index
+    }
+
+    fn convert(&mut self, chunk: ChatChunk) {
+        if !std::mem::replace(&mut self.started, true) {
+            self.pending
+                .push_back(MessagesResponseEvent::MessageStart(Message {
+                    id: String::new(),
+                    content: Vec::new(),
+                    stop_reason: None,
+                    stop_sequence: None,
+                    usage: None,
+                }));
+        }
+
+        if let Some(message) = chunk.message {
+            if !message.thinking.is_empty() {
+                let index = self.open_block(OpenBlock::Thinking);
+                self.pending
+                    .push_back(MessagesResponseEvent::ContentBlockDelta(
+                        ContentBlockDelta::Thinking {
+                            index,
+                            thinking: message.thinking,
+                        },
+                    ));
+            }
+            if !message.content.is_empty() {
+                let index = self.open_block(OpenBlock::Text);
+                self.pending
+                    .push_back(MessagesResponseEvent::ContentBlockDelta(
+                        ContentBlockDelta::Text {
+                            index,
+                            text: message.content,
+                        },
+                    ));
+            }
+            for tool_call in message.tool_calls {
+                self.close_block();
+                let index = self.next_index;
+                self.next_index += 1;
+                self.called_tools = true;
+                self.pending.extend([
+                    MessagesResponseEvent::ContentBlockStart(ContentBlockStart {
+                        index,
+                        content_block: ContentBlock::ToolUse {
+                            id: format!("call_{}", NEXT_CALL_ID.fetch_add(1, Ordering::Relaxed)),
+                            input: String::new(),
+                            name: tool_call.function.name,
+                        },
+                    }),
+                    MessagesResponseEvent::ContentBlockDelta(ContentBlockDelta::InputJson {
+                        index,
+                        partial_json: tool_call.function.arguments.to_string(),
+                    }),
+                    MessagesResponseEvent::ContentBlockStop(ContentBlockStop { index }),
+                ]);
+            }
+        }
+
+        if chunk.done {
+            self.close_block();
+            let stop_reason = match (self.called_tools, chunk.done_reason.as_deref()) {
+                (true, _) => StopReason::ToolUse,
+                (false, Some("length")) => StopReason::MaxTokens,
+                (false, Some("stop") | None) => StopReason::EndTurn,
+                (false, Some(reason)) => StopReason::Other(reason.to_string()),
+            };
+            self.pending.extend([
+                MessagesResponseEvent::MessageDelta(MessageDelta {
+                    stop_reason: Some(stop_reason),
+                    stop_sequence: None,
+                    usage: Some(Usage {
+                        input_tokens: chunk.prompt_eval_count.unwrap_or_default(),
+                        output_tokens: chunk.eval_count.unwrap_or_default(),
+                    }),
+                }),
+                MessagesResponseEvent::MessageStop,
+            ]);
+            self.finished = true;
+        }
+    }
+}
+
+impl MessageStream for OllamaMessageStream {
+    fn recv(&mut self) -> RecvFuture<'_> {
+        Box::pin(async move {
+            loop {
+                if let Some(event) = self.pending.pop_front() {
+                    return Ok(Some(event));
+                }
+                if self.finished {
+                    return Ok(None);
+                }
+
+                let Some(line) = self.next_line().await? else {
+                    return Ok(None);
+                };
+                if line.is_empty() {
+                    continue;
+                }
+
+                let chunk = serde_json::from_str::<ChatChunk>(&line).map_err(OllamaError::from)?;
+                if let Some(error) = chunk.error {
+                    return Err(OllamaError::Api(error).into());
+                }
+                self.convert(chunk);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use kepoki::backend::Payload;
+    use kepoki::backend::Tool;
+    use kepoki::backend::assembly::MessageAssembler;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_build_request() {
+        let request = MessagesRequest::<OllamaBackend> {
+            model: "llama3.1:8b".to_string(),
+            messages: vec![
+                InputMessage {
+                    id: String::new(),
+                    role: Role::User,
+                    content: vec![
+                        ContentBlock::Text {
+                            text: "What's on it?".to_string(),
+                        },
+                        ContentBlock::Image {
+                            source: ImageSource::Base64 {
+                                data: Payload::from_base64("aGk=").unwrap(),
+                                media_type: ImageMediaType::Png,
+                            },
+                        },
+                    ],
+                },
+                InputMessage {
+                    id: String::new(),
+                    role: Role::Assistant,
+                    content: vec![ContentBlock::ToolUse {
+                        id: "call_1".to_string(),
+                        input: r#"{"path":"a.png"}"#.to_string(),
+                        name: "describe".to_string(),
+                    }],
+                },
+                InputMessage {
+                    id: String::new(),
+                    role: Role::User,
+                    content: vec![ContentBlock::ToolResult {
+                        tool_use_id: "call_1".to_string(),
+                        content: Some(vec![ToolResultContentBlock::Text {
+                            text: "A cat".to_string(),
+                        }]),
+                        is_error: None,
+                    }],
+                },
+            ],
+            max_tokens: 256,
+            system: Some(Cow::Borrowed("Be brief.")),
+            temperature: Some(0.5),
+            stop_sequences: None,
+            tool_choice: None,
+            tools: Some(vec![Tool {
+                name: "describe".into(),
+                input_schema: None,
+                description: Some("Describes an image".into()),
+            }]),
+            reasoning: None,
+            user_id: None,
+            extensions: HashMap::from([
+                ("num_ctx".to_string(), json!(8192)),
+                ("temperature".to_string(), json!(1.0)),
+            ]),
+        };
+
+        let body = serde_json::to_value(build_request(request, Some("10m".to_string()))).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "model": "llama3.1:8b",
+                "messages": [
+                    { "role": "system", "content": "Be brief." },
+                    { "role": "user", "content": "What's on it?", "images": ["aGk="] },
+                    {
+                        "role": "assistant",
+                        "content": "",
+                        "tool_calls": [
+                            { "function": { "name": "describe", "arguments": { "path": "a.png" } } },
+                        ],
+                    },
+                    { "role": "tool", "content": "A cat", "tool_name": "describe" },
+                ],
+                "tools": [{
+                    "type": "function",
+                    "function": {
+                        "name": "describe",
+                        "description": "Describes an image",
+                        "parameters": { "type": "object", "properties": {} },
+                    },
+                }],
+                "stream": true,
+                "keep_alive": "10m",
+                "options": { "num_predict": 256, "temperature": 0.5, "num_ctx": 8192 },
+            })
+        );
+    }
+
+    fn stream(lines: &[Value]) -> OllamaMessageStream {
+        let body = lines
+            .iter()
+            .map(|line| format!("{line}\n"))
+            .collect::<String>();
+        OllamaMessageStream::new(http::Response::new(body).into())
+    }
+
+    #[tokio::test]
+    async fn test_stream_converts_chunks() {
+        let mut stream = stream(&[
+            json!({ "message": { "role": "assistant", "content": "", "thinking": "The user" } }),
+            json!({ "message": { "role": "assistant", "content": "", "thinking": " wants files" } }),
+            json!({ "message": { "role": "assistant", "content": "Listing." } }),
+            json!({ "message": { "role": "assistant", "content": "", "tool_calls": [
+                { "function": { "name": "list_files", "arguments": { "path": "." } } },
+            ] } }),
+            json!({ "done": true, "done_reason": "stop", "prompt_eval_count": 12, "eval_count": 7 }),
+        ]);
+
+        let mut assembler = MessageAssembler::new();
+        while let Some(event) = stream.recv().await.unwrap() {
+            assembler.push(event).unwrap();
+        }
+        let message = assembler.finish().unwrap();
+        assert!(matches!(message.stop_reason, Some(StopReason::ToolUse)));
+        assert!(matches!(
+            message.usage,
+            Some(Usage {
+                input_tokens: 12,
+                output_tokens: 7
+            })
+        ));
+        assert!(matches!(
+            &message.content[..],
+            [
+                ContentBlock::Thinking { thinking, .. },
+                ContentBlock::Text { text },
+                ContentBlock::ToolUse { name, input, .. },
+            ] if thinking == "The user wants files"
+                && text == "Listing."
+                && name == "list_files"
+                && input == r#"{"path":"."}"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stream_error() {
+        let mut stream = stream(&[json!({ "error": "model 'llama9' not found" })]);
+        let Err(KepokiError::CustomError(err)) = stream.recv().await else {
+            panic!("Expected an error");
+        };
+        assert_eq!(
+            err.to_string(),
+            "Ollama returned an error: model 'llama9' not found"
+        );
+    }
+}
@@ -0,0 +1,716 @@
+//! The OpenAI chat completions protocol: request bodies built from kepoki requests, and the
+//! server-sent events of streamed completions converted into response events.
+
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+
+use kepoki::backend::Backend;
+use kepoki::backend::ContentBlock;
+use kepoki::backend::ContentBlockDelta;
+use kepoki::backend::ContentBlockStart;
+use kepoki::backend::ContentBlockStop;
+use kepoki::backend::DocumentMediaType;
+use kepoki::backend::DocumentSource;
+use kepoki::backend::ImageMediaType;
+use kepoki::backend::ImageSource;
+use kepoki::backend::InputMessage;
+use kepoki::backend::Message;
+use kepoki::backend::MessageDelta;
+use kepoki::backend::MessageStream;
+use kepoki::backend::MessagesRequest;
+use kepoki::backend::MessagesResponseEvent;
+use kepoki::backend::RecvFuture;
+use kepoki::backend::Role;
+use kepoki::backend::StopReason;
+use kepoki::backend::ToolChoice;
+use kepoki::backend::ToolResultContentBlock;
+use kepoki::backend::Usage;
+use kepoki::error::KepokiError;
+use kepoki::error::ProviderErrorKind;
+use serde_json::Value;
+use serde_json::json;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChatError {
+    #[error("Request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Invalid response: {0}")]
+    InvalidResponse(#[from] serde_json::Error),
+}
+
+impl From<ChatError> for KepokiError {
+    fn from(err: ChatError) -> Self {
+        KepokiError::CustomError(Box::new(err))
+    }
+}
+
+/// The images chat completions accept, other attachments are limited to plain text documents,
+/// which are sent inline.
+pub const IMAGE_MEDIA_TYPES: &[ImageMediaType] = &[
+    ImageMediaType::Jpeg,
+    ImageMediaType::Png,
+    ImageMediaType::Gif,
+    ImageMediaType::Webp,
+];
+
+/// The body of a streamed chat completion request, with `model` set unless the endpoint
+/// implies it.
+pub fn request_body<B: Backend>(request: MessagesRequest<'_, B>, model: Option<String>) -> Value {
+    let mut messages = Vec::new();
+    if let Some(system) = request.system {
+        messages.push(json!({ "role": "system", "content": system }));
+    }
+    for message in request.messages {
+        messages.extend(build_messages(message));
+    }
+
+    let mut body = json!({
+        "messages": messages,
+        "stream": true,
+        "stream_options": { "include_usage": true },
+    });
+    if let Some(model) = model {
+        body["model"] = model.into();
+    }
+
+    match request.reasoning {
+        // Reasoning models reject `max_tokens` and custom temperatures.
+        Some(reasoning) => {
+            body["max_completion_tokens"] = (request.max_tokens + reasoning.budget_tokens()).into();
+            body["reasoning_effort"] = format!("{:?}", reasoning.effort()).to_lowercase().into();
+        }
+        None => {
+            body["max_tokens"] = request.max_tokens.into();
+            if let Some(temperature) = request.temperature {
+                body["temperature"] = temperature.into();
+            }
+        }
+    }
+
+    if let Some(stop_sequences) = request.stop_sequences {
+        body["stop"] = stop_sequences.into();
+    }
+    if let Some(user_id) = request.user_id {
+        body["user"] = user_id.into();
+    }
+
+    let tools = request.tools.unwrap_or_default();
+    if !tools.is_empty() {
+        body["tools"] = tools
+            .into_iter()
+            .map(|tool| {
+                let parameters = tool
+                    .input_schema
+                    .and_then(|schema| serde_json::from_str::<Value>(&schema).ok())
+                    .unwrap_or_else(|| json!({ "type": "object", "properties": {} }));
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool.name,
+                        "description": tool.description.unwrap_or_default(),
+                        "parameters": parameters,
+                    },
+                })
+            })
+            .collect();
+
+        if let Some(tool_choice) = request.tool_choice {
+            let disable_parallel_tool_use = match &tool_choice {
+                ToolChoice::Auto {
+                    disable_parallel_tool_use,
+                }
+                | ToolChoice::Any {
+                    disable_parallel_tool_use,
+                }
+                | ToolChoice::Tool {
+                    disable_parallel_tool_use,
+                    ..
+                } => *disable_parallel_tool_use,
+            };
+            body["tool_choice"] = match tool_choice {
+                ToolChoice::Auto { .. } => json!("auto"),
+                ToolChoice::Any { .. } => json!("required"),
+                ToolChoice::Tool { tool_name, .. } => {
+                    json!({ "type": "function", "function": { "name": tool_name } })
+                }
+            };
+            if disable_parallel_tool_use {
+                body["parallel_tool_calls"] = false.into();
+            }
+        }
+    }
+
+    // Extensions are sent as they are, replacing the parameters above.
+    for (name, value) in request.extensions {
+        body[name] = value;
+    }
+
+    body
+}
+
+/// Converts a message into chat messages, tool results become messages of their own.
+fn build_messages(message: InputMessage) -> Vec<Value> {
+    let mut messages = Vec::new();
+    let mut parts = Vec::new();
+    let mut tool_calls = Vec::new();
+
+    for block in message.content {
+        match block {
+            ContentBlock::Text { text } => parts.push(json!({ "type": "text", "text": text })),
+            ContentBlock::Image {
+                source: ImageSource::Base64 { data, media_type },
+            } => parts.push(image_part(&data.to_base64(), media_type)),
+            ContentBlock::Document {
+                source: DocumentSource::Base64 { data, media_type },
+                title,
+            } => match media_type {
+                DocumentMediaType::PlainText => {
+                    let text = String::from_utf8_lossy(&data);
+                    let title = title.as_deref().unwrap_or("document");
+                    parts.push(json!({
+                        "type": "text",
+                        "text": format!("<document title=\"{title}\">\n{text}\n</document>"),
+                    }));
+                }
+                DocumentMediaType::Pdf => {
+                    tracing::warn!("Chat completions don't support PDF documents, skipping");
+                }
+            },
+            ContentBlock::ToolUse { id, input, name } => tool_calls.push(json!({
+                "id": id,
+                "type": "function",
+                "function": {
+                    "name": name,
+                    "arguments": match input.trim() {
+                        "" => "{}".to_string(),
+                        _ => input,
+                    },
+                },
+            })),
+            ContentBlock::ToolResult {
+                tool_use_id,
+                content,
+                is_error,
+            } => {
+                let mut text = match is_error {
+                    Some(true) => "Error: ".to_string(),
+                    _ => String::new(),
+                };
+                let mut images = Vec::new();
+                for block in content.into_iter().flatten() {
+                    match block {
+                        ToolResultContentBlock::Text { text: block } => {
+                            if !text.is_empty() && !text.ends_with(": ") {
+                                text.push_str("\n\n");
+                            }
+                            text.push_str(&block);
+                        }
+                        ToolResultContentBlock::Image {
+                            source: ImageSource::Base64 { data, media_type },
+                        } => images.push(image_part(&data.to_base64(), media_type)),
+                        ToolResultContentBlock::Unknown { .. } => {}
+                    }
+                }
+                messages.push(json!({
+                    "role": "tool",
+                    "tool_call_id": tool_use_id,
+                    "content": text,
+                }));
+                // Tool messages can only hold text, images follow in a user message.
+                if !images.is_empty() {
+                    messages.push(json!({ "role": "user", "content": images }));
+                }
+            }
+            // Chat completions can't take reasoning or blocks of other providers back.
+            ContentBlock::Thinking { .. }
+            | ContentBlock::RedactedThinking { .. }
+            | ContentBlock::ServerToolUse { .. }
+            | ContentBlock::ServerToolResult { .. }
+            | ContentBlock::Unknown { .. } => {}
+        }
+    }
+
+    match message.role {
+        Role::User if !parts.is_empty() => {
+            messages.push(json!({ "role": "user", "content": parts }));
+        }
+        Role::User => {}
+        Role::Assistant => {
+            let text = parts
+                .iter()
+                .filter_map(|part| part["text"].as_str())
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            let mut assistant = json!({ "role": "assistant", "content": text });
+            if !tool_calls.is_empty() {
+                assistant["tool_calls"] = tool_calls.into();
+            }
+            messages.push(assistant);
+        }
+    }
+    messages
+}
+
+fn image_part(data: &str, media_type: ImageMediaType) -> Value {
+    let media_type = match media_type {
+        ImageMediaType::Jpeg => "image/jpeg",
+        ImageMediaType::Png => "image/png",
+        ImageMediaType::Gif => "image/gif",
+        ImageMediaType::Webp => "image/webp",
+    };
+    json!({
+        "type": "image_url",
+        "image_url": { "url": format!("data:{media_type};base64,{data}") },
+    })
+}
+
+/// The kind of an error reported in the middle of a stream, by its HTTP status code if the
+/// server reports one, otherwise by its type.
+fn error_kind(error: &Value) -> ProviderErrorKind {
+    if let Some(status) = error["code"].as_u64() {
+        return ProviderErrorKind::from_status(status as u16);
+    }
+
+    let r#type = error["type"].as_str().or(error["code"].as_str());
+    match r#type.unwrap_or_default() {
+        "rate_limit_exceeded" | "rate_limit_error" | "insufficient_quota" => {
+            ProviderErrorKind::RateLimited
+        }
+        "overloaded_error" => ProviderErrorKind::Overloaded,
+        "server_error" | "api_error" => ProviderErrorKind::Server,
+        "invalid_request_error" => ProviderErrorKind::InvalidRequest,
+        "content_filter" | "content_policy_violation" => ProviderErrorKind::ContentPolicy,
+        _ => ProviderErrorKind::Other,
+    }
+}
+
+/// Sends a request, failing with the error message of the API if it wasn't successful.
+pub async fn send(request: reqwest::RequestBuilder) -> Result<ChatCompletionStream, KepokiError> {
+    let response = request.send().await.map_err(ChatError::from)?;
+    if !response.status().is_success() {
+        return Err(provider_error(response).await);
+    }
+
+    Ok(ChatCompletionStream::new(response))
+}
+
+/// The error of a response with an error status, with the message of its body if it has one.
+pub(crate) async fn provider_error(response: reqwest::Response) -> KepokiError {
+    let status = response.status();
+    let text = response.text().await.unwrap_or_default();
+    let error = serde_json::from_str::<Value>(&text).unwrap_or_default();
+    let message = error["error"]["message"]
+        .as_str()
+        .map_or(text.clone(), str::to_string);
+    KepokiError::Provider {
+        kind: ProviderErrorKind::from_status(status.as_u16()),
+        message: format!("{status}: {message}"),
+    }
+}
+
+/// The kind of block a streamed completion is currently adding text to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum OpenBlock {
+    Text,
+    Thinking,
+}
+
+/// Converts the server-sent events of a streamed chat completion into response events.
+pub struct ChatCompletionStream {
+    response: reqwest::Response,
+    /// Bytes received after the last complete line.
+    buffer: Vec<u8>,
+    pending: VecDeque<MessagesResponseEvent>,
+    open: Option<(OpenBlock, usize)>,
+    /// The block index of each tool call by the index of the call.
+    tool_calls: BTreeMap<u64, usize>,
+    next_index: usize,
+    started: bool,
+    stop_reason: Option<StopReason>,
+    finished: bool,
+}
+
+impl ChatCompletionStream {
+    fn new(response: reqwest::Response) -> Self {
+        Self {
+            response,
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+            open: None,
+            tool_calls: BTreeMap::new(),
+            next_index: 0,
+            started: false,
+            stop_reason: None,
+            finished: false,
+        }
+    }
+
+    async fn next_line(&mut self) -> Result<Option<String>, ChatError> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+                return Ok(Some(String::from_utf8_lossy(&line).trim().to_string()));
+            }
+
+            match self.response.chunk().await? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None if self.buffer.is_empty() => return Ok(None),
+                None => {
+                    let line = std::mem::take(&mut self.buffer);
+                    return Ok(Some(String::from_utf8_lossy(&line).trim().to_string()));
+                }
+            }
+        }
+    }
+
+    fn close_text(&mut self) {
+        if let Some((_, index)) = self.open.take() {
+            self.pending
+                .push_back(MessagesResponseEvent::ContentBlockStop(ContentBlockStop {
+                    index,
+                }));
+        }
+    }
+
+    /// The index of the open block of `kind`, closing any other block and starting a new one.
+    fn open_block(&mut self, kind: OpenBlock) -> usize {
+        if let Some((open, index)) = self.open {
+            if open == kind {
+                return index;
+            }
+            self.close_text();
+        }
+
+        let index = self.next_index;
+        self.next_index += 1;
+        self.open = Some((kind, index));
+        self.pending
+            .push_back(MessagesResponseEvent::ContentBlockStart(
+                ContentBlockStart {
+                    index,
+                    content_block: match kind {
+                        OpenBlock::Text => ContentBlock::Text {
+                            text: String::new(),
+                        },
+                        OpenBlock::Thinking => ContentBlock::Thinking {
+                            thinking: String::new(),
+                            signature: None,
+                        },
+                    },
+                },
+            ));
+        index
+    }
+
+    fn start(&mut self, id: &str) {
+        if !std::mem::replace(&mut self.started, true) {
+            self.pending
+                .push_back(MessagesResponseEvent::MessageStart(Message {
+                    id: id.to_string(),
+                    content: Vec::new(),
+                    stop_reason: None,
+                    stop_sequence: None,
+                    usage: None,
+                }));
+        }
+    }
+
+    /// Closes the open blocks and emits the stop reason once the choice finished.
+    fn finish_choice(&mut self) {
+        self.close_text();
+        for index in std::mem::take(&mut self.tool_calls).into_values() {
+            self.pending
+                .push_back(MessagesResponseEvent::ContentBlockStop(ContentBlockStop {
+                    index,
+                }));
+        }
+    }
+
+    fn convert(&mut self, chunk: Value) {
+        self.start(chunk["id"].as_str().unwrap_or_default());
+
+        // Only the first choice is requested.
+        if let Some(choice) = chunk["choices"].get(0) {
+            let delta = &choice["delta"];
+            // Reasoning is returned under either name by servers such as vLLM and DeepSeek.
+            for field in ["reasoning_content", "reasoning"] {
+                if let Some(thinking) = delta[field].as_str().filter(|text| !text.is_empty()) {
+                    let index = self.open_block(OpenBlock::Thinking);
+                    self.pending
+                        .push_back(MessagesResponseEvent::ContentBlockDelta(
+                            ContentBlockDelta::Thinking {
+                                index,
+                                thinking: thinking.to_string(),
+                            },
+                        ));
+                }
+            }
+            if let Some(text) = delta["content"].as_str().filter(|text| !text.is_empty()) {
+                let index = self.open_block(OpenBlock::Text);
+                self.pending
+                    .push_back(MessagesResponseEvent::ContentBlockDelta(
+                        ContentBlockDelta::Text {
+                            index,
+                            text: text.to_string(),
+                        },
+                    ));
+            }
+            for tool_call in delta["tool_calls"].as_array().into_iter().flatten() {
+                self.convert_tool_call(tool_call);
+            }
+
+            if let Some(finish_reason) = choice["finish_reason"].as_str() {
+                self.finish_choice();
+                self.stop_reason = Some(match finish_reason {
+                    "stop" => StopReason::EndTurn,
+                    "length" => StopReason::MaxTokens,
+                    "tool_calls" | "function_call" => StopReason::ToolUse,
+                    "content_filter" => StopReason::Refusal,
+                    reason => StopReason::Other(reason.to_string()),
+                });
+            }
+        }
+
+        if let Some(usage) = chunk["usage"].as_object() {
+            let tokens = |field: &str| usage.get(field).and_then(Value::as_u64).unwrap_or(0);
+            self.pending
+                .push_back(MessagesResponseEvent::MessageDelta(MessageDelta {
+                    stop_reason: None,
+                    stop_sequence: None,
+                    usage: Some(Usage {
+                        input_tokens: tokens("prompt_tokens") as u32,
+                        output_tokens: tokens("completion_tokens") as u32,
+                    }),
+                }));
+        }
+    }
+
+    fn convert_tool_call(&mut self, tool_call: &Value) {
+        let call_index = tool_call["index"].as_u64().unwrap_or_default();
+        let index = match self.tool_calls.get(&call_index) {
+            Some(index) => *index,
+            None => {
+                self.close_text();
+                let index = self.next_index;
+                self.next_index += 1;
+                self.tool_calls.insert(call_index, index);
+                self.pending
+                    .push_back(MessagesResponseEvent::ContentBlockStart(
+                        ContentBlockStart {
+                            index,
+                            content_block: ContentBlock::ToolUse {
+                                id: tool_call["id"].as_str().unwrap_or_default().to_string(),
+                                input: String::new(),
+                                name: tool_call["function"]["name"]
+                                    .as_str()
+                                    .unwrap_or_default()
+                                    .to_string(),
+                            },
+                        },
+                    ));
+                index
+            }
+        };
+
+        if let Some(arguments) = tool_call["function"]["arguments"]
+            .as_str()
+            .filter(|arguments| !arguments.is_empty())
+        {
+            self.pending
+                .push_back(MessagesResponseEvent::ContentBlockDelta(
+                    ContentBlockDelta::InputJson {
+                        index,
+                        partial_json: arguments.to_string(),
+                    },
+                ));
+        }
+    }
+
+    /// Emits the end of the message once the stream is done.
+    fn finish(&mut self) {
+        if !self.started {
+            return;
+        }
+
+        self.finish_choice();
+        self.pending.extend([
+            MessagesResponseEvent::MessageDelta(MessageDelta {
+                stop_reason: Some(self.stop_reason.take().unwrap_or(StopReason::EndTurn)),
+                stop_sequence: None,
+                usage: None,
+            }),
+            MessagesResponseEvent::MessageStop,
+        ]);
+    }
+}
+
+impl MessageStream for ChatCompletionStream {
+    fn recv(&mut self) -> RecvFuture<'_> {
+        Box::pin(async move {
+            loop {
+                if let Some(event) = self.pending.pop_front() {
+                    return Ok(Some(event));
+                }
+                if self.finished {
+                    return Ok(None);
+                }
+
+                let Some(line) = self.next_line().await? else {
+                    // Completions end with `[DONE]`, a stream closing before it was cut.
+                    if self.started {
+                        return Err(KepokiError::Io(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            "The stream ended before the completion did",
+                        )));
+                    }
+                    self.finished = true;
+                    continue;
+                };
+                let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+                    continue;
+                };
+                if data == "[DONE]" {
+                    self.finished = true;
+                    self.finish();
+                    continue;
+                }
+
+                let chunk = serde_json::from_str::<Value>(data).map_err(ChatError::from)?;
+                if let Some(message) = chunk["error"]["message"].as_str() {
+                    return Err(KepokiError::Provider {
+                        kind: error_kind(&chunk["error"]),
+                        message: message.to_string(),
+                    });
+                }
+                self.convert(chunk);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kepoki::backend::Payload;
+    use kepoki::backend::assembly::MessageAssembler;
+
+    use super::*;
+
+    fn stream(chunks: &[Value], done: bool) -> ChatCompletionStream {
+        let mut body = chunks
+            .iter()
+            .map(|chunk| format!("data: {chunk}\n\n"))
+            .collect::<String>();
+        if done {
+            body.push_str("data: [DONE]\n\n");
+        }
+        ChatCompletionStream::new(http::Response::new(body).into())
+    }
+
+    #[tokio::test]
+    async fn test_stream_interleaves_blocks() {
+        let mut stream = stream(
+            &[
+                json!({ "id": "chat", "choices": [{ "delta": { "reasoning_content": "Hmm" } }] }),
+                json!({ "id": "chat", "choices": [{ "delta": { "reasoning": ", search" } }] }),
+                json!({ "id": "chat", "choices": [{ "delta": { "content": "Searching" } }] }),
+                json!({ "id": "chat", "choices": [{ "delta": { "tool_calls": [
+                    { "index": 0, "id": "a", "function": { "name": "search", "arguments": "{\"q\":" } },
+                ] } }] }),
+                json!({ "id": "chat", "choices": [{ "delta": { "tool_calls": [
+                    { "index": 1, "id": "b", "function": { "name": "fetch", "arguments": "{}" } },
+                    { "index": 0, "function": { "arguments": "\"kepoki\"}" } },
+                ] } }] }),
+                json!({ "id": "chat", "choices": [{ "delta": {}, "finish_reason": "tool_calls" }] }),
+                json!({ "id": "chat", "choices": [], "usage": { "prompt_tokens": 3, "completion_tokens": 5 } }),
+            ],
+            true,
+        );
+
+        let mut assembler = MessageAssembler::new();
+        while let Some(event) = stream.recv().await.unwrap() {
+            assembler.push(event).unwrap();
+        }
+        let message = assembler.finish().unwrap();
+        assert_eq!(message.id, "chat");
+        assert!(matches!(message.stop_reason, Some(StopReason::ToolUse)));
+        assert!(matches!(
+            message.usage,
+            Some(Usage {
+                input_tokens: 3,
+                output_tokens: 5
+            })
+        ));
+        assert!(matches!(
+            &message.content[..],
+            [
+                ContentBlock::Thinking { thinking, .. },
+                ContentBlock::Text { text },
+                ContentBlock::ToolUse { id: search_id, name: search, input: search_input },
+                ContentBlock::ToolUse { id: fetch_id, name: fetch, input: fetch_input },
+            ] if thinking == "Hmm, search"
+                && text == "Searching"
+                && (search_id.as_str(), search.as_str(), search_input.as_str())
+                    == ("a", "search", r#"{"q":"kepoki"}"#)
+                && (fetch_id.as_str(), fetch.as_str(), fetch_input.as_str())
+                    == ("b", "fetch", "{}")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stream_cut_before_done() {
+        let mut cut = stream(
+            &[json!({ "id": "chat", "choices": [{ "delta": { "content": "Hel" } }] })],
+            false,
+        );
+
+        let result = loop {
+            match cut.recv().await {
+                Ok(Some(_)) => (),
+                result => break result,
+            }
+        };
+        assert!(matches!(result, Err(KepokiError::Io(_))));
+        assert!(matches!(stream(&[], false).recv().await, Ok(None)));
+    }
+
+    #[test]
+    fn test_tool_result_images_follow_in_user_message() {
+        let image = Payload::from_base64("aGk=").unwrap();
+        let messages = build_messages(InputMessage {
+            id: String::new(),
+            role: Role::User,
+            content: vec![
+                ContentBlock::ToolResult {
+                    tool_use_id: "a".to_string(),
+                    content: Some(vec![
+                        ToolResultContentBlock::Text {
+                            text: "A screenshot".to_string(),
+                        },
+                        ToolResultContentBlock::Image {
+                            source: ImageSource::Base64 {
+                                data: image,
+                                media_type: ImageMediaType::Png,
+                            },
+                        },
+                    ]),
+                    is_error: None,
+                },
+                ContentBlock::Text {
+                    text: "What's on it?".to_string(),
+                },
+            ],
+        });
+
+        assert_eq!(
+            messages,
+            [
+                json!({ "role": "tool", "tool_call_id": "a", "content": "A screenshot" }),
+                json!({ "role": "user", "content": [{
+                    "type": "image_url",
+                    "image_url": { "url": "data:image/png;base64,aGk=" },
+                }] }),
+                json!({ "role": "user", "content": [{ "type": "text", "text": "What's on it?" }] }),
+            ]
+        );
+    }
+}
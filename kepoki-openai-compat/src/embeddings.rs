@@ -0,0 +1,53 @@
+//! The OpenAI embeddings protocol, for backends of providers that only differ in how they are
+//! addressed.
+
+use kepoki::error::KepokiError;
+use serde_json::Value;
+use serde_json::json;
+
+use crate::chat::ChatError;
+use crate::chat::provider_error;
+
+/// The body of an embeddings request, with `model` set unless the endpoint implies it.
+pub fn request_body(texts: &[String], model: Option<&str>) -> Value {
+    let mut body = json!({ "input": texts });
+    if let Some(model) = model {
+        body["model"] = model.into();
+    }
+    body
+}
+
+/// Sends an embeddings request, returning the embeddings in the order of the texts.
+pub async fn send(request: reqwest::RequestBuilder) -> Result<Vec<Vec<f32>>, KepokiError> {
+    let response = request.send().await.map_err(ChatError::from)?;
+    if !response.status().is_success() {
+        return Err(provider_error(response).await);
+    }
+
+    let text = response.text().await.map_err(ChatError::from)?;
+    let body = serde_json::from_str::<Value>(&text).map_err(ChatError::from)?;
+    parse_embeddings(&body)
+}
+
+fn parse_embeddings(body: &Value) -> Result<Vec<Vec<f32>>, KepokiError> {
+    let mut data = body["data"].as_array().cloned().unwrap_or_default();
+    // Servers may return the embeddings in any order, each names the text it embeds.
+    data.sort_by_key(|embedding| embedding["index"].as_u64());
+    data.iter()
+        .map(|embedding| {
+            embedding["embedding"]
+                .as_array()
+                .map(|values| {
+                    values
+                        .iter()
+                        .map(|value| value.as_f64().unwrap_or_default() as f32)
+                        .collect()
+                })
+                .ok_or_else(|| {
+                    KepokiError::MalformedResponse(
+                        "An embedding isn't an array of numbers".to_string(),
+                    )
+                })
+        })
+        .collect()
+}
@@ -0,0 +1,109 @@
+//! Runs agents on any server speaking the OpenAI chat completions protocol, such as vLLM,
+//! LM Studio, llamafile, or an LLM proxy.
+//!
+//! Requests are streamed from `<base URL>/chat/completions`. Extension parameters of requests
+//! are sent as fields of the request body, such as `logit_bias` or `seed`. Embeddings are
+//! requested from `<base URL>/embeddings`. The [`chat`] and [`embeddings`] modules implement
+//! the protocols for backends of providers that only differ in how they are addressed, such as
+//! Azure OpenAI.
+
+pub mod chat;
+pub mod embeddings;
+
+use kepoki::backend::AttachmentLimits;
+use kepoki::backend::Backend;
+use kepoki::backend::DocumentMediaType;
+use kepoki::backend::EmbedFuture;
+use kepoki::backend::EmbeddingBackend;
+use kepoki::backend::MessagesFuture;
+use kepoki::backend::MessagesRequest;
+
+use crate::chat::ChatCompletionStream;
+
+#[derive(Clone, Debug)]
+pub struct OpenAiCompatBackend {
+    base_url: String,
+    api_key: Option<String>,
+    headers: Vec<(String, String)>,
+    client: reqwest::Client,
+}
+
+impl OpenAiCompatBackend {
+    /// A backend for the server at `base_url`, including the version of the API such as
+    /// `http://localhost:8000/v1` for vLLM or `http://localhost:1234/v1` for LM Studio.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: None,
+            headers: Vec::new(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Sends `api_key` as a bearer token, local servers usually don't require one.
+    pub fn with_api_key(mut self, api_key: Option<String>) -> Self {
+        self.api_key = api_key;
+        self
+    }
+
+    /// Adds a header to every request, such as the routing or tenant headers of a proxy.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+impl OpenAiCompatBackend {
+    fn post(&self, path: &str, body: serde_json::Value) -> reqwest::RequestBuilder {
+        let mut builder = self
+            .client
+            .post(format!("{}/{path}", self.base_url))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+        builder
+    }
+}
+
+impl Backend for OpenAiCompatBackend {
+    /// The name of a model served by the server.
+    type Model = String;
+    type MessagesEventStream = ChatCompletionStream;
+
+    fn parse_model(&self, name: &str) -> Option<Self::Model> {
+        Some(name.to_string())
+    }
+
+    fn model_name(&self, model: &Self::Model) -> Option<String> {
+        Some(model.clone())
+    }
+
+    fn attachment_limits(&self) -> AttachmentLimits {
+        AttachmentLimits {
+            image_media_types: Some(chat::IMAGE_MEDIA_TYPES),
+            document_media_types: Some(&[DocumentMediaType::PlainText]),
+            ..Default::default()
+        }
+    }
+
+    fn messages<'a>(
+        &'a self,
+        request: MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, Self::MessagesEventStream> {
+        let model = request.model.clone();
+        let body = chat::request_body(request, Some(model));
+        Box::pin(chat::send(self.post("chat/completions", body)))
+    }
+}
+
+impl EmbeddingBackend for OpenAiCompatBackend {
+    fn embed<'a>(&'a self, model: &'a str, texts: &'a [String]) -> EmbedFuture<'a> {
+        let body = embeddings::request_body(texts, Some(model));
+        Box::pin(embeddings::send(self.post("embeddings", body)))
+    }
+}
@@ -0,0 +1,268 @@
+//! Runs agents on the models of many providers through [OpenRouter](https://openrouter.ai).
+//!
+//! Models are named with the prefix of their provider, such as `anthropic/claude-sonnet-4`.
+//! A name may list fallbacks separated by commas, `anthropic/claude-sonnet-4,openai/gpt-4o`,
+//! which OpenRouter tries in order when a model is unavailable or refuses the request.
+//! [`OpenRouterModel::auto`] lets OpenRouter pick a model for every request.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use kepoki::backend::AttachmentLimits;
+use kepoki::backend::Backend;
+use kepoki::backend::DocumentMediaType;
+use kepoki::backend::MessagesFuture;
+use kepoki::backend::MessagesRequest;
+use kepoki_openai_compat::chat;
+use kepoki_openai_compat::chat::ChatCompletionStream;
+use serde::Serialize;
+use serde_json::Value;
+use serde_json::json;
+
+/// The URL of the OpenRouter API.
+pub const BASE_URL: &str = "https://openrouter.ai/api/v1";
+
+/// The model letting OpenRouter pick a model for every request.
+pub const AUTO_ROUTER: &str = "openrouter/auto";
+
+/// A model and the models OpenRouter falls back to, in order.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct OpenRouterModel {
+    pub id: String,
+    pub fallbacks: Vec<String>,
+}
+
+impl OpenRouterModel {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            fallbacks: Vec::new(),
+        }
+    }
+
+    /// Lets OpenRouter pick a model for every request.
+    pub fn auto() -> Self {
+        Self::new(AUTO_ROUTER)
+    }
+
+    /// Falls back to `id` if the models before it are unavailable.
+    pub fn with_fallback(mut self, id: impl Into<String>) -> Self {
+        self.fallbacks.push(id.into());
+        self
+    }
+}
+
+impl FromStr for OpenRouterModel {
+    type Err = ();
+
+    /// Parses a model followed by its fallbacks, separated by commas.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ids = s.split(',').map(str::trim).filter(|id| !id.is_empty());
+        let id = ids.next().ok_or(())?;
+        Ok(Self {
+            id: id.to_string(),
+            fallbacks: ids.map(str::to_string).collect(),
+        })
+    }
+}
+
+impl Display for OpenRouterModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.id)?;
+        for fallback in &self.fallbacks {
+            write!(f, ",{fallback}")?;
+        }
+        Ok(())
+    }
+}
+
+/// How OpenRouter picks among the providers serving a model, see
+/// <https://openrouter.ai/docs/features/provider-routing>.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ProviderPreferences {
+    /// Providers to try first, in order, such as `Anthropic` or `Together`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub order: Vec<String>,
+    /// Providers never to use.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ignore: Vec<String>,
+    /// Whether providers other than those in `order` may serve requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_fallbacks: Option<bool>,
+    /// Sorts providers by `price`, `throughput`, or `latency` instead of load balancing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct OpenRouterBackend {
+    base_url: String,
+    api_key: String,
+    providers: Option<ProviderPreferences>,
+    headers: Vec<(&'static str, String)>,
+    client: reqwest::Client,
+}
+
+impl OpenRouterBackend {
+    pub fn new(api_key: impl Into<String>) -> Self {
+        Self {
+            base_url: BASE_URL.to_string(),
+            api_key: api_key.into(),
+            providers: None,
+            headers: Vec::new(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Attributes requests to an application in the rankings and dashboards of OpenRouter.
+    pub fn with_app(mut self, url: impl Into<String>, title: impl Into<String>) -> Self {
+        self.headers.push(("HTTP-Referer", url.into()));
+        self.headers.push(("X-Title", title.into()));
+        self
+    }
+
+    pub fn with_provider_preferences(mut self, providers: ProviderPreferences) -> Self {
+        self.providers = Some(providers);
+        self
+    }
+
+    /// The chat completion request body, with the fallbacks of the model and the provider
+    /// preferences of the backend.
+    fn request_body(&self, request: MessagesRequest<'_, Self>) -> Value {
+        let model = request.model.clone();
+        let mut body = chat::request_body(request, Some(model.id.clone()));
+        if !model.fallbacks.is_empty() {
+            body["models"] = json!(
+                std::iter::once(&model.id)
+                    .chain(&model.fallbacks)
+                    .collect::<Vec<_>>()
+            );
+        }
+        if let Some(providers) = &self.providers {
+            body["provider"] = json!(providers);
+        }
+        body
+    }
+}
+
+impl Backend for OpenRouterBackend {
+    type Model = OpenRouterModel;
+    type MessagesEventStream = ChatCompletionStream;
+
+    fn parse_model(&self, name: &str) -> Option<Self::Model> {
+        name.parse().ok()
+    }
+
+    /// The model without its provider prefix, such as `gpt-4o` for `openai/gpt-4o`.
+    fn model_name(&self, model: &Self::Model) -> Option<String> {
+        let name = model
+            .id
+            .split_once('/')
+            .map_or(&*model.id, |(_, name)| name);
+        Some(name.to_string())
+    }
+
+    fn attachment_limits(&self) -> AttachmentLimits {
+        AttachmentLimits {
+            image_media_types: Some(chat::IMAGE_MEDIA_TYPES),
+            document_media_types: Some(&[DocumentMediaType::PlainText]),
+            ..Default::default()
+        }
+    }
+
+    fn messages<'a>(
+        &'a self,
+        request: MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, Self::MessagesEventStream> {
+        let body = self.request_body(request);
+        let mut builder = self
+            .client
+            .post(format!("{}/chat/completions", self.base_url))
+            .bearer_auth(&self.api_key)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        for (name, value) in &self.headers {
+            builder = builder.header(*name, value);
+        }
+
+        Box::pin(chat::send(builder))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_model() {
+        let model = "anthropic/claude-sonnet-4, openai/gpt-4o,"
+            .parse::<OpenRouterModel>()
+            .unwrap();
+        assert_eq!(
+            model,
+            OpenRouterModel::new("anthropic/claude-sonnet-4").with_fallback("openai/gpt-4o")
+        );
+        assert_eq!(model.to_string(), "anthropic/claude-sonnet-4,openai/gpt-4o");
+        assert!(" , ".parse::<OpenRouterModel>().is_err());
+
+        let backend = OpenRouterBackend::new("key");
+        assert_eq!(
+            backend.model_name(&model).as_deref(),
+            Some("claude-sonnet-4")
+        );
+        assert_eq!(
+            backend
+                .model_name(&OpenRouterModel::new("mistral"))
+                .as_deref(),
+            Some("mistral")
+        );
+    }
+
+    #[test]
+    fn test_request_body_routes_models_and_providers() {
+        let backend =
+            OpenRouterBackend::new("key").with_provider_preferences(ProviderPreferences {
+                order: vec!["Anthropic".to_string()],
+                allow_fallbacks: Some(false),
+                ..Default::default()
+            });
+        let request = |model: OpenRouterModel| MessagesRequest::<OpenRouterBackend> {
+            model,
+            messages: Vec::new(),
+            max_tokens: 64,
+            system: None,
+            temperature: None,
+            stop_sequences: None,
+            tool_choice: None,
+            tools: None,
+            reasoning: None,
+            user_id: None,
+            extensions: HashMap::new(),
+        };
+
+        let body = backend.request_body(request(
+            OpenRouterModel::new("anthropic/claude-sonnet-4").with_fallback("openai/gpt-4o"),
+        ));
+        assert_eq!(body["model"], "anthropic/claude-sonnet-4");
+        assert_eq!(
+            body["models"],
+            json!(["anthropic/claude-sonnet-4", "openai/gpt-4o"])
+        );
+        assert_eq!(
+            body["provider"],
+            json!({ "order": ["Anthropic"], "allow_fallbacks": false })
+        );
+
+        let body = OpenRouterBackend::new("key").request_body(request(OpenRouterModel::auto()));
+        assert_eq!(body["model"], AUTO_ROUTER);
+        assert!(body.get("models").is_none());
+        assert!(body.get("provider").is_none());
+    }
+}
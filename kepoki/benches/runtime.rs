@@ -0,0 +1,248 @@
+//! Overhead of the runtime itself, measured against a backend that streams canned responses.
+//!
+//! Timings are compared against a saved baseline, a change beyond the noise threshold is
+//! reported as a regression:
+//!
+//! ```sh
+//! cargo bench -p kepoki -- --save-baseline main
+//! cargo bench -p kepoki -- --baseline main
+//! ```
+//!
+//! Heap usage per idle agent is checked against [`MAX_IDLE_AGENT_BYTES`] on every run.
+
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::collections::VecDeque;
+use std::hint::black_box;
+use std::sync::atomic::AtomicIsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use criterion::criterion_group;
+use criterion::criterion_main;
+use kepoki::agent::Agent;
+use kepoki::backend::Backend;
+use kepoki::backend::ContentBlock;
+use kepoki::backend::ContentBlockDelta;
+use kepoki::backend::ContentBlockStart;
+use kepoki::backend::ContentBlockStop;
+use kepoki::backend::Message;
+use kepoki::backend::MessageDelta;
+use kepoki::backend::MessageStream;
+use kepoki::backend::MessagesFuture;
+use kepoki::backend::MessagesRequest;
+use kepoki::backend::MessagesResponseEvent;
+use kepoki::backend::RecvFuture;
+use kepoki::backend::StopReason;
+use kepoki::error::KepokiError;
+use kepoki::runtime::Runtime;
+use kepoki::runtime::agent::AgentCommand;
+use kepoki::runtime::agent::AgentEvent;
+use tokio::runtime::Runtime as TokioRuntime;
+
+/// Heap bytes an idle agent may hold before the benchmark fails.
+const MAX_IDLE_AGENT_BYTES: isize = 64 * 1024;
+
+/// Agents spawned to measure the memory of an idle agent.
+const IDLE_AGENTS: usize = 64;
+
+struct CountingAllocator;
+
+static ALLOCATED: AtomicIsize = AtomicIsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size() as isize, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size() as isize, Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Responds to every request with a text block streamed in `deltas` single token deltas.
+struct CannedBackend {
+    deltas: usize,
+}
+
+struct CannedStream(VecDeque<MessagesResponseEvent>);
+
+impl MessageStream for CannedStream {
+    fn recv(&mut self) -> RecvFuture<'_> {
+        Box::pin(std::future::ready(Ok(self.0.pop_front())))
+    }
+}
+
+impl Backend for CannedBackend {
+    type Model = ();
+    type MessagesEventStream = CannedStream;
+
+    fn messages<'a>(&'a self, _: MessagesRequest<'a, Self>) -> MessagesFuture<'a, CannedStream> {
+        let mut events = VecDeque::with_capacity(self.deltas + 5);
+        events.push_back(MessagesResponseEvent::MessageStart(Message {
+            id: String::new(),
+            content: Vec::new(),
+            stop_reason: None,
+            stop_sequence: None,
+            usage: None,
+        }));
+        events.push_back(MessagesResponseEvent::ContentBlockStart(
+            ContentBlockStart {
+                index: 0,
+                content_block: ContentBlock::Text {
+                    text: String::new(),
+                },
+            },
+        ));
+        events.extend((0..self.deltas).map(|_| {
+            MessagesResponseEvent::ContentBlockDelta(ContentBlockDelta::Text {
+                index: 0,
+                text: "token ".to_string(),
+            })
+        }));
+        events.push_back(MessagesResponseEvent::ContentBlockStop(ContentBlockStop {
+            index: 0,
+        }));
+        events.push_back(MessagesResponseEvent::MessageDelta(MessageDelta {
+            stop_reason: Some(StopReason::EndTurn),
+            stop_sequence: None,
+            usage: None,
+        }));
+        events.push_back(MessagesResponseEvent::MessageStop);
+        Box::pin(std::future::ready(Ok(CannedStream(events))))
+    }
+}
+
+/// Receives events until every agent of the runtime completed, returning how many there were.
+async fn drain(runtime: &mut Runtime) -> usize {
+    let mut events = 0;
+    loop {
+        match runtime.recv().await {
+            Ok(_) => events += 1,
+            Err(KepokiError::NoRunningAgents) => return events,
+            Err(_) => (),
+        }
+    }
+}
+
+/// Times receiving every event of a response, from its first event to the assembled message.
+///
+/// Idle agents poll for commands, so the time until the response starts and the time to exit
+/// the agent are dominated by the polling interval and not timed.
+async fn respond(deltas: usize) -> Duration {
+    let mut runtime = Runtime::builder().build();
+    let agent = runtime.spawn_agent(CannedBackend { deltas }, (), Agent::default());
+    runtime
+        .send_as_owner(&agent, AgentCommand::UserMessage("Hello".to_string()))
+        .unwrap();
+    while !matches!(runtime.recv().await.unwrap(), AgentEvent::MessageStart(_)) {}
+    let start = Instant::now();
+    while !matches!(runtime.recv().await.unwrap(), AgentEvent::Message(_)) {}
+    let elapsed = start.elapsed();
+
+    runtime.send_as_owner(&agent, AgentCommand::Exit).unwrap();
+    drain(&mut runtime).await;
+    elapsed
+}
+
+/// Times spawning an agent until it answers its first command.
+///
+/// Includes the polling interval whenever the agent starts waiting before the command arrives.
+async fn spawn() -> Duration {
+    let mut runtime = Runtime::builder().build();
+    let start = Instant::now();
+    let agent = runtime.spawn_agent(CannedBackend { deltas: 0 }, (), Agent::default());
+    runtime
+        .send_as_owner(&agent, AgentCommand::DumpState)
+        .unwrap();
+    while !matches!(runtime.recv().await.unwrap(), AgentEvent::StateDump(_)) {}
+    let elapsed = start.elapsed();
+
+    runtime.send_as_owner(&agent, AgentCommand::Exit).unwrap();
+    drain(&mut runtime).await;
+    elapsed
+}
+
+fn timed(tokio: &TokioRuntime, iterations: u64, run: impl AsyncFn() -> Duration) -> Duration {
+    (0..iterations)
+        .map(|_| black_box(tokio.block_on(run())))
+        .sum()
+}
+
+fn event_throughput(c: &mut Criterion) {
+    let tokio = TokioRuntime::new().unwrap();
+    let mut group = c.benchmark_group("event_throughput");
+    for deltas in [100, 1_000, 10_000] {
+        group.throughput(Throughput::Elements(deltas as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(deltas),
+            &deltas,
+            |b, &deltas| {
+                b.iter_custom(|iterations| timed(&tokio, iterations, || respond(deltas)));
+            },
+        );
+    }
+    group.finish();
+}
+
+fn spawn_latency(c: &mut Criterion) {
+    let tokio = TokioRuntime::new().unwrap();
+    c.bench_function("spawn_latency", |b| {
+        b.iter_custom(|iterations| timed(&tokio, iterations, spawn));
+    });
+}
+
+fn idle_agent_memory(_: &mut Criterion) {
+    let tokio = TokioRuntime::new().unwrap();
+    tokio.block_on(async {
+        let mut runtime = Runtime::builder().build();
+        let before = ALLOCATED.load(Ordering::Relaxed);
+        let agents = (0..IDLE_AGENTS)
+            .map(|_| runtime.spawn_agent(CannedBackend { deltas: 0 }, (), Agent::default()))
+            .collect::<Vec<_>>();
+
+        // Give every agent time to reach its idle loop.
+        let start = Instant::now();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let per_agent = (ALLOCATED.load(Ordering::Relaxed) - before) / IDLE_AGENTS as isize;
+        println!(
+            "idle_agent_memory: {per_agent} bytes per agent ({IDLE_AGENTS} agents, settled for {:?})",
+            start.elapsed()
+        );
+
+        for agent in &agents {
+            runtime.send_as_owner(agent, AgentCommand::Exit).unwrap();
+        }
+        drain(&mut runtime).await;
+
+        assert!(
+            per_agent <= MAX_IDLE_AGENT_BYTES,
+            "An idle agent holds {per_agent} bytes, more than the threshold of {MAX_IDLE_AGENT_BYTES}"
+        );
+    });
+}
+
+fn config() -> Criterion {
+    // Every iteration waits for an agent to exit, keep the sample count low.
+    Criterion::default()
+        .sample_size(20)
+        .noise_threshold(0.05)
+        .significance_level(0.01)
+}
+
+criterion_group! {
+    name = benches;
+    config = config();
+    targets = event_throughput, spawn_latency, idle_agent_memory
+}
+criterion_main!(benches);
@@ -1,8 +1,12 @@
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::hash::Hash;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 use serde::Deserialize;
 use serde::Serialize;
+use serde_json::Value;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
@@ -23,22 +27,115 @@ pub struct Agent {
     /// The amount of randomness injected into the response.
     #[serde(default = "Agent::default_temperature")]
     pub temperature: f32,
+    /// Lets the model think before responding, if the backend supports it.
+    #[serde(default)]
+    pub reasoning: Option<Reasoning>,
+    /// Custom text sequences that cause the model to stop generating.
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
     #[serde(default)]
     pub mcp_servers: HashMap<String, McpServer>,
+    /// Whether the usage instructions MCP servers provide are appended to the prompt.
+    #[serde(default = "Agent::default_mcp_instructions")]
+    pub mcp_instructions: bool,
     #[serde(default)]
     pub tools: Vec<ToolName>,
     #[serde(default)]
     pub allowed_tools: Vec<ToolName>,
+    /// Descriptions tools are advertised with instead of their own, such as shorter versions
+    /// of verbose MCP tool descriptions to save tokens.
+    #[serde(default)]
+    pub tool_descriptions: HashMap<ToolName, String>,
+    /// Limits the tools advertised each turn to those most relevant to the conversation.
+    #[serde(default)]
+    pub tool_selection: Option<ToolSelection>,
+    /// Limits on how long tool calls may run before they are cancelled.
+    #[serde(default)]
+    pub tool_timeouts: ToolTimeouts,
+    /// The directory the agent works in, defaults to the current directory of the runtime.
+    ///
+    /// Advertised to MCP servers as a root together with any file resources.
+    #[serde(default)]
+    pub working_directory: Option<PathBuf>,
     #[serde(default)]
     pub resources: Vec<String>,
     #[serde(default)]
     pub hooks: HashMap<HookTrigger, Vec<Hook>>,
+    /// What a critic checks the responses of the agent against, see
+    /// [`reflect`](crate::runtime::patterns::reflect).
+    #[serde(default)]
+    pub review_criteria: Vec<String>,
+    /// Checks user input for personal information before it is added to the history and sent
+    /// to the backend.
+    #[serde(default)]
+    pub pii_policy: Option<PiiPolicy>,
+    /// Where the users of the agent are, so it responds with their local time and formats.
+    #[serde(default)]
+    pub locale: Option<Locale>,
+    /// Loads instruction files such as `AGENTS.md` from the project into the system prompt.
+    #[serde(default)]
+    pub context_files: Option<ContextFiles>,
+    /// Providers whose context is added to every request, see [`crate::context`].
+    #[serde(default)]
+    pub context_providers: Vec<ContextProviderSetting>,
+    /// Transformations applied to responses before they are emitted, in order, see
+    /// [`crate::output`].
+    #[serde(default)]
+    pub output_processors: Vec<OutputProcessing>,
+    /// Confines the local MCP servers of the agent to its working directory, see
+    /// [`crate::sandbox`].
+    #[serde(default)]
+    pub sandbox: Option<Sandbox>,
+    /// Where the local MCP servers of the agent run, see [`crate::sandbox`].
+    #[serde(default)]
+    pub execution: Execution,
+    /// The container the local MCP servers of the agent run in with `execution: container`.
+    #[serde(default)]
+    pub container: Option<ContainerSettings>,
+    /// Which pending requests are sent first once the runtime limits concurrent requests, see
+    /// [`crate::runtime::scheduling`].
+    #[serde(default)]
+    pub priority: Priority,
+    /// Provider specific parameters sent with every request, such as `logit_bias`, see
+    /// [`MessagesRequest::extensions`](crate::backend::MessagesRequest::extensions).
+    #[serde(default)]
+    pub extensions: HashMap<String, Value>,
 }
 
 impl Agent {
     fn default_temperature() -> f32 {
         0.5
     }
+
+    fn default_mcp_instructions() -> bool {
+        true
+    }
+
+    /// Adds MCP servers, such as those loaded from a configuration file, to the agent.
+    ///
+    /// Servers already defined by the agent take precedence over servers with the same name.
+    pub fn merge_mcp_servers(&mut self, servers: impl IntoIterator<Item = (String, McpServer)>) {
+        for (name, server) in servers {
+            self.mcp_servers.entry(name).or_insert(server);
+        }
+    }
+
+    /// The filesystem locations the agent works with: its working directory followed by any
+    /// resources that are absolute paths or `file://` URIs.
+    pub fn roots(&self) -> Vec<PathBuf> {
+        let working_directory = self
+            .working_directory
+            .clone()
+            .or_else(|| std::env::current_dir().ok());
+
+        working_directory
+            .into_iter()
+            .chain(self.resources.iter().filter_map(|resource| {
+                let path = PathBuf::from(resource.strip_prefix("file://").unwrap_or(resource));
+                path.is_absolute().then_some(path)
+            }))
+            .collect()
+    }
 }
 
 impl Default for Agent {
@@ -50,11 +147,29 @@ impl Default for Agent {
             prompt: "You are a helpful assistant designed for basic knowledge tasks. Always respond even if it means asking for guidance.".to_string(),
             model_preferences: ModelPreferences::default(),
             temperature: Self::default_temperature(),
+            reasoning: None,
+            stop_sequences: Vec::new(),
             mcp_servers: HashMap::new(),
+            mcp_instructions: Self::default_mcp_instructions(),
             tools: Vec::new(),
             allowed_tools: Vec::new(),
+            tool_descriptions: HashMap::new(),
+            tool_selection: None,
+            tool_timeouts: ToolTimeouts::default(),
+            working_directory: None,
             resources: Vec::new(),
             hooks: HashMap::new(),
+            review_criteria: Vec::new(),
+            pii_policy: None,
+            locale: None,
+            context_files: None,
+            context_providers: Vec::new(),
+            output_processors: Vec::new(),
+            sandbox: None,
+            execution: Execution::default(),
+            container: None,
+            priority: Priority::default(),
+            extensions: HashMap::new(),
         }
     }
 }
@@ -98,6 +213,45 @@ pub enum ModelMetric {
     Code,
 }
 
+/// How much a model may reason before responding.
+///
+/// Backends translate this to their own configuration, an effort level is mapped to a token
+/// budget for providers that expect one and vice versa.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum Reasoning {
+    Effort(ReasoningEffort),
+    BudgetTokens(u32),
+}
+
+impl Reasoning {
+    pub fn effort(&self) -> ReasoningEffort {
+        match *self {
+            Self::Effort(effort) => effort,
+            Self::BudgetTokens(..=2048) => ReasoningEffort::Low,
+            Self::BudgetTokens(..=8192) => ReasoningEffort::Medium,
+            Self::BudgetTokens(_) => ReasoningEffort::High,
+        }
+    }
+
+    pub fn budget_tokens(&self) -> u32 {
+        match *self {
+            Self::Effort(ReasoningEffort::Low) => 1024,
+            Self::Effort(ReasoningEffort::Medium) => 4096,
+            Self::Effort(ReasoningEffort::High) => 16384,
+            Self::BudgetTokens(budget_tokens) => budget_tokens,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub enum McpServer {
@@ -109,7 +263,9 @@ pub enum McpServer {
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct LocalMcpServer {
     pub command: String,
+    #[serde(default)]
     pub args: Vec<String>,
+    #[serde(default)]
     pub env: HashMap<String, String>,
 }
 
@@ -117,38 +273,276 @@ impl Hash for LocalMcpServer {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.command.hash(state);
         self.args.hash(state);
-        for (key, value) in &self.env {
-            key.hash(state);
-            value.hash(state);
-        }
+        // Maps that compare equal must hash equally regardless of iteration order.
+        self.env.iter().collect::<BTreeMap<_, _>>().hash(state);
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct RemoteMcpServer {
     pub url: String,
+    /// Headers sent with every request, such as `Authorization`.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl Hash for RemoteMcpServer {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.url.hash(state);
+        self.headers.iter().collect::<BTreeMap<_, _>>().hash(state);
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ToolTimeouts {
+    /// Timeout in seconds for tools without a specific timeout, overriding the runtime default.
+    pub default: Option<u64>,
+    /// Timeouts in seconds for individual tools.
+    #[serde(default)]
+    pub tools: HashMap<ToolName, u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ToolSelection {
+    /// The maximum number of tools advertised in a turn.
+    pub max_tools: usize,
+    /// Tools that are always advertised, they count towards `max_tools`.
+    #[serde(default)]
+    pub always: Vec<ToolName>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Locale {
+    /// A BCP 47 language tag, such as `de-DE`.
+    pub language: String,
+    /// An IANA time zone name such as `Europe/Berlin`, or a fixed offset from UTC such as
+    /// `+05:30` or `UTC-8`.
+    pub timezone: String,
+}
+
+/// Instruction files loaded into the system prompt, see
+/// [`load_context_files`](crate::project::load_context_files).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ContextFiles {
+    /// The names of the files, in order of preference. Only the first one found in each
+    /// directory is loaded.
+    #[serde(default = "ContextFiles::default_names")]
+    pub names: Vec<String>,
+    /// The most bytes loaded from a file, longer files are truncated.
+    #[serde(default = "ContextFiles::default_max_bytes")]
+    pub max_bytes: usize,
+}
+
+impl ContextFiles {
+    fn default_names() -> Vec<String> {
+        vec!["AGENTS.md".to_string(), "CLAUDE.md".to_string()]
+    }
+
+    fn default_max_bytes() -> usize {
+        32 * 1024
+    }
+}
+
+impl Default for ContextFiles {
+    fn default() -> Self {
+        Self {
+            names: Self::default_names(),
+            max_bytes: Self::default_max_bytes(),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+/// A context provider an agent enables, by the name it is registered with.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ContextProviderSetting {
+    pub name: String,
+    /// The most tokens of context the provider adds to a request, longer context is truncated.
+    #[serde(default = "ContextProviderSetting::default_max_tokens")]
+    pub max_tokens: u32,
+}
+
+impl ContextProviderSetting {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            max_tokens: Self::default_max_tokens(),
+        }
+    }
+
+    fn default_max_tokens() -> u32 {
+        1024
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum OutputProcessing {
+    /// Removes XML elements such as `<thinking>` with their content.
+    StripTags { tags: Vec<String> },
+    /// Moves fenced code blocks into artifacts, leaving a reference in the response.
+    ExtractCodeBlocks,
+    /// Requires the final response to be JSON matching a schema.
+    Structured { schema: Value },
+    /// A processor registered with the runtime.
+    Custom { name: String },
+}
+
+/// What processes of a sandboxed agent may access besides its working directory.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Sandbox {
+    /// Paths hidden by the sandbox that may be read, such as package manager caches.
+    #[serde(default)]
+    pub readable_paths: Vec<PathBuf>,
+    /// Paths outside the working directory that may be written.
+    #[serde(default)]
+    pub writable_paths: Vec<PathBuf>,
+    /// Whether the network may be accessed.
+    #[serde(default)]
+    pub network: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Execution {
+    /// Processes run on the host, in the sandbox of the agent if it has one.
+    #[default]
+    Host,
+    /// Processes run in a container with the working directory mounted.
+    Container,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ContainerSettings {
+    /// The image containers are created from, which must provide the programs the agent runs.
+    pub image: String,
+    #[serde(default)]
+    pub engine: ContainerEngine,
+    /// The memory limit, such as `512m`.
+    #[serde(default)]
+    pub memory: Option<String>,
+    /// The number of CPUs containers may use, such as `1.5`.
+    #[serde(default)]
+    pub cpus: Option<String>,
+    /// Whether containers may access the network.
+    #[serde(default)]
+    pub network: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ContainerEngine {
+    #[default]
+    Docker,
+    Podman,
+}
+
+impl ContainerEngine {
+    /// The command line client of the engine.
+    pub fn program(&self) -> &'static str {
+        match self {
+            Self::Docker => "docker",
+            Self::Podman => "podman",
+        }
+    }
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Agents a user is waiting on, served before batch agents.
+    #[default]
+    Interactive,
+    /// Agents running in the background, served once no interactive agent is waiting.
+    Batch,
+}
+
+/// What happens to personal information found in user input, see [`crate::pii`].
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PiiPolicy {
+    /// The action for kinds of information without an action of their own.
+    #[serde(default)]
+    pub default: PiiAction,
+    #[serde(default)]
+    pub actions: HashMap<PiiKind, PiiAction>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum PiiKind {
+    Email,
+    Phone,
+    Ssn,
+    CreditCard,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum PiiAction {
+    /// Leaves the information as is.
+    Allow,
+    /// Replaces the information with a placeholder naming its kind, such as `[EMAIL]`.
+    #[default]
+    Mask,
+    /// Rejects the whole message.
+    Block,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct ToolName {
     namespace: String,
     name: String,
 }
 
-impl<'de> Deserialize<'de> for ToolName {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
+impl ToolName {
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether this tool is provided by the runtime rather than an MCP server.
+    pub fn is_builtin(&self) -> bool {
+        self.namespace == "builtin"
+    }
+
+    /// The name the tool is advertised to the model as.
+    ///
+    /// Model providers restrict tool names to `[a-zA-Z0-9_-]`, so MCP tools are advertised as
+    /// `namespace__name`.
+    pub fn wire_name(&self) -> String {
+        match self.is_builtin() {
+            true => self.name.clone(),
+            false => format!("{}__{}", self.namespace, self.name),
+        }
+    }
+}
+
+impl FromStr for ToolName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
         Ok(match s.split_once('/') {
             Some((namespace, name)) => {
                 let Some(namespace) = namespace.strip_prefix("@") else {
-                    return Err(serde::de::Error::custom(
-                        "Tool namespace must start with '@'",
-                    ));
+                    return Err("Tool namespace must start with '@'".to_string());
                 };
 
                 ToolName {
@@ -158,12 +552,23 @@ impl<'de> Deserialize<'de> for ToolName {
             }
             None => ToolName {
                 namespace: "builtin".to_string(),
-                name: s,
+                name: s.to_string(),
             },
         })
     }
 }
 
+impl<'de> Deserialize<'de> for ToolName {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 impl Serialize for ToolName {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -173,9 +578,15 @@ impl Serialize for ToolName {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+/// The points in a turn at which hooks run, see [`crate::runtime::hooks`].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
-pub enum HookTrigger {}
+pub enum HookTrigger {
+    UserMessage,
+    BeforeToolUse,
+    AfterToolUse,
+    Response,
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
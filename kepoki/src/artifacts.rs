@@ -0,0 +1,463 @@
+//! Artifacts are named documents, such as code files or reports, that agents create and update
+//! through builtin tools. They live outside of the transcript so large outputs only need to be
+//! generated once and can be edited in place afterwards.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::attachments::MediaType;
+use crate::backend::ImageSource;
+use crate::backend::Payload;
+use crate::backend::Tool;
+use crate::backend::ToolResultContentBlock;
+use crate::runtime::AgentHandle;
+use crate::runtime::agent::AgentEvent;
+use crate::tools::BuiltinTool;
+use crate::tools::ToolContext;
+use crate::tools::ToolFuture;
+use crate::tools::ToolOutput;
+use crate::tools::required_str;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Artifact {
+    /// The unique name of the artifact within the owning agent.
+    pub name: String,
+    /// The media type of the content, such as `text/markdown`.
+    pub media_type: Option<String>,
+    /// The current content of the artifact, a description for binary artifacts.
+    pub content: String,
+    /// The content of binary artifacts, such as generated images.
+    #[serde(default)]
+    pub data: Option<Payload>,
+    /// Incremented every time the artifact changes, starting at 1.
+    pub version: u32,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum ArtifactChange {
+    Created,
+    Updated,
+}
+
+/// Artifacts for every agent in a runtime, keyed by the agent that owns them.
+#[derive(Clone, Debug, Default)]
+pub struct ArtifactStore {
+    artifacts: Arc<Mutex<HashMap<AgentHandle, HashMap<String, Artifact>>>>,
+}
+
+impl ArtifactStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, agent: &AgentHandle, name: &str) -> Option<Artifact> {
+        self.artifacts
+            .lock()
+            .unwrap()
+            .get(agent)
+            .and_then(|artifacts| artifacts.get(name))
+            .cloned()
+    }
+
+    pub fn list(&self, agent: &AgentHandle) -> Vec<Artifact> {
+        let mut artifacts = self
+            .artifacts
+            .lock()
+            .unwrap()
+            .get(agent)
+            .map(|artifacts| artifacts.values().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        artifacts.sort_by(|a, b| a.name.cmp(&b.name));
+        artifacts
+    }
+
+    /// Creates an artifact, failing if one with the same name already exists.
+    pub fn create(
+        &self,
+        agent: &AgentHandle,
+        name: &str,
+        media_type: Option<String>,
+        content: String,
+    ) -> Result<Artifact, String> {
+        self.insert(
+            agent,
+            Artifact {
+                name: name.to_string(),
+                media_type,
+                content,
+                data: None,
+                version: 1,
+            },
+        )
+    }
+
+    /// Creates a binary artifact described by `description`, failing if one with the same name
+    /// already exists.
+    pub fn create_binary(
+        &self,
+        agent: &AgentHandle,
+        name: &str,
+        media_type: String,
+        description: String,
+        data: Payload,
+    ) -> Result<Artifact, String> {
+        self.insert(
+            agent,
+            Artifact {
+                name: name.to_string(),
+                media_type: Some(media_type),
+                content: description,
+                data: Some(data),
+                version: 1,
+            },
+        )
+    }
+
+    /// The first of `name`, `name-2`, `name-3`, and so on that no artifact of `agent` has.
+    pub fn unused_name(&self, agent: &AgentHandle, name: &str) -> String {
+        let artifacts = self.artifacts.lock().unwrap();
+        let taken = |candidate: &str| {
+            artifacts
+                .get(agent)
+                .is_some_and(|artifacts| artifacts.contains_key(candidate))
+        };
+        (1..)
+            .map(|number| match number {
+                1 => name.to_string(),
+                number => format!("{name}-{number}"),
+            })
+            .find(|candidate| !taken(candidate))
+            .unwrap()
+    }
+
+    fn insert(&self, agent: &AgentHandle, artifact: Artifact) -> Result<Artifact, String> {
+        let mut artifacts = self.artifacts.lock().unwrap();
+        let artifacts = artifacts.entry(agent.clone()).or_default();
+        if artifacts.contains_key(&artifact.name) {
+            return Err(format!("Artifact `{}` already exists", artifact.name));
+        }
+
+        artifacts.insert(artifact.name.clone(), artifact.clone());
+        Ok(artifact)
+    }
+
+    /// Applies `edit` to the content of an existing artifact and bumps its version.
+    pub fn update(
+        &self,
+        agent: &AgentHandle,
+        name: &str,
+        edit: impl FnOnce(&mut String) -> Result<(), String>,
+    ) -> Result<Artifact, String> {
+        let mut artifacts = self.artifacts.lock().unwrap();
+        let Some(artifact) = artifacts
+            .get_mut(agent)
+            .and_then(|artifacts| artifacts.get_mut(name))
+        else {
+            return Err(format!("Artifact `{name}` does not exist"));
+        };
+
+        edit(&mut artifact.content)?;
+        artifact.version += 1;
+        Ok(artifact.clone())
+    }
+
+    /// Drops every artifact owned by `agent`.
+    pub fn remove_agent(&self, agent: &AgentHandle) {
+        self.artifacts.lock().unwrap().remove(agent);
+    }
+}
+
+pub struct CreateArtifactTool(pub ArtifactStore);
+
+impl BuiltinTool for CreateArtifactTool {
+    fn definition(&self) -> Tool<'static> {
+        Tool {
+            name: "create_artifact".into(),
+            input_schema: Some(
+                r#"{"type":"object","properties":{"name":{"type":"string"},"media_type":{"type":"string"},"content":{"type":"string"}},"required":["name","content"]}"#.into(),
+            ),
+            description: Some(
+                "Creates a named artifact, such as a code file or report, stored outside of the conversation. Prefer artifacts over repeating large outputs in messages.".into(),
+            ),
+        }
+    }
+
+    fn call(&self, context: ToolContext, input: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let (name, content) = match (
+                required_str(&input, "name"),
+                required_str(&input, "content"),
+            ) {
+                (Ok(name), Ok(content)) => (name, content),
+                (Err(err), _) | (_, Err(err)) => return err,
+            };
+            let media_type = input
+                .get("media_type")
+                .and_then(Value::as_str)
+                .map(str::to_string);
+
+            match self
+                .0
+                .create(&context.agent, name, media_type, content.to_string())
+            {
+                Ok(artifact) => {
+                    context.emit(AgentEvent::ArtifactChanged {
+                        name: artifact.name,
+                        version: artifact.version,
+                        change: ArtifactChange::Created,
+                    });
+                    ToolOutput::text(format!("Created artifact `{name}`"))
+                }
+                Err(err) => ToolOutput::error(err),
+            }
+        })
+    }
+}
+
+pub struct UpdateArtifactTool(pub ArtifactStore);
+
+impl BuiltinTool for UpdateArtifactTool {
+    fn definition(&self) -> Tool<'static> {
+        Tool {
+            name: "update_artifact".into(),
+            input_schema: Some(
+                r#"{"type":"object","properties":{"name":{"type":"string"},"old_text":{"type":"string"},"new_text":{"type":"string"},"content":{"type":"string"}},"required":["name"]}"#.into(),
+            ),
+            description: Some(
+                "Updates an existing artifact. Either replace a unique `old_text` with `new_text`, or replace the whole artifact with `content`.".into(),
+            ),
+        }
+    }
+
+    fn call(&self, context: ToolContext, input: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let name = match required_str(&input, "name") {
+                Ok(name) => name,
+                Err(err) => return err,
+            };
+
+            let result = self.0.update(&context.agent, name, |content| {
+                if let Some(replacement) = input.get("content").and_then(Value::as_str) {
+                    *content = replacement.to_string();
+                    return Ok(());
+                }
+
+                let (Some(old_text), Some(new_text)) = (
+                    input.get("old_text").and_then(Value::as_str),
+                    input.get("new_text").and_then(Value::as_str),
+                ) else {
+                    return Err("Provide either `content` or both `old_text` and `new_text`".into());
+                };
+
+                match content.matches(old_text).count() {
+                    0 => Err("`old_text` was not found in the artifact".into()),
+                    1 => {
+                        *content = content.replacen(old_text, new_text, 1);
+                        Ok(())
+                    }
+                    _ => Err("`old_text` matches more than once, include more context".into()),
+                }
+            });
+
+            match result {
+                Ok(artifact) => {
+                    context.emit(AgentEvent::ArtifactChanged {
+                        name: artifact.name,
+                        version: artifact.version,
+                        change: ArtifactChange::Updated,
+                    });
+                    ToolOutput::text(format!(
+                        "Updated artifact `{name}` to version {}",
+                        artifact.version
+                    ))
+                }
+                Err(err) => ToolOutput::error(err),
+            }
+        })
+    }
+}
+
+pub struct ReadArtifactTool(pub ArtifactStore);
+
+impl BuiltinTool for ReadArtifactTool {
+    fn definition(&self) -> Tool<'static> {
+        Tool {
+            name: "read_artifact".into(),
+            input_schema: Some(
+                r#"{"type":"object","properties":{"name":{"type":"string"}},"required":["name"]}"#
+                    .into(),
+            ),
+            description: Some("Reads the current content of an artifact.".into()),
+        }
+    }
+
+    fn call(&self, context: ToolContext, input: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let name = match required_str(&input, "name") {
+                Ok(name) => name,
+                Err(err) => return err,
+            };
+
+            let Some(artifact) = self.0.get(&context.agent, name) else {
+                return ToolOutput::error(format!("Artifact `{name}` does not exist"));
+            };
+
+            let mut output = ToolOutput::text(artifact.content);
+            if let Some(data) = artifact.data
+                && let Some(MediaType::Image(media_type)) = MediaType::sniff(&data)
+            {
+                output.content.push(ToolResultContentBlock::Image {
+                    source: ImageSource::Base64 { data, media_type },
+                });
+            }
+            output
+        })
+    }
+
+    fn side_effecting(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::Agent;
+    use crate::backend::ContentBlock;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+    use crate::runtime::Runtime;
+    use crate::runtime::agent::AgentCommand;
+
+    #[test]
+    fn test_store_keeps_artifacts_per_agent() {
+        let store = ArtifactStore::new();
+        let (a, b) = (AgentHandle::detached("a"), AgentHandle::detached("b"));
+        store.create(&a, "report", None, "A".to_string()).unwrap();
+        store.create(&a, "notes", None, "B".to_string()).unwrap();
+        store.create(&b, "report", None, "C".to_string()).unwrap();
+        assert!(store.create(&a, "report", None, "D".to_string()).is_err());
+
+        let names = store
+            .list(&a)
+            .into_iter()
+            .map(|artifact| artifact.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["notes", "report"]);
+        assert_eq!(store.get(&a, "report").unwrap().content, "A");
+        assert_eq!(store.get(&b, "report").unwrap().content, "C");
+        assert_eq!(store.unused_name(&a, "report"), "report-2");
+        assert_eq!(store.unused_name(&a, "summary"), "summary");
+
+        store.remove_agent(&a);
+        assert!(store.list(&a).is_empty());
+        assert!(store.get(&b, "report").is_some());
+    }
+
+    #[test]
+    fn test_store_update_bumps_version() {
+        let store = ArtifactStore::new();
+        let agent = AgentHandle::detached("a");
+        store
+            .create(&agent, "report", None, "Draft".to_string())
+            .unwrap();
+
+        let artifact = store
+            .update(&agent, "report", |content| {
+                content.push_str(" 2");
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(artifact.content, "Draft 2");
+        assert_eq!(artifact.version, 2);
+
+        // Failed edits leave the artifact as it was.
+        assert!(
+            store
+                .update(&agent, "report", |_| Err("Nope".to_string()))
+                .is_err()
+        );
+        assert_eq!(store.get(&agent, "report").unwrap().version, 2);
+        assert!(store.update(&agent, "missing", |_| Ok(())).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tools_emit_artifact_changes() {
+        let backend = MockBackend::new()
+            .with_response(MockResponse::tool_use(
+                "call_1",
+                "create_artifact",
+                serde_json::json!({ "name": "report", "content": "Sales are up." }),
+            ))
+            .with_response(MockResponse::tool_use(
+                "call_2",
+                "update_artifact",
+                serde_json::json!({ "name": "report", "old_text": "up", "new_text": "down" }),
+            ))
+            .with_response(MockResponse::tool_use(
+                "call_3",
+                "read_artifact",
+                serde_json::json!({ "name": "report" }),
+            ))
+            .with_response(MockResponse::text("Done"));
+        let mut runtime = Runtime::builder().build();
+        let tools = ["create_artifact", "update_artifact", "read_artifact"]
+            .map(|tool| tool.parse().unwrap())
+            .to_vec();
+        let agent = Agent {
+            tools: tools.clone(),
+            allowed_tools: tools,
+            ..Default::default()
+        };
+        let agent = runtime.spawn_agent(backend.clone(), "mock".to_string(), agent);
+        runtime
+            .send_as_owner(
+                &agent,
+                AgentCommand::UserMessage("Write a report".to_string()),
+            )
+            .unwrap();
+
+        let mut changes = Vec::new();
+        loop {
+            match runtime.recv().await.unwrap() {
+                AgentEvent::ArtifactChanged {
+                    name,
+                    version,
+                    change,
+                } => changes.push((name, version, change)),
+                AgentEvent::Message(message)
+                    if matches!(&message.content[..], [ContentBlock::Text { .. }]) =>
+                {
+                    break;
+                }
+                _ => (),
+            }
+        }
+        runtime.send_as_owner(&agent, AgentCommand::Exit).unwrap();
+
+        assert_eq!(
+            changes,
+            [
+                ("report".to_string(), 1, ArtifactChange::Created),
+                ("report".to_string(), 2, ArtifactChange::Updated),
+            ]
+        );
+        assert_eq!(
+            runtime.artifact(&agent, "report").unwrap().content,
+            "Sales are down."
+        );
+        let requests = backend.requests();
+        assert!(matches!(
+            &requests[3].messages.last().unwrap().content[..],
+            [ContentBlock::ToolResult { tool_use_id, is_error: None | Some(false), .. }]
+                if tool_use_id == "call_3"
+        ));
+    }
+}
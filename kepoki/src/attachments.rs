@@ -0,0 +1,349 @@
+//! Reads files into image and document blocks, checking their size before they are read and
+//! sniffing their format from their content, so oversized or mislabeled attachments never reach
+//! a provider.
+
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::backend::AttachmentLimits;
+use crate::backend::ContentBlock;
+use crate::backend::DocumentMediaType;
+use crate::backend::DocumentSource;
+use crate::backend::ImageMediaType;
+use crate::backend::ImageSource;
+use crate::backend::Payload;
+use crate::backend::ToolResultContentBlock;
+use crate::error::KepokiError;
+
+/// The number of bytes read to detect the format of a file.
+const SNIFF_BYTES: u64 = 8192;
+
+impl Payload {
+    /// Reads the raw bytes of an attachment, failing as soon as more than `max_bytes` were read.
+    ///
+    /// Payloads are base64 encoded only while a request is serialized, so no encoded copy is
+    /// kept in memory.
+    pub fn read(reader: impl Read, max_bytes: Option<u64>) -> Result<Self, KepokiError> {
+        let mut data = Vec::new();
+        reader
+            .take(max_bytes.map_or(u64::MAX, |limit| limit.saturating_add(1)))
+            .read_to_end(&mut data)?;
+        check_size(data.len() as u64, max_bytes)?;
+        Ok(data.into())
+    }
+
+    fn read_file(path: &Path, max_bytes: Option<u64>) -> Result<Self, KepokiError> {
+        let file = File::open(path)?;
+        check_size(file.metadata()?.len(), max_bytes)?;
+        Self::read(file, max_bytes)
+    }
+}
+
+/// The format of an attachment, as detected by [`MediaType::sniff`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MediaType {
+    Image(ImageMediaType),
+    Document(DocumentMediaType),
+}
+
+impl MediaType {
+    /// Detects the format of an attachment from its first bytes, `None` if it isn't one that
+    /// providers accept.
+    ///
+    /// Content that isn't any of the binary formats is plain text if it is valid UTF-8 without
+    /// NUL bytes, a character cut off at the end of `bytes` is tolerated.
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        Some(match bytes {
+            [0xFF, 0xD8, 0xFF, ..] => Self::Image(ImageMediaType::Jpeg),
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => {
+                Self::Image(ImageMediaType::Png)
+            }
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Self::Image(ImageMediaType::Gif),
+            [
+                b'R',
+                b'I',
+                b'F',
+                b'F',
+                _,
+                _,
+                _,
+                _,
+                b'W',
+                b'E',
+                b'B',
+                b'P',
+                ..,
+            ] => Self::Image(ImageMediaType::Webp),
+            [b'%', b'P', b'D', b'F', b'-', ..] => Self::Document(DocumentMediaType::Pdf),
+            bytes if is_text(bytes) => Self::Document(DocumentMediaType::PlainText),
+            _ => return None,
+        })
+    }
+}
+
+impl ImageMediaType {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Gif => "image/gif",
+            Self::Webp => "image/webp",
+        }
+    }
+}
+
+impl ContentBlock {
+    /// Reads an image or document file, detecting its format from its content.
+    ///
+    /// Pass the limits of the backend, see [`Backend::attachment_limits`], so attachments the
+    /// provider would reject fail here instead. With the `image-resize` feature, oversized
+    /// images are downscaled rather than rejected.
+    ///
+    /// [`Backend::attachment_limits`]: crate::backend::Backend::attachment_limits
+    pub fn attachment_from_file(
+        path: impl AsRef<Path>,
+        limits: &AttachmentLimits,
+    ) -> Result<Self, KepokiError> {
+        let path = path.as_ref();
+        let block = match sniff_file(path)? {
+            MediaType::Image(media_type) => {
+                let (data, media_type) = read_image(path, media_type, limits.max_image_bytes)?;
+                Self::Image {
+                    source: ImageSource::Base64 { data, media_type },
+                }
+            }
+            MediaType::Document(media_type) => Self::Document {
+                source: DocumentSource::Base64 {
+                    data: Payload::read_file(path, limits.max_document_bytes)?,
+                    media_type,
+                },
+                title: title(path),
+            },
+        };
+
+        limits.check(std::slice::from_ref(&block))?;
+        Ok(block)
+    }
+
+    /// Reads a JPEG, PNG, GIF, or WebP image file, detecting its format from its content.
+    ///
+    /// Pass the limit of the backend, see [`Backend::attachment_limits`]. With the
+    /// `image-resize` feature, images over the limit are downscaled until they fit instead of
+    /// failing.
+    ///
+    /// [`Backend::attachment_limits`]: crate::backend::Backend::attachment_limits
+    pub fn image_from_file(
+        path: impl AsRef<Path>,
+        max_bytes: Option<u64>,
+    ) -> Result<Self, KepokiError> {
+        let path = path.as_ref();
+        let MediaType::Image(media_type) = sniff_file(path)? else {
+            return Err(unsupported(path, "is not a JPEG, PNG, GIF, or WebP image"));
+        };
+
+        let (data, media_type) = read_image(path, media_type, max_bytes)?;
+        Ok(Self::Image {
+            source: ImageSource::Base64 { data, media_type },
+        })
+    }
+
+    /// Reads a PDF or plain text file, titled with its file name.
+    pub fn document_from_file(
+        path: impl AsRef<Path>,
+        max_bytes: Option<u64>,
+    ) -> Result<Self, KepokiError> {
+        let path = path.as_ref();
+        let MediaType::Document(media_type) = sniff_file(path)? else {
+            return Err(unsupported(path, "is not a PDF or plain text document"));
+        };
+
+        Ok(Self::Document {
+            source: DocumentSource::Base64 {
+                data: Payload::read_file(path, max_bytes)?,
+                media_type,
+            },
+            title: title(path),
+        })
+    }
+}
+
+impl AttachmentLimits {
+    /// Checks the size and format of every image and document in `content`, including those
+    /// in tool results.
+    pub fn check(&self, content: &[ContentBlock]) -> Result<(), KepokiError> {
+        content.iter().try_for_each(|block| match block {
+            ContentBlock::Image { source } => self.check_image(source),
+            ContentBlock::Document {
+                source: DocumentSource::Base64 { data, media_type },
+                ..
+            } => {
+                check_supported(media_type, self.document_media_types)?;
+                check_size(data.len() as u64, self.max_document_bytes)
+            }
+            ContentBlock::ToolResult {
+                content: Some(content),
+                ..
+            } => content.iter().try_for_each(|block| match block {
+                ToolResultContentBlock::Image { source } => self.check_image(source),
+                ToolResultContentBlock::Text { .. } | ToolResultContentBlock::Unknown { .. } => {
+                    Ok(())
+                }
+            }),
+            _ => Ok(()),
+        })
+    }
+
+    fn check_image(&self, source: &ImageSource) -> Result<(), KepokiError> {
+        let ImageSource::Base64 { data, media_type } = source;
+        check_supported(media_type, self.image_media_types)?;
+        check_size(data.len() as u64, self.max_image_bytes)
+    }
+}
+
+fn check_size(size: u64, limit: Option<u64>) -> Result<(), KepokiError> {
+    match limit {
+        Some(limit) if size > limit => Err(KepokiError::AttachmentTooLarge { size, limit }),
+        _ => Ok(()),
+    }
+}
+
+fn check_supported<T: Copy + Debug + PartialEq>(
+    media_type: &T,
+    supported: Option<&[T]>,
+) -> Result<(), KepokiError> {
+    match supported {
+        Some(supported) if !supported.contains(media_type) => {
+            Err(KepokiError::UnsupportedAttachment(format!(
+                "{media_type:?} is not supported by the backend"
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "image-resize"))]
+fn read_image(
+    path: &Path,
+    media_type: ImageMediaType,
+    max_bytes: Option<u64>,
+) -> Result<(Payload, ImageMediaType), KepokiError> {
+    Ok((Payload::read_file(path, max_bytes)?, media_type))
+}
+
+/// Reads an image file, downscaling it if it is larger than `max_bytes`.
+#[cfg(feature = "image-resize")]
+fn read_image(
+    path: &Path,
+    media_type: ImageMediaType,
+    max_bytes: Option<u64>,
+) -> Result<(Payload, ImageMediaType), KepokiError> {
+    let data = Payload::read_file(path, None)?;
+    match max_bytes {
+        Some(limit) if data.len() as u64 > limit => downscale(&data, media_type, limit),
+        _ => Ok((data, media_type)),
+    }
+}
+
+/// Shrinks an image until its encoding fits in `limit` bytes.
+///
+/// JPEGs stay JPEGs, every other format is re-encoded as PNG since the image crate can't
+/// encode lossy WebP and GIF palettes degrade badly when resized.
+#[cfg(feature = "image-resize")]
+fn downscale(
+    bytes: &[u8],
+    media_type: ImageMediaType,
+    limit: u64,
+) -> Result<(Payload, ImageMediaType), KepokiError> {
+    use std::io::Cursor;
+
+    use image::ImageFormat;
+    use image::imageops::FilterType;
+
+    let invalid = |err: image::ImageError| KepokiError::UnsupportedAttachment(err.to_string());
+
+    let mut image = image::load_from_memory(bytes).map_err(invalid)?;
+    let (format, media_type) = match media_type {
+        ImageMediaType::Jpeg => {
+            // The JPEG encoder rejects alpha channels.
+            image = image.to_rgb8().into();
+            (ImageFormat::Jpeg, ImageMediaType::Jpeg)
+        }
+        _ => (ImageFormat::Png, ImageMediaType::Png),
+    };
+
+    let mut size = bytes.len() as u64;
+    loop {
+        // Encoded size scales roughly with the pixel count, aim a little below the limit.
+        let scale = (limit as f64 / size as f64).sqrt() * 0.9;
+        let width = (image.width() as f64 * scale) as u32;
+        let height = (image.height() as f64 * scale) as u32;
+        if width == 0 || height == 0 {
+            return Err(KepokiError::AttachmentTooLarge { size, limit });
+        }
+
+        image = image.resize(width, height, FilterType::Triangle);
+        let mut encoded = Cursor::new(Vec::new());
+        image.write_to(&mut encoded, format).map_err(invalid)?;
+        let encoded = encoded.into_inner();
+        size = encoded.len() as u64;
+        if size <= limit {
+            return Ok((encoded.into(), media_type));
+        }
+    }
+}
+
+/// Sniffs the format of a file from its first bytes.
+fn sniff_file(path: &Path) -> Result<MediaType, KepokiError> {
+    let mut head = Vec::with_capacity(SNIFF_BYTES as usize);
+    File::open(path)?.take(SNIFF_BYTES).read_to_end(&mut head)?;
+    MediaType::sniff(&head).ok_or_else(|| unsupported(path, "has an unsupported format"))
+}
+
+fn is_text(bytes: &[u8]) -> bool {
+    let valid = match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        // Only an incomplete character at the end, cut off by sniffing a prefix of a file.
+        Err(err) => err.error_len().is_none(),
+    };
+    valid && !bytes.contains(&0)
+}
+
+fn title(path: &Path) -> Option<String> {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+fn unsupported(path: &Path, reason: &str) -> KepokiError {
+    KepokiError::UnsupportedAttachment(format!("{} {reason}", path.display()))
+}
+
+#[cfg(all(test, feature = "image-resize"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downscales_oversized_images() {
+        let noise = image::RgbImage::from_fn(256, 256, |x, y| {
+            image::Rgb([((x * 7) ^ (y * 13)) as u8, (x * y) as u8, (x + y * 3) as u8])
+        });
+        let path = std::env::temp_dir().join(format!("kepoki-{}.png", uuid::Uuid::new_v4()));
+        noise.save(&path).unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+
+        let block = ContentBlock::image_from_file(&path, Some(size / 4));
+        std::fs::remove_file(&path).unwrap();
+
+        let ContentBlock::Image {
+            source: ImageSource::Base64 { data, media_type },
+        } = block.unwrap()
+        else {
+            panic!("expected an image");
+        };
+        assert_eq!(media_type, ImageMediaType::Png);
+        assert!(data.len() as u64 <= size / 4);
+        let resized = image::load_from_memory(&data).unwrap();
+        assert!(resized.width() < 256 && resized.height() < 256);
+    }
+}
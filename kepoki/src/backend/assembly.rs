@@ -0,0 +1,223 @@
+//! Assembles the events of a streamed response into the message they describe, for consumers
+//! that want the whole response rather than its events, see [`Backend::complete`].
+//!
+//! [`Backend::complete`]: crate::backend::Backend::complete
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use crate::backend::ContentBlock;
+use crate::backend::ContentBlockDelta;
+use crate::backend::Message;
+use crate::backend::MessageStream;
+use crate::backend::MessagesResponseEvent;
+use crate::backend::Usage;
+use crate::error::KepokiError;
+
+#[derive(Debug, Default)]
+pub struct MessageAssembler {
+    message: Option<Message>,
+    blocks: BTreeMap<usize, ContentBlock>,
+    /// The blocks received completely.
+    stopped: BTreeSet<usize>,
+}
+
+impl MessageAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an event of the response, failing with [`KepokiError::MalformedResponse`] if it
+    /// doesn't follow the events before it, such as a delta of a block that never started.
+    pub fn push(&mut self, event: MessagesResponseEvent) -> Result<(), KepokiError> {
+        match event {
+            MessagesResponseEvent::Ping | MessagesResponseEvent::Unknown(_) => (),
+            MessagesResponseEvent::MessageStart(start) => {
+                if self.message.is_some() {
+                    return Err(malformed("The message started twice"));
+                }
+
+                self.message = Some(start);
+            }
+            MessagesResponseEvent::MessageDelta(delta) => {
+                let message = self
+                    .message
+                    .as_mut()
+                    .ok_or_else(|| malformed("A delta preceded the start of the message"))?;
+
+                if let Some(stop_reason) = delta.stop_reason {
+                    message.stop_reason = Some(stop_reason);
+                }
+
+                if let Some(stop_sequence) = delta.stop_sequence {
+                    message.stop_sequence = Some(stop_sequence);
+                }
+
+                // Input tokens are reported once, output tokens as running totals.
+                if let Some(usage) = delta.usage {
+                    message.usage = Some(match message.usage.take() {
+                        Some(reported) if usage.input_tokens == 0 => Usage {
+                            input_tokens: reported.input_tokens,
+                            ..usage
+                        },
+                        _ => usage,
+                    });
+                }
+            }
+            MessagesResponseEvent::MessageStop => {
+                if self.message.is_none() {
+                    return Err(malformed("The message stopped before it started"));
+                }
+            }
+            MessagesResponseEvent::ContentBlockStart(block) => {
+                if self
+                    .blocks
+                    .insert(block.index, block.content_block)
+                    .is_some()
+                {
+                    return Err(malformed("A content block started twice"));
+                }
+            }
+            MessagesResponseEvent::ContentBlockDelta(delta) => self.push_delta(delta)?,
+            MessagesResponseEvent::ContentBlockStop(content_block_stop) => {
+                if !self.blocks.contains_key(&content_block_stop.index) {
+                    return Err(malformed("A content block stopped before it started"));
+                }
+
+                self.stopped.insert(content_block_stop.index);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push_delta(&mut self, delta: ContentBlockDelta) -> Result<(), KepokiError> {
+        let index = match &delta {
+            ContentBlockDelta::Text { index, .. }
+            | ContentBlockDelta::InputJson { index, .. }
+            | ContentBlockDelta::Thinking { index, .. }
+            | ContentBlockDelta::Signature { index, .. }
+            | ContentBlockDelta::Unknown { index, .. } => *index,
+        };
+        let Some(block) = self.blocks.get_mut(&index) else {
+            return Err(malformed("A delta preceded the start of its content block"));
+        };
+
+        match (delta, block) {
+            (ContentBlockDelta::Text { text, .. }, ContentBlock::Text { text: block_text }) => {
+                block_text.push_str(&text);
+            }
+            (
+                ContentBlockDelta::InputJson { partial_json, .. },
+                ContentBlock::ToolUse { input, .. } | ContentBlock::ServerToolUse { input, .. },
+            ) => {
+                input.push_str(&partial_json);
+            }
+            (
+                ContentBlockDelta::Thinking { thinking, .. },
+                ContentBlock::Thinking {
+                    thinking: block_thinking,
+                    ..
+                },
+            ) => {
+                block_thinking.push_str(&thinking);
+            }
+            (
+                ContentBlockDelta::Signature { signature, .. },
+                ContentBlock::Thinking {
+                    signature: block_signature,
+                    ..
+                },
+            ) => {
+                block_signature.get_or_insert_default().push_str(&signature);
+            }
+            (ContentBlockDelta::Unknown { .. }, _) => (),
+            _ => {
+                return Err(malformed(
+                    "A delta doesn't match the type of its content block",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The assembled message, `None` if the message never started.
+    pub fn finish(self) -> Option<Message> {
+        let mut message = self.message?;
+        message.content = self.blocks.into_values().collect();
+        Some(message)
+    }
+
+    /// The part of an interrupted response worth keeping: blocks received completely and text,
+    /// which is valid however it is cut. `None` if nothing was received.
+    pub fn salvage(mut self) -> Option<Message> {
+        let mut message = self.message?;
+        let stopped = self.stopped;
+        self.blocks.retain(|index, block| {
+            stopped.contains(index)
+                || matches!(block, ContentBlock::Text { text } if !text.is_empty())
+        });
+        if self.blocks.is_empty() {
+            return None;
+        }
+
+        message.content = self.blocks.into_values().collect();
+        message.stop_reason = None;
+        message.stop_sequence = None;
+        Some(message)
+    }
+}
+
+/// Receives the whole stream and assembles its events, the default of [`Backend::complete`].
+///
+/// [`Backend::complete`]: crate::backend::Backend::complete
+pub(crate) async fn assemble(mut stream: impl MessageStream) -> Result<Message, KepokiError> {
+    let mut assembler = MessageAssembler::new();
+    while let Some(event) = stream.recv().await? {
+        assembler.push(event)?;
+    }
+    assembler
+        .finish()
+        .ok_or_else(|| malformed("The response has no message"))
+}
+
+fn malformed(reason: &str) -> KepokiError {
+    KepokiError::MalformedResponse(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+    use crate::backend::ContentBlockStop;
+    use crate::backend::StopReason;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+    use crate::mock::request;
+
+    #[tokio::test]
+    async fn test_complete_assembles_stream() {
+        let backend = MockBackend::new()
+            .with_response(MockResponse::tool_use(
+                "call",
+                "search",
+                serde_json::json!({ "query": "kepoki" }),
+            ))
+            .with_response(MockResponse::events([
+                MessagesResponseEvent::ContentBlockStop(ContentBlockStop { index: 0 }),
+            ]));
+
+        let message = backend.complete(request("mock".to_string())).await.unwrap();
+        assert!(matches!(message.stop_reason, Some(StopReason::ToolUse)));
+        assert!(matches!(
+            &message.content[..],
+            [ContentBlock::ToolUse { name, input, .. }]
+                if name == "search" && input == r#"{"query":"kepoki"}"#
+        ));
+        assert!(matches!(
+            backend.complete(request("mock".to_string())).await,
+            Err(KepokiError::MalformedResponse(_))
+        ));
+    }
+}
@@ -0,0 +1,370 @@
+//! Serves repeated requests from a cache of earlier responses, for eval runs and CI where the
+//! same prompts are sent over and over.
+//!
+//! Requests are keyed by everything sent to the provider except the ids of messages, which are
+//! new for every run. Responses are recorded as they stream and replayed event by event, only
+//! responses that streamed completely are cached. Messages of [`Backend::complete`] are cached
+//! as the events of whole blocks, replaying them streams every block at once. Cached responses
+//! never expire, clear the directory of a disk cache to send its requests again.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::agent::Reasoning;
+use crate::backend::AttachmentLimits;
+use crate::backend::Backend;
+use crate::backend::ContentBlock;
+use crate::backend::ContentBlockStart;
+use crate::backend::ContentBlockStop;
+use crate::backend::Message;
+use crate::backend::MessageStream;
+use crate::backend::MessagesFuture;
+use crate::backend::MessagesRequest;
+use crate::backend::MessagesResponseEvent;
+use crate::backend::RecvFuture;
+use crate::backend::Role;
+use crate::backend::Tool;
+use crate::backend::ToolChoice;
+use crate::backend::assembly::assemble;
+use crate::error::KepokiError;
+
+/// What identifies a request in the cache.
+#[derive(Serialize)]
+struct CacheKey<'a> {
+    model: Option<String>,
+    messages: Vec<(Role, &'a [ContentBlock])>,
+    max_tokens: u32,
+    system: Option<&'a str>,
+    temperature: Option<f32>,
+    stop_sequences: Option<&'a [Cow<'a, str>]>,
+    tool_choice: Option<&'a ToolChoice>,
+    tools: Option<&'a [Tool<'a>]>,
+    reasoning: Option<Reasoning>,
+    user_id: Option<&'a str>,
+    /// Sorted, so that equal extensions are keyed the same.
+    extensions: BTreeMap<&'a str, &'a serde_json::Value>,
+}
+
+/// A response of a disk cache, stored with its key to tell hash collisions apart.
+#[derive(Deserialize, Serialize)]
+struct CacheEntry {
+    key: String,
+    events: Vec<MessagesResponseEvent>,
+}
+
+#[derive(Clone, Debug)]
+enum CacheStore {
+    Memory(Arc<Mutex<HashMap<String, Vec<MessagesResponseEvent>>>>),
+    /// A file of every response, named by the hash of its key.
+    Disk(PathBuf),
+}
+
+impl CacheStore {
+    fn path(dir: &std::path::Path, key: &str) -> PathBuf {
+        dir.join(format!("{:x}.json", Sha256::digest(key)))
+    }
+
+    async fn get(&self, key: &str) -> Option<Vec<MessagesResponseEvent>> {
+        match self {
+            Self::Memory(responses) => responses.lock().unwrap().get(key).cloned(),
+            Self::Disk(dir) => {
+                let entry = tokio::fs::read(Self::path(dir, key)).await.ok()?;
+                match serde_json::from_slice::<CacheEntry>(&entry) {
+                    Ok(entry) if entry.key == key => Some(entry.events),
+                    Ok(_) => None,
+                    Err(err) => {
+                        tracing::warn!("Ignoring invalid cached response: {err}");
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    async fn insert(&self, key: String, events: Vec<MessagesResponseEvent>) {
+        match self {
+            Self::Memory(responses) => {
+                responses.lock().unwrap().insert(key, events);
+            }
+            Self::Disk(dir) => {
+                let path = Self::path(dir, &key);
+                let entry = CacheEntry { key, events };
+                // Written next to the entry and renamed, so readers never see half of it.
+                let temporary = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+                let result = async {
+                    let entry = serde_json::to_vec(&entry)?;
+                    tokio::fs::create_dir_all(dir).await?;
+                    tokio::fs::write(&temporary, entry).await?;
+                    tokio::fs::rename(&temporary, &path).await
+                };
+                if let Err(err) = result.await {
+                    tracing::warn!("Failed to cache response in {}: {err}", path.display());
+                    let _ = tokio::fs::remove_file(&temporary).await;
+                }
+            }
+        }
+    }
+}
+
+/// Wraps a backend to answer requests identical to earlier ones with the earlier response.
+///
+/// Clones share their cache. Caches are in memory unless given a directory.
+#[derive(Clone, Debug)]
+pub struct CachingBackend<B> {
+    backend: B,
+    store: CacheStore,
+}
+
+impl<B: Backend> CachingBackend<B> {
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            store: CacheStore::Memory(Arc::default()),
+        }
+    }
+
+    /// Caches responses in `dir` instead of memory, so that they are kept across runs.
+    pub fn with_directory(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.store = CacheStore::Disk(dir.into());
+        self
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.backend
+    }
+
+    fn key(&self, request: &MessagesRequest<Self>) -> Result<String, KepokiError> {
+        let key = CacheKey {
+            model: self.backend.model_name(&request.model),
+            messages: request
+                .messages
+                .iter()
+                .map(|message| (message.role, &message.content[..]))
+                .collect(),
+            max_tokens: request.max_tokens,
+            system: request.system.as_deref(),
+            temperature: request.temperature,
+            stop_sequences: request.stop_sequences.as_deref(),
+            tool_choice: request.tool_choice.as_ref(),
+            tools: request.tools.as_deref(),
+            reasoning: request.reasoning,
+            user_id: request.user_id.as_deref(),
+            extensions: request
+                .extensions
+                .iter()
+                .map(|(name, value)| (name.as_str(), value))
+                .collect(),
+        };
+        Ok(serde_json::to_string(&key).map_err(std::io::Error::from)?)
+    }
+}
+
+impl<B: Backend> Backend for CachingBackend<B> {
+    type Model = B::Model;
+    type MessagesEventStream = CachingStream<B::MessagesEventStream>;
+
+    /// Replays the cached response of the request if there is one.
+    ///
+    /// Models are keyed by their name, backends whose models have no name share the responses
+    /// of all of them.
+    fn messages<'a>(
+        &'a self,
+        request: MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, Self::MessagesEventStream> {
+        Box::pin(async move {
+            let key = self.key(&request)?;
+            if let Some(events) = self.store.get(&key).await {
+                tracing::debug!("Replaying cached response");
+                return Ok(CachingStream(CachedResponse::Replay(events.into_iter())));
+            }
+
+            let model = request.model.clone();
+            let stream = self.backend.messages(request.with_model(model)).await?;
+            Ok(CachingStream(CachedResponse::Record {
+                stream,
+                store: self.store.clone(),
+                key,
+                events: Vec::new(),
+            }))
+        })
+    }
+
+    fn complete<'a>(&'a self, request: MessagesRequest<'a, Self>) -> MessagesFuture<'a, Message> {
+        Box::pin(async move {
+            let key = self.key(&request)?;
+            if let Some(events) = self.store.get(&key).await {
+                tracing::debug!("Replaying cached response");
+                let replay = CachedResponse::<B::MessagesEventStream>::Replay(events.into_iter());
+                return assemble(CachingStream(replay)).await;
+            }
+
+            let model = request.model.clone();
+            let message = self.backend.complete(request.with_model(model)).await?;
+            self.store.insert(key, message_events(&message)).await;
+            Ok(message)
+        })
+    }
+
+    fn parse_model(&self, name: &str) -> Option<Self::Model> {
+        self.backend.parse_model(name)
+    }
+
+    fn model_name(&self, model: &Self::Model) -> Option<String> {
+        self.backend.model_name(model)
+    }
+
+    fn attachment_limits(&self) -> AttachmentLimits {
+        self.backend.attachment_limits()
+    }
+}
+
+/// The events streaming `message`, with every block started whole.
+fn message_events(message: &Message) -> Vec<MessagesResponseEvent> {
+    let start = Message {
+        content: Vec::new(),
+        ..message.clone()
+    };
+    let mut events = vec![MessagesResponseEvent::MessageStart(start)];
+    for (index, block) in message.content.iter().enumerate() {
+        events.push(MessagesResponseEvent::ContentBlockStart(
+            ContentBlockStart {
+                index,
+                content_block: block.clone(),
+            },
+        ));
+        events.push(MessagesResponseEvent::ContentBlockStop(ContentBlockStop {
+            index,
+        }));
+    }
+    events.push(MessagesResponseEvent::MessageStop);
+    events
+}
+
+/// A cached response, or a response of the backend being recorded.
+pub struct CachingStream<S>(CachedResponse<S>);
+
+enum CachedResponse<S> {
+    /// Replays a cached response.
+    Replay(std::vec::IntoIter<MessagesResponseEvent>),
+    /// Records a response of the backend, caching it once it streamed completely.
+    Record {
+        stream: S,
+        store: CacheStore,
+        key: String,
+        events: Vec<MessagesResponseEvent>,
+    },
+}
+
+impl<S: MessageStream> MessageStream for CachingStream<S> {
+    fn recv(&mut self) -> RecvFuture<'_> {
+        Box::pin(async move {
+            match &mut self.0 {
+                CachedResponse::Replay(events) => Ok(events.next()),
+                CachedResponse::Record {
+                    stream,
+                    store,
+                    key,
+                    events,
+                } => {
+                    let event = stream.recv().await?;
+                    match &event {
+                        Some(event) => events.push(event.clone()),
+                        // Streams may be polled again after they end, the key is taken by then.
+                        None if !key.is_empty() => {
+                            store
+                                .insert(std::mem::take(key), std::mem::take(events))
+                                .await;
+                        }
+                        None => (),
+                    }
+                    Ok(event)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::InputMessage;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+    use crate::mock::request;
+
+    /// A request saying hi in a message of `id`.
+    fn greeting(id: &str) -> MessagesRequest<'static, CachingBackend<MockBackend>> {
+        MessagesRequest {
+            messages: vec![InputMessage {
+                id: id.to_string(),
+                role: Role::User,
+                content: vec![ContentBlock::Text {
+                    text: "Hi".to_string(),
+                }],
+            }],
+            ..request("mock".to_string())
+        }
+    }
+
+    async fn drain(mut stream: impl MessageStream) -> usize {
+        let mut events = 0;
+        while stream.recv().await.unwrap().is_some() {
+            events += 1;
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn test_replays_identical_requests() {
+        let mock = MockBackend::new().with_response(MockResponse::text("Hello"));
+        let backend = CachingBackend::new(mock.clone());
+
+        let recorded = drain(backend.messages(greeting("a")).await.unwrap()).await;
+        // Message ids don't take part in the key.
+        let replayed = drain(backend.messages(greeting("b")).await.unwrap()).await;
+        assert_eq!(recorded, replayed);
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_replays_from_disk() {
+        let dir = std::env::temp_dir().join(format!("kepoki-cache-{}", uuid::Uuid::new_v4()));
+        let backend =
+            CachingBackend::new(MockBackend::new().with_response(MockResponse::text("Hello")))
+                .with_directory(&dir);
+        let recorded = drain(backend.messages(greeting("a")).await.unwrap()).await;
+
+        // Another backend on the same directory, such as of the next run, replays it.
+        let mock = MockBackend::new();
+        let backend = CachingBackend::new(mock.clone()).with_directory(&dir);
+        let replayed = drain(backend.messages(greeting("b")).await.unwrap()).await;
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        assert_eq!(recorded, replayed);
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_caches_completed_messages() {
+        let mock = MockBackend::new().with_response(MockResponse::text("Hello"));
+        let backend = CachingBackend::new(mock.clone());
+
+        let completed = backend.complete(greeting("a")).await.unwrap();
+        assert!(!mock.requests()[0].streamed);
+        // Completed messages are replayed to either method.
+        let completed = serde_json::to_value(completed).unwrap();
+        let replayed = backend.complete(greeting("b")).await.unwrap();
+        assert_eq!(serde_json::to_value(replayed).unwrap(), completed);
+        let streamed = assemble(backend.messages(greeting("c")).await.unwrap()).await;
+        assert_eq!(serde_json::to_value(streamed.unwrap()).unwrap(), completed);
+        assert_eq!(mock.requests().len(), 1);
+    }
+}
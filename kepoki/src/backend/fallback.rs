@@ -0,0 +1,201 @@
+//! Fails over between backends, so that agents keep running while a provider is rate limited
+//! or down.
+//!
+//! Requests go to the first backend, and to the next one whenever a request fails with an error
+//! that is [retryable](crate::error::KepokiError::is_retryable). Requests only fail over before a response
+//! starts streaming, a stream failing midway is returned to the agent like any other error.
+
+use crate::backend::AttachmentLimits;
+use crate::backend::Backend;
+use crate::backend::Message;
+use crate::backend::MessageStream;
+use crate::backend::MessagesFuture;
+use crate::backend::MessagesRequest;
+use crate::error::KepokiError;
+
+/// A backend and the model requests are sent to, erased so backends of different types can be
+/// combined.
+trait Target: Send + Sync {
+    fn messages<'a>(
+        &'a self,
+        request: MessagesRequest<'a, FallbackBackend>,
+    ) -> MessagesFuture<'a, Box<dyn MessageStream>>;
+
+    fn complete<'a>(
+        &'a self,
+        request: MessagesRequest<'a, FallbackBackend>,
+    ) -> MessagesFuture<'a, Message>;
+
+    fn model_name(&self) -> Option<String>;
+
+    fn attachment_limits(&self) -> AttachmentLimits;
+}
+
+struct BackendTarget<B: Backend> {
+    backend: B,
+    model: B::Model,
+}
+
+impl<B: Backend> Target for BackendTarget<B> {
+    fn messages<'a>(
+        &'a self,
+        request: MessagesRequest<'a, FallbackBackend>,
+    ) -> MessagesFuture<'a, Box<dyn MessageStream>> {
+        Box::pin(async move {
+            let stream = self
+                .backend
+                .messages(request.with_model(self.model.clone()))
+                .await?;
+            Ok(Box::new(stream) as Box<dyn MessageStream>)
+        })
+    }
+
+    fn complete<'a>(
+        &'a self,
+        request: MessagesRequest<'a, FallbackBackend>,
+    ) -> MessagesFuture<'a, Message> {
+        self.backend
+            .complete(request.with_model(self.model.clone()))
+    }
+
+    fn model_name(&self) -> Option<String> {
+        self.backend.model_name(&self.model)
+    }
+
+    fn attachment_limits(&self) -> AttachmentLimits {
+        self.backend.attachment_limits()
+    }
+}
+
+/// Backends with the models they serve, in the order they are tried.
+///
+/// Every backend is paired with its own model, so the model given when spawning the agent is
+/// `()`.
+pub struct FallbackBackend {
+    targets: Vec<Box<dyn Target>>,
+}
+
+impl FallbackBackend {
+    /// Sends requests to `model` of `backend` first.
+    pub fn new<B: Backend>(backend: B, model: B::Model) -> Self {
+        Self {
+            targets: vec![Box::new(BackendTarget { backend, model })],
+        }
+    }
+
+    /// Fails over to `model` of `backend` when the backends before it fail.
+    pub fn with_fallback<B: Backend>(mut self, backend: B, model: B::Model) -> Self {
+        self.targets
+            .push(Box::new(BackendTarget { backend, model }));
+        self
+    }
+
+    /// Calls `send` with every backend in order until one succeeds or fails with an error that
+    /// isn't retryable.
+    async fn fail_over<'a, T>(
+        &'a self,
+        send: impl Fn(&'a dyn Target) -> MessagesFuture<'a, T>,
+    ) -> Result<T, KepokiError> {
+        let (last, targets) = self
+            .targets
+            .split_last()
+            .expect("Fallback backends have at least one backend");
+        for (position, target) in targets.iter().enumerate() {
+            match send(target.as_ref()).await {
+                Err(err) if err.is_retryable() => {
+                    tracing::warn!(
+                        "Backend {position} failed, falling back to backend {}: {err}",
+                        position + 1
+                    );
+                }
+                result => return result,
+            }
+        }
+        send(last.as_ref()).await
+    }
+}
+
+impl Backend for FallbackBackend {
+    type Model = ();
+    type MessagesEventStream = Box<dyn MessageStream>;
+
+    fn messages<'a>(
+        &'a self,
+        request: MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, Self::MessagesEventStream> {
+        Box::pin(async move {
+            self.fail_over(|target| target.messages(request.clone()))
+                .await
+        })
+    }
+
+    fn complete<'a>(&'a self, request: MessagesRequest<'a, Self>) -> MessagesFuture<'a, Message> {
+        Box::pin(async move {
+            self.fail_over(|target| target.complete(request.clone()))
+                .await
+        })
+    }
+
+    /// The name of the model of the first backend, which prices and limits are looked up by.
+    fn model_name(&self, _model: &Self::Model) -> Option<String> {
+        self.targets[0].model_name()
+    }
+
+    /// The limits of the first backend.
+    ///
+    /// Attachments a fallback doesn't accept fail the request once it fails over.
+    fn attachment_limits(&self) -> AttachmentLimits {
+        self.targets[0].attachment_limits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ContentBlock;
+    use crate::error::ProviderErrorKind;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+    use crate::mock::request;
+
+    #[tokio::test]
+    async fn test_fails_over_on_retryable_errors() {
+        let primary = MockBackend::new()
+            .with_response(MockResponse::error(KepokiError::Provider {
+                kind: ProviderErrorKind::Overloaded,
+                message: "Overloaded".to_string(),
+            }))
+            .with_response(MockResponse::error(KepokiError::Provider {
+                kind: ProviderErrorKind::InvalidRequest,
+                message: "Invalid".to_string(),
+            }));
+        let secondary = MockBackend::new().with_response(MockResponse::text("Hello"));
+        let backend = FallbackBackend::new(primary.clone(), "primary".to_string())
+            .with_fallback(secondary.clone(), "secondary".to_string());
+
+        assert!(backend.messages(request(())).await.is_ok());
+        assert_eq!(secondary.requests()[0].model, "secondary");
+
+        // Errors that aren't retryable are returned right away.
+        assert!(backend.messages(request(())).await.is_err());
+        assert_eq!(primary.requests().len(), 2);
+        assert_eq!(secondary.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_complete_fails_over() {
+        let primary =
+            MockBackend::new().with_response(MockResponse::error(KepokiError::Provider {
+                kind: ProviderErrorKind::Overloaded,
+                message: "Overloaded".to_string(),
+            }));
+        let secondary = MockBackend::new().with_response(MockResponse::text("Hello"));
+        let backend = FallbackBackend::new(primary.clone(), "primary".to_string())
+            .with_fallback(secondary.clone(), "secondary".to_string());
+
+        let message = backend.complete(request(())).await.unwrap();
+        assert!(matches!(&message.content[..], [ContentBlock::Text { text }] if text == "Hello"));
+        assert!(!primary.requests()[0].streamed);
+        assert!(!secondary.requests()[0].streamed);
+    }
+}
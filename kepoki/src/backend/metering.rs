@@ -0,0 +1,298 @@
+//! Counts the tokens and estimated cost of requests per agent, so operators can attribute spend
+//! across the agents sharing a provider account.
+//!
+//! Give every agent a backend of [`MeteredBackend::for_agent`], all of them add to the same
+//! [`UsageReport`]. Usage is counted as the provider reports it while the response streams, so
+//! responses that fail midway are counted as far as they got. Costs are estimated from the list
+//! prices of the [`ModelRegistry`], see [`crate::models`].
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::backend::AttachmentLimits;
+use crate::backend::Backend;
+use crate::backend::Message;
+use crate::backend::MessageStream;
+use crate::backend::MessagesFuture;
+use crate::backend::MessagesRequest;
+use crate::backend::MessagesResponseEvent;
+use crate::backend::RecvFuture;
+use crate::backend::Usage;
+use crate::models::ModelRegistry;
+use crate::models::TokenPrice;
+
+/// The usage of an agent, or of every agent in [`UsageReport::total`].
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct MeteredUsage {
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// The estimated cost in US dollars, of requests to models with a known price.
+    pub cost: f64,
+    /// Requests to models without a known price, which aren't part of the cost.
+    pub unpriced_requests: u64,
+}
+
+impl MeteredUsage {
+    fn add(&mut self, other: &MeteredUsage) {
+        self.requests += other.requests;
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cost += other.cost;
+        self.unpriced_requests += other.unpriced_requests;
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct UsageReport {
+    /// The usage of every agent, by the name it was metered as.
+    pub agents: BTreeMap<String, MeteredUsage>,
+    pub total: MeteredUsage,
+}
+
+/// Wraps a backend to count the usage of its requests.
+///
+/// Clones share their report, requests of a backend are attributed to its agent.
+#[derive(Clone, Debug)]
+pub struct MeteredBackend<B> {
+    backend: B,
+    agent: String,
+    models: Arc<ModelRegistry>,
+    usage: Arc<Mutex<BTreeMap<String, MeteredUsage>>>,
+}
+
+impl<B: Backend> MeteredBackend<B> {
+    /// Attributes requests to the agent `default` until given another with
+    /// [`MeteredBackend::for_agent`]. Prices are those of [`ModelRegistry::builtin`].
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            agent: "default".to_string(),
+            models: Arc::new(ModelRegistry::builtin()),
+            usage: Arc::default(),
+        }
+    }
+
+    /// Looks up the prices of models in `models`.
+    pub fn with_models(mut self, models: ModelRegistry) -> Self {
+        self.models = Arc::new(models);
+        self
+    }
+
+    /// A clone attributing its requests to `agent`, adding to the same report.
+    pub fn for_agent(&self, agent: impl Into<String>) -> Self
+    where
+        B: Clone,
+    {
+        Self {
+            agent: agent.into(),
+            ..self.clone()
+        }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.backend
+    }
+
+    /// The usage of every agent so far.
+    pub fn usage_report(&self) -> UsageReport {
+        let agents = self.usage.lock().unwrap().clone();
+        let mut total = MeteredUsage::default();
+        for usage in agents.values() {
+            total.add(usage);
+        }
+        UsageReport { agents, total }
+    }
+
+    fn price(&self, request: &MessagesRequest<'_, Self>) -> Option<TokenPrice> {
+        self.backend
+            .model_name(&request.model)
+            .and_then(|name| self.models.get(&name)?.price)
+    }
+
+    /// Counts a request that was sent, its usage is added to the report by the returned meter.
+    fn meter(&self, price: Option<TokenPrice>) -> Meter {
+        self.usage
+            .lock()
+            .unwrap()
+            .entry(self.agent.clone())
+            .or_default()
+            .add(&MeteredUsage {
+                requests: 1,
+                unpriced_requests: u64::from(price.is_none()),
+                ..Default::default()
+            });
+        Meter {
+            agent: self.agent.clone(),
+            price,
+            usage: self.usage.clone(),
+            reported: Usage {
+                input_tokens: 0,
+                output_tokens: 0,
+            },
+        }
+    }
+}
+
+impl<B: Backend> Backend for MeteredBackend<B> {
+    type Model = B::Model;
+    type MessagesEventStream = MeteredStream<B::MessagesEventStream>;
+
+    fn messages<'a>(
+        &'a self,
+        request: MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, Self::MessagesEventStream> {
+        Box::pin(async move {
+            let price = self.price(&request);
+            let model = request.model.clone();
+            let stream = self.backend.messages(request.with_model(model)).await?;
+            Ok(MeteredStream {
+                stream,
+                meter: self.meter(price),
+            })
+        })
+    }
+
+    fn complete<'a>(&'a self, request: MessagesRequest<'a, Self>) -> MessagesFuture<'a, Message> {
+        Box::pin(async move {
+            let price = self.price(&request);
+            let model = request.model.clone();
+            let message = self.backend.complete(request.with_model(model)).await?;
+            let mut meter = self.meter(price);
+            if let Some(usage) = &message.usage {
+                meter.record(usage);
+            }
+            Ok(message)
+        })
+    }
+
+    fn parse_model(&self, name: &str) -> Option<Self::Model> {
+        self.backend.parse_model(name)
+    }
+
+    fn model_name(&self, model: &Self::Model) -> Option<String> {
+        self.backend.model_name(model)
+    }
+
+    fn attachment_limits(&self) -> AttachmentLimits {
+        self.backend.attachment_limits()
+    }
+}
+
+/// The usage of a response added to the report.
+struct Meter {
+    agent: String,
+    price: Option<TokenPrice>,
+    usage: Arc<Mutex<BTreeMap<String, MeteredUsage>>>,
+    /// The usage added to the report so far.
+    reported: Usage,
+}
+
+impl Meter {
+    /// Adds the usage the provider reported so far to the report.
+    fn record(&mut self, usage: &Usage) {
+        // Input tokens are reported once, output tokens as running totals.
+        let added = Usage {
+            input_tokens: match self.reported.input_tokens {
+                0 => usage.input_tokens,
+                _ => 0,
+            },
+            output_tokens: usage
+                .output_tokens
+                .saturating_sub(self.reported.output_tokens),
+        };
+        if added.input_tokens == 0 && added.output_tokens == 0 {
+            return;
+        }
+        self.reported.input_tokens += added.input_tokens;
+        self.reported.output_tokens += added.output_tokens;
+
+        self.usage
+            .lock()
+            .unwrap()
+            .entry(self.agent.clone())
+            .or_default()
+            .add(&MeteredUsage {
+                input_tokens: u64::from(added.input_tokens),
+                output_tokens: u64::from(added.output_tokens),
+                cost: self.price.map_or(0.0, |price| price.cost(&added)),
+                ..Default::default()
+            });
+    }
+}
+
+/// Adds the usage of the response to the report as the provider reports it.
+pub struct MeteredStream<S> {
+    stream: S,
+    meter: Meter,
+}
+
+impl<S: MessageStream> MessageStream for MeteredStream<S> {
+    fn recv(&mut self) -> RecvFuture<'_> {
+        Box::pin(async move {
+            let event = self.stream.recv().await?;
+            match &event {
+                Some(MessagesResponseEvent::MessageStart(message)) => {
+                    if let Some(usage) = &message.usage {
+                        self.meter.record(usage);
+                    }
+                }
+                Some(MessagesResponseEvent::MessageDelta(delta)) => {
+                    if let Some(usage) = &delta.usage {
+                        self.meter.record(usage);
+                    }
+                }
+                _ => (),
+            }
+            Ok(event)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+    use crate::mock::request;
+
+    #[tokio::test]
+    async fn test_attributes_usage_to_agents() {
+        let mock = MockBackend::new()
+            .with_response(MockResponse::text("One"))
+            .with_response(MockResponse::text("Two"));
+        let backend = MeteredBackend::new(mock);
+        for agent in ["planner", "coder"] {
+            let backend = backend.for_agent(agent);
+            let mut stream = backend.messages(request("mock".to_string())).await.unwrap();
+            while stream.recv().await.unwrap().is_some() {}
+        }
+
+        let report = backend.usage_report();
+        assert_eq!(report.agents.len(), 2);
+        assert_eq!(report.agents["coder"].input_tokens, 1);
+        assert_eq!(report.agents["coder"].output_tokens, 1);
+        assert_eq!(report.total.requests, 2);
+        // The mock model has no price.
+        assert_eq!(report.total.unpriced_requests, 2);
+    }
+
+    #[tokio::test]
+    async fn test_meters_completed_messages() {
+        let mock = MockBackend::new().with_response(MockResponse::text("One"));
+        let backend = MeteredBackend::new(mock.clone());
+        backend.complete(request("mock".to_string())).await.unwrap();
+
+        let report = backend.usage_report();
+        assert_eq!(report.total.requests, 1);
+        assert_eq!(report.total.input_tokens, 1);
+        assert_eq!(report.total.output_tokens, 1);
+        assert!(!mock.requests()[0].streamed);
+    }
+}
@@ -0,0 +1,538 @@
+pub mod assembly;
+pub mod cache;
+pub mod fallback;
+pub mod metering;
+pub mod rate_limit;
+pub mod retry;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use base64::Engine;
+use base64::display::Base64Display;
+use base64::engine::GeneralPurpose;
+use base64::engine::general_purpose::STANDARD;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::agent::Reasoning;
+use crate::backend::assembly::assemble;
+use crate::error::KepokiError;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Tool<'a> {
+    /// Name of the tool.
+    pub name: Cow<'a, str>,
+    /// JSON schema for this tool's input.
+    pub input_schema: Option<Cow<'a, str>>,
+    /// Description of what this tool does.
+    pub description: Option<Cow<'a, str>>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        source: ImageSource,
+    },
+    Document {
+        source: DocumentSource,
+        /// Shown to the model alongside the document, such as its file name.
+        title: Option<String>,
+    },
+    ToolUse {
+        id: String,
+        input: String,
+        name: String,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: Option<Vec<ToolResultContentBlock>>,
+        is_error: Option<bool>,
+    },
+    Thinking {
+        thinking: String,
+        /// Verifies the thinking was generated by the model, required to pass it back.
+        signature: Option<String>,
+    },
+    /// Thinking the provider encrypted, it is only meaningful to the provider that produced it.
+    RedactedThinking {
+        data: String,
+    },
+    /// A tool call the provider executed itself, such as a call of a remote MCP server's tool.
+    ///
+    /// Kept in the history so it can be sent back to the provider, agents don't execute it.
+    ServerToolUse {
+        id: String,
+        name: String,
+        input: String,
+        /// The server providing the tool, if the provider reports one.
+        server_name: Option<String>,
+    },
+    /// The result of a [`ContentBlock::ServerToolUse`].
+    ServerToolResult {
+        tool_use_id: String,
+        content: Option<Vec<ToolResultContentBlock>>,
+        is_error: Option<bool>,
+    },
+    /// A block of a type kepoki doesn't model, kept as the provider's raw JSON so it can be
+    /// sent back to the provider that produced it.
+    Unknown {
+        data: serde_json::Value,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum ContentBlockDelta {
+    Text {
+        index: usize,
+        text: String,
+    },
+    InputJson {
+        index: usize,
+        partial_json: String,
+    },
+    Thinking {
+        index: usize,
+        thinking: String,
+    },
+    Signature {
+        index: usize,
+        signature: String,
+    },
+    /// A delta of a type kepoki doesn't model, as the provider's raw JSON.
+    Unknown {
+        index: usize,
+        data: serde_json::Value,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ContentBlockStop {
+    pub index: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ContentBlockStart {
+    pub index: usize,
+    pub content_block: ContentBlock,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum ToolResultContentBlock {
+    Text { text: String },
+    Image { source: ImageSource },
+    Unknown { data: serde_json::Value },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum ImageSource {
+    Base64 {
+        data: Payload,
+        media_type: ImageMediaType,
+    },
+}
+
+/// Binary content, such as an image, shared between the history, requests, and events instead
+/// of being copied into each of them.
+///
+/// Serialized as a base64 string, backends encode it only when the request is sent.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Payload(Arc<[u8]>);
+
+impl Payload {
+    pub fn from_base64(data: &str) -> Result<Self, base64::DecodeError> {
+        STANDARD.decode(data).map(Self::from)
+    }
+
+    /// Formats the payload as base64 without allocating the encoded string up front.
+    pub fn base64(&self) -> Base64Display<'_, 'static, GeneralPurpose> {
+        Base64Display::new(&self.0, &STANDARD)
+    }
+
+    pub fn to_base64(&self) -> String {
+        STANDARD.encode(&self.0)
+    }
+
+    /// The shared bytes of the payload, cloning them doesn't copy the content.
+    pub fn shared(&self) -> Arc<[u8]> {
+        self.0.clone()
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes.into())
+    }
+}
+
+impl From<Arc<[u8]>> for Payload {
+    fn from(bytes: Arc<[u8]>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl Debug for Payload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Payload({} bytes)", self.0.len())
+    }
+}
+
+impl Serialize for Payload {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.base64())
+    }
+}
+
+impl<'de> Deserialize<'de> for Payload {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = String::deserialize(deserializer)?;
+        Self::from_base64(&data).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "schemars")]
+impl schemars::JsonSchema for Payload {
+    fn schema_name() -> Cow<'static, str> {
+        "Payload".into()
+    }
+
+    fn json_schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        schemars::json_schema!({
+            "type": "string",
+            "contentEncoding": "base64",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum ImageMediaType {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum DocumentSource {
+    Base64 {
+        data: Payload,
+        media_type: DocumentMediaType,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum DocumentMediaType {
+    Pdf,
+    PlainText,
+}
+
+/// The attachments a provider accepts, `None` if it doesn't document a limit.
+///
+/// Agents check the attachments in their history before every request, so oversized or
+/// unsupported ones fail without a round trip to the provider.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AttachmentLimits {
+    pub max_image_bytes: Option<u64>,
+    pub max_document_bytes: Option<u64>,
+    /// The image formats the provider accepts, `None` for all of them.
+    pub image_media_types: Option<&'static [ImageMediaType]>,
+    /// The document formats the provider accepts, `None` for all of them.
+    pub document_media_types: Option<&'static [DocumentMediaType]>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub enum ToolChoice {
+    Auto {
+        /// Whether to disable parallel tool use.
+        disable_parallel_tool_use: bool,
+    },
+    Any {
+        /// Whether to disable parallel tool use.
+        disable_parallel_tool_use: bool,
+    },
+    Tool {
+        /// The name of the tool to use.
+        tool_name: String,
+        /// Whether to disable parallel tool use.
+        disable_parallel_tool_use: bool,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct ApiError {
+    pub error: ApiErrorDetails,
+}
+
+#[derive(Clone, Debug)]
+pub struct ApiErrorDetails {
+    pub r#type: String,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct InputMessage {
+    /// Identifies the message within the history of an agent.
+    #[serde(default)]
+    pub id: String,
+    pub role: Role,
+    pub content: Vec<ContentBlock>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Message {
+    /// Unique object identifier.
+    pub id: String,
+    /// Content generated by the model.
+    pub content: Vec<ContentBlock>,
+    /// The reason that we stopped.
+    pub stop_reason: Option<StopReason>,
+    /// Which custom stop sequence was generated, if any.
+    pub stop_sequence: Option<String>,
+    /// Billing and rate-limit usage.
+    pub usage: Option<Usage>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct MessageDelta {
+    /// The reason that we stopped.
+    pub stop_reason: Option<StopReason>,
+    /// Which custom stop sequence was generated, if any.
+    pub stop_sequence: Option<String>,
+    /// Billing and rate-limit usage.
+    pub usage: Option<Usage>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Usage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+/// Estimates the number of tokens in `text` without a provider specific tokenizer.
+///
+/// Uses the common approximation of four characters per token.
+pub fn estimate_tokens(text: &str) -> u32 {
+    text.chars().count().div_ceil(4) as u32
+}
+
+pub struct MessagesRequest<'a, B: Backend> {
+    /// The model to use for this request.
+    pub model: B::Model,
+    /// Input messages.
+    pub messages: Vec<InputMessage>,
+    /// The maximum number of tokens to generate before stopping.
+    pub max_tokens: u32,
+    /// System prompt.
+    pub system: Option<Cow<'a, str>>,
+    /// Amount of randomness injected into the response.
+    pub temperature: Option<f32>,
+    /// Custom text sequences that will cause the model to stop generating.
+    pub stop_sequences: Option<Vec<Cow<'a, str>>>,
+    /// How the model should use the provided tools.
+    pub tool_choice: Option<ToolChoice>,
+    /// Definitions of tools that the model may use.
+    pub tools: Option<Vec<Tool<'a>>>,
+    /// How much the model may reason before responding.
+    pub reasoning: Option<Reasoning>,
+    /// An opaque identifier of the end user the request is made for, used by providers to
+    /// attribute abuse.
+    pub user_id: Option<Cow<'a, str>>,
+    /// Parameters of a specific provider by name, which have no equivalent in the other fields.
+    ///
+    /// Every backend documents the parameters it supports, and ignores those it doesn't.
+    pub extensions: HashMap<String, serde_json::Value>,
+}
+
+impl<'a, B: Backend> MessagesRequest<'a, B> {
+    /// The same request to another backend, such as one a combinator of backends wraps.
+    pub fn with_model<C: Backend>(self, model: C::Model) -> MessagesRequest<'a, C> {
+        MessagesRequest {
+            model,
+            messages: self.messages,
+            max_tokens: self.max_tokens,
+            system: self.system,
+            temperature: self.temperature,
+            stop_sequences: self.stop_sequences,
+            tool_choice: self.tool_choice,
+            tools: self.tools,
+            reasoning: self.reasoning,
+            user_id: self.user_id,
+            extensions: self.extensions,
+        }
+    }
+
+    /// The extension parameter `name` as a `T`, `None` if the request doesn't set it.
+    pub fn extension<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, KepokiError> {
+        self.extensions
+            .get(name)
+            .map(|value| {
+                T::deserialize(value).map_err(|err| KepokiError::InvalidExtension {
+                    name: name.to_string(),
+                    error: err.to_string(),
+                })
+            })
+            .transpose()
+    }
+}
+
+impl<B: Backend> Clone for MessagesRequest<'_, B> {
+    fn clone(&self) -> Self {
+        Self {
+            model: self.model.clone(),
+            messages: self.messages.clone(),
+            max_tokens: self.max_tokens,
+            system: self.system.clone(),
+            temperature: self.temperature,
+            stop_sequences: self.stop_sequences.clone(),
+            tool_choice: self.tool_choice.clone(),
+            tools: self.tools.clone(),
+            reasoning: self.reasoning,
+            user_id: self.user_id.clone(),
+            extensions: self.extensions.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum StopReason {
+    /// The model reached a natural stopping point
+    EndTurn,
+    /// We exceeded the requested max_tokens or the model's maximum
+    MaxTokens,
+    /// One of your provided custom stop_sequences was generated
+    StopSequence,
+    /// The model invoked one or more tools
+    ToolUse,
+    /// We paused a long-running turn. You may provide the response back as-is in a subsequent request to let the model continue.
+    PauseTurn,
+    /// When streaming classifiers intervene to handle potential policy violations
+    Refusal,
+    /// A stop reason kepoki doesn't model, as reported by the provider.
+    Other(String),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum MessagesResponseEvent {
+    Ping,
+    MessageStart(Message),
+    MessageDelta(MessageDelta),
+    MessageStop,
+    ContentBlockStart(ContentBlockStart),
+    ContentBlockDelta(ContentBlockDelta),
+    ContentBlockStop(ContentBlockStop),
+    /// An event of a type kepoki doesn't model, as the provider's raw JSON.
+    Unknown(serde_json::Value),
+}
+
+/// The next event of a [`MessageStream`].
+pub type RecvFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Option<MessagesResponseEvent>, KepokiError>> + Send + 'a>>;
+
+/// A future of the response of a [`Backend`] to a request, such as a [`MessageStream`] or a
+/// whole [`Message`].
+pub type MessagesFuture<'a, S> = Pin<Box<dyn Future<Output = Result<S, KepokiError>> + Send + 'a>>;
+
+pub trait MessageStream: Send + 'static {
+    /// Waits for the next event of the response, `None` once the response is complete.
+    fn recv(&mut self) -> RecvFuture<'_>;
+}
+
+impl MessageStream for Box<dyn MessageStream> {
+    fn recv(&mut self) -> RecvFuture<'_> {
+        self.as_mut().recv()
+    }
+}
+
+/// A provider of models, awaited by agents on the Tokio runtime they run on.
+///
+/// Implementations must not block the thread, such as by calling `block_on`, as agents share
+/// the threads of the runtime.
+pub trait Backend: Sized + Send + Sync + 'static {
+    type Model: Clone + Send + Sync + 'static;
+    type MessagesEventStream: MessageStream;
+
+    /// Sends a request, resolving once the provider accepted it.
+    fn messages<'a>(
+        &'a self,
+        request: MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, Self::MessagesEventStream>;
+
+    /// Sends a request and waits for the whole response.
+    ///
+    /// Assembles the events of [`Backend::messages`] by default. Backends of providers with
+    /// an endpoint that doesn't stream may use it instead.
+    fn complete<'a>(&'a self, request: MessagesRequest<'a, Self>) -> MessagesFuture<'a, Message> {
+        Box::pin(async move { assemble(self.messages(request).await?).await })
+    }
+
+    /// Parses the name of a model, such as one requested in a per-turn override.
+    ///
+    /// Backends that don't support selecting models by name return `None`.
+    fn parse_model(&self, name: &str) -> Option<Self::Model> {
+        let _ = name;
+        None
+    }
+
+    /// The name of a model, used to look it up in the
+    /// [`ModelRegistry`](crate::models::ModelRegistry).
+    ///
+    /// Backends whose models don't have names return `None`.
+    fn model_name(&self, model: &Self::Model) -> Option<String> {
+        let _ = model;
+        None
+    }
+
+    fn attachment_limits(&self) -> AttachmentLimits {
+        AttachmentLimits::default()
+    }
+}
+
+/// The embeddings of texts, see [`EmbeddingBackend`].
+pub type EmbedFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Vec<Vec<f32>>, KepokiError>> + Send + 'a>>;
+
+/// A provider of embedding models, so that memory and retrieval can be built on the same
+/// backends as agents.
+pub trait EmbeddingBackend: Send + Sync + 'static {
+    /// Embeds every text with the embedding model named `model`, returning one embedding per
+    /// text in the same order.
+    fn embed<'a>(&'a self, model: &'a str, texts: &'a [String]) -> EmbedFuture<'a>;
+}
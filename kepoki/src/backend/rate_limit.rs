@@ -0,0 +1,371 @@
+//! Keeps the requests of agents within the rate limits of a provider, so a swarm of agents
+//! sharing a key doesn't run into them.
+//!
+//! Budgets of requests and tokens per minute are token buckets that refill continuously.
+//! Requests take their estimated input tokens when they are sent, see
+//! [`estimate_tokens`](crate::backend::estimate_tokens), which is corrected once the provider
+//! reports the usage of the response. Output tokens are taken as they are reported, so a long
+//! response delays the requests after it rather than itself. Responses of backends that don't
+//! report usage only take the estimate.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::backend::AttachmentLimits;
+use crate::backend::Backend;
+use crate::backend::ContentBlock;
+use crate::backend::Message;
+use crate::backend::MessageStream;
+use crate::backend::MessagesFuture;
+use crate::backend::MessagesRequest;
+use crate::backend::MessagesResponseEvent;
+use crate::backend::RecvFuture;
+use crate::backend::ToolResultContentBlock;
+use crate::backend::Usage;
+use crate::backend::estimate_tokens;
+use crate::error::KepokiError;
+use crate::error::ProviderErrorKind;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimits {
+    pub requests_per_minute: Option<u32>,
+    /// Input and output tokens per minute.
+    pub tokens_per_minute: Option<u32>,
+}
+
+/// What happens to a request exceeding the budget.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RateLimitMode {
+    /// Waits until the budget allows the request.
+    #[default]
+    Wait,
+    /// Fails the request as [`ProviderErrorKind::RateLimited`], leaving it to the error handler
+    /// of the agent.
+    Fail,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(per_minute: u32) -> Self {
+        Self {
+            capacity: f64::from(per_minute),
+            available: f64::from(per_minute),
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.capacity / 60.0;
+        self.available = (self.available + refill).min(self.capacity);
+        self.refilled = now;
+    }
+
+    /// How long until `amount` can be taken. Amounts beyond the capacity only need a full
+    /// bucket, or they could never be taken.
+    fn wait(&mut self, amount: f64) -> Duration {
+        self.refill();
+        let missing = amount.min(self.capacity) - self.available;
+        match missing > 0.0 {
+            true => Duration::from_secs_f64(missing * 60.0 / self.capacity),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// Takes `amount`, which may be negative to return tokens taken before. The bucket goes
+    /// into debt if it holds less.
+    fn take(&mut self, amount: f64) {
+        self.refill();
+        self.available = (self.available - amount).min(self.capacity);
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+impl Buckets {
+    /// How long until a request of `tokens` input tokens can be sent.
+    fn wait(&mut self, tokens: f64) -> Duration {
+        let requests = self
+            .requests
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.wait(1.0));
+        let tokens = self
+            .tokens
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.wait(tokens));
+        requests.max(tokens)
+    }
+
+    fn take_tokens(&mut self, tokens: f64) {
+        if let Some(bucket) = &mut self.tokens {
+            bucket.take(tokens);
+        }
+    }
+}
+
+/// Wraps a backend to keep its requests within budgets of requests and tokens per minute.
+///
+/// Clones share their budgets, give a clone to every agent using the same provider account.
+#[derive(Clone, Debug)]
+pub struct RateLimitedBackend<B> {
+    backend: B,
+    mode: RateLimitMode,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl<B: Backend> RateLimitedBackend<B> {
+    pub fn new(backend: B, limits: RateLimits) -> Self {
+        Self {
+            backend,
+            mode: RateLimitMode::default(),
+            buckets: Arc::new(Mutex::new(Buckets {
+                requests: limits.requests_per_minute.map(Bucket::new),
+                tokens: limits.tokens_per_minute.map(Bucket::new),
+            })),
+        }
+    }
+
+    pub fn with_mode(mut self, mode: RateLimitMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.backend
+    }
+
+    /// Admits the request with its estimated input tokens, which the returned charge corrects.
+    async fn charge(&self, request: &MessagesRequest<'_, Self>) -> Result<Charge, KepokiError> {
+        let estimated_input = estimate_request_tokens(request);
+        self.admit(estimated_input).await?;
+        Ok(Charge {
+            buckets: self.buckets.clone(),
+            estimated_input,
+            usage: Usage {
+                input_tokens: 0,
+                output_tokens: 0,
+            },
+        })
+    }
+
+    /// Waits until the budget allows a request of `tokens` input tokens and takes them, or
+    /// fails if the mode doesn't wait.
+    async fn admit(&self, tokens: u32) -> Result<(), KepokiError> {
+        let tokens = f64::from(tokens);
+        loop {
+            let wait = {
+                let mut buckets = self.buckets.lock().unwrap();
+                let wait = buckets.wait(tokens);
+                if wait.is_zero() {
+                    if let Some(bucket) = &mut buckets.requests {
+                        bucket.take(1.0);
+                    }
+                    buckets.take_tokens(tokens);
+                    return Ok(());
+                }
+                wait
+            };
+
+            match self.mode {
+                RateLimitMode::Wait => tokio::time::sleep(wait).await,
+                RateLimitMode::Fail => {
+                    return Err(KepokiError::Provider {
+                        kind: ProviderErrorKind::RateLimited,
+                        message: format!(
+                            "The rate limit budget allows the request in {:.1}s",
+                            wait.as_secs_f64()
+                        ),
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl<B: Backend> Backend for RateLimitedBackend<B> {
+    type Model = B::Model;
+    type MessagesEventStream = RateLimitedStream<B::MessagesEventStream>;
+
+    fn messages<'a>(
+        &'a self,
+        request: MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, Self::MessagesEventStream> {
+        Box::pin(async move {
+            let charge = self.charge(&request).await?;
+            let model = request.model.clone();
+            let stream = self.backend.messages(request.with_model(model)).await?;
+            Ok(RateLimitedStream { stream, charge })
+        })
+    }
+
+    fn complete<'a>(&'a self, request: MessagesRequest<'a, Self>) -> MessagesFuture<'a, Message> {
+        Box::pin(async move {
+            let mut charge = self.charge(&request).await?;
+            let model = request.model.clone();
+            let message = self.backend.complete(request.with_model(model)).await?;
+            if let Some(usage) = &message.usage {
+                charge.record(usage);
+            }
+            Ok(message)
+        })
+    }
+
+    fn parse_model(&self, name: &str) -> Option<Self::Model> {
+        self.backend.parse_model(name)
+    }
+
+    fn model_name(&self, model: &Self::Model) -> Option<String> {
+        self.backend.model_name(model)
+    }
+
+    fn attachment_limits(&self) -> AttachmentLimits {
+        self.backend.attachment_limits()
+    }
+}
+
+/// The tokens a request took from the budget.
+struct Charge {
+    buckets: Arc<Mutex<Buckets>>,
+    estimated_input: u32,
+    /// The usage taken from the budget so far.
+    usage: Usage,
+}
+
+impl Charge {
+    /// Takes the usage the provider reported so far from the budget.
+    fn record(&mut self, usage: &Usage) {
+        let mut correction = 0.0;
+        // Providers report input tokens once, replacing the estimate taken before the request.
+        if usage.input_tokens > 0 && self.usage.input_tokens == 0 {
+            correction += f64::from(usage.input_tokens) - f64::from(self.estimated_input);
+            self.usage.input_tokens = usage.input_tokens;
+        }
+        // Output tokens are reported as running totals.
+        if usage.output_tokens > self.usage.output_tokens {
+            correction += f64::from(usage.output_tokens - self.usage.output_tokens);
+            self.usage.output_tokens = usage.output_tokens;
+        }
+        if correction != 0.0 {
+            self.buckets.lock().unwrap().take_tokens(correction);
+        }
+    }
+}
+
+/// Takes the tokens of the response from the budget as the provider reports them.
+pub struct RateLimitedStream<S> {
+    stream: S,
+    charge: Charge,
+}
+
+impl<S: MessageStream> MessageStream for RateLimitedStream<S> {
+    fn recv(&mut self) -> RecvFuture<'_> {
+        Box::pin(async move {
+            let event = self.stream.recv().await?;
+            match &event {
+                Some(MessagesResponseEvent::MessageStart(message)) => {
+                    if let Some(usage) = &message.usage {
+                        self.charge.record(usage);
+                    }
+                }
+                Some(MessagesResponseEvent::MessageDelta(delta)) => {
+                    if let Some(usage) = &delta.usage {
+                        self.charge.record(usage);
+                    }
+                }
+                _ => (),
+            }
+            Ok(event)
+        })
+    }
+}
+
+/// Estimates the input tokens of a request from its text.
+fn estimate_request_tokens<B: Backend>(request: &MessagesRequest<B>) -> u32 {
+    let system = request.system.as_deref().map_or(0, estimate_tokens);
+    let tools = request.tools.iter().flatten().map(|tool| {
+        estimate_tokens(&tool.name)
+            + tool.description.as_deref().map_or(0, estimate_tokens)
+            + tool.input_schema.as_deref().map_or(0, estimate_tokens)
+    });
+    let messages = request
+        .messages
+        .iter()
+        .flat_map(|message| &message.content)
+        .map(|block| match block {
+            ContentBlock::Text { text } => estimate_tokens(text),
+            ContentBlock::ToolUse { input, .. } => estimate_tokens(input),
+            ContentBlock::ToolResult {
+                content: Some(content),
+                ..
+            } => content
+                .iter()
+                .map(|block| match block {
+                    ToolResultContentBlock::Text { text } => estimate_tokens(text),
+                    _ => 0,
+                })
+                .sum(),
+            ContentBlock::Thinking { thinking, .. } => estimate_tokens(thinking),
+            _ => 0,
+        });
+    system + tools.chain(messages).sum::<u32>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+    use crate::mock::request;
+
+    #[test]
+    fn test_bucket_refills_over_time() {
+        let mut bucket = Bucket::new(60);
+        assert!(bucket.wait(60.0).is_zero());
+        bucket.take(60.0);
+        // One token refills per second.
+        let wait = bucket.wait(2.0);
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+        // Requests larger than the budget only wait for a full bucket.
+        bucket.take(-60.0);
+        assert!(bucket.wait(1000.0).is_zero());
+    }
+
+    #[tokio::test]
+    async fn test_complete_takes_reported_usage() {
+        let mock = MockBackend::new().with_response(MockResponse::text("Hello"));
+        let backend = RateLimitedBackend::new(
+            mock.clone(),
+            RateLimits {
+                requests_per_minute: None,
+                tokens_per_minute: Some(60),
+            },
+        );
+
+        // The request has no text to estimate, so only the reported usage is taken.
+        let message = backend.complete(request("mock".to_string())).await.unwrap();
+        let usage = message.usage.unwrap();
+        let taken = f64::from(usage.input_tokens + usage.output_tokens);
+        let available = backend
+            .buckets
+            .lock()
+            .unwrap()
+            .tokens
+            .as_ref()
+            .unwrap()
+            .available;
+        assert!(taken > 0.0 && (available - (60.0 - taken)).abs() < 0.5);
+        assert!(!mock.requests()[0].streamed);
+    }
+}
@@ -0,0 +1,222 @@
+//! Retries requests failing with transient errors, such as when the provider is rate limited,
+//! overloaded, or the connection timed out.
+//!
+//! Retries wait with exponential backoff, half of every wait is random so that agents failing
+//! together don't retry together. A response is only retried until it starts streaming: most
+//! providers report errors as the first event of the stream, which is received before the
+//! stream is returned to the agent. A stream failing after its first event is returned to the
+//! agent like any other error, see [`ErrorRecovery::Continue`] to salvage it.
+//!
+//! [`ErrorRecovery::Continue`]: crate::runtime::recovery::ErrorRecovery::Continue
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::time::Duration;
+
+use crate::backend::AttachmentLimits;
+use crate::backend::Backend;
+use crate::backend::Message;
+use crate::backend::MessageStream;
+use crate::backend::MessagesFuture;
+use crate::backend::MessagesRequest;
+use crate::backend::MessagesResponseEvent;
+use crate::backend::RecvFuture;
+use crate::error::KepokiError;
+
+/// Wraps a backend to retry requests failing with [retryable](KepokiError::is_retryable) errors.
+#[derive(Clone, Debug)]
+pub struct RetryingBackend<B> {
+    backend: B,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl<B: Backend> RetryingBackend<B> {
+    /// Sends every request at most 4 times, waiting 1 second before the first retry and at most
+    /// 30 seconds before any retry.
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            max_attempts: 4,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+
+    /// The most times a request is sent, including the first time.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// The wait before the first retry, doubled for every retry after it.
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// The longest wait before a retry, before jitter.
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.backend
+    }
+
+    /// The wait before retrying a request that failed `attempt` times.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_backoff);
+        backoff / 2 + backoff.mul_f64(random() / 2.0)
+    }
+
+    /// Calls `send` until it succeeds, fails with an error that isn't retryable, or ran out of
+    /// attempts.
+    async fn retry<T, F: Future<Output = Result<T, KepokiError>>>(
+        &self,
+        mut send: impl FnMut() -> F,
+    ) -> Result<T, KepokiError> {
+        let mut attempt = 1;
+        loop {
+            match send().await {
+                Err(err) if err.is_retryable() && attempt < self.max_attempts => {
+                    let backoff = self.backoff(attempt);
+                    tracing::warn!(
+                        "Request failed on attempt {attempt}, retrying in {:.1}s: {err}",
+                        backoff.as_secs_f64()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sends the request and waits for its first event.
+    async fn attempt(
+        &self,
+        request: MessagesRequest<'_, Self>,
+    ) -> Result<RetryingStream<B::MessagesEventStream>, KepokiError> {
+        let model = request.model.clone();
+        let mut stream = self.backend.messages(request.with_model(model)).await?;
+        let first = stream.recv().await?;
+        Ok(RetryingStream {
+            stream,
+            first: Some(first),
+        })
+    }
+}
+
+impl<B: Backend> Backend for RetryingBackend<B> {
+    type Model = B::Model;
+    type MessagesEventStream = RetryingStream<B::MessagesEventStream>;
+
+    fn messages<'a>(
+        &'a self,
+        request: MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, Self::MessagesEventStream> {
+        Box::pin(async move { self.retry(|| self.attempt(request.clone())).await })
+    }
+
+    fn complete<'a>(&'a self, request: MessagesRequest<'a, Self>) -> MessagesFuture<'a, Message> {
+        Box::pin(async move {
+            self.retry(|| {
+                let model = request.model.clone();
+                self.backend.complete(request.clone().with_model(model))
+            })
+            .await
+        })
+    }
+
+    fn parse_model(&self, name: &str) -> Option<Self::Model> {
+        self.backend.parse_model(name)
+    }
+
+    fn model_name(&self, model: &Self::Model) -> Option<String> {
+        self.backend.model_name(model)
+    }
+
+    fn attachment_limits(&self) -> AttachmentLimits {
+        self.backend.attachment_limits()
+    }
+}
+
+/// A stream whose first event was received to check that the request succeeded.
+pub struct RetryingStream<S> {
+    stream: S,
+    first: Option<Option<MessagesResponseEvent>>,
+}
+
+impl<S: MessageStream> MessageStream for RetryingStream<S> {
+    fn recv(&mut self) -> RecvFuture<'_> {
+        match self.first.take() {
+            Some(first) => Box::pin(async move { Ok(first) }),
+            None => self.stream.recv(),
+        }
+    }
+}
+
+/// A random number between 0 and 1, random enough for jitter.
+fn random() -> f64 {
+    RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ProviderErrorKind;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+    use crate::mock::request;
+
+    fn overloaded() -> KepokiError {
+        KepokiError::Provider {
+            kind: ProviderErrorKind::Overloaded,
+            message: "Overloaded".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_errors() {
+        let mock = MockBackend::new()
+            .with_response(MockResponse::error(overloaded()))
+            .with_response(MockResponse::Stream(vec![Err(overloaded())]))
+            .with_response(MockResponse::text("Hello"))
+            .with_response(MockResponse::error(overloaded()));
+        let backend = RetryingBackend::new(mock.clone())
+            .with_max_attempts(3)
+            .with_initial_backoff(Duration::ZERO);
+
+        let mut stream = backend.messages(request("mock".to_string())).await.unwrap();
+        assert!(matches!(
+            stream.recv().await,
+            Ok(Some(MessagesResponseEvent::MessageStart(_)))
+        ));
+        assert_eq!(mock.requests().len(), 3);
+
+        // Errors that aren't retryable end the retries, such as running out of responses.
+        assert!(backend.messages(request("mock".to_string())).await.is_err());
+        assert_eq!(mock.remaining(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_complete_retries_transient_errors() {
+        let mock = MockBackend::new()
+            .with_response(MockResponse::error(overloaded()))
+            .with_response(MockResponse::text("Hello"));
+        let backend = RetryingBackend::new(mock.clone()).with_initial_backoff(Duration::ZERO);
+
+        assert!(backend.complete(request("mock".to_string())).await.is_ok());
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|request| !request.streamed));
+    }
+}
@@ -0,0 +1,302 @@
+//! The blackboard is a key-value store shared by every agent in a runtime. Agents read and
+//! write it through builtin tools, so agents of a workflow can coordinate without routing
+//! everything through messages.
+//!
+//! Keys are open to every agent unless permissions restrict who may read or write them.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::backend::Tool;
+use crate::runtime::agent::AgentEvent;
+use crate::tools::BuiltinTool;
+use crate::tools::ToolContext;
+use crate::tools::ToolFuture;
+use crate::tools::ToolOutput;
+use crate::tools::required_str;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct BlackboardEntry {
+    pub key: String,
+    pub value: Value,
+    /// Incremented every time the entry is written, starting at 1.
+    pub version: u32,
+    /// The name of the agent that wrote the entry last, `None` if it was written by the host.
+    pub written_by: Option<String>,
+}
+
+/// The agents allowed to access a key, by agent name.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct KeyPermissions {
+    /// `None` allows every agent to read the key.
+    pub readers: Option<Vec<String>>,
+    /// `None` allows every agent to write the key.
+    pub writers: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Blackboard {
+    inner: Arc<Mutex<BlackboardInner>>,
+}
+
+#[derive(Debug, Default)]
+struct BlackboardInner {
+    entries: HashMap<String, BlackboardEntry>,
+    /// Keyed by key, or by a key prefix followed by `*`.
+    permissions: HashMap<String, KeyPermissions>,
+}
+
+impl Blackboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restricts access to `pattern`, a key or a key prefix followed by `*` such as `review/*`.
+    ///
+    /// The permissions of the longest pattern matching a key apply.
+    pub fn set_permissions(&self, pattern: impl Into<String>, permissions: KeyPermissions) {
+        self.inner
+            .lock()
+            .unwrap()
+            .permissions
+            .insert(pattern.into(), permissions);
+    }
+
+    pub fn remove_permissions(&self, pattern: &str) {
+        self.inner.lock().unwrap().permissions.remove(pattern);
+    }
+
+    pub fn get(&self, key: &str) -> Option<BlackboardEntry> {
+        self.inner.lock().unwrap().entries.get(key).cloned()
+    }
+
+    /// Every entry whose key starts with `prefix`, sorted by key.
+    pub fn list(&self, prefix: &str) -> Vec<BlackboardEntry> {
+        let mut entries = self
+            .inner
+            .lock()
+            .unwrap()
+            .entries
+            .values()
+            .filter(|entry| entry.key.starts_with(prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        entries
+    }
+
+    /// Writes an entry on behalf of the host, ignoring permissions.
+    pub fn set(&self, key: &str, value: Value) -> BlackboardEntry {
+        self.inner.lock().unwrap().write(key, value, None)
+    }
+
+    pub fn remove(&self, key: &str) -> Option<BlackboardEntry> {
+        self.inner.lock().unwrap().entries.remove(key)
+    }
+
+    fn readable(&self, agent: &str, key: &str) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .permitted(key, agent, |permissions| &permissions.readers)
+    }
+
+    fn read_as(&self, agent: &str, key: &str) -> Result<Option<BlackboardEntry>, String> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.permitted(key, agent, |permissions| &permissions.readers) {
+            return Err(format!("Not permitted to read `{key}`"));
+        }
+
+        Ok(inner.entries.get(key).cloned())
+    }
+
+    /// Writes an entry on behalf of an agent, if `expected_version` is set the entry is only
+    /// written if it is still at that version, `0` meaning that it must not exist yet.
+    fn write_as(
+        &self,
+        agent: &str,
+        key: &str,
+        value: Value,
+        expected_version: Option<u32>,
+    ) -> Result<BlackboardEntry, String> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.permitted(key, agent, |permissions| &permissions.writers) {
+            return Err(format!("Not permitted to write `{key}`"));
+        }
+
+        let version = inner.entries.get(key).map_or(0, |entry| entry.version);
+        if let Some(expected_version) = expected_version
+            && expected_version != version
+        {
+            return Err(format!(
+                "`{key}` is at version {version}, not {expected_version}, read it again before writing"
+            ));
+        }
+
+        Ok(inner.write(key, value, Some(agent.to_string())))
+    }
+}
+
+impl BlackboardInner {
+    fn write(&mut self, key: &str, value: Value, written_by: Option<String>) -> BlackboardEntry {
+        let version = self.entries.get(key).map_or(0, |entry| entry.version) + 1;
+        let entry = BlackboardEntry {
+            key: key.to_string(),
+            value,
+            version,
+            written_by,
+        };
+        self.entries.insert(key.to_string(), entry.clone());
+        entry
+    }
+
+    fn permitted(
+        &self,
+        key: &str,
+        agent: &str,
+        agents: impl Fn(&KeyPermissions) -> &Option<Vec<String>>,
+    ) -> bool {
+        let permissions = self
+            .permissions
+            .iter()
+            .filter(|(pattern, _)| match pattern.strip_suffix('*') {
+                Some(prefix) => key.starts_with(prefix),
+                None => key == pattern.as_str(),
+            })
+            .max_by_key(|(pattern, _)| pattern.len());
+
+        match permissions.and_then(|(_, permissions)| agents(permissions).as_ref()) {
+            Some(agents) => agents.iter().any(|name| name == agent),
+            None => true,
+        }
+    }
+}
+
+pub struct ReadBlackboardTool(pub Blackboard);
+
+impl BuiltinTool for ReadBlackboardTool {
+    fn definition(&self) -> Tool<'static> {
+        Tool {
+            name: "read_blackboard".into(),
+            input_schema: Some(
+                r#"{"type":"object","properties":{"key":{"type":"string"}},"required":["key"]}"#
+                    .into(),
+            ),
+            description: Some(
+                "Reads an entry of the blackboard shared with the other agents of the workflow, along with its version.".into(),
+            ),
+        }
+    }
+
+    fn call(&self, context: ToolContext, input: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let key = match required_str(&input, "key") {
+                Ok(key) => key,
+                Err(err) => return err,
+            };
+
+            match self.0.read_as(context.agent.name(), key) {
+                Ok(Some(entry)) => {
+                    ToolOutput::text(format!("Version {}:\n{}", entry.version, entry.value))
+                }
+                Ok(None) => ToolOutput::text(format!("`{key}` is not set (version 0)")),
+                Err(err) => ToolOutput::error(err),
+            }
+        })
+    }
+
+    fn side_effecting(&self) -> bool {
+        false
+    }
+}
+
+pub struct WriteBlackboardTool(pub Blackboard);
+
+impl BuiltinTool for WriteBlackboardTool {
+    fn definition(&self) -> Tool<'static> {
+        Tool {
+            name: "write_blackboard".into(),
+            input_schema: Some(
+                r#"{"type":"object","properties":{"key":{"type":"string"},"value":{},"expected_version":{"type":"integer","minimum":0}},"required":["key","value"]}"#.into(),
+            ),
+            description: Some(
+                "Writes an entry of the blackboard shared with the other agents of the workflow. Set `expected_version` to the version you read to avoid overwriting changes made by others since.".into(),
+            ),
+        }
+    }
+
+    fn call(&self, context: ToolContext, input: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let key = match required_str(&input, "key") {
+                Ok(key) => key,
+                Err(err) => return err,
+            };
+            let Some(value) = input.get("value") else {
+                return ToolOutput::error("Missing required field `value`");
+            };
+            let expected_version = input
+                .get("expected_version")
+                .and_then(Value::as_u64)
+                .map(|version| version as u32);
+
+            match self
+                .0
+                .write_as(context.agent.name(), key, value.clone(), expected_version)
+            {
+                Ok(entry) => {
+                    context.emit(AgentEvent::BlackboardWritten {
+                        key: entry.key,
+                        version: entry.version,
+                    });
+                    ToolOutput::text(format!("Wrote `{key}` at version {}", entry.version))
+                }
+                Err(err) => ToolOutput::error(err),
+            }
+        })
+    }
+}
+
+pub struct ListBlackboardTool(pub Blackboard);
+
+impl BuiltinTool for ListBlackboardTool {
+    fn definition(&self) -> Tool<'static> {
+        Tool {
+            name: "list_blackboard".into(),
+            input_schema: Some(
+                r#"{"type":"object","properties":{"prefix":{"type":"string"}}}"#.into(),
+            ),
+            description: Some(
+                "Lists the keys of the shared blackboard you can read, optionally only those starting with `prefix`.".into(),
+            ),
+        }
+    }
+
+    fn call(&self, context: ToolContext, input: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let prefix = input.get("prefix").and_then(Value::as_str).unwrap_or("");
+            let keys = self
+                .0
+                .list(prefix)
+                .into_iter()
+                .filter(|entry| self.0.readable(context.agent.name(), &entry.key))
+                .map(|entry| format!("{} (version {})", entry.key, entry.version))
+                .collect::<Vec<_>>();
+
+            match keys.is_empty() {
+                true => ToolOutput::text("No entries"),
+                false => ToolOutput::text(keys.join("\n")),
+            }
+        })
+    }
+
+    fn side_effecting(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,377 @@
+//! Context providers add up-to-date information, such as the state of the repository an agent
+//! works in, to every request of the agents that enable them.
+//!
+//! Provided context is appended to the last user message of each request without being added
+//! to the history, so it never goes stale. Agents enable providers by name in
+//! [`Agent::context_providers`](crate::agent::Agent::context_providers), each with a token
+//! budget its context is truncated to.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::path::Path;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::process::Command;
+
+use crate::backend::ContentBlock;
+use crate::backend::estimate_tokens;
+use crate::runtime::agent::AgentState;
+
+pub type ContextFuture<'a> = Pin<Box<dyn Future<Output = Vec<ContentBlock>> + Send + 'a>>;
+
+pub trait ContextProvider: Send + Sync + 'static {
+    /// The name agents enable the provider with.
+    fn name(&self) -> &str;
+
+    /// The context to add to the next request of an agent, empty if there is nothing to add.
+    fn provide<'a>(&'a self, state: &'a AgentState) -> ContextFuture<'a>;
+}
+
+/// The context providers of a runtime by name.
+#[derive(Clone, Default)]
+pub struct ContextProviders {
+    providers: HashMap<String, Arc<dyn ContextProvider>>,
+}
+
+impl ContextProviders {
+    /// The builtin providers: `git_status`, `directory_listing`, `clipboard`, and
+    /// `shell_history`.
+    pub fn new() -> Self {
+        let mut providers = Self::default();
+        providers.register(GitStatusProvider);
+        providers.register(DirectoryListingProvider);
+        providers.register(ClipboardProvider);
+        providers.register(ShellHistoryProvider::default());
+        providers
+    }
+
+    /// Registers a provider, replacing any provider previously registered with the same name.
+    pub fn register(&mut self, provider: impl ContextProvider) {
+        self.providers
+            .insert(provider.name().to_string(), Arc::new(provider));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn ContextProvider>> {
+        self.providers.get(name)
+    }
+}
+
+impl Debug for ContextProviders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.providers.keys()).finish()
+    }
+}
+
+/// Wraps the text a provider returned in a `<context>` element and truncates it to
+/// `max_tokens`.
+///
+/// Only text counts against the budget, other blocks are kept unless the budget is exhausted.
+pub(crate) fn within_budget(
+    provider: &str,
+    blocks: Vec<ContentBlock>,
+    max_tokens: u32,
+) -> Vec<ContentBlock> {
+    let mut remaining = max_tokens;
+    let mut budgeted = Vec::new();
+    for block in blocks {
+        if remaining == 0 {
+            break;
+        }
+
+        let ContentBlock::Text { text } = block else {
+            budgeted.push(block);
+            continue;
+        };
+        let tokens = estimate_tokens(&text);
+        let text = match tokens > remaining {
+            true => {
+                let end = text
+                    .char_indices()
+                    .nth(remaining as usize * 4)
+                    .map_or(text.len(), |(end, _)| end);
+                format!("{}\n[truncated]", &text[..end])
+            }
+            false => text,
+        };
+        remaining = remaining.saturating_sub(tokens);
+        budgeted.push(ContentBlock::Text {
+            text: format!("<context provider=\"{provider}\">\n{text}\n</context>"),
+        });
+    }
+
+    budgeted
+}
+
+/// The directory the agent works in.
+fn working_directory(state: &AgentState) -> Option<PathBuf> {
+    state.definition.roots().into_iter().next()
+}
+
+/// Runs a command, returning its output if it succeeded and printed anything.
+async fn command_output(command: &mut Command) -> Option<String> {
+    let output = command.output().await.ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    (output.status.success() && !stdout.trim().is_empty()).then(|| stdout.trim_end().to_string())
+}
+
+fn text(text: String) -> Vec<ContentBlock> {
+    vec![ContentBlock::Text { text }]
+}
+
+/// The branch and changed files of the git repository the agent works in.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GitStatusProvider;
+
+impl ContextProvider for GitStatusProvider {
+    fn name(&self) -> &str {
+        "git_status"
+    }
+
+    fn provide<'a>(&'a self, state: &'a AgentState) -> ContextFuture<'a> {
+        Box::pin(async move {
+            let Some(dir) = working_directory(state) else {
+                return Vec::new();
+            };
+            command_output(
+                Command::new("git")
+                    .args(["status", "--short", "--branch"])
+                    .current_dir(dir),
+            )
+            .await
+            .map(|status| text(format!("git status --short --branch\n{status}")))
+            .unwrap_or_default()
+        })
+    }
+}
+
+/// The files and directories in the working directory of the agent.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DirectoryListingProvider;
+
+impl ContextProvider for DirectoryListingProvider {
+    fn name(&self) -> &str {
+        "directory_listing"
+    }
+
+    fn provide<'a>(&'a self, state: &'a AgentState) -> ContextFuture<'a> {
+        Box::pin(async move {
+            let Some(dir) = working_directory(state) else {
+                return Vec::new();
+            };
+            let Ok(mut entries) = tokio::fs::read_dir(&dir).await else {
+                return Vec::new();
+            };
+
+            let mut names = Vec::new();
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                names.push(
+                    match entry
+                        .file_type()
+                        .await
+                        .is_ok_and(|file_type| file_type.is_dir())
+                    {
+                        true => format!("{name}/"),
+                        false => name,
+                    },
+                );
+            }
+            names.sort();
+            text(format!(
+                "Contents of {}\n{}",
+                dir.display(),
+                names.join("\n")
+            ))
+        })
+    }
+}
+
+/// The text on the clipboard of the user, read with the clipboard utility of the platform.
+///
+/// Reads `wl-paste`, `xclip`, or `xsel` on Linux, and requires one of them to be installed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClipboardProvider;
+
+impl ContextProvider for ClipboardProvider {
+    fn name(&self) -> &str {
+        "clipboard"
+    }
+
+    fn provide<'a>(&'a self, _state: &'a AgentState) -> ContextFuture<'a> {
+        Box::pin(async move {
+            let commands: &[(&str, &[&str])] = match std::env::consts::OS {
+                "macos" => &[("pbpaste", &[])],
+                "windows" => &[("powershell", &["-NoProfile", "-Command", "Get-Clipboard"])],
+                _ => &[
+                    ("wl-paste", &["--no-newline"]),
+                    ("xclip", &["-selection", "clipboard", "-o"]),
+                    ("xsel", &["--clipboard", "--output"]),
+                ],
+            };
+
+            for (program, args) in commands {
+                if let Some(clipboard) = command_output(Command::new(program).args(*args)).await {
+                    return text(format!("Clipboard\n{clipboard}"));
+                }
+            }
+            Vec::new()
+        })
+    }
+}
+
+/// The most recent commands in the shell history of the user.
+///
+/// Reads `$HISTFILE`, falling back to the zsh, bash, and fish history files in the home
+/// directory.
+#[derive(Clone, Debug)]
+pub struct ShellHistoryProvider {
+    lines: usize,
+    path: Option<PathBuf>,
+}
+
+impl ShellHistoryProvider {
+    /// Provides the last `lines` commands.
+    pub fn with_lines(mut self, lines: usize) -> Self {
+        self.lines = lines;
+        self
+    }
+
+    /// Reads the history from `path` instead of looking it up.
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    async fn history_file(&self) -> Option<PathBuf> {
+        if let Some(path) = &self.path {
+            return Some(path.clone());
+        }
+        if let Some(path) = std::env::var_os("HISTFILE") {
+            return Some(path.into());
+        }
+
+        let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+        for file in [
+            ".zsh_history",
+            ".bash_history",
+            ".local/share/fish/fish_history",
+        ] {
+            let path = Path::new(&home).join(file);
+            if tokio::fs::metadata(&path)
+                .await
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                return Some(path);
+            }
+        }
+        None
+    }
+}
+
+impl Default for ShellHistoryProvider {
+    fn default() -> Self {
+        Self {
+            lines: 20,
+            path: None,
+        }
+    }
+}
+
+impl ContextProvider for ShellHistoryProvider {
+    fn name(&self) -> &str {
+        "shell_history"
+    }
+
+    fn provide<'a>(&'a self, _state: &'a AgentState) -> ContextFuture<'a> {
+        Box::pin(async move {
+            let Some(path) = self.history_file().await else {
+                return Vec::new();
+            };
+            let Ok(history) = tokio::fs::read(&path).await else {
+                return Vec::new();
+            };
+
+            let fish = path.ends_with("fish_history");
+            let history = String::from_utf8_lossy(&history);
+            let commands = history
+                .lines()
+                .filter_map(|line| match fish {
+                    true => line.strip_prefix("- cmd: "),
+                    // Extended zsh history prefixes commands with `: <time>:<duration>;`.
+                    false => Some(match line.strip_prefix(": ") {
+                        Some(rest) => rest.split_once(';').map_or(line, |(_, command)| command),
+                        None => line,
+                    }),
+                })
+                .filter(|command| !command.trim().is_empty())
+                .collect::<Vec<_>>();
+
+            if commands.is_empty() {
+                return Vec::new();
+            }
+            let recent = &commands[commands.len().saturating_sub(self.lines)..];
+            text(format!("Recent shell commands\n{}", recent.join("\n")))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shell_history() {
+        let path = std::env::temp_dir().join(format!("kepoki-{}.history", uuid::Uuid::new_v4()));
+        tokio::fs::write(&path, ": 1700000000:0;cargo build\nls\n\ngit status\n")
+            .await
+            .unwrap();
+        let provider = ShellHistoryProvider::default()
+            .with_path(&path)
+            .with_lines(2);
+
+        let state = AgentState::new(Default::default());
+        let blocks = provider.provide(&state).await;
+        let _ = tokio::fs::remove_file(&path).await;
+        assert!(matches!(
+            &blocks[..],
+            [ContentBlock::Text { text }] if text == "Recent shell commands\nls\ngit status"
+        ));
+    }
+
+    #[test]
+    fn test_within_budget() {
+        let blocks = vec![
+            ContentBlock::Text {
+                text: "a".repeat(20),
+            },
+            ContentBlock::Text {
+                text: "b".repeat(20),
+            },
+        ];
+
+        let budgeted = within_budget("test", blocks, 8);
+        let texts = budgeted
+            .iter()
+            .map(|block| match block {
+                ContentBlock::Text { text } => text.as_str(),
+                _ => panic!("Expected text"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            [
+                format!(
+                    "<context provider=\"test\">\n{}\n</context>",
+                    "a".repeat(20)
+                ),
+                format!(
+                    "<context provider=\"test\">\n{}\n[truncated]\n</context>",
+                    "b".repeat(12)
+                ),
+            ]
+        );
+    }
+}
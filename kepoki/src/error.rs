@@ -1,31 +1,173 @@
+#[cfg(feature = "mcp")]
 use rmcp::RmcpError;
 use thiserror::Error;
 
-use crate::backend::MessagesResponseEvent;
+use crate::backend::Message;
 use crate::runtime::AgentHandle;
 
 #[derive(Debug, Error)]
 pub enum KepokiError {
+    #[cfg(feature = "mcp")]
     #[error("Error with MCP server: {0}")]
     McpServerError(Box<RmcpError>),
+    #[error("Invalid MCP configuration: {0}")]
+    InvalidMcpConfig(String),
+    #[error("Invalid project configuration: {0}")]
+    InvalidProjectConfig(String),
+    #[error("Invalid conversation export: {0}")]
+    InvalidImport(String),
+    #[error("Remote MCP servers are not supported: {0}")]
+    RemoteMcpServerUnsupported(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
     #[error("Failed to join thread: {0}")]
     JoinFailed(#[from] tokio::task::JoinError),
     #[error("Attempted to communicate with the runtime without running agents")]
     NoRunningAgents,
     #[error("Agent does not exist: {0}")]
     AgentNotFound(AgentHandle),
+    #[error("Command source `{0}` is not permitted to send this command")]
+    PermissionDenied(String),
+    #[error("Agent {0} failed: {1}")]
+    AgentFailed(AgentHandle, String),
+    #[error("Output of pipeline stage {stage} is invalid: {error}")]
+    InvalidStageOutput { stage: String, error: String },
+    #[error("Output processor {processor} rejected the response: {error}")]
+    OutputProcessingFailed { processor: String, error: String },
+    #[error("Blocked by hook: {0}")]
+    HookBlocked(String),
+    #[error("Unsupported sandbox: {0}")]
+    SandboxUnsupported(String),
+    #[error("Tool executor disconnected")]
+    ToolExecutorDisconnected,
+    #[error("Tool executor error: {0}")]
+    ToolExecutorError(String),
+    #[error("Daily {metric} quota of {scope} exhausted, the limit is {limit}")]
+    QuotaExceeded {
+        scope: String,
+        metric: String,
+        limit: f64,
+    },
+    #[error("Invalid container settings: {0}")]
+    InvalidContainer(String),
+    #[error("Invalid policy: {0}")]
+    InvalidPolicy(String),
+    #[error("Invalid mission plan: {0}")]
+    InvalidPlan(String),
+    #[error("Invalid review findings: {0}")]
+    InvalidReview(String),
+    #[error("Agent panicked: {0}")]
+    AgentPanicked(AgentHandle),
     #[error("Agent manually terminated: {0}")]
     AgentManuallyTerminated(AgentHandle),
+    /// The turn was cancelled by the token of the command that started it, see
+    /// [`crate::runtime::cancellation`].
+    #[error("The turn was cancelled")]
+    Cancelled,
     #[error("Agent event receiver closed unexpectedly: {0}")]
     EventReceiverClosed(AgentHandle),
     #[error("Unexpected event received for agent {0}")]
     UnexpectedEvent(AgentHandle),
+    /// The events of a response don't describe a message, see
+    /// [`MessageAssembler`](crate::backend::assembly::MessageAssembler).
+    #[error("Malformed response: {0}")]
+    MalformedResponse(String),
+    #[error("Attachment of {size} bytes exceeds the limit of {limit} bytes")]
+    AttachmentTooLarge { size: u64, limit: u64 },
+    #[error("Unsupported attachment: {0}")]
+    UnsupportedAttachment(String),
+    #[error("Invalid extension parameter {name}: {error}")]
+    InvalidExtension { name: String, error: String },
+    #[error("Provider error ({kind}): {message}")]
+    Provider {
+        kind: ProviderErrorKind,
+        message: String,
+    },
+    /// A stream failed after part of the response was received, see
+    /// [`ErrorRecovery::Continue`](crate::runtime::recovery::ErrorRecovery::Continue).
+    #[error("The stream ended early: {error}")]
+    StreamInterrupted {
+        /// The blocks received completely, and text received before the stream failed.
+        partial: Box<Message>,
+        error: Box<KepokiError>,
+    },
     #[error("No message received from backend for agent: {0}")]
     NoMessageReceived(AgentHandle),
     #[error(transparent)]
     CustomError(Box<dyn std::error::Error + Send + Sync>),
 }
 
+impl KepokiError {
+    /// Whether the request may succeed if it is sent again later or to another provider, such as
+    /// when the provider is rate limited or failed internally.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Provider { kind, .. } => kind.is_transient(),
+            Self::StreamInterrupted { error, .. } => error.is_retryable(),
+            Self::Io(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// The class of an error reported by a provider, before or in the middle of a response.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ProviderErrorKind {
+    /// The provider is temporarily overloaded.
+    Overloaded,
+    RateLimited,
+    /// The provider failed internally.
+    Server,
+    InvalidRequest,
+    RequestTooLarge,
+    Authentication,
+    PermissionDenied,
+    NotFound,
+    /// The response was blocked by the content policy of the provider.
+    ContentPolicy,
+    Other,
+}
+
+impl ProviderErrorKind {
+    /// Classifies an HTTP status code.
+    pub fn from_status(status: u16) -> Self {
+        match status {
+            400 => Self::InvalidRequest,
+            401 => Self::Authentication,
+            403 => Self::PermissionDenied,
+            404 => Self::NotFound,
+            413 => Self::RequestTooLarge,
+            429 => Self::RateLimited,
+            503 | 529 => Self::Overloaded,
+            500..=599 => Self::Server,
+            _ => Self::Other,
+        }
+    }
+
+    /// Whether retrying the request later may succeed.
+    pub fn is_transient(self) -> bool {
+        matches!(self, Self::Overloaded | Self::RateLimited | Self::Server)
+    }
+}
+
+impl std::fmt::Display for ProviderErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Overloaded => "overloaded",
+            Self::RateLimited => "rate limited",
+            Self::Server => "server error",
+            Self::InvalidRequest => "invalid request",
+            Self::RequestTooLarge => "request too large",
+            Self::Authentication => "authentication failed",
+            Self::PermissionDenied => "permission denied",
+            Self::NotFound => "not found",
+            Self::ContentPolicy => "content policy",
+            Self::Other => "other",
+        })
+    }
+}
+
+#[cfg(feature = "mcp")]
 impl From<RmcpError> for KepokiError {
     fn from(err: RmcpError) -> Self {
         KepokiError::McpServerError(Box::new(err))
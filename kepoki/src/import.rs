@@ -0,0 +1,322 @@
+//! Loads conversations exported from ChatGPT and Claude.ai, so they can be continued by kepoki
+//! agents with [`Runtime::spawn_agent_with_state`](crate::runtime::Runtime::spawn_agent_with_state).
+//!
+//! Both exports contain a `conversations.json` listing every conversation of the account. Only
+//! the text of user and assistant messages is imported, tool calls, reasoning, and system
+//! messages of the original service are left out. Text attachments of Claude.ai are imported as
+//! documents. Neither export contains images, they are replaced by a note naming the file.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::agent::Agent;
+use crate::backend::ContentBlock;
+use crate::backend::DocumentMediaType;
+use crate::backend::DocumentSource;
+use crate::backend::InputMessage;
+use crate::backend::Role;
+use crate::error::KepokiError;
+use crate::runtime::agent::AgentState;
+
+#[derive(Clone, Debug)]
+pub struct ImportedConversation {
+    pub title: Option<String>,
+    /// The messages of the conversation, consecutive messages of the same role are merged.
+    pub messages: VecDeque<InputMessage>,
+}
+
+impl ImportedConversation {
+    /// The state of an agent of `definition` continuing the conversation.
+    pub fn into_state(self, definition: Agent) -> AgentState {
+        AgentState {
+            messages: self.messages,
+            ..AgentState::new(definition)
+        }
+    }
+
+    fn push(&mut self, id: String, role: Role, content: Vec<ContentBlock>) {
+        if content.is_empty() {
+            return;
+        }
+
+        match self.messages.back_mut() {
+            Some(last) if last.role == role => last.content.extend(content),
+            _ => self.messages.push_back(InputMessage { id, role, content }),
+        }
+    }
+}
+
+/// Parses the `conversations.json` of a ChatGPT export, or a single conversation of it.
+///
+/// Of conversations with edited messages, only the branch that was shown last is imported.
+pub fn chatgpt(json: &str) -> Result<Vec<ImportedConversation>, KepokiError> {
+    parse::<ChatGptConversation>(json)?
+        .into_iter()
+        .map(ChatGptConversation::import)
+        .collect()
+}
+
+/// Parses the `conversations.json` of a Claude.ai export, or a single conversation of it.
+pub fn claude(json: &str) -> Result<Vec<ImportedConversation>, KepokiError> {
+    Ok(parse::<ClaudeConversation>(json)?
+        .into_iter()
+        .map(ClaudeConversation::import)
+        .collect())
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    Many(Vec<T>),
+    One(T),
+}
+
+fn parse<T: DeserializeOwned>(json: &str) -> Result<Vec<T>, KepokiError> {
+    match serde_json::from_str(json) {
+        Ok(OneOrMany::Many(conversations)) => Ok(conversations),
+        Ok(OneOrMany::One(conversation)) => Ok(vec![conversation]),
+        Err(err) => Err(KepokiError::InvalidImport(err.to_string())),
+    }
+}
+
+fn note(text: String) -> ContentBlock {
+    ContentBlock::Text { text }
+}
+
+#[derive(Deserialize)]
+struct ChatGptConversation {
+    title: Option<String>,
+    mapping: HashMap<String, ChatGptNode>,
+    current_node: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatGptNode {
+    message: Option<ChatGptMessage>,
+    parent: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatGptMessage {
+    id: String,
+    author: ChatGptAuthor,
+    content: Value,
+    /// Who the message is addressed to, `all` for messages shown in the conversation.
+    recipient: Option<String>,
+    #[serde(default)]
+    metadata: Value,
+}
+
+#[derive(Deserialize)]
+struct ChatGptAuthor {
+    role: String,
+}
+
+impl ChatGptConversation {
+    fn import(mut self) -> Result<ImportedConversation, KepokiError> {
+        let mut conversation = ImportedConversation {
+            title: self.title,
+            messages: VecDeque::new(),
+        };
+
+        // Messages form a tree of every edit and regeneration, walk up from the shown one.
+        let mut branch = Vec::new();
+        let mut node = self.current_node.take();
+        while let Some(id) = node {
+            let current = self.mapping.remove(&id).ok_or_else(|| {
+                KepokiError::InvalidImport(format!("Conversation node {id} doesn't exist"))
+            })?;
+            node = current.parent;
+            branch.extend(current.message);
+        }
+
+        for message in branch.into_iter().rev() {
+            let role = match message.author.role.as_str() {
+                "user" => Role::User,
+                "assistant" => Role::Assistant,
+                _ => continue,
+            };
+            let hidden = message.metadata["is_visually_hidden_from_conversation"]
+                .as_bool()
+                .unwrap_or(false);
+            if hidden || message.recipient.as_deref().is_some_and(|to| to != "all") {
+                continue;
+            }
+
+            let mut content = Vec::new();
+            if let Some("text" | "multimodal_text") = message.content["content_type"].as_str()
+                && let Some(parts) = message.content["parts"].as_array()
+            {
+                for part in parts {
+                    match part {
+                        Value::String(text) if !text.trim().is_empty() => {
+                            content.push(ContentBlock::Text { text: text.clone() });
+                        }
+                        Value::Object(part)
+                            if part.get("content_type").and_then(Value::as_str)
+                                == Some("image_asset_pointer") =>
+                        {
+                            content.push(note("[Image not included in the export]".to_string()));
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            for attachment in message.metadata["attachments"]
+                .as_array()
+                .into_iter()
+                .flatten()
+            {
+                if let Some(name) = attachment["name"].as_str() {
+                    content.push(note(format!(
+                        "[Attached file not included in the export: {name}]"
+                    )));
+                }
+            }
+            conversation.push(message.id, role, content);
+        }
+        Ok(conversation)
+    }
+}
+
+#[derive(Deserialize)]
+struct ClaudeConversation {
+    name: Option<String>,
+    #[serde(default)]
+    chat_messages: Vec<ClaudeMessage>,
+}
+
+#[derive(Deserialize)]
+struct ClaudeMessage {
+    uuid: String,
+    sender: String,
+    /// The text of the message, older exports have no content.
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    content: Vec<Value>,
+    /// Files whose text Claude.ai extracted.
+    #[serde(default)]
+    attachments: Vec<ClaudeAttachment>,
+    /// Files shown to the model as they are, such as images.
+    #[serde(default)]
+    files: Vec<ClaudeFile>,
+}
+
+#[derive(Deserialize)]
+struct ClaudeAttachment {
+    file_name: String,
+    #[serde(default)]
+    extracted_content: String,
+}
+
+#[derive(Deserialize)]
+struct ClaudeFile {
+    file_name: String,
+}
+
+impl ClaudeConversation {
+    fn import(self) -> ImportedConversation {
+        let mut conversation = ImportedConversation {
+            title: self.name.filter(|name| !name.is_empty()),
+            messages: VecDeque::new(),
+        };
+
+        for message in self.chat_messages {
+            let role = match message.sender.as_str() {
+                "human" => Role::User,
+                "assistant" => Role::Assistant,
+                _ => continue,
+            };
+
+            let mut content = Vec::new();
+            for attachment in message.attachments {
+                content.push(ContentBlock::Document {
+                    source: DocumentSource::Base64 {
+                        data: attachment.extracted_content.into_bytes().into(),
+                        media_type: DocumentMediaType::PlainText,
+                    },
+                    title: Some(attachment.file_name),
+                });
+            }
+            for file in message.files {
+                content.push(note(format!(
+                    "[Attached file not included in the export: {}]",
+                    file.file_name
+                )));
+            }
+
+            let texts = message
+                .content
+                .iter()
+                .filter(|block| block["type"] == "text")
+                .filter_map(|block| block["text"].as_str())
+                .filter(|text| !text.trim().is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            match texts.is_empty() {
+                true if !message.text.trim().is_empty() => {
+                    content.push(ContentBlock::Text { text: message.text });
+                }
+                _ => content.extend(texts.into_iter().map(|text| ContentBlock::Text { text })),
+            }
+            conversation.push(message.uuid, role, content);
+        }
+        conversation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chatgpt_current_branch() {
+        let json = r#"{
+            "title": "Greeting",
+            "current_node": "c",
+            "mapping": {
+                "root": { "message": null, "parent": null },
+                "a": {
+                    "parent": "root",
+                    "message": {
+                        "id": "a",
+                        "author": { "role": "user" },
+                        "content": { "content_type": "text", "parts": ["Hi"] },
+                        "recipient": "all"
+                    }
+                },
+                "b": {
+                    "parent": "a",
+                    "message": {
+                        "id": "b",
+                        "author": { "role": "assistant" },
+                        "content": { "content_type": "text", "parts": ["Regenerated away"] },
+                        "recipient": "all"
+                    }
+                },
+                "c": {
+                    "parent": "a",
+                    "message": {
+                        "id": "c",
+                        "author": { "role": "assistant" },
+                        "content": { "content_type": "text", "parts": ["Hello!"] },
+                        "recipient": "all"
+                    }
+                }
+            }
+        }"#;
+        let conversations = chatgpt(json).unwrap();
+        assert_eq!(conversations.len(), 1);
+        let messages = &conversations[0].messages;
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(
+            &messages[1].content[..],
+            [ContentBlock::Text { text }] if text == "Hello!"
+        ));
+    }
+}
@@ -2,7 +2,25 @@
 //! It provides a runtime for executing agents, a protocol for managing interactions, and an agent model for defining agent behavior.
 
 pub mod agent;
+pub mod artifacts;
+pub mod attachments;
 pub mod backend;
+pub mod blackboard;
+pub mod context;
 pub mod error;
+pub mod import;
+pub mod lint;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+pub mod models;
+pub mod output;
+pub mod pii;
+pub mod policy;
+pub mod project;
 pub mod runtime;
+pub mod sandbox;
 pub mod servers;
+pub mod summarizer;
+pub mod templates;
+pub mod tools;
+pub mod transcript;
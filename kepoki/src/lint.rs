@@ -0,0 +1,191 @@
+//! Static checks of agent specifications that catch common mistakes before an agent runs.
+//!
+//! Token counts are estimates, see [`estimate_tokens`]. The descriptions of MCP tools are only
+//! known once their server runs, so they are counted only when the agent overrides them.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::agent::Agent;
+use crate::agent::ModelMetric;
+use crate::backend::estimate_tokens;
+use crate::models::ModelInfo;
+use crate::tools::ToolRegistry;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct LintReport {
+    /// The estimated tokens of the system prompt.
+    pub prompt_tokens: u32,
+    /// The estimated tokens of the definitions of the tools the agent advertises, sent with
+    /// every request.
+    pub tool_tokens: u32,
+    pub warnings: Vec<LintWarning>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum LintWarning {
+    /// An instruction appears more than once in the prompt.
+    DuplicateInstruction { instruction: String },
+    /// The prompt tells the agent to always and never do the same thing.
+    ConflictingInstructions { always: String, never: String },
+    /// The prompt mentions a builtin tool the agent doesn't list.
+    UnlistedTool { tool: String },
+    /// A tool is allowed or has a description override but isn't listed.
+    UnusedToolSetting { tool: String },
+    /// A builtin tool the agent lists isn't registered with the runtime.
+    UnknownTool { tool: String },
+    /// The temperature doesn't suit the model preferences, or is out of range.
+    Temperature { temperature: f32, reason: String },
+    /// The prompt and tool definitions take up more than half of the context window of the
+    /// model, leaving little room for the conversation.
+    ContextWindow { tokens: u32, context_window: u32 },
+    /// The agent lists tools but the model can't call them.
+    ToolsUnsupported { model: String },
+}
+
+/// Checks `agent` against the builtin tools of the runtime it is spawned on and the model it
+/// runs on, as described by the [`ModelRegistry`](crate::models::ModelRegistry).
+pub fn lint_for_model(agent: &Agent, tools: &ToolRegistry, model: &ModelInfo) -> LintReport {
+    let mut report = lint(agent, tools);
+    let tokens = report.prompt_tokens + report.tool_tokens;
+    if tokens > model.context_window / 2 {
+        report.warnings.push(LintWarning::ContextWindow {
+            tokens,
+            context_window: model.context_window,
+        });
+    }
+    if !agent.tools.is_empty() && !model.tools {
+        report.warnings.push(LintWarning::ToolsUnsupported {
+            model: model.id.clone(),
+        });
+    }
+    report
+}
+
+/// Checks `agent` against the builtin tools of the runtime it is spawned on.
+pub fn lint(agent: &Agent, tools: &ToolRegistry) -> LintReport {
+    let mut warnings = Vec::new();
+
+    // Instructions are compared by sentence, ignoring case and whitespace.
+    let sentences = agent
+        .prompt
+        .split(['.', '!', '?', '\n'])
+        .map(|sentence| {
+            sentence
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase()
+        })
+        .filter(|sentence| sentence.split(' ').count() >= 3)
+        .collect::<Vec<_>>();
+
+    let mut counts = HashMap::<&str, usize>::new();
+    for sentence in &sentences {
+        let count = counts.entry(sentence).or_default();
+        *count += 1;
+        if *count == 2 {
+            warnings.push(LintWarning::DuplicateInstruction {
+                instruction: sentence.clone(),
+            });
+        }
+    }
+
+    for always in &sentences {
+        let Some(action) = always.strip_prefix("always ") else {
+            continue;
+        };
+        for never in &sentences {
+            let negated = ["never ", "do not ", "don't "]
+                .iter()
+                .find_map(|prefix| never.strip_prefix(prefix));
+            let warning = LintWarning::ConflictingInstructions {
+                always: always.clone(),
+                never: never.clone(),
+            };
+            if negated == Some(action) && !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+    }
+
+    for word in agent.prompt.split('`').skip(1).step_by(2) {
+        if tools.contains(word) && !agent.tools.iter().any(|tool| tool.wire_name() == word) {
+            warnings.push(LintWarning::UnlistedTool {
+                tool: word.to_string(),
+            });
+        }
+    }
+
+    for tool in agent
+        .allowed_tools
+        .iter()
+        .chain(agent.tool_descriptions.keys())
+    {
+        let warning = LintWarning::UnusedToolSetting {
+            tool: tool.wire_name(),
+        };
+        if !agent.tools.contains(tool) && !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+
+    let mut tool_tokens = 0;
+    for tool in &agent.tools {
+        let definition = match tool.is_builtin() {
+            true => match tools.get(tool.name()) {
+                Some(builtin) => Some(builtin.definition()),
+                None => {
+                    warnings.push(LintWarning::UnknownTool {
+                        tool: tool.wire_name(),
+                    });
+                    None
+                }
+            },
+            false => None,
+        };
+
+        let description = agent
+            .tool_descriptions
+            .get(tool)
+            .map(String::as_str)
+            .or_else(|| definition.as_ref()?.description.as_deref());
+        let schema = definition
+            .as_ref()
+            .and_then(|definition| definition.input_schema.as_deref());
+        tool_tokens += estimate_tokens(&tool.wire_name())
+            + description.map_or(0, estimate_tokens)
+            + schema.map_or(0, estimate_tokens);
+    }
+
+    let temperature = agent.temperature;
+    let preferences = &agent.model_preferences.preferred_metrics;
+    let reason = if !(0.0..=1.0).contains(&temperature) {
+        Some("Anthropic and Bedrock models only accept temperatures from 0 to 1".to_string())
+    } else if temperature > 0.5
+        && preferences
+            .iter()
+            .any(|metric| matches!(metric, ModelMetric::Code))
+    {
+        Some("Code is usually generated with a temperature of 0.5 or less".to_string())
+    } else {
+        None
+    };
+    if let Some(reason) = reason {
+        warnings.push(LintWarning::Temperature {
+            temperature,
+            reason,
+        });
+    }
+
+    LintReport {
+        prompt_tokens: estimate_tokens(&agent.prompt),
+        tool_tokens,
+        warnings,
+    }
+}
@@ -0,0 +1,680 @@
+//! A backend answering requests with scripted responses, so that the behavior of agents can be
+//! tested without a provider or network access.
+//!
+//! Every request is answered with the next response of the script and recorded, so tests can
+//! assert on what the agent sent. Requests beyond the end of the script fail.
+//!
+//! Enable the `test-util` feature to use the mock backend outside of kepoki:
+//!
+//! ```ignore
+//! let backend = MockBackend::new()
+//!     .with_response(MockResponse::tool_use("call_1", "read_file", json!({ "path": "a" })))
+//!     .with_response(MockResponse::text("The file is empty."));
+//! let agent = runtime.spawn_agent(backend.clone(), "mock".to_string(), Agent::default());
+//! ```
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::backend::Backend;
+use crate::backend::ContentBlock;
+use crate::backend::ContentBlockDelta;
+use crate::backend::ContentBlockStart;
+use crate::backend::ContentBlockStop;
+use crate::backend::InputMessage;
+use crate::backend::Message;
+use crate::backend::MessageDelta;
+use crate::backend::MessageStream;
+use crate::backend::MessagesFuture;
+use crate::backend::MessagesRequest;
+use crate::backend::MessagesResponseEvent;
+use crate::backend::RecvFuture;
+use crate::backend::StopReason;
+use crate::backend::Usage;
+use crate::backend::assembly::assemble;
+use crate::error::KepokiError;
+
+/// A response of the script.
+#[derive(Debug)]
+pub enum MockResponse {
+    /// Streams the events in order, an error ends the stream.
+    Stream(Vec<Result<MessagesResponseEvent, KepokiError>>),
+    /// Fails the request before anything is streamed.
+    Error(KepokiError),
+    /// Streams the events in order, then never streams another or ends the stream.
+    Stall(Vec<Result<MessagesResponseEvent, KepokiError>>),
+}
+
+impl MockResponse {
+    pub fn events(events: impl IntoIterator<Item = MessagesResponseEvent>) -> Self {
+        Self::Stream(events.into_iter().map(Ok).collect())
+    }
+
+    /// A complete message of the blocks, streamed the way providers do.
+    pub fn message(content: Vec<ContentBlock>, stop_reason: StopReason) -> Self {
+        let mut events = vec![MessagesResponseEvent::MessageStart(Message {
+            id: String::new(),
+            content: Vec::new(),
+            stop_reason: None,
+            stop_sequence: None,
+            usage: Some(Usage {
+                input_tokens: 1,
+                output_tokens: 0,
+            }),
+        })];
+        for (index, block) in content.into_iter().enumerate() {
+            let (content_block, delta) = match block {
+                ContentBlock::Text { text } => (
+                    ContentBlock::Text {
+                        text: String::new(),
+                    },
+                    Some(ContentBlockDelta::Text { index, text }),
+                ),
+                ContentBlock::ToolUse { id, input, name } => (
+                    ContentBlock::ToolUse {
+                        id,
+                        input: String::new(),
+                        name,
+                    },
+                    Some(ContentBlockDelta::InputJson {
+                        index,
+                        partial_json: input,
+                    }),
+                ),
+                block => (block, None),
+            };
+            events.push(MessagesResponseEvent::ContentBlockStart(
+                ContentBlockStart {
+                    index,
+                    content_block,
+                },
+            ));
+            events.extend(delta.map(MessagesResponseEvent::ContentBlockDelta));
+            events.push(MessagesResponseEvent::ContentBlockStop(ContentBlockStop {
+                index,
+            }));
+        }
+        events.push(MessagesResponseEvent::MessageDelta(MessageDelta {
+            stop_reason: Some(stop_reason),
+            stop_sequence: None,
+            usage: Some(Usage {
+                input_tokens: 0,
+                output_tokens: 1,
+            }),
+        }));
+        events.push(MessagesResponseEvent::MessageStop);
+        Self::events(events)
+    }
+
+    /// A message of `text` ending the turn.
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::message(
+            vec![ContentBlock::Text { text: text.into() }],
+            StopReason::EndTurn,
+        )
+    }
+
+    /// A message calling the tool `name` with `input`.
+    pub fn tool_use(
+        id: impl Into<String>,
+        name: impl Into<String>,
+        input: serde_json::Value,
+    ) -> Self {
+        Self::message(
+            vec![ContentBlock::ToolUse {
+                id: id.into(),
+                input: input.to_string(),
+                name: name.into(),
+            }],
+            StopReason::ToolUse,
+        )
+    }
+
+    pub fn error(error: KepokiError) -> Self {
+        Self::Error(error)
+    }
+
+    /// Stalls the stream after its first `events` events, such as a provider that stops
+    /// streaming without closing the connection.
+    ///
+    /// Responses that fail before streaming are returned unchanged.
+    pub fn with_stall(self, events: usize) -> Self {
+        match self {
+            Self::Stream(mut streamed) | Self::Stall(mut streamed) => {
+                streamed.truncate(events);
+                Self::Stall(streamed)
+            }
+            response => response,
+        }
+    }
+
+    /// Fails the stream with `error` after the events streamed so far.
+    ///
+    /// Responses that fail before streaming are returned unchanged.
+    pub fn with_stream_error(mut self, error: KepokiError) -> Self {
+        if let Self::Stream(events) = &mut self {
+            events.push(Err(error));
+        }
+        self
+    }
+}
+
+/// A request to `model` of no messages, for tests of backends.
+pub fn request<B: Backend>(model: B::Model) -> MessagesRequest<'static, B> {
+    MessagesRequest {
+        model,
+        messages: Vec::new(),
+        max_tokens: 16,
+        system: None,
+        temperature: None,
+        stop_sequences: None,
+        tool_choice: None,
+        tools: None,
+        reasoning: None,
+        user_id: None,
+        extensions: HashMap::new(),
+    }
+}
+
+/// What the agent sent in a request.
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    pub model: String,
+    pub messages: Vec<InputMessage>,
+    pub system: Option<String>,
+    /// The names of the tools advertised.
+    pub tools: Vec<String>,
+    pub max_tokens: u32,
+    /// Whether it was sent with [`Backend::messages`] rather than [`Backend::complete`].
+    pub streamed: bool,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    responses: VecDeque<MockResponse>,
+    requests: Vec<MockRequest>,
+}
+
+/// Clones share their script and recorded requests, keep a clone to inspect the requests of an
+/// agent the backend was given to.
+#[derive(Clone, Debug, Default)]
+pub struct MockBackend {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `response` to the script.
+    pub fn with_response(self, response: MockResponse) -> Self {
+        self.push_response(response);
+        self
+    }
+
+    /// Appends `response` to the script, such as while an agent is running.
+    pub fn push_response(&self, response: MockResponse) {
+        self.state.lock().unwrap().responses.push_back(response);
+    }
+
+    /// The responses of the script that weren't requested yet.
+    pub fn remaining(&self) -> usize {
+        self.state.lock().unwrap().responses.len()
+    }
+
+    /// The requests received so far, in order.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+pub struct MockStream {
+    events: std::vec::IntoIter<Result<MessagesResponseEvent, KepokiError>>,
+    stall: bool,
+}
+
+impl MessageStream for MockStream {
+    fn recv(&mut self) -> RecvFuture<'_> {
+        Box::pin(async move {
+            match self.events.next() {
+                Some(event) => event.map(Some),
+                None if self.stall => std::future::pending().await,
+                None => Ok(None),
+            }
+        })
+    }
+}
+
+impl Backend for MockBackend {
+    type Model = String;
+    type MessagesEventStream = MockStream;
+
+    fn parse_model(&self, name: &str) -> Option<Self::Model> {
+        Some(name.to_string())
+    }
+
+    fn model_name(&self, model: &Self::Model) -> Option<String> {
+        Some(model.clone())
+    }
+
+    fn messages<'a>(
+        &'a self,
+        request: MessagesRequest<'a, Self>,
+    ) -> MessagesFuture<'a, MockStream> {
+        Box::pin(async move { self.respond(request, true) })
+    }
+
+    fn complete<'a>(&'a self, request: MessagesRequest<'a, Self>) -> MessagesFuture<'a, Message> {
+        Box::pin(async move { assemble(self.respond(request, false)?).await })
+    }
+}
+
+impl MockBackend {
+    /// Records the request and takes the next response of the script.
+    fn respond(
+        &self,
+        request: MessagesRequest<'_, Self>,
+        streamed: bool,
+    ) -> Result<MockStream, KepokiError> {
+        let mut state = self.state.lock().unwrap();
+        state.requests.push(MockRequest {
+            model: request.model,
+            messages: request.messages,
+            system: request.system.map(String::from),
+            tools: request
+                .tools
+                .unwrap_or_default()
+                .into_iter()
+                .map(|tool| tool.name.into_owned())
+                .collect(),
+            max_tokens: request.max_tokens,
+            streamed,
+        });
+
+        match state.responses.pop_front() {
+            Some(MockResponse::Stream(events)) => Ok(MockStream {
+                events: events.into_iter(),
+                stall: false,
+            }),
+            Some(MockResponse::Stall(events)) => Ok(MockStream {
+                events: events.into_iter(),
+                stall: true,
+            }),
+            Some(MockResponse::Error(error)) => Err(error),
+            None => Err(KepokiError::CustomError(
+                "The script of the mock backend has no more responses".into(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::Agent;
+    use crate::agent::HookTrigger;
+    use crate::policy::PolicyDecision;
+    use crate::policy::PolicyEngine;
+    use crate::policy::PolicyFuture;
+    use crate::policy::ToolRequest;
+    use crate::runtime::AgentHandle;
+    use crate::runtime::Runtime;
+    use crate::runtime::agent::AgentCommand;
+    use crate::runtime::agent::AgentEvent;
+    use crate::runtime::agent::AgentState;
+    use crate::runtime::agent::HistoryChange;
+    use crate::runtime::hooks::HookDecision;
+    use crate::runtime::hooks::HookEvent;
+    use crate::runtime::hooks::Hooks;
+    use crate::runtime::recovery::ErrorHandler;
+    use crate::runtime::recovery::ErrorHandlerFuture;
+    use crate::runtime::recovery::ErrorRecovery;
+    use crate::runtime::recovery::TurnFailure;
+
+    struct ContinueHandler;
+
+    impl ErrorHandler for ContinueHandler {
+        fn handle<'a>(&'a self, _failure: &'a TurnFailure) -> ErrorHandlerFuture<'a> {
+            Box::pin(async { ErrorRecovery::Continue })
+        }
+    }
+
+    struct PauseHandler;
+
+    impl ErrorHandler for PauseHandler {
+        fn handle<'a>(&'a self, _failure: &'a TurnFailure) -> ErrorHandlerFuture<'a> {
+            Box::pin(async { ErrorRecovery::Pause })
+        }
+    }
+
+    async fn final_text(runtime: &mut Runtime) -> String {
+        loop {
+            if let AgentEvent::Message(message) = runtime.recv().await.unwrap()
+                && let [ContentBlock::Text { text }] = &message.content[..]
+            {
+                return text.clone();
+            }
+        }
+    }
+
+    async fn dump_state(runtime: &mut Runtime, agent: &AgentHandle) -> AgentState {
+        runtime
+            .send_as_owner(agent, AgentCommand::DumpState)
+            .unwrap();
+        loop {
+            if let AgentEvent::StateDump(state) = runtime.recv().await.unwrap() {
+                return *state;
+            }
+        }
+    }
+
+    fn text_of(message: &InputMessage) -> &str {
+        match &message.content[..] {
+            [ContentBlock::Text { text }] => text,
+            content => panic!("expected text, got {content:?}"),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tool_use_round_trip() {
+        let backend = MockBackend::new()
+            .with_response(MockResponse::tool_use(
+                "call_1",
+                "missing",
+                serde_json::json!({}),
+            ))
+            .with_response(MockResponse::text("Done"));
+        let mut runtime = Runtime::builder().build();
+        let agent = runtime.spawn_agent(backend.clone(), "mock".to_string(), Agent::default());
+        runtime
+            .send_as_owner(&agent, AgentCommand::UserMessage("Hello".to_string()))
+            .unwrap();
+
+        assert_eq!(final_text(&mut runtime).await, "Done");
+        runtime.send_as_owner(&agent, AgentCommand::Exit).unwrap();
+
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(backend.remaining(), 0);
+        assert!(matches!(
+            &requests[1].messages.last().unwrap().content[..],
+            [ContentBlock::ToolResult { tool_use_id, is_error: Some(true), .. }]
+                if tool_use_id == "call_1"
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tool_use_cut_off_is_not_run() {
+        let tool_uses = Arc::new(Mutex::new(0));
+        let hook_tool_uses = tool_uses.clone();
+        let hooks = Hooks::new().with_hook(
+            HookTrigger::BeforeToolUse,
+            move |_: &AgentHandle, _: &HookEvent| {
+                *hook_tool_uses.lock().unwrap() += 1;
+                HookDecision::Continue
+            },
+        );
+        let backend = MockBackend::new().with_response(MockResponse::message(
+            vec![ContentBlock::ToolUse {
+                id: "call_1".to_string(),
+                input: "{}".to_string(),
+                name: "missing".to_string(),
+            }],
+            StopReason::MaxTokens,
+        ));
+        let mut runtime = Runtime::builder().build();
+        let agent = runtime.spawn_agent_with_hooks(
+            backend.clone(),
+            "mock".to_string(),
+            Agent::default(),
+            hooks,
+        );
+        runtime
+            .send_as_owner(&agent, AgentCommand::UserMessage("Hello".to_string()))
+            .unwrap();
+
+        while !matches!(runtime.recv().await.unwrap(), AgentEvent::Message(_)) {}
+        runtime.send_as_owner(&agent, AgentCommand::Exit).unwrap();
+        while runtime.recv().await.is_ok() {}
+
+        assert_eq!(*tool_uses.lock().unwrap(), 0);
+        assert_eq!(backend.requests().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_invalid_tool_input_is_denied() {
+        struct AllowAll(Arc<Mutex<usize>>);
+
+        impl PolicyEngine for AllowAll {
+            fn authorize<'a>(&'a self, _request: &'a ToolRequest) -> PolicyFuture<'a> {
+                *self.0.lock().unwrap() += 1;
+                Box::pin(async { PolicyDecision::Allow })
+            }
+        }
+
+        let authorized = Arc::new(Mutex::new(0));
+        let backend = MockBackend::new()
+            .with_response(MockResponse::message(
+                vec![ContentBlock::ToolUse {
+                    id: "call_1".to_string(),
+                    input: "{\"path\":".to_string(),
+                    name: "missing".to_string(),
+                }],
+                StopReason::ToolUse,
+            ))
+            .with_response(MockResponse::text("Done"));
+        let mut runtime = Runtime::builder()
+            .with_policy(AllowAll(authorized.clone()))
+            .build();
+        let agent = runtime.spawn_agent(backend.clone(), "mock".to_string(), Agent::default());
+        runtime
+            .send_as_owner(&agent, AgentCommand::UserMessage("Hello".to_string()))
+            .unwrap();
+
+        let reason = loop {
+            if let AgentEvent::ToolDenied { reason, .. } = runtime.recv().await.unwrap() {
+                break reason;
+            }
+        };
+        assert!(reason.starts_with("The input isn't valid JSON"));
+        assert_eq!(final_text(&mut runtime).await, "Done");
+        runtime.send_as_owner(&agent, AgentCommand::Exit).unwrap();
+        assert_eq!(*authorized.lock().unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_continue_interrupted_stream() {
+        let MockResponse::Stream(mut events) = MockResponse::text("The answer is ") else {
+            unreachable!();
+        };
+        // Cut the stream after the text delta.
+        events.truncate(3);
+        let interrupted = MockResponse::Stream(events)
+            .with_stream_error(KepokiError::CustomError("Connection reset".into()));
+        let backend = MockBackend::new()
+            .with_response(interrupted)
+            .with_response(MockResponse::text(" 42."));
+        let mut runtime = Runtime::builder()
+            .with_error_handler(ContinueHandler)
+            .build();
+        let agent = runtime.spawn_agent(backend.clone(), "mock".to_string(), Agent::default());
+        runtime
+            .send_as_owner(&agent, AgentCommand::UserMessage("Hello".to_string()))
+            .unwrap();
+
+        assert_eq!(final_text(&mut runtime).await, "The answer is 42.");
+        runtime.send_as_owner(&agent, AgentCommand::Exit).unwrap();
+
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(matches!(
+            requests[1].messages.last().unwrap(),
+            InputMessage { role: crate::backend::Role::Assistant, content, .. }
+                if matches!(&content[..], [ContentBlock::Text { text }] if text == "The answer is")
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rewind_regenerates_response() {
+        let backend = MockBackend::new()
+            .with_response(MockResponse::text("One"))
+            .with_response(MockResponse::text("Two"));
+        let mut runtime = Runtime::builder().build();
+        let agent = runtime.spawn_agent(backend.clone(), "mock".to_string(), Agent::default());
+        runtime
+            .send_as_owner(&agent, AgentCommand::UserMessage("Hello".to_string()))
+            .unwrap();
+        assert_eq!(final_text(&mut runtime).await, "One");
+
+        let state = dump_state(&mut runtime, &agent).await;
+        let question = state.messages[0].id.clone();
+        runtime
+            .send_as_owner(&agent, AgentCommand::RewindTo(question.clone()))
+            .unwrap();
+
+        let edit = loop {
+            if let AgentEvent::HistoryEdited(edit) = runtime.recv().await.unwrap() {
+                break edit;
+            }
+        };
+        let HistoryChange::Rewound { to, removed } = edit.change else {
+            panic!("expected a rewind, got {:?}", edit.change);
+        };
+        assert_eq!(to, question);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(text_of(&removed[0]), "One");
+        assert_eq!(final_text(&mut runtime).await, "Two");
+
+        let state = dump_state(&mut runtime, &agent).await;
+        runtime.send_as_owner(&agent, AgentCommand::Exit).unwrap();
+        assert_eq!(state.messages.len(), 2);
+        assert_eq!(text_of(&state.messages[1]), "Two");
+        assert_eq!(state.history_edits.len(), 1);
+        assert_eq!(backend.requests().len(), 2);
+
+        // The regenerated response is recorded although the history is no longer than before.
+        let diff = runtime.state_diff(&agent, 1, 2).unwrap();
+        assert_eq!(diff.messages_added.len(), 1);
+        assert_eq!(text_of(&diff.messages_added[0]), "Two");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tool_messages_are_not_rewound_or_edited() {
+        let backend = MockBackend::new()
+            .with_response(MockResponse::tool_use(
+                "call_1",
+                "missing",
+                serde_json::json!({}),
+            ))
+            .with_response(MockResponse::text("Done"));
+        let mut runtime = Runtime::builder().build();
+        let agent = runtime.spawn_agent(backend.clone(), "mock".to_string(), Agent::default());
+        runtime
+            .send_as_owner(&agent, AgentCommand::UserMessage("Hello".to_string()))
+            .unwrap();
+        assert_eq!(final_text(&mut runtime).await, "Done");
+
+        let state = dump_state(&mut runtime, &agent).await;
+        let ids: Vec<_> = state
+            .messages
+            .iter()
+            .map(|message| message.id.clone())
+            .collect();
+        assert_eq!(ids.len(), 4);
+        runtime
+            .send_as_owner(&agent, AgentCommand::RewindTo(ids[1].clone()))
+            .unwrap();
+        runtime
+            .send_as_owner(
+                &agent,
+                AgentCommand::EditMessage {
+                    id: ids[2].clone(),
+                    content: "Edited".to_string(),
+                },
+            )
+            .unwrap();
+        runtime
+            .send_as_owner(
+                &agent,
+                AgentCommand::EditMessage {
+                    id: ids[0].clone(),
+                    content: "Hi".to_string(),
+                },
+            )
+            .unwrap();
+
+        // Only the edit of the user message goes through.
+        let edit = loop {
+            if let AgentEvent::HistoryEdited(edit) = runtime.recv().await.unwrap() {
+                break edit;
+            }
+        };
+        assert!(matches!(
+            edit.change,
+            HistoryChange::Edited { id, previous }
+                if id == ids[0]
+                    && matches!(&previous[..], [ContentBlock::Text { text }] if text == "Hello")
+        ));
+
+        let state = dump_state(&mut runtime, &agent).await;
+        runtime.send_as_owner(&agent, AgentCommand::Exit).unwrap();
+        assert_eq!(state.messages.len(), 4);
+        assert_eq!(state.history_edits.len(), 1);
+        assert_eq!(text_of(&state.messages[0]), "Hi");
+        assert_eq!(backend.requests().len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_salvage_stream_cut_mid_block() {
+        let MockResponse::Stream(mut events) = MockResponse::message(
+            vec![
+                ContentBlock::Text {
+                    text: "Let me check.".to_string(),
+                },
+                ContentBlock::ToolUse {
+                    id: "call_1".to_string(),
+                    input: "{\"path\":\"a\"}".to_string(),
+                    name: "missing".to_string(),
+                },
+            ],
+            StopReason::ToolUse,
+        ) else {
+            unreachable!();
+        };
+        // Cut the stream after the input delta of the tool use, before its block stops.
+        events.truncate(6);
+        let interrupted = MockResponse::Stream(events)
+            .with_stream_error(KepokiError::CustomError("Connection reset".into()));
+        let backend = MockBackend::new().with_response(interrupted);
+        let mut runtime = Runtime::builder().with_error_handler(PauseHandler).build();
+        let agent = runtime.spawn_agent(backend.clone(), "mock".to_string(), Agent::default());
+        runtime
+            .send_as_owner(&agent, AgentCommand::UserMessage("Hello".to_string()))
+            .unwrap();
+
+        let partial = loop {
+            if let AgentEvent::TurnFailed { partial, .. } = runtime.recv().await.unwrap() {
+                break partial;
+            }
+        };
+        let state = dump_state(&mut runtime, &agent).await;
+        runtime.send_as_owner(&agent, AgentCommand::Exit).unwrap();
+
+        // The unfinished tool use is dropped, the text before it is kept out of the history.
+        let partial = partial.unwrap();
+        assert!(matches!(
+            &partial.content[..],
+            [ContentBlock::Text { text }] if text == "Let me check."
+        ));
+        assert!(partial.stop_reason.is_none());
+        assert_eq!(state.messages.len(), 1);
+        assert!(matches!(
+            state.incomplete.as_ref().map(|message| &message.content[..]),
+            Some([ContentBlock::Text { text }]) if text == "Let me check."
+        ));
+        assert_eq!(backend.requests().len(), 1);
+    }
+}
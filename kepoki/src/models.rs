@@ -0,0 +1,244 @@
+//! What kepoki knows about the models of its providers, so that limits and prices are looked up
+//! in one place instead of being repeated wherever they are needed.
+//!
+//! [`ModelRegistry::builtin`] describes the models of the Anthropic and OpenAI APIs and their
+//! Bedrock counterparts. Prices are list prices in US dollars and go stale, register your own
+//! [`ModelInfo`] to correct them or to describe models kepoki doesn't know.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::backend::Usage;
+
+/// What requests cost, in dollars per million tokens.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct TokenPrice {
+    pub input_per_million: f64,
+    pub output_per_million: f64,
+}
+
+impl TokenPrice {
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        (usage.input_tokens as f64 * self.input_per_million
+            + usage.output_tokens as f64 * self.output_per_million)
+            / 1_000_000.0
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ModelInfo {
+    /// The canonical name of the model.
+    pub id: String,
+    /// The tokens of input and output the model attends to.
+    pub context_window: u32,
+    /// The most tokens the model generates in one response.
+    pub max_output_tokens: u32,
+    /// Whether the model can call tools.
+    pub tools: bool,
+    /// Whether the model accepts images.
+    pub vision: bool,
+    /// The list price of the model, `None` for local models or unknown prices.
+    pub price: Option<TokenPrice>,
+    /// The identifiers of the model at each provider serving it, such as `bedrock`.
+    #[serde(default)]
+    pub provider_ids: HashMap<String, String>,
+}
+
+/// Describes models by any of their names.
+#[derive(Clone, Debug, Default)]
+pub struct ModelRegistry {
+    models: HashMap<String, Arc<ModelInfo>>,
+}
+
+impl ModelRegistry {
+    /// An empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry of the models kepoki knows.
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        for &(id, anthropic, bedrock, context_window, max_output_tokens, input, output) in CLAUDE {
+            registry.register(ModelInfo {
+                id: id.to_string(),
+                context_window,
+                max_output_tokens,
+                tools: true,
+                vision: true,
+                price: Some(TokenPrice {
+                    input_per_million: input,
+                    output_per_million: output,
+                }),
+                provider_ids: HashMap::from([
+                    ("anthropic".to_string(), anthropic.to_string()),
+                    ("bedrock".to_string(), bedrock.to_string()),
+                ]),
+            });
+        }
+        for &(id, context_window, max_output_tokens, vision, input, output) in OPENAI {
+            registry.register(ModelInfo {
+                id: id.to_string(),
+                context_window,
+                max_output_tokens,
+                tools: true,
+                vision,
+                price: Some(TokenPrice {
+                    input_per_million: input,
+                    output_per_million: output,
+                }),
+                provider_ids: HashMap::from([("openai".to_string(), id.to_string())]),
+            });
+        }
+        registry
+    }
+
+    /// Describes a model by its id and provider ids, replacing models of the same names.
+    pub fn register(&mut self, model: ModelInfo) {
+        let model = Arc::new(model);
+        for name in std::iter::once(&model.id).chain(model.provider_ids.values()) {
+            self.models.insert(name.clone(), model.clone());
+        }
+    }
+
+    /// The model with the id or provider id `name`.
+    ///
+    /// Bedrock inference profiles are found by the model they route to, such as
+    /// `us.anthropic.claude-sonnet-4-20250514-v1:0`.
+    pub fn get(&self, name: &str) -> Option<&ModelInfo> {
+        self.models
+            .get(name)
+            .or_else(|| {
+                let (_, model) = name.split_once('.')?;
+                self.models.get(model)
+            })
+            .map(Arc::as_ref)
+    }
+
+    /// The identifier of the model named `name` at `provider`.
+    pub fn provider_id(&self, name: &str, provider: &str) -> Option<&str> {
+        self.get(name)?
+            .provider_ids
+            .get(provider)
+            .map(String::as_str)
+    }
+}
+
+/// Claude models by alias, Anthropic id, and Bedrock id, with their context window, max
+/// output, and input and output prices.
+const CLAUDE: &[(&str, &str, &str, u32, u32, f64, f64)] = &[
+    (
+        "claude-opus-4-5",
+        "claude-opus-4-5-20251101",
+        "anthropic.claude-opus-4-5-20251101-v1:0",
+        200_000,
+        64_000,
+        5.0,
+        25.0,
+    ),
+    (
+        "claude-sonnet-4-5",
+        "claude-sonnet-4-5-20250929",
+        "anthropic.claude-sonnet-4-5-20250929-v1:0",
+        200_000,
+        64_000,
+        3.0,
+        15.0,
+    ),
+    (
+        "claude-haiku-4-5",
+        "claude-haiku-4-5-20251001",
+        "anthropic.claude-haiku-4-5-20251001-v1:0",
+        200_000,
+        64_000,
+        1.0,
+        5.0,
+    ),
+    (
+        "claude-opus-4-1",
+        "claude-opus-4-1-20250805",
+        "anthropic.claude-opus-4-1-20250805-v1:0",
+        200_000,
+        32_000,
+        15.0,
+        75.0,
+    ),
+    (
+        "claude-opus-4-0",
+        "claude-opus-4-20250514",
+        "anthropic.claude-opus-4-20250514-v1:0",
+        200_000,
+        32_000,
+        15.0,
+        75.0,
+    ),
+    (
+        "claude-sonnet-4-0",
+        "claude-sonnet-4-20250514",
+        "anthropic.claude-sonnet-4-20250514-v1:0",
+        200_000,
+        64_000,
+        3.0,
+        15.0,
+    ),
+    (
+        "claude-3-7-sonnet-latest",
+        "claude-3-7-sonnet-20250219",
+        "anthropic.claude-3-7-sonnet-20250219-v1:0",
+        200_000,
+        64_000,
+        3.0,
+        15.0,
+    ),
+    (
+        "claude-3-5-sonnet-latest",
+        "claude-3-5-sonnet-20241022",
+        "anthropic.claude-3-5-sonnet-20241022-v2:0",
+        200_000,
+        8_192,
+        3.0,
+        15.0,
+    ),
+    (
+        "claude-3-5-sonnet-20240620",
+        "claude-3-5-sonnet-20240620",
+        "anthropic.claude-3-5-sonnet-20240620-v1:0",
+        200_000,
+        8_192,
+        3.0,
+        15.0,
+    ),
+    (
+        "claude-3-5-haiku-latest",
+        "claude-3-5-haiku-20241022",
+        "anthropic.claude-3-5-haiku-20241022-v1:0",
+        200_000,
+        8_192,
+        0.8,
+        4.0,
+    ),
+    (
+        "claude-3-haiku-20240307",
+        "claude-3-haiku-20240307",
+        "anthropic.claude-3-haiku-20240307-v1:0",
+        200_000,
+        4_096,
+        0.25,
+        1.25,
+    ),
+];
+
+/// OpenAI models with their context window, max output, vision support, and input and output
+/// prices.
+const OPENAI: &[(&str, u32, u32, bool, f64, f64)] = &[
+    ("gpt-4.1", 1_047_576, 32_768, true, 2.0, 8.0),
+    ("gpt-4.1-mini", 1_047_576, 32_768, true, 0.4, 1.6),
+    ("gpt-4o", 128_000, 16_384, true, 2.5, 10.0),
+    ("gpt-4o-mini", 128_000, 16_384, true, 0.15, 0.6),
+    ("o3-mini", 200_000, 100_000, false, 1.1, 4.4),
+];
@@ -0,0 +1,293 @@
+//! Output processors transform the responses of agents before they are emitted, the
+//! counterpart of [context providers](crate::context) on the output side.
+//!
+//! Agents list the processors applied to their responses in
+//! [`Agent::output_processors`](crate::agent::Agent::output_processors), in order. Processors
+//! only change the emitted [`AgentEvent::Message`](crate::runtime::agent::AgentEvent::Message),
+//! the history keeps the original response so the model sees what it wrote. A processor
+//! failing fails the turn, which is recovered like a failed request.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::agent::OutputProcessing;
+use crate::artifacts::ArtifactChange;
+use crate::artifacts::ArtifactStore;
+use crate::backend::ContentBlock;
+use crate::backend::Message;
+use crate::runtime::agent::AgentEvent;
+use crate::runtime::patterns::strip_code_fence;
+use crate::runtime::patterns::validate;
+use crate::tools::ToolContext;
+
+pub type OutputFuture<'a> = Pin<Box<dyn Future<Output = Result<Message, String>> + Send + 'a>>;
+
+pub trait OutputProcessor: Send + Sync + 'static {
+    /// The name agents refer to the processor by.
+    fn name(&self) -> &str;
+
+    /// Transforms a complete response of the agent described by `context`, or fails with the
+    /// reason the response is unacceptable.
+    fn process<'a>(&'a self, context: &'a ToolContext, message: Message) -> OutputFuture<'a>;
+}
+
+/// The custom output processors of a runtime by name.
+#[derive(Clone, Default)]
+pub struct OutputProcessors {
+    processors: HashMap<String, Arc<dyn OutputProcessor>>,
+}
+
+impl OutputProcessors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a processor, replacing any processor previously registered with the same name.
+    pub fn register(&mut self, processor: impl OutputProcessor) {
+        self.processors
+            .insert(processor.name().to_string(), Arc::new(processor));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn OutputProcessor>> {
+        self.processors.get(name)
+    }
+
+    /// The processor applying `processing`, `None` for unregistered custom processors.
+    pub(crate) fn resolve(
+        &self,
+        processing: &OutputProcessing,
+        artifacts: &ArtifactStore,
+    ) -> Option<Arc<dyn OutputProcessor>> {
+        Some(match processing {
+            OutputProcessing::StripTags { tags } => Arc::new(StripTagsProcessor::new(tags.clone())),
+            OutputProcessing::ExtractCodeBlocks => {
+                Arc::new(ExtractCodeBlocksProcessor(artifacts.clone()))
+            }
+            OutputProcessing::Structured { schema } => {
+                Arc::new(StructuredProcessor::new(schema.clone()))
+            }
+            OutputProcessing::Custom { name } => self.get(name)?.clone(),
+        })
+    }
+}
+
+impl Debug for OutputProcessors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.processors.keys()).finish()
+    }
+}
+
+/// Applies `edit` to every text block of `message`, dropping blocks left empty.
+fn map_text(mut message: Message, mut edit: impl FnMut(&str) -> String) -> Message {
+    message.content = message
+        .content
+        .into_iter()
+        .filter_map(|block| match block {
+            ContentBlock::Text { text } => {
+                let text = edit(&text);
+                (!text.trim().is_empty()).then_some(ContentBlock::Text { text })
+            }
+            block => Some(block),
+        })
+        .collect();
+    message
+}
+
+/// Removes XML elements the model uses as scaffolding, such as `<thinking>`, with their
+/// content.
+#[derive(Clone, Debug)]
+pub struct StripTagsProcessor {
+    tags: Vec<String>,
+}
+
+impl StripTagsProcessor {
+    pub fn new(tags: Vec<String>) -> Self {
+        Self { tags }
+    }
+
+    fn strip(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for tag in &self.tags {
+            let close = format!("</{tag}>");
+            while let Some(start) = find_open_tag(&text, tag) {
+                let end = text[start..]
+                    .find(&close)
+                    .map_or(text.len(), |end| start + end + close.len());
+                text.replace_range(start..end, "");
+            }
+        }
+        text.trim().to_string()
+    }
+}
+
+/// The position of the first `<tag>` or `<tag attribute="...">` in `text`.
+fn find_open_tag(text: &str, tag: &str) -> Option<usize> {
+    let open = format!("<{tag}");
+    text.match_indices(&open)
+        .map(|(start, _)| start)
+        .find(|start| matches!(text[start + open.len()..].chars().next(), Some('>' | ' ')))
+}
+
+impl OutputProcessor for StripTagsProcessor {
+    fn name(&self) -> &str {
+        "strip_tags"
+    }
+
+    fn process<'a>(&'a self, _context: &'a ToolContext, message: Message) -> OutputFuture<'a> {
+        Box::pin(async move { Ok(map_text(message, |text| self.strip(text))) })
+    }
+}
+
+/// Moves fenced code blocks into artifacts of the agent, leaving a reference to the artifact
+/// in their place.
+#[derive(Clone, Debug)]
+pub struct ExtractCodeBlocksProcessor(pub ArtifactStore);
+
+impl OutputProcessor for ExtractCodeBlocksProcessor {
+    fn name(&self) -> &str {
+        "extract_code_blocks"
+    }
+
+    fn process<'a>(&'a self, context: &'a ToolContext, message: Message) -> OutputFuture<'a> {
+        Box::pin(async move {
+            let mut error = None;
+            let message = map_text(message, |text| {
+                let mut output = String::new();
+                let mut rest = text;
+                while let Some((before, language, code, after)) = next_code_block(rest) {
+                    let name = self.0.unused_name(&context.agent, "code-block");
+                    let media_type = (!language.is_empty()).then(|| format!("text/x-{language}"));
+                    match self
+                        .0
+                        .create(&context.agent, &name, media_type, code.to_string())
+                    {
+                        Ok(artifact) => {
+                            context.emit(AgentEvent::ArtifactChanged {
+                                name: artifact.name,
+                                version: artifact.version,
+                                change: ArtifactChange::Created,
+                            });
+                            output.push_str(before);
+                            output.push_str(&format!("[Code in artifact `{name}`]"));
+                        }
+                        Err(err) => {
+                            error = Some(err);
+                            output.push_str(&rest[..rest.len() - after.len()]);
+                        }
+                    }
+                    rest = after;
+                }
+                output.push_str(rest);
+                output
+            });
+
+            match error {
+                Some(error) => Err(error),
+                None => Ok(message),
+            }
+        })
+    }
+}
+
+/// Splits `text` around its first fenced code block into the text before it, its language,
+/// its code, and the text after it.
+fn next_code_block(text: &str) -> Option<(&str, &str, &str, &str)> {
+    let start = text
+        .match_indices("```")
+        .map(|(start, _)| start)
+        .find(|start| *start == 0 || text[..*start].ends_with('\n'))?;
+    let (info, body) = text[start + 3..].split_once('\n')?;
+    let end = body
+        .match_indices("```")
+        .map(|(end, _)| end)
+        .find(|end| *end == 0 || body[..*end].ends_with('\n'))?;
+
+    Some((&text[..start], info.trim(), &body[..end], &body[end + 3..]))
+}
+
+/// Parses the text of responses as JSON matching a schema, replacing it with the JSON.
+///
+/// Responses calling tools are passed through, only the final response must match. Supports
+/// the schema subset of [`Stage::output_schema`](crate::runtime::patterns::Stage::output_schema).
+#[derive(Clone, Debug)]
+pub struct StructuredProcessor {
+    schema: Value,
+}
+
+impl StructuredProcessor {
+    pub fn new(schema: Value) -> Self {
+        Self { schema }
+    }
+}
+
+impl OutputProcessor for StructuredProcessor {
+    fn name(&self) -> &str {
+        "structured"
+    }
+
+    fn process<'a>(&'a self, _context: &'a ToolContext, mut message: Message) -> OutputFuture<'a> {
+        Box::pin(async move {
+            if message
+                .content
+                .iter()
+                .any(|block| matches!(block, ContentBlock::ToolUse { .. }))
+            {
+                return Ok(message);
+            }
+
+            let text = message
+                .content
+                .iter()
+                .filter_map(|block| match block {
+                    ContentBlock::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<String>();
+            let output = serde_json::from_str::<Value>(strip_code_fence(&text))
+                .map_err(|err| format!("The response is not JSON: {err}"))?;
+            validate(&output, &self.schema, "$")?;
+
+            message
+                .content
+                .retain(|block| !matches!(block, ContentBlock::Text { .. }));
+            message.content.insert(
+                0,
+                ContentBlock::Text {
+                    text: output.to_string(),
+                },
+            );
+            Ok(message)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_tags() {
+        let processor = StripTagsProcessor::new(vec!["thinking".to_string()]);
+        assert_eq!(
+            processor.strip(
+                "<thinking>Hmm</thinking>\nHello <thinking id=\"2\">x</thinking>there <thinkingcap>"
+            ),
+            "Hello there <thinkingcap>"
+        );
+    }
+
+    #[test]
+    fn test_next_code_block() {
+        let text = "Here:\n```rust\nfn main() {}\n```\nDone";
+        assert_eq!(
+            next_code_block(text),
+            Some(("Here:\n", "rust", "fn main() {}\n", "\nDone"))
+        );
+        assert_eq!(next_code_block("Inline ``` fences"), None);
+    }
+}
@@ -0,0 +1,169 @@
+//! Detects personal information in text, so that agents with a [`PiiPolicy`] can mask it or
+//! reject the input before it reaches a provider.
+//!
+//! Detection combines patterns with checks that rule out look-alikes, such as the Luhn checksum
+//! of credit card numbers and the number ranges never assigned to social security numbers.
+//! It is a safeguard against accidental disclosure, not a guarantee.
+
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use regress::Regex;
+
+use crate::agent::PiiAction;
+use crate::agent::PiiKind;
+use crate::agent::PiiPolicy;
+
+static EMAIL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}").unwrap()
+});
+static PHONE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?<![\w+])(?:\+\d{1,3}[ .-]?)?(?:\(\d{3}\)|\d{3})[ .-]?\d{3}[ .-]?\d{4}(?!\w)")
+        .unwrap()
+});
+static SSN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(\d{3})-(\d{2})-(\d{4})\b").unwrap());
+static CREDIT_CARD: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b\d(?:[ -]?\d){12,18}\b").unwrap());
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PiiMatch {
+    pub kind: PiiKind,
+    /// The byte range of the information in the text.
+    pub range: Range<usize>,
+}
+
+impl PiiKind {
+    /// The placeholder masked information is replaced with.
+    pub fn placeholder(self) -> &'static str {
+        match self {
+            Self::Email => "[EMAIL]",
+            Self::Phone => "[PHONE]",
+            Self::Ssn => "[SSN]",
+            Self::CreditCard => "[CREDIT_CARD]",
+        }
+    }
+}
+
+/// Finds personal information in `text`, ordered by position and never overlapping.
+pub fn detect(text: &str) -> Vec<PiiMatch> {
+    // Earlier kinds are more specific, digits of a credit card number could pass for a phone
+    // number but not the other way around.
+    let candidates = CREDIT_CARD
+        .find_iter(text)
+        .filter(|found| luhn(&text[found.range()]))
+        .map(|found| (PiiKind::CreditCard, found.range()))
+        .chain(
+            SSN.find_iter(text)
+                .filter(|found| {
+                    let group = |index| &text[found.group(index).unwrap()];
+                    valid_ssn(group(1), group(2), group(3))
+                })
+                .map(|found| (PiiKind::Ssn, found.range())),
+        )
+        .chain(
+            EMAIL
+                .find_iter(text)
+                .map(|found| (PiiKind::Email, found.range())),
+        )
+        .chain(
+            PHONE
+                .find_iter(text)
+                .map(|found| (PiiKind::Phone, found.range())),
+        );
+
+    let mut matches = Vec::<PiiMatch>::new();
+    for (kind, range) in candidates {
+        if matches
+            .iter()
+            .all(|found| range.end <= found.range.start || found.range.end <= range.start)
+        {
+            matches.push(PiiMatch { kind, range });
+        }
+    }
+
+    matches.sort_by_key(|found| found.range.start);
+    matches
+}
+
+impl PiiPolicy {
+    pub fn action(&self, kind: PiiKind) -> PiiAction {
+        self.actions.get(&kind).copied().unwrap_or(self.default)
+    }
+
+    /// Masks the information in `text` according to the policy, or returns the kind of the
+    /// first information found that blocks it.
+    pub fn apply(&self, text: &str) -> Result<String, PiiKind> {
+        let mut masked = String::with_capacity(text.len());
+        let mut end = 0;
+        for found in detect(text) {
+            match self.action(found.kind) {
+                PiiAction::Allow => continue,
+                PiiAction::Mask => {
+                    masked.push_str(&text[end..found.range.start]);
+                    masked.push_str(found.kind.placeholder());
+                    end = found.range.end;
+                }
+                PiiAction::Block => return Err(found.kind),
+            }
+        }
+
+        masked.push_str(&text[end..]);
+        Ok(masked)
+    }
+}
+
+/// Whether a number passes the Luhn checksum used by payment cards.
+fn luhn(number: &str) -> bool {
+    let digits = number
+        .chars()
+        .filter_map(|c| c.to_digit(10))
+        .collect::<Vec<_>>();
+    let sum = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(index, &digit)| match index % 2 {
+            0 => digit,
+            _ if digit > 4 => digit * 2 - 9,
+            _ => digit * 2,
+        })
+        .sum::<u32>();
+    (13..=19).contains(&digits.len()) && sum % 10 == 0
+}
+
+/// Whether the parts of a social security number are in ranges that are ever assigned.
+fn valid_ssn(area: &str, group: &str, serial: &str) -> bool {
+    area != "000" && area != "666" && !area.starts_with('9') && group != "00" && serial != "0000"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_and_blocks() {
+        let policy = PiiPolicy {
+            default: PiiAction::Mask,
+            actions: [(PiiKind::Phone, PiiAction::Allow)].into(),
+        };
+        assert_eq!(
+            policy.apply("Mail jane.doe@example.co.uk, card 4111 1111 1111 1111, call (555) 123-4567, SSN 123-45-6789"),
+            Ok("Mail [EMAIL], card [CREDIT_CARD], call (555) 123-4567, SSN [SSN]".to_string())
+        );
+
+        // Look-alikes failing the checksum or in unassigned ranges are left alone.
+        assert_eq!(
+            policy.apply("Order 4111 1111 1111 1112, ticket 666-12-3456"),
+            Ok("Order 4111 1111 1111 1112, ticket 666-12-3456".to_string())
+        );
+
+        let policy = PiiPolicy {
+            default: PiiAction::Allow,
+            actions: [(PiiKind::CreditCard, PiiAction::Block)].into(),
+        };
+        assert_eq!(
+            policy.apply("Pay with 5500-0000-0000-0004"),
+            Err(PiiKind::CreditCard)
+        );
+    }
+}
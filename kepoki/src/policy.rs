@@ -0,0 +1,269 @@
+//! Policy engines authorize every tool call of an agent, for controls finer than the tools an
+//! agent lists, such as limiting what a tool may be called with or which tenants may use it.
+//!
+//! A tool call that is denied is not executed, the model receives the reason instead. Engines
+//! are set with [`RuntimeBuilder::with_policy`](crate::runtime::builder::RuntimeBuilder::with_policy).
+//! The `cedar` feature adds [`CedarPolicy`] to evaluate an embedded Cedar policy set, and the
+//! `opa` feature adds [`OpaPolicy`] to consult an Open Policy Agent server.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::runtime::AgentHandle;
+
+pub type PolicyFuture<'a> = Pin<Box<dyn Future<Output = PolicyDecision> + Send + 'a>>;
+
+pub trait PolicyEngine: Send + Sync + 'static {
+    /// Decides whether the tool call may be executed.
+    fn authorize<'a>(&'a self, request: &'a ToolRequest) -> PolicyFuture<'a>;
+}
+
+impl std::fmt::Debug for dyn PolicyEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PolicyEngine")
+    }
+}
+
+/// A tool call to authorize.
+#[derive(Clone, Debug, Serialize)]
+pub struct ToolRequest {
+    pub agent: AgentHandle,
+    /// The name of the tool as advertised to the model, `@server/tool` for MCP tools.
+    pub tool: String,
+    /// The input the model generated. Calls whose input isn't valid JSON are denied without
+    /// asking the engine.
+    pub input: Value,
+    /// The tenant the agent runs for, see
+    /// [`RuntimeBuilder::with_tenant`](crate::runtime::builder::RuntimeBuilder::with_tenant).
+    pub tenant: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PolicyDecision {
+    Allow,
+    /// Denies the call for the given reason.
+    Deny(String),
+}
+
+/// Evaluates tool calls against a Cedar policy set.
+///
+/// Requests have the agent as principal, `Agent::"<name>"`, the action `Action::"call_tool"`,
+/// and the tool as resource, `Tool::"<name>"`. The context holds the `input` of the call, the
+/// `tenant`, and the `agent_id`. Input that Cedar can't represent, such as decimal numbers,
+/// denies the call.
+///
+/// ```cedar
+/// permit(principal == Agent::"support", action == Action::"call_tool", resource)
+/// when { context.tenant == "acme" };
+///
+/// forbid(principal, action, resource == Tool::"@payments/refund")
+/// when { context.input.amount > 100 };
+/// ```
+#[cfg(feature = "cedar")]
+pub struct CedarPolicy {
+    policies: cedar_policy::PolicySet,
+    entities: cedar_policy::Entities,
+}
+
+#[cfg(feature = "cedar")]
+impl CedarPolicy {
+    /// Parses a policy set in the Cedar policy language.
+    pub fn new(policies: &str) -> Result<Self, crate::error::KepokiError> {
+        let policies = policies.parse().map_err(|err| {
+            crate::error::KepokiError::InvalidPolicy(format!("Invalid Cedar policies: {err}"))
+        })?;
+        Ok(Self {
+            policies,
+            entities: cedar_policy::Entities::empty(),
+        })
+    }
+
+    /// Sets the entities policies can refer to, such as groups the agents belong to.
+    pub fn with_entities(mut self, entities: cedar_policy::Entities) -> Self {
+        self.entities = entities;
+        self
+    }
+
+    fn request(request: &ToolRequest) -> Result<cedar_policy::Request, String> {
+        use cedar_policy::Context;
+        use cedar_policy::EntityUid;
+
+        let uid = |entity_type: &str, id: &str| -> Result<EntityUid, String> {
+            Ok(EntityUid::from_type_name_and_id(
+                entity_type.parse().map_err(|err| format!("{err}"))?,
+                id.parse().map_err(|err| format!("{err}"))?,
+            ))
+        };
+        let context = Context::from_json_value(
+            serde_json::json!({
+                "input": request.input,
+                "tenant": request.tenant.as_deref().unwrap_or(""),
+                "agent_id": request.agent.id().to_string(),
+            }),
+            None,
+        )
+        .map_err(|err| format!("Invalid policy context: {err}"))?;
+
+        Ok(cedar_policy::Request::new(
+            Some(uid("Agent", request.agent.name())?),
+            Some(uid("Action", "call_tool")?),
+            Some(uid("Tool", &request.tool)?),
+            context,
+        ))
+    }
+}
+
+#[cfg(feature = "cedar")]
+impl PolicyEngine for CedarPolicy {
+    fn authorize<'a>(&'a self, request: &'a ToolRequest) -> PolicyFuture<'a> {
+        Box::pin(async move {
+            let cedar_request = match Self::request(request) {
+                Ok(cedar_request) => cedar_request,
+                Err(err) => return PolicyDecision::Deny(err),
+            };
+
+            let response = cedar_policy::Authorizer::new().is_authorized(
+                &cedar_request,
+                &self.policies,
+                &self.entities,
+            );
+            match response.decision() {
+                cedar_policy::Decision::Allow => PolicyDecision::Allow,
+                cedar_policy::Decision::Deny => {
+                    let policies = response
+                        .diagnostics()
+                        .reason()
+                        .map(|policy| policy.to_string())
+                        .collect::<Vec<_>>();
+                    PolicyDecision::Deny(match policies.is_empty() {
+                        true => format!("No policy permits calling {}", request.tool),
+                        false => format!("Forbidden by policy {}", policies.join(", ")),
+                    })
+                }
+            }
+        })
+    }
+}
+
+/// Consults an Open Policy Agent server, posting `{"input": <request>}` to the URL of a
+/// decision such as `http://localhost:8181/v1/data/kepoki/allow`.
+///
+/// The decision must be a boolean, or an object with a boolean `allow` and an optional
+/// `reason`. Undefined decisions and failed requests deny the call.
+#[cfg(feature = "opa")]
+pub struct OpaPolicy {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[cfg(feature = "opa")]
+impl OpaPolicy {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.into(),
+        }
+    }
+
+    async fn decide(&self, request: &ToolRequest) -> Result<PolicyDecision, String> {
+        let body = serde_json::to_string(&serde_json::json!({ "input": request }))
+            .map_err(|err| err.to_string())?;
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|err| format!("Policy request failed: {err}"))?;
+        let text = response
+            .text()
+            .await
+            .map_err(|err| format!("Policy request failed: {err}"))?;
+        let result = serde_json::from_str::<Value>(&text)
+            .map_err(|err| format!("Invalid policy response: {err}"))?
+            .get("result")
+            .cloned()
+            .unwrap_or(Value::Null);
+
+        let (allow, reason) = match &result {
+            Value::Bool(allow) => (Some(*allow), None),
+            Value::Object(decision) => (
+                decision.get("allow").and_then(Value::as_bool),
+                decision.get("reason").and_then(Value::as_str),
+            ),
+            _ => (None, None),
+        };
+        Ok(match allow {
+            Some(true) => PolicyDecision::Allow,
+            Some(false) => PolicyDecision::Deny(reason.map_or_else(
+                || format!("Policy denied calling {}", request.tool),
+                str::to_string,
+            )),
+            None => {
+                PolicyDecision::Deny(format!("No policy decision for calling {}", request.tool))
+            }
+        })
+    }
+}
+
+#[cfg(feature = "opa")]
+impl PolicyEngine for OpaPolicy {
+    fn authorize<'a>(&'a self, request: &'a ToolRequest) -> PolicyFuture<'a> {
+        Box::pin(async move {
+            self.decide(request)
+                .await
+                .unwrap_or_else(PolicyDecision::Deny)
+        })
+    }
+}
+
+#[cfg(all(test, feature = "cedar"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cedar_policy() {
+        let policy = CedarPolicy::new(
+            r#"
+            permit(principal == Agent::"support", action == Action::"call_tool", resource)
+            when { context.tenant == "acme" };
+
+            forbid(principal, action, resource == Tool::"@payments/refund")
+            when { context.input.amount > 100 };
+            "#,
+        )
+        .unwrap();
+        let request = |tool: &str, amount: i64, tenant: &str| ToolRequest {
+            agent: serde_json::from_value(serde_json::json!({
+                "name": "support",
+                "uuid": vec![0; 16],
+            }))
+            .unwrap(),
+            tool: tool.to_string(),
+            input: serde_json::json!({ "amount": amount }),
+            tenant: Some(tenant.to_string()),
+        };
+
+        assert_eq!(
+            policy
+                .authorize(&request("@payments/refund", 50, "acme"))
+                .await,
+            PolicyDecision::Allow
+        );
+        assert!(matches!(
+            policy
+                .authorize(&request("@payments/refund", 500, "acme"))
+                .await,
+            PolicyDecision::Deny(_)
+        ));
+        assert!(matches!(
+            policy.authorize(&request("current_time", 0, "other")).await,
+            PolicyDecision::Deny(_)
+        ));
+    }
+}
@@ -0,0 +1,224 @@
+//! Agent setups committed to a repository, so everyone working in it gets the same agents.
+//!
+//! A project is configured by a `.kepoki` directory in the repository:
+//!
+//! ```text
+//! .kepoki/
+//!     agents/*.json   agent specifications
+//!     mcp.json        MCP servers, in the format read by [`load_mcp_config`]
+//!     settings.json   { "allowedTools": ["read_artifact", "@git/git_status"] }
+//!     memory/         memory shared by the agents of the project
+//! ```
+//!
+//! Every part is optional.
+//!
+//! Instruction files such as `AGENTS.md` are loaded from the project for agents with
+//! [`context_files`](crate::agent::Agent::context_files), see [`load_context_files`].
+//!
+//! [`load_mcp_config`]: crate::servers::config::load_mcp_config
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::agent::Agent;
+use crate::agent::ContextFiles;
+use crate::agent::McpServer;
+use crate::agent::ToolName;
+use crate::error::KepokiError;
+use crate::servers::config::load_mcp_config;
+
+/// The name of the directory holding the configuration of a project.
+pub const PROJECT_DIR: &str = ".kepoki";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Settings {
+    #[serde(default)]
+    allowed_tools: Vec<ToolName>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ProjectConfig {
+    /// The directory containing the `.kepoki` directory.
+    pub root: PathBuf,
+    /// The agents of the project, keyed by name.
+    pub agents: HashMap<String, Agent>,
+    pub mcp_servers: HashMap<String, McpServer>,
+    /// Tools every agent of the project may call without approval.
+    pub allowed_tools: Vec<ToolName>,
+}
+
+impl ProjectConfig {
+    /// Finds the closest `.kepoki` directory in `start` or its ancestors and loads it.
+    pub fn discover(start: impl AsRef<Path>) -> Result<Option<Self>, KepokiError> {
+        start
+            .as_ref()
+            .ancestors()
+            .find(|dir| dir.join(PROJECT_DIR).is_dir())
+            .map(Self::load)
+            .transpose()
+    }
+
+    /// Loads the configuration of the project rooted at `root`.
+    pub fn load(root: impl AsRef<Path>) -> Result<Self, KepokiError> {
+        let root = root.as_ref();
+        let dir = root.join(PROJECT_DIR);
+
+        let mut agents = HashMap::new();
+        let agents_dir = dir.join("agents");
+        if agents_dir.is_dir() {
+            let mut paths = std::fs::read_dir(&agents_dir)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            paths.retain(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "json")
+            });
+            paths.sort();
+
+            for path in paths {
+                let agent = read_json::<Agent>(&path)?;
+                agents.insert(agent.name.clone(), agent);
+            }
+        }
+
+        let mcp_path = dir.join("mcp.json");
+        let mcp_servers = match mcp_path.is_file() {
+            true => load_mcp_config(mcp_path)?,
+            false => HashMap::new(),
+        };
+
+        let settings_path = dir.join("settings.json");
+        let settings = match settings_path.is_file() {
+            true => read_json::<Settings>(&settings_path)?,
+            false => Settings::default(),
+        };
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            agents,
+            mcp_servers,
+            allowed_tools: settings.allowed_tools,
+        })
+    }
+
+    /// The directory for memory shared by the agents of the project.
+    pub fn memory_dir(&self) -> PathBuf {
+        self.root.join(PROJECT_DIR).join("memory")
+    }
+
+    /// Adds the MCP servers and allowed tools of the project to an agent, and makes the
+    /// project root its working directory if it has none.
+    ///
+    /// Settings of the agent take precedence, so applying the project configuration before a
+    /// user configuration gives the project precedence over the user.
+    pub fn apply(&self, agent: &mut Agent) {
+        agent.merge_mcp_servers(self.mcp_servers.clone());
+        for tool in &self.allowed_tools {
+            if !agent.allowed_tools.contains(tool) {
+                agent.allowed_tools.push(tool.clone());
+            }
+        }
+        agent
+            .working_directory
+            .get_or_insert_with(|| self.root.clone());
+    }
+}
+
+/// An instruction file loaded from a project.
+#[derive(Clone, Debug)]
+pub struct ContextFile {
+    pub path: PathBuf,
+    pub content: String,
+    /// Whether the content was cut off at [`ContextFiles::max_bytes`].
+    pub truncated: bool,
+}
+
+/// Loads the instruction files for an agent working in `dir`.
+///
+/// Files are looked for in `dir` and its ancestors up to the root of the git repository
+/// containing it, or only in `dir` outside of a repository. They are ordered from the
+/// repository root down, so instructions for more specific directories come last.
+pub fn load_context_files(dir: impl AsRef<Path>, config: &ContextFiles) -> Vec<ContextFile> {
+    let dir = dir.as_ref();
+    let dirs = match dir.ancestors().position(|dir| dir.join(".git").exists()) {
+        Some(position) => dir.ancestors().take(position + 1).collect::<Vec<_>>(),
+        None => vec![dir],
+    };
+
+    let mut files = dirs
+        .into_iter()
+        .filter_map(|dir| {
+            config
+                .names
+                .iter()
+                .map(|name| dir.join(name))
+                .find(|path| path.is_file())
+        })
+        .filter_map(|path| match std::fs::read_to_string(&path) {
+            Ok(mut content) => {
+                let truncated = content.len() > config.max_bytes;
+                if truncated {
+                    let mut end = config.max_bytes;
+                    while !content.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    content.truncate(end);
+                }
+
+                Some(ContextFile {
+                    path,
+                    content,
+                    truncated,
+                })
+            }
+            Err(err) => {
+                tracing::warn!("Failed to read context file {}: {err}", path.display());
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    files.reverse();
+    files
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T, KepokiError> {
+    serde_json::from_str(&std::fs::read_to_string(path)?)
+        .map_err(|err| KepokiError::InvalidProjectConfig(format!("{}: {err}", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discover() {
+        let root = std::env::temp_dir().join(format!("kepoki-project-{}", uuid::Uuid::new_v4()));
+        let nested = root.join("src").join("nested");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::create_dir_all(root.join(PROJECT_DIR).join("agents")).unwrap();
+        std::fs::write(
+            root.join(PROJECT_DIR).join("mcp.json"),
+            r#"{ "mcpServers": { "git": { "command": "uvx", "args": ["mcp-server-git"] } } }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            root.join(PROJECT_DIR).join("settings.json"),
+            r#"{ "allowedTools": ["@git/git_status"] }"#,
+        )
+        .unwrap();
+
+        let project = ProjectConfig::discover(&nested).unwrap().unwrap();
+        let mut agent = Agent::default();
+        project.apply(&mut agent);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(project.root, root);
+        assert!(agent.mcp_servers.contains_key("git"));
+        assert_eq!(agent.allowed_tools, ["@git/git_status".parse().unwrap()]);
+        assert_eq!(agent.working_directory, Some(root));
+    }
+}
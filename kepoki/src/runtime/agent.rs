@@ -1,12 +1,31 @@
+use std::backtrace::Backtrace;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::cell::Cell;
 use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
 use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::Once;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
+use futures::FutureExt;
+use futures::future;
 use serde::Deserialize;
 use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::mpsc::error::TryRecvError;
+use uuid::Uuid;
 
+use crate::agent::HookTrigger;
+use crate::agent::McpServer;
+use crate::agent::PiiKind;
+use crate::agent::ToolName;
+use crate::artifacts::ArtifactChange;
+use crate::artifacts::ArtifactStore;
 use crate::backend::Backend;
 use crate::backend::ContentBlock;
 use crate::backend::ContentBlockDelta;
@@ -19,8 +38,56 @@ use crate::backend::MessageStream;
 use crate::backend::MessagesRequest;
 use crate::backend::MessagesResponseEvent;
 use crate::backend::Role;
+use crate::backend::StopReason;
+use crate::backend::Tool;
+use crate::backend::Usage;
+use crate::backend::assembly::MessageAssembler;
+use crate::context::ContextProviders;
+use crate::context::within_budget;
 use crate::error::KepokiError;
+use crate::models::ModelInfo;
+use crate::models::ModelRegistry;
+use crate::models::TokenPrice;
+use crate::output::OutputProcessors;
+use crate::policy::PolicyDecision;
+use crate::policy::PolicyEngine;
+use crate::policy::ToolRequest;
+use crate::project::ContextFile;
+use crate::project::load_context_files;
 use crate::runtime::AgentHandle;
+use crate::runtime::cancellation::Cancellation;
+use crate::runtime::cancellation::CancellationToken;
+use crate::runtime::drafting::DraftPath;
+use crate::runtime::drafting::Drafting;
+use crate::runtime::events::EventBus;
+use crate::runtime::heartbeat::Heartbeat;
+use crate::runtime::heartbeat::TurnPhase;
+use crate::runtime::hooks::HookDecision;
+use crate::runtime::hooks::HookEvent;
+use crate::runtime::hooks::Hooks;
+use crate::runtime::notifications::Notification;
+use crate::runtime::notifications::NotificationKind;
+use crate::runtime::notifications::Notifications;
+use crate::runtime::quotas::QuotaScope;
+use crate::runtime::quotas::QuotaWarning;
+use crate::runtime::quotas::Quotas;
+use crate::runtime::recovery::ErrorHandlers;
+use crate::runtime::recovery::ErrorRecovery;
+use crate::runtime::recovery::RequestAdjustments;
+use crate::runtime::recovery::TurnFailure;
+use crate::runtime::replay::Divergence;
+use crate::runtime::replay::Replay;
+use crate::runtime::sampling::BestOf;
+use crate::runtime::scheduling::RequestScheduler;
+use crate::runtime::turns::TurnLog;
+use crate::runtime::turns::TurnRecord;
+use crate::runtime::watchdog::Progress;
+use crate::servers::McpServers;
+use crate::tools::ToolContext;
+use crate::tools::ToolOutput;
+use crate::tools::ToolRegistry;
+use crate::tools::selection::ToolSelector;
+use crate::tools::stats::ToolStats;
 
 #[derive(Debug, Deserialize, Serialize)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
@@ -31,6 +98,69 @@ pub enum AgentCommand {
     Terminate,
     DumpState,
     UserMessage(String),
+    /// A user message with overrides that only apply while the model responds to it.
+    UserMessageWithOverrides(String, TurnOverrides),
+    /// A user message with content other than text, such as images and documents.
+    UserContent(Vec<ContentBlock>),
+    /// Moves the agent to another working directory, updating the roots of its MCP servers.
+    SetWorkingDirectory(PathBuf),
+    /// Removes every message after the given one from the history.
+    ///
+    /// Message ids are those of [`AgentEvent::Message`] for responses, and can be looked up in
+    /// a [`AgentEvent::StateDump`] for other messages.
+    ///
+    /// Rewinding to a user message makes the agent respond to it again. Messages containing
+    /// tool uses can't be rewound to.
+    RewindTo(String),
+    /// Replaces the content of a message with text, combine with [`AgentCommand::RewindTo`] to
+    /// edit and resend a message.
+    ///
+    /// Messages containing tool uses or results can't be edited.
+    EditMessage {
+        id: String,
+        content: String,
+    },
+}
+
+/// A change made to the history of an agent by a command.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct HistoryEdit {
+    pub at: SystemTime,
+    pub change: HistoryChange,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum HistoryChange {
+    Rewound {
+        to: String,
+        removed: Vec<InputMessage>,
+    },
+    Edited {
+        id: String,
+        previous: Vec<ContentBlock>,
+    },
+}
+
+/// Settings that replace those of the agent definition for a single turn.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct TurnOverrides {
+    /// Replaces the stop sequences of the agent, such as "```" to stop after a code block.
+    #[serde(default)]
+    pub stop_sequences: Option<Vec<String>>,
+    /// Replaces the user id of the runtime, for hosts serving several end users.
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// Replaces the temperature of the agent.
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    /// The name of a model to use instead of the agent's, as understood by the backend.
+    #[serde(default)]
+    pub model: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -43,11 +173,126 @@ pub enum AgentEvent {
     /// Represents a message that has been fully received and processed.
     Message(Message),
     ContentBlockStart(ContentBlockStart),
+    /// Any content block delta other than thinking.
     ContentBlockDelta(ContentBlockDelta),
     ContentBlockStop(ContentBlockStop),
+    /// Reasoning generated by the model before its response, regardless of backend.
+    ThinkingDelta {
+        index: usize,
+        thinking: String,
+    },
     Terminated(String),
+    /// The agent panicked, the state is a snapshot from the moment it did.
+    Crashed {
+        state: Box<AgentState>,
+        backtrace: String,
+    },
     Completed(AgentHandle),
     StateDump(Box<AgentState>),
+    /// A side-effecting tool call that was skipped because the runtime is in dry-run mode.
+    DryRunToolUse {
+        id: String,
+        name: String,
+        input: String,
+    },
+    /// The history was rewound or a message in it was edited.
+    HistoryEdited(HistoryEdit),
+    /// Several candidate responses were sampled for a turn, the selected one follows as a
+    /// message. Contains the usage of every candidate, including those that were discarded.
+    CandidatesSampled {
+        selected: usize,
+        usage: Vec<Option<Usage>>,
+    },
+    /// A turn was drafted by the draft model. Contains the usage of the draft, a response of the
+    /// model of the agent follows as a message if it was escalated.
+    Drafted {
+        path: DraftPath,
+        draft_usage: Option<Usage>,
+    },
+    /// A response of an agent replaying a recorded session differs from the recorded one.
+    ReplayDiverged(Divergence),
+    /// A turn failed and the agent was paused by its error handler, unpausing retries the turn.
+    TurnFailed {
+        error: String,
+        /// The response received before the stream failed, if any.
+        #[serde(default)]
+        partial: Option<Message>,
+    },
+    /// The turn in progress was cancelled by the token of the command that started it, the
+    /// agent waits for the next command.
+    TurnCancelled,
+    /// The agent made no progress in the middle of a turn for longer than the window of the
+    /// watchdog, see [`crate::runtime::watchdog`].
+    Stalled {
+        idle: Duration,
+    },
+    /// The agent is still busy with a phase of its turn that emits no other events.
+    Heartbeat {
+        phase: TurnPhase,
+        elapsed: Duration,
+    },
+    /// A request of the agent crossed a warning threshold of a quota, see
+    /// [`Quota::warn_at`](crate::runtime::quotas::Quota::warn_at).
+    QuotaWarning(QuotaWarning),
+    /// The tools provided by one of the agent's MCP servers changed, they will be advertised
+    /// to the model from the next turn on.
+    ToolsChanged {
+        server: String,
+    },
+    /// An artifact owned by the agent was created or updated.
+    ArtifactChanged {
+        name: String,
+        version: u32,
+        change: ArtifactChange,
+    },
+    /// A user message was rejected by the PII policy of the agent and not added to the history.
+    InputBlocked {
+        kind: PiiKind,
+    },
+    /// The policy engine denied a tool call, which wasn't executed.
+    ToolDenied {
+        tool: String,
+        reason: String,
+    },
+    /// A hook blocked a user message, tool call, tool result, or response.
+    HookBlocked {
+        trigger: HookTrigger,
+        reason: String,
+    },
+    /// A domain-specific event published by a tool, see [`ToolContext::publish`].
+    ///
+    /// [`ToolContext::publish`]: crate::tools::ToolContext::publish
+    Custom {
+        topic: String,
+        payload: serde_json::Value,
+    },
+    /// The agent wrote an entry of the runtime's blackboard.
+    BlackboardWritten {
+        key: String,
+        version: u32,
+    },
+    /// An event of a type kepoki doesn't model, as the provider's raw JSON.
+    Unknown(serde_json::Value),
+}
+
+/// The most tokens a response may have unless the turn or the model limits it further.
+const DEFAULT_MAX_TOKENS: u32 = 8192;
+
+thread_local! {
+    /// The panic message and backtrace of the last panic on this thread.
+    static PANIC_BACKTRACE: Cell<Option<String>> = const { Cell::new(None) };
+}
+
+/// Records backtraces of panics so they can be reported once the agent has unwound.
+fn install_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            PANIC_BACKTRACE.set(Some(format!("{info}\n{}", Backtrace::force_capture())));
+            previous(info);
+        }));
+    });
 }
 
 impl From<MessagesResponseEvent> for AgentEvent {
@@ -58,8 +303,13 @@ impl From<MessagesResponseEvent> for AgentEvent {
             MessagesResponseEvent::MessageDelta(event) => Self::MessageDelta(event),
             MessagesResponseEvent::MessageStop => Self::MessageStop,
             MessagesResponseEvent::ContentBlockStart(event) => Self::ContentBlockStart(event),
+            MessagesResponseEvent::ContentBlockDelta(ContentBlockDelta::Thinking {
+                index,
+                thinking,
+            }) => Self::ThinkingDelta { index, thinking },
             MessagesResponseEvent::ContentBlockDelta(event) => Self::ContentBlockDelta(event),
             MessagesResponseEvent::ContentBlockStop(event) => Self::ContentBlockStop(event),
+            MessagesResponseEvent::Unknown(event) => Self::Unknown(event),
         }
     }
 }
@@ -70,156 +320,1306 @@ pub struct AgentState {
     pub definition: crate::agent::Agent,
     pub messages: VecDeque<InputMessage>,
     pub paused: bool,
+    /// Every change made to the history after the fact, oldest first.
+    #[serde(default)]
+    pub history_edits: Vec<HistoryEdit>,
+    /// The partial response of the last turn, if its stream failed midway. Kept until the turn
+    /// succeeds or is retried, it isn't part of the history.
+    #[serde(default)]
+    pub incomplete: Option<Message>,
+}
+
+impl AgentState {
+    /// The state of an agent that hasn't started a conversation yet.
+    pub fn new(definition: crate::agent::Agent) -> Self {
+        Self {
+            definition,
+            messages: VecDeque::new(),
+            paused: false,
+            history_edits: Vec::new(),
+            incomplete: None,
+        }
+    }
 }
 
 pub struct Agent<B: Backend> {
     pub backend: B,
     pub model: B::Model,
     pub handle: AgentHandle,
-    pub command_receiver: tokio::sync::mpsc::UnboundedReceiver<AgentCommand>,
+    /// Commands with the token cancelling the turn they start, if any.
+    pub command_receiver:
+        tokio::sync::mpsc::UnboundedReceiver<(AgentCommand, Option<CancellationToken>)>,
+    /// Cancelled to terminate the agent, see [`crate::runtime::cancellation`].
+    pub cancellation: CancellationToken,
+    /// Cancels the turn in progress, set by the command that started it.
+    pub turn_cancellation: Option<CancellationToken>,
     pub event_emitter: tokio::sync::mpsc::UnboundedSender<AgentEvent>,
+    pub tools: ToolRegistry,
+    pub context_providers: ContextProviders,
+    pub output_processors: OutputProcessors,
+    pub hooks: Hooks,
+    pub mcp_servers: McpServers,
+    /// How often heartbeats are emitted during long phases of a turn, `None` emits none.
+    pub heartbeat_interval: Option<Duration>,
+    /// The tool timeout used when the agent definition doesn't specify one.
+    pub tool_timeout: Duration,
+    /// Whether side-effecting tools are skipped instead of executed.
+    pub dry_run: bool,
+    pub tool_stats: ToolStats,
+    pub error_handlers: ErrorHandlers,
+    /// Authorizes tool calls, if set.
+    pub policy: Option<Arc<dyn PolicyEngine>>,
+    /// The tenant the agent runs for, passed to the policy engine.
+    pub tenant: Option<String>,
+    /// The backend credential the agent uses, for quotas of credentials.
+    pub credential: Option<String>,
+    pub quotas: Quotas,
+    pub scheduler: RequestScheduler,
+    /// When the agent last made progress, for the watchdog of the runtime.
+    pub progress: Progress,
+    /// Has a cheap model draft the responses of the agent, if set.
+    pub drafting: Option<Drafting>,
+    pub models: ModelRegistry,
+    /// Samples several responses per turn and keeps the best one, if set.
+    pub best_of: Option<BestOf>,
+    /// Identifies the end user to the provider, see [`MessagesRequest::user_id`].
+    pub user_id: Option<String>,
+    pub artifacts: ArtifactStore,
+    pub event_bus: EventBus,
+    pub turn_log: TurnLog,
+    pub tool_selector: ToolSelector,
+    /// Notifies a human of turns that need their attention, if set.
+    pub notifications: Option<Notifications>,
+    /// The recorded session being replayed, if any.
+    pub replay: Option<Replay>,
+    /// The number of messages in the history that were recorded in the turn log.
+    pub recorded_messages: usize,
+    /// Overrides for the turn in progress, cleared once the model ends its turn.
+    pub turn_overrides: TurnOverrides,
+    /// The instruction files loaded for the agent, in the order they are added to the prompt.
+    pub context_files: Vec<ContextFile>,
+    /// The context of the agent's providers, appended to the requests of the current turn.
+    pub provided_context: Vec<ContentBlock>,
     pub state: AgentState,
 }
 
 impl<B: Backend> Agent<B> {
-    pub fn run(mut self) -> Result<ExitCode, KepokiError> {
+    pub async fn run(mut self) -> Result<ExitCode, KepokiError> {
+        install_panic_hook();
+        let result = match AssertUnwindSafe(self.run_turns()).catch_unwind().await {
+            Ok(result) => result,
+            Err(_) => {
+                let backtrace = PANIC_BACKTRACE
+                    .take()
+                    .unwrap_or_else(|| "No backtrace captured".to_string());
+                tracing::error!("Agent {} panicked: {backtrace}", self.handle);
+                let _ = self.event_emitter.send(AgentEvent::Crashed {
+                    state: Box::new(self.state.clone()),
+                    backtrace,
+                });
+                Err(KepokiError::AgentPanicked(self.handle.clone()))
+            }
+        };
+
+        self.mcp_servers.release(&self.handle).await;
+        result
+    }
+
+    async fn run_turns(&mut self) -> Result<ExitCode, KepokiError> {
+        let mut tools_changed = self.mcp_servers.subscribe_tools_changed();
+        let mut adjustments = RequestAdjustments::default();
+        let mut failed_attempts = 0;
+        let mut continuing = false;
+        // Whether the last turn was cancelled, the agent waits for a command before responding.
+        let mut idle = false;
+        // When the turn in progress started, over every response and tool call it takes.
+        let mut turn_started = None;
+        self.mcp_servers
+            .set_roots(&self.handle, self.state.definition.roots())
+            .await;
+        if let Some(config) = &self.state.definition.context_files
+            && let Some(dir) = self.state.definition.roots().into_iter().next()
+        {
+            self.context_files = load_context_files(dir, config);
+        }
         loop {
             // Handle incoming commands
             loop {
                 match self.command_receiver.try_recv() {
-                    Ok(command) => {
-                        if let Some(exit_code) = self.handle_command(command)? {
+                    Ok((command, cancellation)) => {
+                        if cancellation.is_some() {
+                            self.turn_cancellation = cancellation;
+                        }
+                        idle &= !matches!(
+                            command,
+                            AgentCommand::Unpause
+                                | AgentCommand::UserMessage(_)
+                                | AgentCommand::UserMessageWithOverrides(..)
+                                | AgentCommand::UserContent(_)
+                                | AgentCommand::RewindTo(_)
+                        );
+                        if let Some(exit_code) = self.handle_command(command).await? {
                             return Ok(exit_code);
                         }
                     }
                     Err(TryRecvError::Empty) => {
-                        if let Some(message) = self.state.messages.back() {
-                            if message.role == Role::User && !self.state.paused {
-                                break;
+                        if self.cancellation.is_cancelled() {
+                            return Err(KepokiError::AgentManuallyTerminated(self.handle.clone()));
+                        }
+
+                        if let Some(message) = self.state.messages.back()
+                            && message.role == Role::User
+                            && !self.state.paused
+                            && !idle
+                        {
+                            break;
+                        }
+
+                        if !self.state.paused
+                            && let Some(replay) = &mut self.replay
+                        {
+                            match replay.next_user_message() {
+                                Some(message) => {
+                                    self.state.messages.push_back(message);
+                                    continue;
+                                }
+                                None => return Ok(ExitCode::SUCCESS),
                             }
                         }
 
-                        std::thread::sleep(std::time::Duration::from_millis(100));
+                        self.mcp_servers.shutdown_idle().await;
+                        self.emit_tools_changed(&mut tools_changed)?;
+                        tokio::time::sleep(Duration::from_millis(100)).await;
                     }
                     Err(TryRecvError::Disconnected) => {
-                        tracing::info!("Agent channel disconnected, shutting down thread.");
+                        tracing::info!("Agent channel disconnected, shutting down.");
                         return Ok(ExitCode::FAILURE);
                     }
                 }
             }
 
             // Continue conversation
-            let mut stream = self.backend.messages(MessagesRequest {
-                model: self.model.clone(),
-                messages: self.state.messages.clone().into(),
-                max_tokens: 8192,
-                system: Some(Cow::Borrowed(&self.state.definition.prompt)),
-                temperature: Some(self.state.definition.temperature),
-                tool_choice: None,
-                tools: None,
-            })?;
-
-            let mut message = None;
-            let mut blocks = HashMap::new();
-            while let Some(event) = stream.recv()? {
-                self.event_emitter
-                    .send(AgentEvent::from(event.clone()))
-                    .map_err(|_| KepokiError::EventReceiverClosed(self.handle.clone()))?;
-
-                match event {
-                    MessagesResponseEvent::Ping => (),
-                    MessagesResponseEvent::MessageStart(start) => {
-                        if message.is_some() {
-                            return Err(KepokiError::UnexpectedEvent(self.handle.clone()));
-                        }
-
-                        message = Some(start);
-                    }
-                    MessagesResponseEvent::MessageDelta(delta) => {
-                        let message = message
-                            .as_mut()
-                            .ok_or_else(|| KepokiError::UnexpectedEvent(self.handle.clone()))?;
-
-                        if let Some(stop_reason) = delta.stop_reason {
-                            message.stop_reason = Some(stop_reason);
-                        }
-
-                        if let Some(stop_sequence) = delta.stop_sequence {
-                            message.stop_sequence = Some(stop_sequence);
-                        }
-
-                        if let Some(usage) = delta.usage {
-                            message.usage = Some(usage);
-                        }
+            turn_started.get_or_insert_with(Instant::now);
+            self.list_mcp_tools().await;
+            self.emit_tools_changed(&mut tools_changed)?;
+            let partial = self.state.incomplete.clone().filter(|_| continuing);
+            let (message, processed) = match self.respond(&adjustments, partial).await {
+                Ok(response) => {
+                    failed_attempts = 0;
+                    adjustments = RequestAdjustments::default();
+                    continuing = false;
+                    self.state.incomplete = None;
+                    response
+                }
+                Err(KepokiError::Cancelled) => {
+                    tracing::info!("Agent {} turn cancelled", self.handle);
+                    failed_attempts = 0;
+                    adjustments = RequestAdjustments::default();
+                    continuing = false;
+                    idle = true;
+                    turn_started = None;
+                    self.state.incomplete = None;
+                    self.turn_overrides = TurnOverrides::default();
+                    self.turn_cancellation = None;
+                    self.event_emitter
+                        .send(AgentEvent::TurnCancelled)
+                        .map_err(|_| KepokiError::EventReceiverClosed(self.handle.clone()))?;
+                    continue;
+                }
+                Err(err) => {
+                    failed_attempts += 1;
+                    if let KepokiError::StreamInterrupted { partial, .. } = &err {
+                        self.state.incomplete = Some(partial.as_ref().clone());
                     }
-                    MessagesResponseEvent::MessageStop => {
-                        if message.is_none() {
-                            return Err(KepokiError::UnexpectedEvent(self.handle.clone()));
-                        }
+                    if failed_attempts == 1 && matches!(err, KepokiError::QuotaExceeded { .. }) {
+                        self.notify(NotificationKind::QuotaExceeded {
+                            error: err.to_string(),
+                        });
                     }
-                    MessagesResponseEvent::ContentBlockStart(block) => {
-                        if blocks.insert(block.index, block.content_block).is_some() {
-                            return Err(KepokiError::UnexpectedEvent(self.handle.clone()));
-                        }
+                    let recovery = self.recover(err, failed_attempts).await?;
+                    continuing = matches!(recovery, ErrorRecovery::Continue);
+                    if matches!(recovery, ErrorRecovery::Retry | ErrorRecovery::RetryWith(_)) {
+                        self.state.incomplete = None;
                     }
-                    MessagesResponseEvent::ContentBlockDelta(delta) => match delta {
-                        ContentBlockDelta::Text { index, text } => {
-                            let Some(block) = blocks.get_mut(&index) else {
-                                return Err(KepokiError::UnexpectedEvent(self.handle.clone()));
-                            };
-
-                            match block {
-                                ContentBlock::Text { text: block_text } => {
-                                    block_text.push_str(&text);
-                                }
-                                _ => {
-                                    return Err(KepokiError::UnexpectedEvent(self.handle.clone()));
-                                }
-                            }
+                    match recovery {
+                        ErrorRecovery::Retry | ErrorRecovery::Continue => (),
+                        ErrorRecovery::RetryWith(retry_adjustments) => {
+                            adjustments = retry_adjustments;
                         }
-                        ContentBlockDelta::InputJson {
-                            index,
-                            partial_json,
-                        } => {
-                            let Some(block) = blocks.get_mut(&index) else {
-                                return Err(KepokiError::UnexpectedEvent(self.handle.clone()));
-                            };
-
-                            match block {
-                                ContentBlock::ToolUse { input, .. } => {
-                                    input.push_str(&partial_json);
-                                }
-                                _ => {
-                                    return Err(KepokiError::UnexpectedEvent(self.handle.clone()));
-                                }
-                            }
-                        }
-                    },
-                    MessagesResponseEvent::ContentBlockStop(content_block_stop) => {
-                        if blocks.contains_key(&content_block_stop.index) {
-                            blocks.remove(&content_block_stop.index);
+                        ErrorRecovery::Pause => {
+                            failed_attempts = 0;
+                            turn_started = None;
+                            self.state.paused = true;
                         }
+                        ErrorRecovery::Terminate => unreachable!("Returned as an error by recover"),
                     }
+                    continue;
                 }
+            };
+
+            self.state.messages.push_back(InputMessage {
+                id: match message.id.is_empty() {
+                    true => new_message_id(),
+                    false => message.id.clone(),
+                },
+                role: Role::Assistant,
+                content: message.content.clone(),
+            });
+            let stop_reason = message.stop_reason.clone();
+            let usage = message.usage.clone();
+            // Tool calls of responses that stopped for another reason, such as the token
+            // limit, are never answered, so they aren't run.
+            let tool_results = match stop_reason {
+                Some(StopReason::ToolUse) => self.run_tools(&message.content).await,
+                _ => Vec::new(),
+            };
+            let divergence = self
+                .replay
+                .as_mut()
+                .and_then(|replay| replay.compare(&message.content));
+            self.event_emitter
+                .send(AgentEvent::Message(processed))
+                .map_err(|_| KepokiError::EventReceiverClosed(self.handle.clone()))?;
+            if let Some(divergence) = divergence {
+                self.event_emitter
+                    .send(AgentEvent::ReplayDiverged(divergence))
+                    .map_err(|_| KepokiError::EventReceiverClosed(self.handle.clone()))?;
             }
 
-            match message {
-                Some(mut msg) => {
-                    msg.content = blocks.into_values().collect();
-                    self.state.messages.push_back(InputMessage {
-                        role: Role::Assistant,
-                        content: msg.content.clone(),
+            if !tool_results.is_empty() {
+                self.state.messages.push_back(InputMessage {
+                    id: new_message_id(),
+                    role: Role::User,
+                    content: tool_results,
+                });
+            } else {
+                self.turn_overrides = TurnOverrides::default();
+                self.turn_cancellation = None;
+                if let Some(started) = turn_started.take()
+                    && self.notifications.as_ref().is_some_and(|notifications| {
+                        started.elapsed() >= notifications.min_turn_duration
+                    })
+                {
+                    self.notify(NotificationKind::TurnFinished {
+                        duration: started.elapsed(),
                     });
+                }
+            }
+            self.record_turn(usage);
+        }
+    }
+
+    /// Sends a notification about the agent if the runtime has a notifier.
+    fn notify(&self, kind: NotificationKind) {
+        if let Some(notifications) = &self.notifications {
+            notifications.send(Notification {
+                agent: self.handle.clone(),
+                kind,
+            });
+        }
+    }
+
+    /// Records the messages added since the last turn and the artifact versions in the turn log.
+    fn record_turn(&mut self, usage: Option<Usage>) {
+        let messages = self
+            .state
+            .messages
+            .range(self.recorded_messages.min(self.state.messages.len())..)
+            .cloned()
+            .collect();
+        self.recorded_messages = self.state.messages.len();
+        let artifacts = self
+            .artifacts
+            .list(&self.handle)
+            .into_iter()
+            .map(|artifact| (artifact.name, artifact.version))
+            .collect();
+        self.turn_log.record(
+            &self.handle,
+            TurnRecord {
+                at: SystemTime::now(),
+                messages,
+                artifacts,
+                usage,
+            },
+        );
+    }
+
+    /// Requests a response, runs the response hooks on it, and applies the output processors,
+    /// returning the response before and after processing.
+    async fn respond(
+        &mut self,
+        adjustments: &RequestAdjustments,
+        partial: Option<Message>,
+    ) -> Result<(Message, Message), KepokiError> {
+        let message = self.request_message(adjustments, partial).await?;
+        if let Some(reason) = self
+            .run_hooks(HookEvent::Response { message: &message })
+            .await?
+        {
+            return Err(KepokiError::HookBlocked(reason));
+        }
+        let processed = self.process_output(message.clone()).await?;
+        Ok((message, processed))
+    }
+
+    /// Sends the conversation to the backend and assembles the streamed response, continuing
+    /// `partial` if given.
+    async fn request_message(
+        &mut self,
+        adjustments: &RequestAdjustments,
+        partial: Option<Message>,
+    ) -> Result<Message, KepokiError> {
+        self.cancellation().check()?;
+        let limits = self.backend.attachment_limits();
+        for message in &self.state.messages {
+            limits.check(&message.content)?;
+        }
+        self.provided_context = self.provide_context().await;
+        self.provided_context
+            .retain(|block| limits.check(std::slice::from_ref(block)).is_ok());
+        self.quotas.check(&self.quota_scopes())?;
+        let queued = Heartbeat::start(
+            &self.event_emitter,
+            TurnPhase::Queued,
+            self.heartbeat_interval,
+        );
+        let _permit = self.scheduler.acquire(self.state.definition.priority).await;
+        drop(queued);
+        let _busy = self.progress.busy();
+        let _heartbeat = Heartbeat::start(
+            &self.event_emitter,
+            TurnPhase::Generating,
+            self.heartbeat_interval,
+        );
+
+        if let Some(partial) = partial {
+            return self.continue_message(adjustments, partial).await;
+        }
+
+        // Turns with a model override go to that model right away.
+        if let Some(drafting) = self.drafting.clone()
+            && self.turn_overrides.model.is_none()
+            && let Some(draft) = self.draft(adjustments, &drafting).await?
+        {
+            return Ok(draft);
+        }
+
+        match self.best_of.clone() {
+            Some(best_of) if best_of.n > 1 => self.sample_best_of(adjustments, &best_of).await,
+            _ => {
+                let model = self.turn_model();
+                let price = self.model_price(&model);
+                let request = self.request_for(adjustments, model).await;
+                let cancellation = self.cancellation();
+                let stream = cancellation.run(self.backend.messages(request)).await??;
+                let message = receive_message(
+                    stream,
+                    &self.handle,
+                    &self.progress,
+                    Some(&self.event_emitter),
+                    &cancellation,
+                )
+                .await;
+                let usage = message
+                    .as_ref()
+                    .ok()
+                    .and_then(|message| message.usage.as_ref());
+                self.record_quota_usage(usage, price)?;
+                message
+            }
+        }
+    }
+
+    /// Asks the model to continue `partial`, the response of a stream that failed midway, and
+    /// joins the continuation to it.
+    async fn continue_message(
+        &mut self,
+        adjustments: &RequestAdjustments,
+        partial: Message,
+    ) -> Result<Message, KepokiError> {
+        let model = self.turn_model();
+        let price = self.model_price(&model);
+        let mut request = self.request_for(adjustments, model).await;
+        let mut content = partial.content.clone();
+        // Providers reject prefilled responses ending in whitespace.
+        if let Some(ContentBlock::Text { text }) = content.last_mut() {
+            text.truncate(text.trim_end().len());
+        }
+        request.messages.push(InputMessage {
+            id: String::new(),
+            role: Role::Assistant,
+            content,
+        });
+
+        let cancellation = self.cancellation();
+        let stream = cancellation.run(self.backend.messages(request)).await??;
+        let message = receive_message(
+            stream,
+            &self.handle,
+            &self.progress,
+            Some(&self.event_emitter),
+            &cancellation,
+        )
+        .await;
+        let usage = message
+            .as_ref()
+            .ok()
+            .and_then(|message| message.usage.as_ref());
+        self.record_quota_usage(usage, price)?;
+        match message {
+            Ok(continuation) => Ok(join_messages(partial, continuation)),
+            Err(KepokiError::StreamInterrupted {
+                partial: rest,
+                error,
+            }) => Err(KepokiError::StreamInterrupted {
+                partial: Box::new(join_messages(partial, *rest)),
+                error,
+            }),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The tokens cancelling the agent and its turn in progress.
+    fn cancellation(&self) -> Cancellation {
+        Cancellation {
+            handle: self.handle.clone(),
+            agent: self.cancellation.clone(),
+            turn: self.turn_cancellation.clone(),
+        }
+    }
+
+    /// The scopes the requests of the agent count against.
+    fn quota_scopes(&self) -> Vec<QuotaScope> {
+        self.tenant
+            .clone()
+            .map(QuotaScope::Tenant)
+            .into_iter()
+            .chain(self.credential.clone().map(QuotaScope::Credential))
+            .collect()
+    }
+
+    /// Counts a request to a model priced at `price` against the quotas of the agent, warning
+    /// about crossed thresholds.
+    fn record_quota_usage(
+        &self,
+        usage: Option<&Usage>,
+        price: Option<TokenPrice>,
+    ) -> Result<(), KepokiError> {
+        for warning in self.quotas.record(&self.quota_scopes(), usage, price) {
+            self.event_emitter
+                .send(AgentEvent::QuotaWarning(warning))
+                .map_err(|_| KepokiError::EventReceiverClosed(self.handle.clone()))?;
+        }
+        Ok(())
+    }
+
+    /// Runs the context providers the agent enables, each truncated to its budget.
+    async fn provide_context(&self) -> Vec<ContentBlock> {
+        let mut context = Vec::new();
+        for setting in &self.state.definition.context_providers {
+            let Some(provider) = self.context_providers.get(&setting.name) else {
+                tracing::warn!(
+                    "Agent {} has unknown context provider {}",
+                    self.handle,
+                    setting.name
+                );
+                continue;
+            };
+
+            let provide = tokio::time::timeout(self.tool_timeout, provider.provide(&self.state));
+            match provide.await {
+                Ok(blocks) => {
+                    context.extend(within_budget(&setting.name, blocks, setting.max_tokens))
+                }
+                Err(_) => tracing::warn!(
+                    "Context provider {} timed out for agent {}",
+                    setting.name,
+                    self.handle
+                ),
+            }
+        }
+
+        context
+    }
+
+    /// Applies the output processors of the agent to a response.
+    async fn process_output(&self, mut message: Message) -> Result<Message, KepokiError> {
+        let context = ToolContext {
+            agent: self.handle.clone(),
+            event_emitter: self.event_emitter.clone(),
+            event_bus: self.event_bus.clone(),
+            locale: self.state.definition.locale.clone(),
+        };
+        for processing in &self.state.definition.output_processors {
+            let Some(processor) = self.output_processors.resolve(processing, &self.artifacts)
+            else {
+                tracing::warn!(
+                    "Agent {} has unknown output processor {processing:?}",
+                    self.handle
+                );
+                continue;
+            };
+
+            message = processor
+                .process(&context, message)
+                .await
+                .map_err(|error| KepokiError::OutputProcessingFailed {
+                    processor: processor.name().to_string(),
+                    error,
+                })?;
+        }
+
+        Ok(message)
+    }
+
+    /// Has the draft model respond, returning the draft if the judge accepts it.
+    ///
+    /// Drafts are not streamed as events, an accepted draft is emitted as a message once it is
+    /// complete. Failed drafts are escalated rather than failing the turn.
+    async fn draft(
+        &mut self,
+        adjustments: &RequestAdjustments,
+        drafting: &Drafting,
+    ) -> Result<Option<Message>, KepokiError> {
+        let Some(model) = self.backend.parse_model(&drafting.model) else {
+            tracing::warn!(
+                "Agent {} ignoring unknown draft model {}",
+                self.handle,
+                drafting.model
+            );
+            return Ok(None);
+        };
+
+        let price = self.model_price(&model);
+        let request = self.request_for(adjustments, model).await;
+        let cancellation = self.cancellation();
+        let draft = match cancellation
+            .run(self.backend.messages(request))
+            .await
+            .and_then(|stream| stream)
+        {
+            Ok(stream) => {
+                receive_message(stream, &self.handle, &self.progress, None, &cancellation).await
+            }
+            Err(err) => Err(err),
+        };
+        let draft_usage = draft.as_ref().ok().and_then(|draft| draft.usage.clone());
+        self.record_quota_usage(draft_usage.as_ref(), price)?;
+
+        let draft = match draft {
+            Ok(draft) if drafting.judge.accept(&self.state.messages, &draft) => Some(draft),
+            Ok(_) => None,
+            Err(err @ (KepokiError::Cancelled | KepokiError::AgentManuallyTerminated(_))) => {
+                return Err(err);
+            }
+            Err(err) => {
+                tracing::warn!("Agent {} failed to draft response: {err}", self.handle);
+                None
+            }
+        };
+        self.event_emitter
+            .send(AgentEvent::Drafted {
+                path: match draft {
+                    Some(_) => DraftPath::Accepted,
+                    None => DraftPath::Escalated,
+                },
+                draft_usage,
+            })
+            .map_err(|_| KepokiError::EventReceiverClosed(self.handle.clone()))?;
+        Ok(draft)
+    }
+
+    /// Samples `best_of.n` responses concurrently and returns the one picked by the selector.
+    ///
+    /// Candidates are not streamed as events, only the selected response is emitted as a
+    /// message once all of them are complete.
+    async fn sample_best_of(
+        &mut self,
+        adjustments: &RequestAdjustments,
+        best_of: &BestOf,
+    ) -> Result<Message, KepokiError> {
+        let request = self.request(adjustments).await;
+        let cancellation = &self.cancellation();
+        let streams = cancellation
+            .run(future::try_join_all(
+                (0..best_of.n).map(|_| self.backend.messages(request.clone())),
+            ))
+            .await??;
+
+        let handle = &self.handle;
+        let results = future::join_all(
+            streams
+                .into_iter()
+                .map(|stream| receive_message(stream, handle, &self.progress, None, cancellation)),
+        )
+        .await;
+
+        let price = self.model_price(&self.turn_model());
+        for result in &results {
+            let usage = result
+                .as_ref()
+                .ok()
+                .and_then(|message| message.usage.as_ref());
+            self.record_quota_usage(usage, price)?;
+        }
+
+        let mut error = None;
+        let candidates = results
+            .into_iter()
+            .filter_map(|result| result.map_err(|err| error = Some(err)).ok())
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Err(error.unwrap_or_else(|| KepokiError::NoMessageReceived(handle.clone())));
+        }
+
+        let selected = best_of
+            .selector
+            .select(&self.state.messages, &candidates)
+            .min(candidates.len() - 1);
+        self.event_emitter
+            .send(AgentEvent::CandidatesSampled {
+                selected,
+                usage: candidates
+                    .iter()
+                    .map(|candidate| candidate.usage.clone())
+                    .collect(),
+            })
+            .map_err(|_| KepokiError::EventReceiverClosed(self.handle.clone()))?;
+
+        Ok(candidates.into_iter().nth(selected).unwrap())
+    }
+
+    /// The model of the turn in progress.
+    fn turn_model(&self) -> B::Model {
+        match &self.turn_overrides.model {
+            Some(name) => self.backend.parse_model(name).unwrap_or_else(|| {
+                tracing::warn!("Agent {} ignoring unknown model {name}", self.handle);
+                self.model.clone()
+            }),
+            None => self.model.clone(),
+        }
+    }
+
+    /// What the model registry knows about `model`.
+    fn model_info(&self, model: &B::Model) -> Option<&ModelInfo> {
+        self.models.get(&self.backend.model_name(model)?)
+    }
+
+    fn model_price(&self, model: &B::Model) -> Option<TokenPrice> {
+        self.model_info(model)?.price
+    }
+
+    /// The request continuing the conversation.
+    async fn request(&self, adjustments: &RequestAdjustments) -> MessagesRequest<'_, B> {
+        self.request_for(adjustments, self.turn_model()).await
+    }
+
+    /// The request continuing the conversation with `model`.
+    async fn request_for(
+        &self,
+        adjustments: &RequestAdjustments,
+        model: B::Model,
+    ) -> MessagesRequest<'_, B> {
+        let max_tokens = adjustments
+            .max_tokens
+            .or(self.turn_overrides.max_tokens)
+            .unwrap_or_else(|| match self.model_info(&model) {
+                Some(info) => info.max_output_tokens.min(DEFAULT_MAX_TOKENS),
+                None => DEFAULT_MAX_TOKENS,
+            });
+
+        let mut messages = Vec::from(self.state.messages.clone());
+        if let Some(message) = messages.last_mut()
+            && message.role == Role::User
+        {
+            message
+                .content
+                .extend(self.provided_context.iter().cloned());
+        }
+
+        MessagesRequest {
+            model,
+            messages,
+            max_tokens,
+            system: Some(self.system_prompt()),
+            temperature: Some(
+                adjustments
+                    .temperature
+                    .or(self.turn_overrides.temperature)
+                    .unwrap_or(self.state.definition.temperature),
+            ),
+            stop_sequences: self.stop_sequences(),
+            tool_choice: None,
+            tools: self.tool_definitions().await,
+            reasoning: self.state.definition.reasoning,
+            user_id: self
+                .turn_overrides
+                .user_id
+                .as_ref()
+                .or(self.user_id.as_ref())
+                .map(|user_id| Cow::Owned(user_id.clone())),
+            extensions: self.state.definition.extensions.clone(),
+        }
+    }
+
+    /// Asks the error handler of the agent how to recover from a failed turn.
+    ///
+    /// Returns the error if the agent should terminate.
+    async fn recover(
+        &mut self,
+        error: KepokiError,
+        attempt: u32,
+    ) -> Result<ErrorRecovery, KepokiError> {
+        if matches!(
+            error,
+            KepokiError::EventReceiverClosed(_) | KepokiError::AgentManuallyTerminated(_)
+        ) {
+            return Err(error);
+        }
+
+        let Some(handler) = self.error_handlers.get(&self.handle) else {
+            return Err(error);
+        };
+
+        let failure = TurnFailure {
+            agent: self.handle.clone(),
+            error,
+            attempt,
+        };
+        let recovery = handler.handle(&failure).await;
+        tracing::info!(
+            "Agent {} recovering from failed turn: {recovery:?}",
+            self.handle
+        );
+        match recovery {
+            ErrorRecovery::Terminate => Err(failure.error),
+            ErrorRecovery::Pause => {
+                self.notify(NotificationKind::Paused {
+                    error: failure.error.to_string(),
+                });
+                self.event_emitter
+                    .send(AgentEvent::TurnFailed {
+                        error: failure.error.to_string(),
+                        partial: self.state.incomplete.clone(),
+                    })
+                    .map_err(|_| KepokiError::EventReceiverClosed(self.handle.clone()))?;
+                Ok(recovery)
+            }
+            recovery => Ok(recovery),
+        }
+    }
+
+    /// Lists the tools of every MCP server the agent uses tools from, starting servers whose
+    /// tools haven't been listed yet.
+    async fn list_mcp_tools(&mut self) {
+        for tool in &self.state.definition.tools {
+            if tool.is_builtin() {
+                continue;
+            }
+
+            let Some(server) = self.state.definition.mcp_servers.get(tool.namespace()) else {
+                tracing::warn!(
+                    "Agent {} uses tool of unknown server: {tool:?}",
+                    self.handle
+                );
+                continue;
+            };
+
+            if let Err(err) = self.mcp_servers.list_tools(&self.handle, server).await {
+                tracing::error!("Agent {} failed to list MCP tools: {err}", self.handle);
+            }
+        }
+    }
+
+    /// Emits [`AgentEvent::ToolsChanged`] for each of the agent's servers whose tools changed.
+    fn emit_tools_changed(
+        &self,
+        receiver: &mut broadcast::Receiver<McpServer>,
+    ) -> Result<(), KepokiError> {
+        loop {
+            let server = match receiver.try_recv() {
+                Ok(server) => server,
+                Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(_) => return Ok(()),
+            };
+
+            for (name, definition) in &self.state.definition.mcp_servers {
+                if *definition == server {
                     self.event_emitter
-                        .send(AgentEvent::Message(msg))
+                        .send(AgentEvent::ToolsChanged {
+                            server: name.clone(),
+                        })
                         .map_err(|_| KepokiError::EventReceiverClosed(self.handle.clone()))?;
                 }
-                None => return Err(KepokiError::NoMessageReceived(self.handle.clone())),
             }
         }
     }
 
-    fn handle_command(&mut self, command: AgentCommand) -> Result<Option<ExitCode>, KepokiError> {
+    /// The prompt of the agent followed by its locale, its context files, and the instructions
+    /// of the MCP servers it uses, if enabled.
+    fn system_prompt(&self) -> Cow<'_, str> {
+        let definition = &self.state.definition;
+        let mut prompt = Cow::Borrowed(definition.prompt.as_str());
+        if let Some(locale) = &definition.locale {
+            prompt.to_mut().push_str(&format!(
+                "\n\n<user_locale>\nThe user's locale is {} and their time zone is {}. Use the conventions of this locale for dates, times, numbers, and currencies, and give times in this time zone.\n</user_locale>",
+                locale.language, locale.timezone
+            ));
+        }
+
+        for file in &self.context_files {
+            let truncated = match file.truncated {
+                true => "\n[truncated]",
+                false => "",
+            };
+            prompt.to_mut().push_str(&format!(
+                "\n\n<project_instructions path=\"{}\">\n{}{truncated}\n</project_instructions>",
+                file.path.display(),
+                file.content.trim()
+            ));
+        }
+
+        if !definition.mcp_instructions {
+            return prompt;
+        }
+
+        let mut servers = definition.mcp_servers.iter().collect::<Vec<_>>();
+        servers.sort_by_key(|(name, _)| *name);
+        for (name, server) in servers {
+            if let Some(instructions) = self.mcp_servers.instructions(server) {
+                let prompt = prompt.to_mut();
+                prompt.push_str(&format!(
+                    "\n\n<mcp_server_instructions server=\"{name}\">\n{}\n</mcp_server_instructions>",
+                    instructions.trim()
+                ));
+            }
+        }
+
+        prompt
+    }
+
+    /// The stop sequences of the current turn, if any.
+    fn stop_sequences(&self) -> Option<Vec<Cow<'static, str>>> {
+        let stop_sequences = self
+            .turn_overrides
+            .stop_sequences
+            .as_ref()
+            .unwrap_or(&self.state.definition.stop_sequences);
+
+        (!stop_sequences.is_empty()).then(|| {
+            stop_sequences
+                .iter()
+                .map(|stop_sequence| Cow::Owned(stop_sequence.clone()))
+                .collect()
+        })
+    }
+
+    /// The tools enabled in the agent definition, with the descriptions it overrides, limited
+    /// to the most relevant ones if it has a tool selection.
+    async fn tool_definitions(&self) -> Option<Vec<Tool<'static>>> {
+        let tools = self
+            .state
+            .definition
+            .tools
+            .iter()
+            .filter_map(|tool| {
+                let mut definition = match tool.is_builtin() {
+                    true => self.tools.get(tool.name())?.definition(),
+                    false => {
+                        let server = self.state.definition.mcp_servers.get(tool.namespace())?;
+                        let definition = self.mcp_servers.definition(server, tool.name())?;
+                        Tool {
+                            name: tool.wire_name().into(),
+                            ..definition
+                        }
+                    }
+                };
+
+                if let Some(description) = self.state.definition.tool_descriptions.get(tool) {
+                    definition.description = Some(description.clone().into());
+                }
+
+                Some((tool, definition))
+            })
+            .collect::<Vec<_>>();
+        let tools = match &self.state.definition.tool_selection {
+            Some(selection) => {
+                self.tool_selector
+                    .select(&self.latest_user_text(), tools, selection)
+                    .await
+            }
+            None => tools
+                .into_iter()
+                .map(|(_, definition)| definition)
+                .collect(),
+        };
+
+        (!tools.is_empty()).then_some(tools)
+    }
+
+    /// The text of the latest user message that isn't a tool result, what tools are selected
+    /// for.
+    fn latest_user_text(&self) -> String {
+        self.state
+            .messages
+            .iter()
+            .rev()
+            .filter(|message| message.role == Role::User)
+            .find_map(|message| {
+                let text = message
+                    .content
+                    .iter()
+                    .filter_map(|block| match block {
+                        ContentBlock::Text { text } => Some(text.as_str()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                (!text.is_empty()).then(|| text.join("\n"))
+            })
+            .unwrap_or_default()
+    }
+
+    /// Executes every tool use in `content` and returns the matching tool results.
+    async fn run_tools(&mut self, content: &[ContentBlock]) -> Vec<ContentBlock> {
+        let mut results = Vec::new();
+        for block in content {
+            let ContentBlock::ToolUse { id, input, name } = block else {
+                continue;
+            };
+
+            let start = Instant::now();
+            let dry_run = self.dry_run && self.is_side_effecting(name);
+            let blocked = match self
+                .run_hooks(HookEvent::BeforeToolUse { tool: name, input })
+                .await
+            {
+                Ok(Some(reason)) => Some(format!("Blocked: {reason}")),
+                _ if self.replay.is_none() => self
+                    .authorize(name, input)
+                    .await
+                    .map(|reason| format!("Denied by policy: {reason}")),
+                _ => None,
+            };
+            let mut output = match (blocked, &mut self.replay, dry_run) {
+                (Some(reason), _, _) => ToolOutput::error(reason),
+                (None, Some(replay), _) => replay.tool_output(name, input),
+                (None, None, true) => self.dry_run_tool(id, name, input),
+                (None, None, false) => {
+                    let _heartbeat = Heartbeat::start(
+                        &self.event_emitter,
+                        TurnPhase::RunningTool { tool: name.clone() },
+                        self.heartbeat_interval,
+                    );
+                    let _busy = self.progress.busy();
+                    self.run_tool(name, input).await
+                }
+            };
+            let event = HookEvent::AfterToolUse {
+                tool: name,
+                input,
+                output: &output,
+            };
+            if let Ok(Some(reason)) = self.run_hooks(event).await {
+                output = ToolOutput::error(format!("Blocked: {reason}"));
+            }
+
+            if self.replay.is_none()
+                && let Some(tool) = self
+                    .state
+                    .definition
+                    .tools
+                    .iter()
+                    .find(|tool| tool.wire_name() == *name)
+            {
+                self.tool_stats
+                    .record(tool, start.elapsed(), &output, dry_run);
+            }
+
+            results.push(ContentBlock::ToolResult {
+                tool_use_id: id.clone(),
+                content: Some(output.content),
+                is_error: Some(output.is_error),
+            });
+        }
+
+        results
+    }
+
+    async fn run_tool(&mut self, name: &str, input: &str) -> ToolOutput {
+        let Some(tool) = self
+            .state
+            .definition
+            .tools
+            .iter()
+            .find(|tool| tool.wire_name() == name)
+            .cloned()
+        else {
+            tracing::warn!("Agent {} requested unknown tool: {name}", self.handle);
+            return ToolOutput::error(format!("Unknown tool: {name}"));
+        };
+
+        let input = match input.trim() {
+            "" => serde_json::Value::Object(Default::default()),
+            input => match serde_json::from_str(input) {
+                Ok(input) => input,
+                Err(err) => return ToolOutput::error(format!("Invalid tool input: {err}")),
+            },
+        };
+
+        let cancellation = self.cancellation();
+        if cancellation.check().is_err() {
+            return ToolOutput::error(format!("Tool `{name}` was cancelled"));
+        }
+
+        let timeout = self.tool_timeout(&tool);
+        tracing::info!("Agent {} calling tool {name}", self.handle);
+        if tool.is_builtin() {
+            let Some(builtin) = self.tools.get(tool.name()) else {
+                return ToolOutput::error(format!("Unknown tool: {name}"));
+            };
+
+            let context = ToolContext {
+                agent: self.handle.clone(),
+                event_emitter: self.event_emitter.clone(),
+                event_bus: self.event_bus.clone(),
+                locale: self.state.definition.locale.clone(),
+            };
+            return match cancellation
+                .run(tokio::time::timeout(timeout, builtin.call(context, input)))
+                .await
+            {
+                Ok(Ok(output)) => output,
+                Ok(Err(_)) => {
+                    tracing::warn!("Agent {} tool {name} timed out", self.handle);
+                    ToolOutput::error(format!(
+                        "Tool `{name}` timed out after {} seconds and was cancelled",
+                        timeout.as_secs_f32()
+                    ))
+                }
+                Err(_) => {
+                    tracing::info!("Agent {} tool {name} cancelled", self.handle);
+                    ToolOutput::error(format!("Tool `{name}` was cancelled"))
+                }
+            };
+        }
+
+        let Some(server) = self.state.definition.mcp_servers.get(tool.namespace()) else {
+            return ToolOutput::error(format!("Unknown MCP server: {}", tool.namespace()));
+        };
+
+        let serde_json::Value::Object(arguments) = input else {
+            return ToolOutput::error("Tool input must be a JSON object");
+        };
+
+        cancellation
+            .run(self.mcp_servers.call_tool(
+                &self.handle,
+                server,
+                tool.name(),
+                Some(arguments),
+                timeout,
+            ))
+            .await
+            .and_then(|output| output)
+            .unwrap_or_else(|err| {
+                tracing::error!("Agent {} tool {name} failed: {err}", self.handle);
+                ToolOutput::error(format!("Tool `{name}` failed: {err}"))
+            })
+    }
+
+    /// Whether the tool advertised as `name` may change anything outside of the conversation.
+    ///
+    /// MCP tools are assumed to have side effects unless the server hints they are read only.
+    fn is_side_effecting(&self, name: &str) -> bool {
+        let Some(tool) = self
+            .state
+            .definition
+            .tools
+            .iter()
+            .find(|tool| tool.wire_name() == name)
+        else {
+            return false;
+        };
+
+        if tool.is_builtin() {
+            return self
+                .tools
+                .get(tool.name())
+                .is_some_and(|tool| tool.side_effecting());
+        }
+
+        let read_only = self
+            .state
+            .definition
+            .mcp_servers
+            .get(tool.namespace())
+            .is_some_and(|server| self.mcp_servers.is_read_only(server, tool.name()));
+
+        !read_only
+    }
+
+    fn dry_run_tool(&self, id: &str, name: &str, input: &str) -> ToolOutput {
+        tracing::info!("Agent {} dry run of tool {name}", self.handle);
+        if self
+            .event_emitter
+            .send(AgentEvent::DryRunToolUse {
+                id: id.to_string(),
+                name: name.to_string(),
+                input: input.to_string(),
+            })
+            .is_err()
+        {
+            tracing::debug!("Event receiver closed for agent {}", self.handle);
+        }
+
+        ToolOutput::text(format!(
+            "Dry run: `{name}` was not executed. Assume it would have succeeded with the given input."
+        ))
+    }
+
+    /// The timeout for `tool`, preferring the agent definition over the runtime default.
+    fn tool_timeout(&self, tool: &ToolName) -> Duration {
+        let timeouts = &self.state.definition.tool_timeouts;
+        timeouts
+            .tools
+            .get(tool)
+            .or(timeouts.default.as_ref())
+            .map(|secs| Duration::from_secs(*secs))
+            .unwrap_or(self.tool_timeout)
+    }
+
+    fn message_position(&self, id: &str) -> Option<usize> {
+        let position = self
+            .state
+            .messages
+            .iter()
+            .position(|message| message.id == id);
+        if position.is_none() {
+            tracing::warn!("Agent {} has no message {id}", self.handle);
+        }
+
+        position
+    }
+
+    fn record_history_edit(&mut self, change: HistoryChange) -> Result<(), KepokiError> {
+        let edit = HistoryEdit {
+            at: SystemTime::now(),
+            change,
+        };
+        self.state.history_edits.push(edit.clone());
+        self.event_emitter
+            .send(AgentEvent::HistoryEdited(edit))
+            .map_err(|_| KepokiError::EventReceiverClosed(self.handle.clone()))
+    }
+
+    /// Asks the policy engine whether a tool call may be executed, returning the reason if
+    /// it was denied.
+    ///
+    /// Input that isn't valid JSON is denied without asking, policies can't judge it.
+    async fn authorize(&self, tool: &str, input: &str) -> Option<String> {
+        let policy = self.policy.as_ref()?;
+        let input = match input.trim() {
+            "" => Ok(serde_json::Value::Object(Default::default())),
+            input => serde_json::from_str(input),
+        };
+        let decision = match input {
+            Ok(input) => {
+                let request = ToolRequest {
+                    agent: self.handle.clone(),
+                    tool: tool.to_string(),
+                    input,
+                    tenant: self.tenant.clone(),
+                };
+                policy.authorize(&request).await
+            }
+            Err(err) => PolicyDecision::Deny(format!("The input isn't valid JSON: {err}")),
+        };
+
+        match decision {
+            PolicyDecision::Allow => None,
+            PolicyDecision::Deny(reason) => {
+                tracing::info!(
+                    "Policy denied agent {} calling {tool}: {reason}",
+                    self.handle
+                );
+                let _ = self.event_emitter.send(AgentEvent::ToolDenied {
+                    tool: tool.to_string(),
+                    reason: reason.clone(),
+                });
+                Some(reason)
+            }
+        }
+    }
+
+    /// Runs the hooks of the agent for `event`, returning the reason if one blocked it.
+    async fn run_hooks(&self, event: HookEvent<'_>) -> Result<Option<String>, KepokiError> {
+        let HookDecision::Block(reason) = self.hooks.run(&self.handle, &event).await else {
+            return Ok(None);
+        };
+
+        tracing::info!(
+            "Hook blocked {:?} of agent {}",
+            event.trigger(),
+            self.handle
+        );
+        self.event_emitter
+            .send(AgentEvent::HookBlocked {
+                trigger: event.trigger(),
+                reason: reason.clone(),
+            })
+            .map_err(|_| KepokiError::EventReceiverClosed(self.handle.clone()))?;
+        Ok(Some(reason))
+    }
+
+    /// Adds a user message to the history after applying the PII policy of the agent to its
+    /// text, returns `false` if the policy blocked it.
+    async fn push_user_content(
+        &mut self,
+        mut content: Vec<ContentBlock>,
+    ) -> Result<bool, KepokiError> {
+        if let Some(policy) = &self.state.definition.pii_policy {
+            for block in &mut content {
+                let ContentBlock::Text { text } = block else {
+                    continue;
+                };
+
+                match policy.apply(text) {
+                    Ok(masked) => *text = masked,
+                    Err(kind) => {
+                        tracing::info!(
+                            "Agent {} blocked user input containing {kind:?}",
+                            self.handle
+                        );
+                        self.event_emitter
+                            .send(AgentEvent::InputBlocked { kind })
+                            .map_err(|_| KepokiError::EventReceiverClosed(self.handle.clone()))?;
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+
+        if self
+            .run_hooks(HookEvent::UserMessage { content: &content })
+            .await?
+            .is_some()
+        {
+            return Ok(false);
+        }
+
+        self.state.messages.push_back(InputMessage {
+            id: new_message_id(),
+            role: Role::User,
+            content,
+        });
+        Ok(true)
+    }
+
+    async fn handle_command(
+        &mut self,
+        command: AgentCommand,
+    ) -> Result<Option<ExitCode>, KepokiError> {
         match command {
             AgentCommand::Exit => {
                 tracing::info!("Agent {} exiting", self.handle);
@@ -241,16 +1641,239 @@ impl<B: Backend> Agent<B> {
             }
             AgentCommand::UserMessage(message) => {
                 tracing::info!("Received user message for agent {}", self.handle);
-                self.state.messages.push_back(InputMessage {
-                    role: Role::User,
-                    content: vec![ContentBlock::Text { text: message }],
-                });
+                self.push_user_content(vec![ContentBlock::Text { text: message }])
+                    .await?;
+            }
+            AgentCommand::UserMessageWithOverrides(message, overrides) => {
+                tracing::info!("Received user message for agent {}", self.handle);
+                if self
+                    .push_user_content(vec![ContentBlock::Text { text: message }])
+                    .await?
+                {
+                    self.turn_overrides = overrides;
+                }
             }
-            command => {
-                unreachable!("Command not intercepted by the runtime: {command:?}")
+            AgentCommand::UserContent(content) => {
+                tracing::info!("Received user content for agent {}", self.handle);
+                self.push_user_content(content).await?;
+            }
+            AgentCommand::SetWorkingDirectory(working_directory) => {
+                tracing::info!(
+                    "Agent {} working directory set to {}",
+                    self.handle,
+                    working_directory.display()
+                );
+                self.state.definition.working_directory = Some(working_directory);
+                self.mcp_servers
+                    .set_roots(&self.handle, self.state.definition.roots())
+                    .await;
+            }
+            AgentCommand::RewindTo(id) => {
+                let Some(position) = self.message_position(&id) else {
+                    return Ok(None);
+                };
+
+                // A tool use without the result that followed it would be rejected by the model.
+                if self.state.messages[position]
+                    .content
+                    .iter()
+                    .any(|block| matches!(block, ContentBlock::ToolUse { .. }))
+                {
+                    tracing::warn!("Agent {} can't rewind to tool use {id}", self.handle);
+                    return Ok(None);
+                }
+
+                tracing::info!("Agent {} rewinding to message {id}", self.handle);
+                let removed = self.state.messages.split_off(position + 1).into();
+                self.recorded_messages = self.recorded_messages.min(self.state.messages.len());
+                self.record_history_edit(HistoryChange::Rewound { to: id, removed })?;
+            }
+            AgentCommand::EditMessage { id, content } => {
+                let Some(position) = self.message_position(&id) else {
+                    return Ok(None);
+                };
+
+                let message = &mut self.state.messages[position];
+                if message.content.iter().any(|block| {
+                    matches!(
+                        block,
+                        ContentBlock::ToolUse { .. } | ContentBlock::ToolResult { .. }
+                    )
+                }) {
+                    tracing::warn!("Agent {} can't edit tool message {id}", self.handle);
+                    return Ok(None);
+                }
+
+                tracing::info!("Agent {} editing message {id}", self.handle);
+                let previous = std::mem::replace(
+                    &mut message.content,
+                    vec![ContentBlock::Text { text: content }],
+                );
+                self.record_history_edit(HistoryChange::Edited { id, previous })?;
+            }
+            AgentCommand::Terminate => {
+                tracing::info!("Agent {} terminated", self.handle);
+                return Err(KepokiError::AgentManuallyTerminated(self.handle.clone()));
             }
         }
 
         Ok(None)
     }
 }
+
+fn new_message_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Appends the continuation of a partial response to it, joining text split between them.
+fn join_messages(mut partial: Message, continuation: Message) -> Message {
+    let mut blocks = continuation.content.into_iter();
+    if let Some(ContentBlock::Text { text }) = partial.content.last_mut() {
+        text.truncate(text.trim_end().len());
+        let mut blocks = blocks.by_ref().peekable();
+        if let Some(ContentBlock::Text { text: rest }) = blocks.peek() {
+            text.push_str(rest);
+            blocks.next();
+        }
+    }
+    partial.content.extend(blocks);
+    if partial.id.is_empty() {
+        partial.id = continuation.id;
+    }
+    partial.stop_reason = continuation.stop_reason;
+    partial.stop_sequence = continuation.stop_sequence;
+    partial.usage = match (partial.usage, continuation.usage) {
+        (Some(first), Some(second)) => Some(Usage {
+            input_tokens: first.input_tokens + second.input_tokens,
+            output_tokens: first.output_tokens + second.output_tokens,
+        }),
+        (first, second) => first.or(second),
+    };
+    partial
+}
+
+/// Assembles a streamed response, forwarding its events to `event_emitter` if given.
+async fn receive_message(
+    mut stream: impl MessageStream,
+    handle: &AgentHandle,
+    progress: &Progress,
+    event_emitter: Option<&UnboundedSender<AgentEvent>>,
+    cancellation: &Cancellation,
+) -> Result<Message, KepokiError> {
+    let mut assembler = MessageAssembler::new();
+    loop {
+        let event = match cancellation.run(stream.recv()).await? {
+            Ok(Some(event)) => event,
+            Ok(None) => break,
+            Err(error) => {
+                return Err(match assembler.salvage() {
+                    Some(partial) => KepokiError::StreamInterrupted {
+                        partial: Box::new(partial),
+                        error: Box::new(error),
+                    },
+                    None => error,
+                });
+            }
+        };
+        cancellation.check()?;
+        progress.advance();
+        if let Some(event_emitter) = event_emitter {
+            event_emitter
+                .send(AgentEvent::from(event.clone()))
+                .map_err(|_| KepokiError::EventReceiverClosed(handle.clone()))?;
+        }
+
+        // Unknown events were forwarded above, they don't change the message.
+        assembler
+            .push(event)
+            .map_err(|_| KepokiError::UnexpectedEvent(handle.clone()))?;
+    }
+
+    assembler
+        .finish()
+        .ok_or_else(|| KepokiError::NoMessageReceived(handle.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(content: Vec<ContentBlock>, usage: Option<Usage>) -> Message {
+        Message {
+            id: String::new(),
+            content,
+            stop_reason: None,
+            stop_sequence: None,
+            usage,
+        }
+    }
+
+    fn text(text: &str) -> ContentBlock {
+        ContentBlock::Text {
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_join_messages_joins_split_text() {
+        let partial = message(
+            vec![text("The answer is ")],
+            Some(Usage {
+                input_tokens: 10,
+                output_tokens: 3,
+            }),
+        );
+        let mut continuation = message(
+            vec![
+                text(" 42."),
+                ContentBlock::ToolUse {
+                    id: "call_1".to_string(),
+                    input: "{}".to_string(),
+                    name: "check".to_string(),
+                },
+            ],
+            Some(Usage {
+                input_tokens: 13,
+                output_tokens: 5,
+            }),
+        );
+        continuation.id = "msg_1".to_string();
+        continuation.stop_reason = Some(StopReason::ToolUse);
+
+        let joined = join_messages(partial, continuation);
+        assert_eq!(joined.id, "msg_1");
+        assert!(matches!(joined.stop_reason, Some(StopReason::ToolUse)));
+        assert!(matches!(
+            &joined.content[..],
+            [ContentBlock::Text { text }, ContentBlock::ToolUse { id, .. }]
+                if text == "The answer is 42." && id == "call_1"
+        ));
+        assert!(matches!(
+            joined.usage,
+            Some(Usage {
+                input_tokens: 23,
+                output_tokens: 8
+            })
+        ));
+    }
+
+    #[test]
+    fn test_join_messages_appends_other_blocks() {
+        let partial = message(
+            vec![ContentBlock::ToolUse {
+                id: "call_1".to_string(),
+                input: "{}".to_string(),
+                name: "check".to_string(),
+            }],
+            None,
+        );
+        let continuation = message(vec![text("Done.")], None);
+
+        let joined = join_messages(partial, continuation);
+        assert!(matches!(
+            &joined.content[..],
+            [ContentBlock::ToolUse { .. }, ContentBlock::Text { text }] if text == "Done."
+        ));
+        assert!(joined.usage.is_none());
+    }
+}
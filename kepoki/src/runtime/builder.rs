@@ -0,0 +1,496 @@
+//! Composes a [`Runtime`] from its components, so that new components don't change how
+//! runtimes are constructed.
+//!
+//! The builder is the only way to configure the components agents are spawned with. The
+//! setters left on the runtime adjust a running runtime, such as its quotas, and take effect
+//! for agents that are already running. Components that run in the background, the retention
+//! purge and the watchdog, are started by [`RuntimeBuilder::build`], which has to be called
+//! within a Tokio runtime if either is set.
+//!
+//! ```ignore
+//! let runtime = Runtime::builder()
+//!     .with_tools(tools)
+//!     .with_max_concurrent_requests(8)
+//!     .with_error_handler(RetryHandler)
+//!     .build();
+//! ```
+//!
+//! A builder can build any number of runtimes, such as the sessions of the
+//! [`patterns`](crate::runtime::patterns). Runtimes built by a builder or its clones share
+//! their quota usage, request limit, and tool stats.
+//!
+//! Rate limits of providers are kept by wrapping the backend of agents in a
+//! [`RateLimitedBackend`](crate::backend::rate_limit::RateLimitedBackend).
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::JoinSet;
+
+use crate::artifacts::ArtifactStore;
+use crate::artifacts::CreateArtifactTool;
+use crate::artifacts::ReadArtifactTool;
+use crate::artifacts::UpdateArtifactTool;
+use crate::blackboard::Blackboard;
+use crate::blackboard::ListBlackboardTool;
+use crate::blackboard::ReadBlackboardTool;
+use crate::blackboard::WriteBlackboardTool;
+use crate::context::ContextProvider;
+use crate::context::ContextProviders;
+use crate::models::ModelInfo;
+use crate::models::ModelRegistry;
+use crate::output::OutputProcessor;
+use crate::output::OutputProcessors;
+use crate::policy::PolicyEngine;
+use crate::runtime::Runtime;
+use crate::runtime::drafting::Drafting;
+use crate::runtime::events::EventBus;
+use crate::runtime::notifications::Notifications;
+use crate::runtime::quotas::Quota;
+use crate::runtime::quotas::QuotaScope;
+use crate::runtime::quotas::Quotas;
+use crate::runtime::recovery::ErrorHandler;
+use crate::runtime::recovery::ErrorHandlers;
+use crate::runtime::retention::Retention;
+use crate::runtime::sampling::BestOf;
+use crate::runtime::scheduling::RequestScheduler;
+use crate::runtime::turns::TurnLog;
+use crate::runtime::watchdog::Watchdog;
+use crate::runtime::watchdog::WatchedAgents;
+use crate::servers::McpServers;
+use crate::tools::BuiltinTool;
+use crate::tools::ToolRegistry;
+use crate::tools::datetime::CurrentTimeTool;
+use crate::tools::images::GenerateImageTool;
+use crate::tools::images::ImageGenerationBackend;
+use crate::tools::selection::ToolEmbeddings;
+use crate::tools::selection::ToolSelector;
+use crate::tools::stats::ToolStats;
+
+#[derive(Clone)]
+pub struct RuntimeBuilder {
+    tools: ToolRegistry,
+    image_generation_backend: Option<Arc<dyn ImageGenerationBackend>>,
+    context_providers: ContextProviders,
+    output_processors: OutputProcessors,
+    models: ModelRegistry,
+    error_handler: Option<Arc<dyn ErrorHandler>>,
+    policy: Option<Arc<dyn PolicyEngine>>,
+    tenant: Option<String>,
+    credential: Option<String>,
+    quotas: Quotas,
+    scheduler: RequestScheduler,
+    user_id: Option<String>,
+    drafting: Option<Drafting>,
+    best_of: Option<BestOf>,
+    tool_selector: ToolSelector,
+    notifications: Option<Notifications>,
+    dry_run: bool,
+    tool_stats: ToolStats,
+    tool_timeout: Duration,
+    heartbeat_interval: Option<Duration>,
+    mcp_idle_timeout: Option<Duration>,
+    share_mcp_servers: bool,
+    retention: Option<Retention>,
+    watchdog: Option<Watchdog>,
+}
+
+impl Default for RuntimeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RuntimeBuilder {
+    /// A builder of a runtime with the default components, see [`Runtime::builder`].
+    pub fn new() -> Self {
+        Self {
+            tools: ToolRegistry::new(),
+            image_generation_backend: None,
+            context_providers: ContextProviders::new(),
+            output_processors: OutputProcessors::new(),
+            models: ModelRegistry::builtin(),
+            error_handler: None,
+            policy: None,
+            tenant: None,
+            credential: None,
+            quotas: Quotas::new(),
+            scheduler: RequestScheduler::new(),
+            user_id: None,
+            drafting: None,
+            best_of: None,
+            tool_selector: ToolSelector::default(),
+            notifications: None,
+            dry_run: false,
+            tool_stats: ToolStats::new(),
+            tool_timeout: Duration::from_secs(300),
+            heartbeat_interval: None,
+            mcp_idle_timeout: Some(Duration::from_secs(600)),
+            share_mcp_servers: false,
+            retention: None,
+            watchdog: None,
+        }
+    }
+
+    /// Registers every tool of `tools` next to the builtin tools of the runtime, replacing
+    /// builtin tools of the same name.
+    pub fn with_tools(mut self, tools: ToolRegistry) -> Self {
+        self.tools.merge(tools);
+        self
+    }
+
+    /// Makes a builtin tool available to agents, replacing builtin tools of the same name.
+    pub fn with_tool(mut self, tool: impl BuiltinTool) -> Self {
+        self.tools.register(tool);
+        self
+    }
+
+    /// Makes the `generate_image` tool available to agents, storing the images it generates
+    /// as artifacts of the calling agent.
+    pub fn with_image_generation_backend(mut self, backend: impl ImageGenerationBackend) -> Self {
+        self.image_generation_backend = Some(Arc::new(backend));
+        self
+    }
+
+    /// Makes a context provider available to agents, in addition to the builtin providers of
+    /// [`ContextProviders::new`].
+    pub fn with_context_provider(mut self, provider: impl ContextProvider) -> Self {
+        self.context_providers.register(provider);
+        self
+    }
+
+    /// Makes an output processor available to agents, which refer to it with
+    /// [`OutputProcessing::Custom`](crate::agent::OutputProcessing::Custom).
+    pub fn with_output_processor(mut self, processor: impl OutputProcessor) -> Self {
+        self.output_processors.register(processor);
+        self
+    }
+
+    /// Describes a model to agents, replacing the builtin description of a model of the same
+    /// name. Descriptions are used for the default response length and the spend counted
+    /// against quotas.
+    pub fn with_model(mut self, model: ModelInfo) -> Self {
+        self.models.register(model);
+        self
+    }
+
+    /// Sets the handler deciding how agents without their own handler recover from failed
+    /// turns. Without one they are terminated.
+    pub fn with_error_handler(mut self, handler: impl ErrorHandler) -> Self {
+        self.error_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Sets the policy engine authorizing tool calls. Without one every call of a tool the
+    /// agent lists is allowed.
+    pub fn with_policy(mut self, policy: impl PolicyEngine) -> Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Sets the tenant agents run for, passed to the policy engine and counted against its
+    /// quota.
+    pub fn with_tenant(mut self, tenant: impl Into<String>) -> Self {
+        self.tenant = Some(tenant.into());
+        self
+    }
+
+    /// Names the backend credential agents use, such as the API key of their backend, so
+    /// their requests count against its quota.
+    pub fn with_credential(mut self, credential: impl Into<String>) -> Self {
+        self.credential = Some(credential.into());
+        self
+    }
+
+    /// Sets the daily quota of a tenant or credential, see [`Runtime::set_quota`].
+    pub fn with_quota(self, scope: QuotaScope, quota: Quota) -> Self {
+        self.quotas.set(scope, Some(quota));
+        self
+    }
+
+    /// Limits how many backend requests all agents send at once, see
+    /// [`Runtime::set_max_concurrent_requests`].
+    pub fn with_max_concurrent_requests(self, limit: usize) -> Self {
+        self.scheduler.set_limit(Some(limit));
+        self
+    }
+
+    /// Sets the end user that requests are attributed to.
+    ///
+    /// Backends forward it to providers that support it, such as Anthropic's
+    /// `metadata.user_id`. Use an opaque identifier rather than personal information.
+    pub fn with_user_id(mut self, user_id: impl Into<String>) -> Self {
+        self.user_id = Some(user_id.into());
+        self
+    }
+
+    /// Has the draft model of `drafting` respond to every turn first, sending the turn to the
+    /// model of the agent only if the judge rejects the draft.
+    pub fn with_drafting(mut self, drafting: Drafting) -> Self {
+        self.drafting = Some(drafting);
+        self
+    }
+
+    /// Samples several responses for every turn, committing only the one picked by the
+    /// selector.
+    pub fn with_best_of(mut self, best_of: BestOf) -> Self {
+        self.best_of = Some(best_of);
+        self
+    }
+
+    /// Ranks tools by embedding similarity for agents with a tool selection, instead of by
+    /// the words they share with the conversation.
+    pub fn with_tool_embeddings(mut self, embeddings: ToolEmbeddings) -> Self {
+        self.tool_selector = ToolSelector::new(Some(embeddings));
+        self
+    }
+
+    /// Notifies a human of agents that need their attention, see
+    /// [`notifications`](crate::runtime::notifications).
+    pub fn with_notifications(mut self, notifications: Notifications) -> Self {
+        self.notifications = Some(notifications);
+        self
+    }
+
+    /// Enables or disables dry-run mode.
+    ///
+    /// In dry-run mode side-effecting tools are not executed. The intended call is emitted as
+    /// [`AgentEvent::DryRunToolUse`](crate::runtime::agent::AgentEvent::DryRunToolUse) and the
+    /// model receives a synthetic result instead.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Sets the timeout for tool calls of agents that don't specify their own.
+    pub fn with_tool_timeout(mut self, timeout: Duration) -> Self {
+        self.tool_timeout = timeout;
+        self
+    }
+
+    /// Emits [`AgentEvent::Heartbeat`](crate::runtime::agent::AgentEvent::Heartbeat) every
+    /// `interval` while agents wait for a response or run a tool.
+    pub fn with_heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// Sets how long local MCP servers may sit idle before they are shut down, `None` keeps
+    /// them running for the lifetime of the agent.
+    ///
+    /// Servers are restarted transparently the next time one of their tools is called.
+    pub fn with_mcp_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.mcp_idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Shares local MCP server processes between agents with identical server definitions
+    /// instead of spawning one per agent.
+    ///
+    /// Servers that keep per-client state should not be shared. Sandboxed and containerized
+    /// agents never share servers.
+    pub fn with_share_mcp_servers(mut self, share: bool) -> Self {
+        self.share_mcp_servers = share;
+        self
+    }
+
+    /// Sets how long data about agents is kept, see [`Runtime::set_retention`].
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Watches agents for stalls, see [`Runtime::set_watchdog`].
+    pub fn with_watchdog(mut self, watchdog: Watchdog) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Builds a runtime of the components, starting its background jobs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime with a watchdog or a retention with a
+    /// `max_age`, see [`Runtime::set_retention`] and [`Runtime::set_watchdog`].
+    pub fn build(&self) -> Runtime {
+        let artifacts = ArtifactStore::new();
+        let blackboard = Blackboard::new();
+        let mut tools = ToolRegistry::new();
+        tools.register(CreateArtifactTool(artifacts.clone()));
+        tools.register(UpdateArtifactTool(artifacts.clone()));
+        tools.register(ReadArtifactTool(artifacts.clone()));
+        tools.register(CurrentTimeTool);
+        tools.register(ReadBlackboardTool(blackboard.clone()));
+        tools.register(WriteBlackboardTool(blackboard.clone()));
+        tools.register(ListBlackboardTool(blackboard.clone()));
+        if let Some(backend) = &self.image_generation_backend {
+            tools.register(GenerateImageTool::with_backend(
+                backend.clone(),
+                artifacts.clone(),
+            ));
+        }
+        tools.merge(self.tools.clone());
+
+        let error_handlers = ErrorHandlers::new();
+        error_handlers.set_default(self.error_handler.clone());
+
+        let mut runtime = Runtime {
+            thread_join_set: JoinSet::new(),
+            recv_join_set: JoinSet::new(),
+            command_emitters: HashMap::new(),
+            cancellations: HashMap::new(),
+            tools,
+            context_providers: self.context_providers.clone(),
+            output_processors: self.output_processors.clone(),
+            artifacts,
+            blackboard,
+            event_bus: EventBus::new(),
+            tool_timeout: self.tool_timeout,
+            heartbeat_interval: self.heartbeat_interval,
+            dry_run: self.dry_run,
+            tool_stats: self.tool_stats.clone(),
+            mcp_idle_timeout: self.mcp_idle_timeout,
+            shared_mcp_servers: self
+                .share_mcp_servers
+                .then(|| McpServers::new().with_idle_timeout(self.mcp_idle_timeout)),
+            user_id: self.user_id.clone(),
+            command_roles: HashMap::new(),
+            error_handlers,
+            policy: self.policy.clone(),
+            tenant: self.tenant.clone(),
+            credential: self.credential.clone(),
+            quotas: self.quotas.clone(),
+            scheduler: self.scheduler.clone(),
+            models: self.models.clone(),
+            drafting: self.drafting.clone(),
+            best_of: self.best_of.clone(),
+            turn_log: TurnLog::new(),
+            tool_selector: self.tool_selector.clone(),
+            retention: Retention::default(),
+            purge_job: JoinSet::new(),
+            watched: WatchedAgents::default(),
+            watchdog_job: JoinSet::new(),
+            notifications: self.notifications.clone(),
+        };
+        if let Some(retention) = &self.retention {
+            runtime.set_retention(retention.clone());
+        }
+        runtime.set_watchdog(self.watchdog);
+        runtime
+    }
+}
+
+impl Debug for RuntimeBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RuntimeBuilder")
+            .field("tenant", &self.tenant)
+            .field("credential", &self.credential)
+            .field("quotas", &self.quotas)
+            .field("scheduler", &self.scheduler)
+            .field("models", &self.models)
+            .field("dry_run", &self.dry_run)
+            .field("tool_timeout", &self.tool_timeout)
+            .field("heartbeat_interval", &self.heartbeat_interval)
+            .field("mcp_idle_timeout", &self.mcp_idle_timeout)
+            .field("share_mcp_servers", &self.share_mcp_servers)
+            .field("retention", &self.retention)
+            .field("watchdog", &self.watchdog)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::Agent;
+    use crate::agent::Priority;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+    use crate::runtime::agent::AgentCommand;
+    use crate::runtime::agent::AgentEvent;
+
+    #[test]
+    fn test_build_registers_builtin_tools() {
+        let runtime = Runtime::builder().build();
+        for tool in [
+            "create_artifact",
+            "update_artifact",
+            "read_artifact",
+            "current_time",
+            "read_blackboard",
+            "write_blackboard",
+            "list_blackboard",
+        ] {
+            assert!(runtime.tools().contains(tool), "{tool} is missing");
+        }
+        assert!(!runtime.tools().contains("generate_image"));
+    }
+
+    #[test]
+    fn test_runtimes_share_quotas() {
+        let builder = Runtime::builder();
+        let mut a = builder.build();
+        let b = builder.clone().build();
+        let scope = QuotaScope::Tenant("acme".to_string());
+        a.set_quota(
+            scope.clone(),
+            Some(Quota {
+                daily_tokens: Some(1000),
+                ..Default::default()
+            }),
+        );
+        assert!(b.quota_usage().contains_key(&scope));
+
+        // Runtimes of separate builders don't share anything.
+        assert!(Runtime::builder().build().quota_usage().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_runtimes_share_request_limit() {
+        let builder = Runtime::builder().with_max_concurrent_requests(1);
+        let a = builder.build();
+        let b = builder.build();
+
+        let permit = a.scheduler.acquire(Priority::default()).await;
+        let waiting = tokio::time::timeout(
+            Duration::from_millis(50),
+            b.scheduler.acquire(Priority::default()),
+        )
+        .await;
+        assert!(waiting.is_err());
+        drop(permit);
+        b.scheduler.acquire(Priority::default()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_runtimes_share_tool_stats() {
+        let backend = MockBackend::new()
+            .with_response(MockResponse::tool_use(
+                "call_1",
+                "current_time",
+                serde_json::json!({}),
+            ))
+            .with_response(MockResponse::text("Done"));
+        let builder = Runtime::builder();
+        let mut a = builder.build();
+        let b = builder.build();
+        let agent = Agent {
+            tools: vec!["current_time".parse().unwrap()],
+            ..Default::default()
+        };
+        let agent = a.spawn_agent(backend, "mock".to_string(), agent);
+        a.send_as_owner(&agent, AgentCommand::UserMessage("Hello".to_string()))
+            .unwrap();
+        let mut messages = 0;
+        while messages < 2 {
+            if let AgentEvent::Message(_) = a.recv().await.unwrap() {
+                messages += 1;
+            }
+        }
+        a.send_as_owner(&agent, AgentCommand::Exit).unwrap();
+
+        assert_eq!(b.tool_stats()[&"current_time".parse().unwrap()].calls, 1);
+    }
+}
@@ -0,0 +1,135 @@
+//! Ties the lifetimes of agents and their turns to those of the embedder, such as the request
+//! an agent serves.
+//!
+//! Every agent has a [`CancellationToken`], cancelling it terminates the agent. Agents spawned
+//! with [`Runtime::spawn_agent_with_cancellation`] are terminated when the given token is
+//! cancelled, [`Runtime::cancellation_token`] returns the token of any agent.
+//!
+//! Commands sent with [`Runtime::send_with_cancellation`] carry a token for the turn they start.
+//! Cancelling it ends the turn, the agent waits for the next command afterwards. In both cases
+//! the request or tool call in progress is abandoned right away, including backends waiting
+//! to send it, such as between retries or for a rate limit. Tool calls that were abandoned are
+//! answered with an error, so the history stays valid.
+//!
+//! [`Runtime::spawn_agent_with_cancellation`]: crate::runtime::Runtime::spawn_agent_with_cancellation
+//! [`Runtime::cancellation_token`]: crate::runtime::Runtime::cancellation_token
+//! [`Runtime::send_with_cancellation`]: crate::runtime::Runtime::send_with_cancellation
+
+use std::future::Future;
+
+pub use tokio_util::sync::CancellationToken;
+
+use crate::error::KepokiError;
+use crate::runtime::AgentHandle;
+
+/// The tokens of an agent and of its turn in progress.
+#[derive(Clone, Debug)]
+pub(crate) struct Cancellation {
+    pub handle: AgentHandle,
+    pub agent: CancellationToken,
+    pub turn: Option<CancellationToken>,
+}
+
+impl Cancellation {
+    /// Fails if the agent or its turn was cancelled.
+    pub fn check(&self) -> Result<(), KepokiError> {
+        if self.agent.is_cancelled() {
+            return Err(KepokiError::AgentManuallyTerminated(self.handle.clone()));
+        }
+        match &self.turn {
+            Some(turn) if turn.is_cancelled() => Err(KepokiError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs `future` until it completes or the agent or its turn is cancelled.
+    pub async fn run<F: Future>(&self, future: F) -> Result<F::Output, KepokiError> {
+        let turn = async {
+            match &self.turn {
+                Some(turn) => turn.cancelled().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            output = future => Ok(output),
+            _ = self.agent.cancelled() => Err(KepokiError::AgentManuallyTerminated(self.handle.clone())),
+            _ = turn => Err(KepokiError::Cancelled),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::agent::Agent;
+    use crate::backend::retry::RetryingBackend;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+    use crate::runtime::Runtime;
+    use crate::runtime::agent::AgentCommand;
+    use crate::runtime::agent::AgentEvent;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cancel_turn_then_agent() {
+        let backend = MockBackend::new().with_response(MockResponse::text("Hello"));
+        let mut runtime = Runtime::builder().build();
+        let token = CancellationToken::new();
+        let agent = runtime.spawn_agent_with_cancellation(
+            backend.clone(),
+            "mock".to_string(),
+            Agent::default(),
+            &token,
+        );
+
+        let turn = CancellationToken::new();
+        turn.cancel();
+        runtime
+            .send_with_cancellation(&agent, AgentCommand::UserMessage("Hi".to_string()), turn)
+            .unwrap();
+        while !matches!(runtime.recv().await.unwrap(), AgentEvent::TurnCancelled) {}
+        assert!(backend.requests().is_empty());
+
+        token.cancel();
+        let mut terminated = false;
+        loop {
+            match runtime.recv().await {
+                Ok(AgentEvent::Terminated(_)) => terminated = true,
+                Err(KepokiError::NoRunningAgents) => break,
+                _ => (),
+            }
+        }
+        assert!(terminated);
+        assert!(runtime.cancellation_token(&agent).is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cancel_turn_waiting_for_retry() {
+        let backend = RetryingBackend::new(MockBackend::new().with_response(MockResponse::error(
+            KepokiError::Io(std::io::Error::other("reset")),
+        )))
+        .with_initial_backoff(Duration::from_secs(3600));
+        let mut runtime = Runtime::builder().build();
+        let agent = runtime.spawn_agent(backend, "mock".to_string(), Agent::default());
+
+        let turn = CancellationToken::new();
+        runtime
+            .send_with_cancellation(
+                &agent,
+                AgentCommand::UserMessage("Hi".to_string()),
+                turn.clone(),
+            )
+            .unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            turn.cancel();
+        });
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !matches!(runtime.recv().await.unwrap(), AgentEvent::TurnCancelled) {}
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+//! Commit messages written by a bundled agent from the staged changes of a git repository.
+//!
+//! The agent prefers cheap models, summarizing a diff doesn't need a strong one. Use
+//! [`review::git_diff`] with [`DiffSource::Staged`] to collect the diff.
+//!
+//! [`review::git_diff`]: crate::runtime::review::git_diff
+//! [`DiffSource::Staged`]: crate::runtime::review::DiffSource::Staged
+
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+
+use crate::agent::Agent;
+use crate::agent::ModelMetric;
+use crate::agent::ModelPreferences;
+use crate::backend::Backend;
+use crate::error::KepokiError;
+use crate::runtime::builder::RuntimeBuilder;
+use crate::runtime::patterns::run_once;
+use crate::runtime::patterns::strip_code_fence;
+
+/// The definition of the agent writing commit messages.
+pub fn committer() -> Agent {
+    Agent {
+        name: "commit-message-writer".to_string(),
+        description: "Writes conventional commit messages for staged changes.".to_string(),
+        prompt: r#"You write git commit messages following the Conventional Commits specification.
+
+The subject line has the form "<type>(<optional scope>): <summary>", where type is one of feat, fix, docs, style, refactor, perf, test, build, ci, or chore. The summary is in the imperative mood, at most 72 characters, and has no trailing period. If the change needs explaining, add a body after a blank line that says what changed and why, wrapped at 72 characters.
+
+Respond only with the commit message."#.to_string(),
+        model_preferences: ModelPreferences {
+            preferred_family: None,
+            preferred_metrics: vec![ModelMetric::Cost, ModelMetric::Speed],
+        },
+        temperature: 0.2,
+        ..Agent::default()
+    }
+}
+
+/// Has the [`committer`] propose a commit message for `diff`, on a runtime built by `runtime`.
+pub async fn commit_message<B: Backend>(
+    runtime: &RuntimeBuilder,
+    backend: B,
+    model: B::Model,
+    diff: &str,
+) -> Result<String, KepokiError> {
+    if diff.trim().is_empty() {
+        return Err(KepokiError::CustomError(
+            "There are no changes to commit".into(),
+        ));
+    }
+
+    let response = run_once(
+        runtime,
+        backend,
+        model,
+        committer(),
+        format!("<diff>\n{diff}\n</diff>"),
+    )
+    .await?;
+    Ok(strip_code_fence(&response).trim().to_string())
+}
+
+/// Commits the staged changes of the git repository at `dir` with `message`.
+pub fn git_commit(dir: impl AsRef<Path>, message: &str) -> Result<(), KepokiError> {
+    let mut child = Command::new("git")
+        .current_dir(dir)
+        .args(["commit", "--file", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(message.as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(KepokiError::CustomError(
+            format!(
+                "git commit failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into(),
+        ));
+    }
+
+    Ok(())
+}
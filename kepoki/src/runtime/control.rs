@@ -0,0 +1,448 @@
+//! A local control endpoint for sidecar processes such as editors, listening on a Unix domain
+//! socket, or a named pipe on Windows.
+//!
+//! Clients and the runtime exchange newline-delimited JSON. Clients send [`ControlCommand`]s
+//! and receive [`ControlMessage`]s: the events the host publishes, and replies to their own
+//! commands.
+//!
+//! ```text
+//! > "list_agents"
+//! < {"agents":[{"name":"coder","uuid":[...]}]}
+//! > {"send":{"agent":{"name":"coder","uuid":[...]},"command":{"UserMessage":"Hi"}}}
+//! < {"event":"MessageStop"}
+//! > "tool_stats"
+//! < {"tool_stats":{"@builtin/read_file":{"calls":3,"errors":0,...}}}
+//! ```
+//!
+//! Replies to `tool_stats` are the metrics of the runtime, see [`Runtime::tool_stats`].
+//!
+//! The socket doesn't own the runtime. The host receives commands next to events and hands
+//! them back to the socket:
+//!
+//! ```ignore
+//! runtime.set_command_role(CONTROL_SOURCE, CommandRole::Operator);
+//! let mut control = ControlSocket::bind("/tmp/kepoki.sock")?;
+//! loop {
+//!     tokio::select! {
+//!         event = runtime.recv() => control.publish(&event?),
+//!         Some(request) = control.recv() => control.handle(&mut runtime, request),
+//!     }
+//! }
+//! ```
+//!
+//! Commands are sent as the [`CONTROL_SOURCE`] command source, which may not send any
+//! commands until the host assigns it a role.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::task::JoinSet;
+
+use crate::agent::ToolName;
+use crate::error::KepokiError;
+use crate::runtime::AgentHandle;
+use crate::runtime::Runtime;
+use crate::runtime::agent::AgentCommand;
+use crate::runtime::agent::AgentEvent;
+use crate::tools::stats::ToolStat;
+
+/// The command source commands received on a control socket are sent as, see
+/// [`Runtime::set_command_role`].
+pub const CONTROL_SOURCE: &str = "control-socket";
+
+/// A line sent by a client.
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// Replies with the running agents.
+    ListAgents,
+    /// Replies with the usage statistics of every tool called so far.
+    ToolStats,
+    Send {
+        agent: AgentHandle,
+        command: AgentCommand,
+    },
+}
+
+/// A line sent to clients.
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ControlMessage {
+    Agents(Vec<AgentHandle>),
+    ToolStats(HashMap<ToolName, ToolStat>),
+    Event(AgentEvent),
+    /// A command of the client was invalid or couldn't be sent.
+    Error(String),
+}
+
+/// A command received from a client, to be handled with [`ControlSocket::handle`].
+#[derive(Debug)]
+pub struct ControlRequest {
+    connection: u64,
+    pub command: ControlCommand,
+}
+
+type Connections = Arc<Mutex<HashMap<u64, UnboundedSender<String>>>>;
+
+#[derive(Debug)]
+pub struct ControlSocket {
+    requests: UnboundedReceiver<ControlRequest>,
+    connections: Connections,
+    /// The task accepting connections and the tasks serving them, aborted on drop.
+    tasks: Arc<Mutex<JoinSet<()>>>,
+    #[cfg(unix)]
+    path: std::path::PathBuf,
+}
+
+impl ControlSocket {
+    /// Listens on a Unix domain socket at `path`, replacing a stale socket left there.
+    ///
+    /// Must be called within a Tokio runtime.
+    #[cfg(unix)]
+    pub fn bind(path: impl AsRef<Path>) -> Result<Self, KepokiError> {
+        use std::os::unix::fs::FileTypeExt;
+
+        let path = path.as_ref();
+        if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = tokio::net::UnixListener::bind(path)?;
+        let (socket, sender) = Self::new(path);
+        let connections = socket.connections.clone();
+        let tasks = Arc::downgrade(&socket.tasks);
+        socket.tasks.lock().unwrap().spawn(async move {
+            let ids = AtomicU64::new(0);
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        tracing::error!("Failed to accept control connection: {err}");
+                        continue;
+                    }
+                };
+
+                let Some(tasks) = tasks.upgrade() else {
+                    return;
+                };
+                let id = ids.fetch_add(1, Ordering::Relaxed);
+                let mut tasks = tasks.lock().unwrap();
+                while tasks.try_join_next().is_some() {}
+                tasks.spawn(serve(stream, id, connections.clone(), sender.clone()));
+            }
+        });
+
+        Ok(socket)
+    }
+
+    /// Listens on a named pipe such as `\\.\pipe\kepoki`.
+    ///
+    /// Must be called within a Tokio runtime.
+    #[cfg(windows)]
+    pub fn bind(name: impl AsRef<Path>) -> Result<Self, KepokiError> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        let name = name.as_ref().as_os_str().to_os_string();
+        let mut server = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&name)?;
+        let (socket, sender) = Self::new(Path::new(&name));
+        let connections = socket.connections.clone();
+        let tasks = Arc::downgrade(&socket.tasks);
+        socket.tasks.lock().unwrap().spawn(async move {
+            let ids = AtomicU64::new(0);
+            loop {
+                if let Err(err) = server.connect().await {
+                    tracing::error!("Failed to accept control connection: {err}");
+                    continue;
+                }
+
+                // A new instance must exist before the next client connects.
+                let stream = match ServerOptions::new().create(&name) {
+                    Ok(next) => std::mem::replace(&mut server, next),
+                    Err(err) => {
+                        tracing::error!("Failed to create control pipe instance: {err}");
+                        return;
+                    }
+                };
+                let Some(tasks) = tasks.upgrade() else {
+                    return;
+                };
+                let id = ids.fetch_add(1, Ordering::Relaxed);
+                let mut tasks = tasks.lock().unwrap();
+                while tasks.try_join_next().is_some() {}
+                tasks.spawn(serve(stream, id, connections.clone(), sender.clone()));
+            }
+        });
+
+        Ok(socket)
+    }
+
+    #[cfg_attr(windows, allow(unused_variables))]
+    fn new(path: &Path) -> (Self, UnboundedSender<ControlRequest>) {
+        let (sender, requests) = unbounded_channel();
+        let socket = Self {
+            requests,
+            connections: Arc::default(),
+            tasks: Arc::default(),
+            #[cfg(unix)]
+            path: path.to_path_buf(),
+        };
+        (socket, sender)
+    }
+
+    /// Waits for the next command of any client.
+    pub async fn recv(&mut self) -> Option<ControlRequest> {
+        self.requests.recv().await
+    }
+
+    /// Replies to queries of the runtime or sends the command to the agent, replying with an
+    /// error if it failed.
+    pub fn handle(&self, runtime: &mut Runtime, request: ControlRequest) {
+        let reply = match request.command {
+            ControlCommand::ListAgents => ControlMessage::Agents(runtime.agents()),
+            ControlCommand::ToolStats => ControlMessage::ToolStats(runtime.tool_stats()),
+            ControlCommand::Send { agent, command } => {
+                match runtime.send_from(CONTROL_SOURCE, &agent, command) {
+                    Ok(()) => return,
+                    Err(err) => ControlMessage::Error(err.to_string()),
+                }
+            }
+        };
+
+        self.reply(request.connection, &reply);
+    }
+
+    /// Sends an event to every connected client.
+    pub fn publish(&self, event: &AgentEvent) {
+        let line = match serde_json::to_string(&EventMessage { event }) {
+            Ok(line) => line,
+            Err(err) => {
+                tracing::error!("Failed to serialize event for control clients: {err}");
+                return;
+            }
+        };
+
+        // Connections whose client went away are dropped.
+        self.connections
+            .lock()
+            .unwrap()
+            .retain(|_, connection| connection.send(line.clone()).is_ok());
+    }
+
+    fn reply(&self, connection: u64, message: &ControlMessage) {
+        if let Some(sender) = self.connections.lock().unwrap().get(&connection)
+            && let Ok(line) = serde_json::to_string(message)
+        {
+            let _ = sender.send(line);
+        }
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Serializes like [`ControlMessage::Event`] without owning the event.
+#[derive(Serialize)]
+struct EventMessage<'a> {
+    event: &'a AgentEvent,
+}
+
+/// Forwards the commands of a client and writes the lines queued for it.
+async fn serve<S: AsyncRead + AsyncWrite + Send + 'static>(
+    stream: S,
+    id: u64,
+    connections: Connections,
+    requests: UnboundedSender<ControlRequest>,
+) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let (sender, mut lines) = unbounded_channel::<String>();
+    connections.lock().unwrap().insert(id, sender.clone());
+
+    let write = async move {
+        while let Some(line) = lines.recv().await {
+            if writer.write_all(line.as_bytes()).await.is_err()
+                || writer.write_all(b"\n").await.is_err()
+            {
+                return;
+            }
+        }
+    };
+
+    let read = async {
+        let mut reader = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = reader.next_line().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str::<ControlCommand>(&line) {
+                Ok(command) => {
+                    let request = ControlRequest {
+                        connection: id,
+                        command,
+                    };
+                    if requests.send(request).is_err() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    if let Ok(line) = serde_json::to_string(&ControlMessage::Error(format!(
+                        "Invalid command: {err}"
+                    ))) {
+                        let _ = sender.send(line);
+                    }
+                }
+            }
+        }
+    };
+
+    // The connection ends once the client closes it.
+    tokio::select! {
+        _ = read => {}
+        _ = write => {}
+    }
+    connections.lock().unwrap().remove(&id);
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use tokio::io::Lines;
+    use tokio::net::UnixStream;
+    use tokio::net::unix::OwnedReadHalf;
+
+    use super::*;
+    use crate::agent::Agent;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+    use crate::runtime::cancellation::CancellationToken;
+    use crate::runtime::permissions::CommandRole;
+
+    async fn reply(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> ControlMessage {
+        let line = lines.next_line().await.unwrap().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_list_agents_and_send() {
+        let backend = MockBackend::new().with_response(MockResponse::text("Hello"));
+        let mut runtime = Runtime::builder().build();
+        runtime.set_command_role(CONTROL_SOURCE, CommandRole::Operator);
+        let token = CancellationToken::new();
+        let agent = runtime.spawn_agent_with_cancellation(
+            backend.clone(),
+            "mock".to_string(),
+            Agent::default(),
+            &token,
+        );
+
+        let path = std::env::temp_dir().join(format!("kepoki-{}.sock", uuid::Uuid::new_v4()));
+        let mut control = ControlSocket::bind(&path).unwrap();
+        let (reader, mut writer) = UnixStream::connect(&path).await.unwrap().into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        writer.write_all(b"\"list_agents\"\n").await.unwrap();
+        let request = control.recv().await.unwrap();
+        control.handle(&mut runtime, request);
+        assert!(
+            matches!(reply(&mut lines).await, ControlMessage::Agents(agents) if agents == [agent.clone()])
+        );
+
+        let send = ControlCommand::Send {
+            agent: agent.clone(),
+            command: AgentCommand::UserMessage("Hi".to_string()),
+        };
+        writer
+            .write_all(format!("{}\n", serde_json::to_string(&send).unwrap()).as_bytes())
+            .await
+            .unwrap();
+        let request = control.recv().await.unwrap();
+        control.handle(&mut runtime, request);
+        loop {
+            let event = runtime.recv().await.unwrap();
+            control.publish(&event);
+            if matches!(event, AgentEvent::Message(_)) {
+                break;
+            }
+        }
+        while !matches!(
+            reply(&mut lines).await,
+            ControlMessage::Event(AgentEvent::Message(_))
+        ) {}
+        assert_eq!(backend.requests().len(), 1);
+
+        // Agents that exited are no longer listed.
+        token.cancel();
+        while !matches!(runtime.recv().await, Err(KepokiError::NoRunningAgents)) {}
+        writer.write_all(b"\"list_agents\"\n").await.unwrap();
+        let request = control.recv().await.unwrap();
+        control.handle(&mut runtime, request);
+        assert!(
+            matches!(reply(&mut lines).await, ControlMessage::Agents(agents) if agents.is_empty())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tool_stats() {
+        let backend = MockBackend::new()
+            .with_response(MockResponse::tool_use(
+                "call_1",
+                "current_time",
+                serde_json::json!({}),
+            ))
+            .with_response(MockResponse::text("Done"));
+        let mut runtime = Runtime::builder().build();
+        let agent = Agent {
+            tools: vec!["current_time".parse().unwrap()],
+            ..Default::default()
+        };
+        let agent = runtime.spawn_agent(backend, "mock".to_string(), agent);
+        runtime
+            .send_as_owner(
+                &agent,
+                AgentCommand::UserMessage("What time is it?".to_string()),
+            )
+            .unwrap();
+        let mut messages = 0;
+        while messages < 2 {
+            if let AgentEvent::Message(_) = runtime.recv().await.unwrap() {
+                messages += 1;
+            }
+        }
+
+        // Querying stats doesn't need a role.
+        let path = std::env::temp_dir().join(format!("kepoki-{}.sock", uuid::Uuid::new_v4()));
+        let mut control = ControlSocket::bind(&path).unwrap();
+        let (reader, mut writer) = UnixStream::connect(&path).await.unwrap().into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"\"tool_stats\"\n").await.unwrap();
+        let request = control.recv().await.unwrap();
+        control.handle(&mut runtime, request);
+        let ControlMessage::ToolStats(stats) = reply(&mut lines).await else {
+            panic!("Expected tool stats");
+        };
+        let stat = &stats[&"current_time".parse().unwrap()];
+        assert_eq!(stat.calls, 1);
+        assert_eq!(stat.errors, 0);
+    }
+}
@@ -0,0 +1,112 @@
+//! Speculative drafting has a cheap model respond to every turn first and sends the turn to the
+//! model of the agent only when a judge doesn't trust the draft, reducing the cost of agents
+//! answering many simple requests.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::backend::ContentBlock;
+use crate::backend::InputMessage;
+use crate::backend::Message;
+use crate::backend::StopReason;
+
+pub trait DraftJudge: Send + Sync + 'static {
+    /// Whether the draft can be committed as the response to the conversation in `messages`.
+    fn accept(&self, messages: &VecDeque<InputMessage>, draft: &Message) -> bool;
+}
+
+#[derive(Clone)]
+pub struct Drafting {
+    /// The model drafting responses, as parsed by the backend of the agent.
+    pub model: String,
+    pub judge: Arc<dyn DraftJudge>,
+}
+
+impl Drafting {
+    pub fn new(model: impl Into<String>, judge: impl DraftJudge) -> Self {
+        Self {
+            model: model.into(),
+            judge: Arc::new(judge),
+        }
+    }
+}
+
+impl std::fmt::Debug for Drafting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Drafting")
+            .field("model", &self.model)
+            .finish()
+    }
+}
+
+/// Which model's response a turn with drafting committed.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum DraftPath {
+    /// The judge accepted the draft.
+    Accepted,
+    /// The draft was rejected or failed, the model of the agent responded instead.
+    Escalated,
+}
+
+/// Accepts complete, non-empty drafts that don't hedge.
+#[derive(Clone, Debug)]
+pub struct ConfidentDraft {
+    /// Phrases that mark a draft as unsure, matched case-insensitively.
+    pub hedges: Vec<String>,
+}
+
+impl Default for ConfidentDraft {
+    fn default() -> Self {
+        Self {
+            hedges: [
+                "i'm not sure",
+                "i am not sure",
+                "i don't know",
+                "i do not know",
+                "i can't",
+                "i cannot",
+                "i'm unable",
+                "i am unable",
+            ]
+            .into_iter()
+            .map(str::to_string)
+            .collect(),
+        }
+    }
+}
+
+impl DraftJudge for ConfidentDraft {
+    fn accept(&self, _messages: &VecDeque<InputMessage>, draft: &Message) -> bool {
+        if !matches!(
+            draft.stop_reason,
+            Some(StopReason::EndTurn | StopReason::StopSequence | StopReason::ToolUse)
+        ) {
+            return false;
+        }
+
+        let mut empty = true;
+        for block in &draft.content {
+            match block {
+                ContentBlock::Text { text } => {
+                    let text = text.to_lowercase();
+                    if self
+                        .hedges
+                        .iter()
+                        .any(|hedge| text.contains(hedge.as_str()))
+                    {
+                        return false;
+                    }
+                    empty &= text.trim().is_empty();
+                }
+                ContentBlock::ToolUse { .. } => empty = false,
+                _ => (),
+            }
+        }
+        !empty
+    }
+}
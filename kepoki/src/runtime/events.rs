@@ -0,0 +1,63 @@
+//! Custom events carry domain-specific signals, such as `deploy_started`, published by tools
+//! onto the event stream of their agent. Consumers interested in a few topics subscribe to them
+//! rather than filtering every event of the runtime.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::runtime::AgentHandle;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CustomEvent {
+    /// The agent on whose behalf the event was published.
+    pub agent: AgentHandle,
+    pub topic: String,
+    pub payload: Value,
+}
+
+/// Delivers custom events to the subscribers of their topic.
+#[derive(Clone, Debug, Default)]
+pub struct EventBus {
+    subscriptions: Arc<Mutex<Vec<Subscription>>>,
+}
+
+#[derive(Debug)]
+struct Subscription {
+    topic: String,
+    sender: UnboundedSender<CustomEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribes to `topic`, or to every topic starting with a prefix followed by `*` such as
+    /// `deploy.*`. Dropping the receiver ends the subscription.
+    pub fn subscribe(&self, topic: impl Into<String>) -> UnboundedReceiver<CustomEvent> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.subscriptions.lock().unwrap().push(Subscription {
+            topic: topic.into(),
+            sender,
+        });
+        receiver
+    }
+
+    pub fn publish(&self, event: CustomEvent) {
+        self.subscriptions.lock().unwrap().retain(|subscription| {
+            let matches = match subscription.topic.strip_suffix('*') {
+                Some(prefix) => event.topic.starts_with(prefix),
+                None => event.topic == subscription.topic,
+            };
+            // Subscriptions whose receiver was dropped are removed.
+            !matches || subscription.sender.send(event.clone()).is_ok()
+        });
+    }
+}
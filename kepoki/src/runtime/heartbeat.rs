@@ -0,0 +1,71 @@
+//! Heartbeats tell hosts that an agent is still working while it emits no other events, such as
+//! before the first delta of a long generation or during a long tool run.
+
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::runtime::agent::AgentEvent;
+
+/// What an agent is busy with.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case", tag = "phase")]
+pub enum TurnPhase {
+    /// Waiting for a request slot, see [`crate::runtime::scheduling`].
+    Queued,
+    /// Waiting for the backend to generate a response.
+    Generating,
+    /// Running a tool.
+    RunningTool { tool: String },
+}
+
+/// Emits [`AgentEvent::Heartbeat`] every interval until dropped.
+#[derive(Debug)]
+pub struct Heartbeat {
+    task: Option<JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    /// Starts beating for `phase`, `None` doesn't emit heartbeats.
+    ///
+    /// Must be called within a tokio runtime.
+    pub fn start(
+        event_emitter: &UnboundedSender<AgentEvent>,
+        phase: TurnPhase,
+        interval: Option<Duration>,
+    ) -> Self {
+        let task = interval
+            .filter(|interval| !interval.is_zero())
+            .map(|interval| {
+                let event_emitter = event_emitter.clone();
+                tokio::runtime::Handle::current().spawn(async move {
+                    let start = Instant::now();
+                    let mut ticks = tokio::time::interval_at(start + interval, interval);
+                    loop {
+                        ticks.tick().await;
+                        let event = AgentEvent::Heartbeat {
+                            phase: phase.clone(),
+                            elapsed: start.elapsed(),
+                        };
+                        if event_emitter.send(event).is_err() {
+                            return;
+                        }
+                    }
+                })
+            });
+        Self { task }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
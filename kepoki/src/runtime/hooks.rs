@@ -0,0 +1,121 @@
+//! Hooks let embedders run Rust code at fixed points of a turn, to inspect what an agent does
+//! and block what breaks their rules without spawning external processes.
+//!
+//! ```ignore
+//! let hooks = Hooks::new().with_hook(HookTrigger::BeforeToolUse, |_: &AgentHandle, event: &HookEvent| {
+//!     match event {
+//!         HookEvent::BeforeToolUse { tool, .. } if tool.starts_with("@payments/") => {
+//!             HookDecision::Block("Payments require a human".to_string())
+//!         }
+//!         _ => HookDecision::Continue,
+//!     }
+//! });
+//! let agent = runtime.spawn_agent_with_hooks(backend, model, definition, hooks);
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::agent::HookTrigger;
+use crate::backend::ContentBlock;
+use crate::backend::Message;
+use crate::runtime::AgentHandle;
+use crate::tools::ToolOutput;
+
+pub type HookFuture<'a> = Pin<Box<dyn Future<Output = HookDecision> + Send + 'a>>;
+
+/// What happened at the trigger point a hook runs at.
+#[derive(Debug)]
+pub enum HookEvent<'a> {
+    /// A user message is about to be added to the history, blocking discards it.
+    UserMessage { content: &'a [ContentBlock] },
+    /// The model called a tool, blocking answers the call with the reason instead of running
+    /// the tool.
+    BeforeToolUse { tool: &'a str, input: &'a str },
+    /// A tool returned, blocking replaces its output with the reason.
+    AfterToolUse {
+        tool: &'a str,
+        input: &'a str,
+        output: &'a ToolOutput,
+    },
+    /// The model completed a response, blocking fails the turn so it can be retried.
+    Response { message: &'a Message },
+}
+
+impl HookEvent<'_> {
+    pub fn trigger(&self) -> HookTrigger {
+        match self {
+            Self::UserMessage { .. } => HookTrigger::UserMessage,
+            Self::BeforeToolUse { .. } => HookTrigger::BeforeToolUse,
+            Self::AfterToolUse { .. } => HookTrigger::AfterToolUse,
+            Self::Response { .. } => HookTrigger::Response,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HookDecision {
+    Continue,
+    /// Blocks what triggered the hook, for the given reason.
+    Block(String),
+}
+
+pub trait AgentHook: Send + Sync + 'static {
+    fn call<'a>(&'a self, agent: &'a AgentHandle, event: &'a HookEvent<'a>) -> HookFuture<'a>;
+}
+
+impl<F> AgentHook for F
+where
+    F: Fn(&AgentHandle, &HookEvent<'_>) -> HookDecision + Send + Sync + 'static,
+{
+    fn call<'a>(&'a self, agent: &'a AgentHandle, event: &'a HookEvent<'a>) -> HookFuture<'a> {
+        let decision = self(agent, event);
+        Box::pin(async move { decision })
+    }
+}
+
+/// The hooks of an agent by the trigger they run at, see [`Runtime::spawn_agent_with_hooks`].
+///
+/// [`Runtime::spawn_agent_with_hooks`]: crate::runtime::Runtime::spawn_agent_with_hooks
+#[derive(Clone, Default)]
+pub struct Hooks {
+    hooks: HashMap<HookTrigger, Vec<Arc<dyn AgentHook>>>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a hook running after the hooks already added for the same trigger.
+    pub fn with_hook(mut self, trigger: HookTrigger, hook: impl AgentHook) -> Self {
+        self.hooks.entry(trigger).or_default().push(Arc::new(hook));
+        self
+    }
+
+    /// Runs the hooks of the trigger of `event` in order, stopping at the first that blocks.
+    pub(crate) async fn run(&self, agent: &AgentHandle, event: &HookEvent<'_>) -> HookDecision {
+        for hook in self.hooks.get(&event.trigger()).into_iter().flatten() {
+            if let HookDecision::Block(reason) = hook.call(agent, event).await {
+                return HookDecision::Block(reason);
+            }
+        }
+
+        HookDecision::Continue
+    }
+}
+
+impl Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(
+                self.hooks
+                    .iter()
+                    .map(|(trigger, hooks)| (trigger, hooks.len())),
+            )
+            .finish()
+    }
+}
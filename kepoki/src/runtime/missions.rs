@@ -0,0 +1,326 @@
+//! Missions are long-running tasks an agent works through step by step, rather than chats.
+//!
+//! A mission plans its goal into steps once, then carries them out one at a time, each step in
+//! a session of its own given the goal, the plan, and the progress log. Missions checkpoint to
+//! disk after every step, so they can be resumed after the process exited, days later if need
+//! be.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::agent::Agent;
+use crate::backend::Backend;
+use crate::error::KepokiError;
+use crate::runtime::builder::RuntimeBuilder;
+use crate::runtime::patterns::run_once;
+use crate::runtime::patterns::strip_code_fence;
+
+/// The number of log entries included when prompting a step.
+const RECENT_LOG_ENTRIES: usize = 20;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Mission {
+    pub goal: String,
+    pub agent: Agent,
+    /// Empty until the mission was planned.
+    pub plan: Vec<PlanStep>,
+    /// Every entry of the progress log, oldest first.
+    pub log: Vec<LogEntry>,
+    /// Where the mission is checkpointed, see [`Mission::with_checkpoint`].
+    #[serde(skip)]
+    checkpoint: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PlanStep {
+    pub description: String,
+    pub done: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct LogEntry {
+    pub at: SystemTime,
+    /// The index of the step in the plan the entry is about, `None` for planning.
+    pub step: Option<usize>,
+    pub text: String,
+}
+
+/// A summary of the progress of a [`Mission`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct MissionProgress {
+    pub completed_steps: usize,
+    /// `0` until the mission was planned.
+    pub total_steps: usize,
+    /// The step carried out next, `None` once the mission is complete or before it was
+    /// planned.
+    pub next_step: Option<String>,
+    /// The time of the latest log entry.
+    pub last_activity: Option<SystemTime>,
+}
+
+impl Mission {
+    pub fn new(goal: impl Into<String>, agent: Agent) -> Self {
+        Self {
+            goal: goal.into(),
+            agent,
+            plan: Vec::new(),
+            log: Vec::new(),
+            checkpoint: None,
+        }
+    }
+
+    /// Checkpoints the mission to `path` after every step.
+    pub fn with_checkpoint(mut self, path: impl Into<PathBuf>) -> Self {
+        self.checkpoint = Some(path.into());
+        self
+    }
+
+    /// Loads a mission from a checkpoint, it continues to checkpoint to the same file.
+    pub async fn resume(path: impl AsRef<Path>) -> Result<Self, KepokiError> {
+        let path = path.as_ref();
+        let mut mission = serde_json::from_slice::<Self>(&tokio::fs::read(path).await?)
+            .map_err(std::io::Error::from)?;
+        mission.checkpoint = Some(path.to_path_buf());
+        Ok(mission)
+    }
+
+    /// Writes the mission to `path`, replacing the file only once it was written completely.
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<(), KepokiError> {
+        let path = path.as_ref();
+        let temporary = path.with_extension("tmp");
+        tokio::fs::write(
+            &temporary,
+            serde_json::to_vec_pretty(self).map_err(std::io::Error::from)?,
+        )
+        .await?;
+        tokio::fs::rename(temporary, path).await?;
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        !self.plan.is_empty() && self.plan.iter().all(|step| step.done)
+    }
+
+    pub fn progress(&self) -> MissionProgress {
+        MissionProgress {
+            completed_steps: self.plan.iter().filter(|step| step.done).count(),
+            total_steps: self.plan.len(),
+            next_step: self
+                .plan
+                .iter()
+                .find(|step| !step.done)
+                .map(|step| step.description.clone()),
+            last_activity: self.log.last().map(|entry| entry.at),
+        }
+    }
+
+    /// Plans the mission if it wasn't yet, then carries out up to `max_steps` steps of the plan.
+    ///
+    /// Every session runs on a runtime built by `runtime`. A step that fails is logged and
+    /// stays pending, advancing the mission again retries it.
+    pub async fn advance<B: Backend + Clone>(
+        &mut self,
+        runtime: &RuntimeBuilder,
+        backend: B,
+        model: B::Model,
+        max_steps: usize,
+    ) -> Result<MissionProgress, KepokiError> {
+        if self.plan.is_empty() {
+            self.plan(runtime, backend.clone(), model.clone()).await?;
+        }
+
+        for _ in 0..max_steps {
+            let Some(index) = self.plan.iter().position(|step| !step.done) else {
+                break;
+            };
+
+            let message = self.step_message(index);
+            let report = run_once(
+                runtime,
+                backend.clone(),
+                model.clone(),
+                self.agent.clone(),
+                message,
+            )
+            .await;
+            match report {
+                Ok(report) => {
+                    self.plan[index].done = true;
+                    self.record(Some(index), report);
+                    self.checkpoint().await?;
+                }
+                Err(err) => {
+                    self.record(Some(index), format!("Failed: {err}"));
+                    self.checkpoint().await?;
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(self.progress())
+    }
+
+    async fn plan<B: Backend>(
+        &mut self,
+        runtime: &RuntimeBuilder,
+        backend: B,
+        model: B::Model,
+    ) -> Result<(), KepokiError> {
+        let response = run_once(
+            runtime,
+            backend,
+            model,
+            self.agent.clone(),
+            format!(
+                "<goal>\n{}\n</goal>\n\nBreak the goal into the steps needed to achieve it. Respond only with a JSON array of strings, one per step, in order.",
+                self.goal
+            ),
+        )
+        .await?;
+
+        let steps = serde_json::from_str::<Vec<String>>(strip_code_fence(&response))
+            .map_err(|err| KepokiError::InvalidPlan(err.to_string()))?;
+        if steps.is_empty() {
+            return Err(KepokiError::InvalidPlan(
+                "The plan has no steps".to_string(),
+            ));
+        }
+
+        self.plan = steps
+            .into_iter()
+            .map(|description| PlanStep {
+                description,
+                done: false,
+            })
+            .collect();
+        self.record(None, format!("Planned {} steps", self.plan.len()));
+        self.checkpoint().await
+    }
+
+    fn step_message(&self, index: usize) -> String {
+        let plan = self
+            .plan
+            .iter()
+            .enumerate()
+            .map(|(number, step)| {
+                let mark = if step.done { "x" } else { " " };
+                format!("{}. [{mark}] {}", number + 1, step.description)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let log = self
+            .log
+            .iter()
+            .skip(self.log.len().saturating_sub(RECENT_LOG_ENTRIES))
+            .map(|entry| match entry.step {
+                Some(step) => format!("Step {}: {}", step + 1, entry.text),
+                None => entry.text.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        format!(
+            "<goal>\n{}\n</goal>\n\n<plan>\n{plan}\n</plan>\n\n<progress_log>\n{log}\n</progress_log>\n\nCarry out step {}: {}\n\nWhen you are done, report what you did and anything the next steps need to know.",
+            self.goal,
+            index + 1,
+            self.plan[index].description
+        )
+    }
+
+    fn record(&mut self, step: Option<usize>, text: String) {
+        self.log.push(LogEntry {
+            at: SystemTime::now(),
+            step,
+            text,
+        });
+    }
+
+    async fn checkpoint(&self) -> Result<(), KepokiError> {
+        match &self.checkpoint {
+            Some(path) => self.save(path).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ContentBlock;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_advance_and_resume() {
+        let path =
+            std::env::temp_dir().join(format!("kepoki-mission-{}.json", uuid::Uuid::new_v4()));
+        let backend = MockBackend::new()
+            .with_response(MockResponse::text(
+                "```json\n[\"Fetch\", \"Summarize\"]\n```",
+            ))
+            .with_response(MockResponse::text("Fetched 3 pages"))
+            .with_response(MockResponse::error(KepokiError::CustomError(
+                "Unavailable".into(),
+            )));
+        let mut mission =
+            Mission::new("Summarize the news", Agent::default()).with_checkpoint(&path);
+
+        let result = mission
+            .advance(
+                &RuntimeBuilder::new(),
+                backend.clone(),
+                "mock".to_string(),
+                5,
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(mission.progress().completed_steps, 1);
+        assert_eq!(mission.progress().next_step.as_deref(), Some("Summarize"));
+        assert_eq!(mission.log.len(), 3);
+
+        let mut mission = Mission::resume(&path).await.unwrap();
+        assert_eq!(mission.log.len(), 3);
+        backend.push_response(MockResponse::text("Summary written"));
+        let progress = mission
+            .advance(
+                &RuntimeBuilder::new(),
+                backend.clone(),
+                "mock".to_string(),
+                5,
+            )
+            .await
+            .unwrap();
+        assert!(mission.is_complete());
+        assert_eq!((progress.completed_steps, progress.total_steps), (2, 2));
+        assert_eq!(Mission::resume(&path).await.unwrap().log.len(), 4);
+
+        let requests = backend.requests();
+        let Some(ContentBlock::Text { text }) = requests[3].messages[0].content.first() else {
+            panic!("Expected the step message");
+        };
+        assert!(text.contains("1. [x] Fetch\n2. [ ] Summarize"));
+        assert!(text.contains("Step 1: Fetched 3 pages"));
+        assert!(text.contains("Carry out step 2: Summarize"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_invalid_plan() {
+        let backend = MockBackend::new().with_response(MockResponse::text("First, fetch."));
+        let mut mission = Mission::new("Summarize the news", Agent::default());
+
+        let result = mission
+            .advance(&RuntimeBuilder::new(), backend, "mock".to_string(), 5)
+            .await;
+        assert!(matches!(result, Err(KepokiError::InvalidPlan(_))));
+        assert!(mission.plan.is_empty());
+    }
+}
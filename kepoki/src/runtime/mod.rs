@@ -1,9 +1,34 @@
 pub mod agent;
+pub mod builder;
+pub mod cancellation;
+pub mod commits;
+pub mod control;
+pub mod drafting;
+pub mod events;
+pub mod heartbeat;
+pub mod hooks;
+pub mod missions;
+pub mod notifications;
+pub mod patterns;
+pub mod permissions;
+pub mod quotas;
+pub mod recovery;
+pub mod replay;
+pub mod retention;
+pub mod review;
+pub mod sampling;
+pub mod scheduling;
+pub mod summary;
+pub mod synthetic;
+pub mod turns;
+pub mod watchdog;
+pub mod workers;
 
 use std::collections::HashMap;
-use std::collections::VecDeque;
 use std::fmt::Display;
 use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 
 use serde::Deserialize;
 use serde::Serialize;
@@ -13,11 +38,52 @@ use tokio::sync::mpsc::UnboundedSender;
 use tokio::task::JoinSet;
 use uuid::Uuid;
 
+use crate::agent::ToolName;
+use crate::artifacts::Artifact;
+use crate::artifacts::ArtifactStore;
 use crate::backend::Backend;
+use crate::backend::InputMessage;
+use crate::blackboard::Blackboard;
+use crate::context::ContextProviders;
 use crate::error::KepokiError;
+use crate::models::ModelRegistry;
+use crate::output::OutputProcessors;
+use crate::policy::PolicyEngine;
 use crate::runtime::agent::AgentCommand;
 use crate::runtime::agent::AgentEvent;
 use crate::runtime::agent::AgentState;
+use crate::runtime::agent::TurnOverrides;
+use crate::runtime::builder::RuntimeBuilder;
+use crate::runtime::cancellation::CancellationToken;
+use crate::runtime::drafting::Drafting;
+use crate::runtime::events::CustomEvent;
+use crate::runtime::events::EventBus;
+use crate::runtime::hooks::Hooks;
+use crate::runtime::notifications::Notifications;
+use crate::runtime::permissions::CommandRole;
+use crate::runtime::quotas::Quota;
+use crate::runtime::quotas::QuotaScope;
+use crate::runtime::quotas::QuotaUsage;
+use crate::runtime::quotas::Quotas;
+use crate::runtime::recovery::ErrorHandler;
+use crate::runtime::recovery::ErrorHandlers;
+use crate::runtime::replay::Replay;
+use crate::runtime::retention::Retention;
+use crate::runtime::sampling::BestOf;
+use crate::runtime::scheduling::RequestScheduler;
+use crate::runtime::turns::Comparison;
+use crate::runtime::turns::StateDiff;
+use crate::runtime::turns::TurnLog;
+use crate::runtime::watchdog::Progress;
+use crate::runtime::watchdog::Watchdog;
+use crate::runtime::watchdog::Watched;
+use crate::runtime::watchdog::WatchedAgents;
+use crate::sandbox::Isolation;
+use crate::servers::McpServers;
+use crate::tools::ToolRegistry;
+use crate::tools::selection::ToolSelector;
+use crate::tools::stats::ToolStat;
+use crate::tools::stats::ToolStats;
 
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
@@ -26,13 +92,34 @@ pub struct AgentHandle {
     uuid: [u8; 16],
 }
 
+impl AgentHandle {
+    /// The name of the agent definition the agent was spawned from.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The id distinguishing the agent from other agents spawned from the same definition.
+    pub fn id(&self) -> Uuid {
+        Uuid::from_bytes(self.uuid)
+    }
+
+    /// A handle of an agent that was never spawned, for tests of components keyed by agent.
+    #[cfg(test)]
+    pub(crate) fn detached(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            uuid: *Uuid::new_v4().as_bytes(),
+        }
+    }
+}
+
 impl Display for AgentHandle {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.name)
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 #[allow(clippy::type_complexity)] // Private API so allowed.
 pub struct Runtime {
     thread_join_set: JoinSet<(AgentHandle, Result<ExitCode, KepokiError>)>,
@@ -40,16 +127,168 @@ pub struct Runtime {
         AgentHandle,
         Option<(UnboundedReceiver<AgentEvent>, AgentEvent)>,
     )>,
-    command_emitters: HashMap<AgentHandle, UnboundedSender<AgentCommand>>,
+    command_emitters:
+        HashMap<AgentHandle, UnboundedSender<(AgentCommand, Option<CancellationToken>)>>,
+    cancellations: HashMap<AgentHandle, CancellationToken>,
+    tools: ToolRegistry,
+    context_providers: ContextProviders,
+    output_processors: OutputProcessors,
+    artifacts: ArtifactStore,
+    blackboard: Blackboard,
+    event_bus: EventBus,
+    tool_timeout: Duration,
+    heartbeat_interval: Option<Duration>,
+    dry_run: bool,
+    tool_stats: ToolStats,
+    mcp_idle_timeout: Option<Duration>,
+    shared_mcp_servers: Option<McpServers>,
+    user_id: Option<String>,
+    command_roles: HashMap<String, CommandRole>,
+    error_handlers: ErrorHandlers,
+    policy: Option<Arc<dyn PolicyEngine>>,
+    tenant: Option<String>,
+    credential: Option<String>,
+    quotas: Quotas,
+    scheduler: RequestScheduler,
+    models: ModelRegistry,
+    drafting: Option<Drafting>,
+    best_of: Option<BestOf>,
+    turn_log: TurnLog,
+    tool_selector: ToolSelector,
+    retention: Retention,
+    purge_job: JoinSet<()>,
+    watched: WatchedAgents,
+    watchdog_job: JoinSet<()>,
+    notifications: Option<Notifications>,
 }
 
 impl Runtime {
-    pub fn new() -> Self {
-        Self {
-            thread_join_set: JoinSet::new(),
-            recv_join_set: JoinSet::new(),
-            command_emitters: HashMap::new(),
+    /// A builder of a runtime, `Runtime::builder().build()` is a runtime with the default
+    /// components.
+    pub fn builder() -> RuntimeBuilder {
+        RuntimeBuilder::new()
+    }
+
+    /// What the runtime knows about models, used for the default response length and the
+    /// spend counted against quotas.
+    pub fn models(&self) -> &ModelRegistry {
+        &self.models
+    }
+
+    /// Sets the error handler of a single agent, taking precedence over the runtime's handler.
+    pub fn set_agent_error_handler(
+        &mut self,
+        agent: &AgentHandle,
+        handler: Option<impl ErrorHandler>,
+    ) {
+        self.error_handlers
+            .set_agent(agent, handler.map(|handler| Arc::new(handler) as _));
+    }
+
+    /// Sets the daily quota of a tenant or credential, `None` removes it.
+    ///
+    /// Applies to running agents as well. Requests of agents whose tenant or credential
+    /// exhausted its quota fail with [`KepokiError::QuotaExceeded`] until the next UTC day.
+    pub fn set_quota(&mut self, scope: QuotaScope, quota: Option<Quota>) {
+        self.quotas.set(scope, quota);
+    }
+
+    /// Limits how many backend requests all agents send at once, `None` doesn't limit them.
+    ///
+    /// Applies to running agents as well. Once the limit is reached, turns are admitted by the
+    /// [`Priority`](crate::agent::Priority) of their agent, see [`scheduling`].
+    pub fn set_max_concurrent_requests(&mut self, limit: Option<usize>) {
+        self.scheduler.set_limit(limit);
+    }
+
+    /// The usage of today of every tenant and credential with a quota.
+    pub fn quota_usage(&self) -> HashMap<QuotaScope, QuotaUsage> {
+        self.quotas.snapshot()
+    }
+
+    /// Sets how long data about agents is kept, purging turn records older than
+    /// [`Retention::max_age`] right away and periodically afterwards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime with a `max_age`, which starts the purge.
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.turn_log.set_enabled(retention.record_turns);
+        self.purge_job.abort_all();
+        if let Some(max_age) = retention.max_age {
+            self.purge_job
+                .spawn(retention::purge_job(self.turn_log.clone(), max_age));
         }
+        self.retention = retention;
+    }
+
+    /// Watches running agents and agents spawned later for stalls, `None` stops watching.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime with a watchdog, which starts watching.
+    pub fn set_watchdog(&mut self, watchdog: Option<Watchdog>) {
+        self.watchdog_job.abort_all();
+        if let Some(watchdog) = watchdog {
+            self.watchdog_job
+                .spawn(watchdog::watch(self.watched.clone(), watchdog));
+        }
+    }
+
+    /// Call counts, latencies, error rates, and result sizes for every tool called so far.
+    pub fn tool_stats(&self) -> HashMap<ToolName, ToolStat> {
+        self.tool_stats.snapshot()
+    }
+
+    /// The builtin tools available to agents spawned on the runtime.
+    pub fn tools(&self) -> &ToolRegistry {
+        &self.tools
+    }
+
+    /// The artifacts created by an agent, sorted by name.
+    pub fn artifacts(&self, agent: &AgentHandle) -> Vec<Artifact> {
+        self.artifacts.list(agent)
+    }
+
+    pub fn artifact(&self, agent: &AgentHandle, name: &str) -> Option<Artifact> {
+        self.artifacts.get(agent, name)
+    }
+
+    /// The blackboard shared by the agents of the runtime, for the host to seed, inspect, and
+    /// set permissions on.
+    pub fn blackboard(&self) -> &Blackboard {
+        &self.blackboard
+    }
+
+    /// Receives the custom events published by tools on behalf of any agent of the runtime,
+    /// see [`EventBus::subscribe`] for the topics that can be subscribed to.
+    ///
+    /// Custom events are also emitted on the event stream of their agent.
+    pub fn subscribe(&self, topic: impl Into<String>) -> UnboundedReceiver<CustomEvent> {
+        self.event_bus.subscribe(topic)
+    }
+
+    /// Diffs the state of an agent after two of its turns, turn 0 being its initial state.
+    ///
+    /// Returns `None` if either turn hasn't happened yet or `turn_a` comes after `turn_b`.
+    pub fn state_diff(
+        &self,
+        agent: &AgentHandle,
+        turn_a: usize,
+        turn_b: usize,
+    ) -> Option<StateDiff> {
+        self.turn_log.diff(agent, turn_a, turn_b)
+    }
+
+    /// Compares the responses of two agents turn by turn, such as to compare prompts or models
+    /// on the same inputs, see [`TurnLog::compare`].
+    pub fn compare_agents(&self, a: &AgentHandle, b: &AgentHandle) -> Comparison {
+        self.turn_log.compare(a, b)
+    }
+
+    /// The number of turns an agent completed so far.
+    pub fn turn_count(&self, agent: &AgentHandle) -> usize {
+        self.turn_log.len(agent)
     }
 
     pub fn spawn_agent<B: Backend>(
@@ -57,9 +296,115 @@ impl Runtime {
         backend: B,
         model: B::Model,
         agent: crate::agent::Agent,
+    ) -> AgentHandle {
+        self.spawn(
+            backend,
+            model,
+            AgentState::new(agent),
+            Hooks::default(),
+            None,
+            CancellationToken::new(),
+        )
+    }
+
+    /// Spawns an agent that is terminated once `cancellation` is cancelled, such as the token of
+    /// the request the agent serves.
+    ///
+    /// The agent runs on a child of the token, terminating the agent doesn't cancel it.
+    pub fn spawn_agent_with_cancellation<B: Backend>(
+        &mut self,
+        backend: B,
+        model: B::Model,
+        agent: crate::agent::Agent,
+        cancellation: &CancellationToken,
+    ) -> AgentHandle {
+        self.spawn(
+            backend,
+            model,
+            AgentState::new(agent),
+            Hooks::default(),
+            None,
+            cancellation.child_token(),
+        )
+    }
+
+    /// The token terminating an agent once cancelled, `None` if the agent completed.
+    pub fn cancellation_token(&self, agent: &AgentHandle) -> Option<CancellationToken> {
+        self.cancellations.get(agent).cloned()
+    }
+
+    /// Spawns an agent continuing a conversation, such as one dumped by
+    /// [`AgentCommand::DumpState`] or loaded by [`crate::import`].
+    ///
+    /// The agent responds right away if the last message of the conversation is from the user
+    /// and the state isn't paused.
+    pub fn spawn_agent_with_state<B: Backend>(
+        &mut self,
+        backend: B,
+        model: B::Model,
+        state: AgentState,
+    ) -> AgentHandle {
+        self.spawn(
+            backend,
+            model,
+            state,
+            Hooks::default(),
+            None,
+            CancellationToken::new(),
+        )
+    }
+
+    /// Spawns an agent that runs `hooks` at their trigger points.
+    pub fn spawn_agent_with_hooks<B: Backend>(
+        &mut self,
+        backend: B,
+        model: B::Model,
+        agent: crate::agent::Agent,
+        hooks: Hooks,
+    ) -> AgentHandle {
+        self.spawn(
+            backend,
+            model,
+            AgentState::new(agent),
+            hooks,
+            None,
+            CancellationToken::new(),
+        )
+    }
+
+    /// Spawns an agent that replays the user messages of a recorded transcript, answering tool
+    /// calls with the recorded results instead of executing them.
+    ///
+    /// Responses that differ from the recording are emitted as [`AgentEvent::ReplayDiverged`].
+    /// The agent completes once every user message was replayed.
+    pub fn spawn_replay<B: Backend>(
+        &mut self,
+        backend: B,
+        model: B::Model,
+        agent: crate::agent::Agent,
+        transcript: impl IntoIterator<Item = InputMessage>,
+    ) -> AgentHandle {
+        self.spawn(
+            backend,
+            model,
+            AgentState::new(agent),
+            Hooks::default(),
+            Some(Replay::new(transcript)),
+            CancellationToken::new(),
+        )
+    }
+
+    fn spawn<B: Backend>(
+        &mut self,
+        backend: B,
+        model: B::Model,
+        state: AgentState,
+        hooks: Hooks,
+        replay: Option<Replay>,
+        cancellation: CancellationToken,
     ) -> AgentHandle {
         let agent_handle = AgentHandle {
-            name: agent.name.clone(),
+            name: state.definition.name.clone(),
             uuid: Uuid::new_v4().into_bytes(),
         };
 
@@ -67,20 +412,83 @@ impl Runtime {
         let (event_emitter, mut event_receiver) = tokio::sync::mpsc::unbounded_channel();
 
         let handle = agent_handle.clone();
-        let join_handle = tokio::runtime::Handle::current().spawn_blocking(|| {
+        let tools = self.tools.clone();
+        let context_providers = self.context_providers.clone();
+        let output_processors = self.output_processors.clone();
+        let tool_timeout = self.tool_timeout;
+        let heartbeat_interval = self.heartbeat_interval;
+        let dry_run = self.dry_run;
+        let tool_stats = self.tool_stats.clone();
+        let user_id = self.user_id.clone();
+        let error_handlers = self.error_handlers.clone();
+        let policy = self.policy.clone();
+        let tenant = self.tenant.clone();
+        let credential = self.credential.clone();
+        let quotas = self.quotas.clone();
+        let scheduler = self.scheduler.clone();
+        let progress = Progress::new();
+        let watched_progress = progress.clone();
+        let watched_event_emitter = event_emitter.downgrade();
+        let agent_cancellation = cancellation.clone();
+        let models = self.models.clone();
+        let drafting = self.drafting.clone();
+        let best_of = self.best_of.clone();
+        let artifacts = self.artifacts.clone();
+        let event_bus = self.event_bus.clone();
+        let turn_log = self.turn_log.clone();
+        let tool_selector = self.tool_selector.clone();
+        let notifications = self.notifications.clone();
+        // Isolated agents get servers of their own, confined to their working directory.
+        let isolation = Isolation::of(&state.definition);
+        let mcp_servers = match (isolation, &self.shared_mcp_servers) {
+            (None, Some(shared_mcp_servers)) => shared_mcp_servers.clone(),
+            (isolation, _) => McpServers::new()
+                .with_idle_timeout(self.mcp_idle_timeout)
+                .with_isolation(isolation),
+        };
+        let join_handle = tokio::runtime::Handle::current().spawn(async move {
             agent::Agent {
                 backend,
                 model,
                 handle,
                 command_receiver,
+                cancellation,
+                turn_cancellation: None,
                 event_emitter,
-                state: AgentState {
-                    definition: agent,
-                    messages: VecDeque::new(),
-                    paused: false,
-                },
+                tools,
+                context_providers,
+                output_processors,
+                hooks,
+                mcp_servers,
+                heartbeat_interval,
+                tool_timeout,
+                dry_run,
+                tool_stats,
+                error_handlers,
+                policy,
+                tenant,
+                credential,
+                quotas,
+                scheduler,
+                progress,
+                models,
+                drafting,
+                best_of,
+                user_id,
+                artifacts,
+                event_bus,
+                turn_log,
+                tool_selector,
+                notifications,
+                recorded_messages: state.messages.len(),
+                replay,
+                turn_overrides: TurnOverrides::default(),
+                context_files: Vec::new(),
+                provided_context: Vec::new(),
+                state,
             }
             .run()
+            .await
         });
 
         let handle = agent_handle.clone();
@@ -99,21 +507,99 @@ impl Runtime {
             }
         });
 
+        self.watched.lock().unwrap().insert(
+            agent_handle.clone(),
+            Watched {
+                progress: watched_progress,
+                event_emitter: watched_event_emitter,
+                command_emitter: command_emitter.downgrade(),
+                cancellation: agent_cancellation.clone(),
+            },
+        );
         self.command_emitters
             .insert(agent_handle.clone(), command_emitter);
+        self.cancellations
+            .insert(agent_handle.clone(), agent_cancellation);
 
         agent_handle
     }
 
-    pub fn send(&mut self, agent: &AgentHandle, command: AgentCommand) -> Result<(), KepokiError> {
-        // Intercept runtime commands
-        if matches!(command, AgentCommand::Terminate) {
-            todo!()
+    /// The agents of the runtime that haven't been received as completed yet.
+    pub fn agents(&self) -> Vec<AgentHandle> {
+        self.command_emitters.keys().cloned().collect()
+    }
+
+    /// Assigns a role to a named command source, replacing its previous role.
+    pub fn set_command_role(&mut self, source: impl Into<String>, role: CommandRole) {
+        self.command_roles.insert(source.into(), role);
+    }
+
+    /// Removes the role of a command source, it can no longer send commands afterwards.
+    pub fn remove_command_role(&mut self, source: &str) {
+        self.command_roles.remove(source);
+    }
+
+    /// Sends a command on behalf of a command source, checking that its role allows it.
+    ///
+    /// This is the only way to send commands for anyone but the embedder, such as HTTP
+    /// clients, connectors, or other agents. Sources without a role may not send any commands.
+    pub fn send_from(
+        &mut self,
+        source: &str,
+        agent: &AgentHandle,
+        command: AgentCommand,
+    ) -> Result<(), KepokiError> {
+        match self.command_roles.get(source) {
+            Some(role) if role.allows(&command) => self.send_command(agent, command, None),
+            _ => {
+                tracing::warn!("Command source {source} denied sending command to {agent}");
+                Err(KepokiError::PermissionDenied(source.to_string()))
+            }
+        }
+    }
+
+    /// Sends a command as the embedder of the runtime, which owns it and may send any command.
+    ///
+    /// No role is checked, never call it with commands received from elsewhere, see
+    /// [`Runtime::send_from`].
+    pub fn send_as_owner(
+        &mut self,
+        agent: &AgentHandle,
+        command: AgentCommand,
+    ) -> Result<(), KepokiError> {
+        self.send_command(agent, command, None)
+    }
+
+    /// Sends a command as the embedder with a token cancelling the turn it starts, or the turn
+    /// in progress if the agent is busy. See [`cancellation`] for what cancelling it does.
+    ///
+    /// Like [`Runtime::send_as_owner`], no role is checked.
+    pub fn send_with_cancellation(
+        &mut self,
+        agent: &AgentHandle,
+        command: AgentCommand,
+        cancellation: CancellationToken,
+    ) -> Result<(), KepokiError> {
+        self.send_command(agent, command, Some(cancellation))
+    }
+
+    fn send_command(
+        &mut self,
+        agent: &AgentHandle,
+        command: AgentCommand,
+        cancellation: Option<CancellationToken>,
+    ) -> Result<(), KepokiError> {
+        // Terminating cancels the agent, abandoning its request or tool call in progress.
+        if matches!(command, AgentCommand::Terminate)
+            && let Some(token) = self.cancellations.get(agent)
+        {
+            token.cancel();
+            return Ok(());
         }
 
         match self.command_emitters.get(agent) {
             Some(emitter) => emitter
-                .send(command)
+                .send((command, cancellation))
                 .map_err(|_| KepokiError::AgentNotFound(agent.clone())),
             None => {
                 tracing::error!("No command emitter found for agent: {:?}", agent);
@@ -130,6 +616,13 @@ impl Runtime {
         let (handle, output) = select! {
             join = self.thread_join_set.join_next(), if !self.thread_join_set.is_empty() => {
                 let (agent, result) = join.transpose()?.unwrap();
+                self.watched.lock().unwrap().remove(&agent);
+                self.cancellations.remove(&agent);
+                self.command_emitters.remove(&agent);
+                if self.retention.drop_on_exit {
+                    self.artifacts.remove_agent(&agent);
+                    self.turn_log.remove_agent(&agent);
+                }
                 return Ok(match result {
                     Ok(_) => AgentEvent::Completed(agent),
                     Err(err) => AgentEvent::Terminated(err.to_string()),
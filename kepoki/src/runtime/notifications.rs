@@ -0,0 +1,310 @@
+//! Notifies a human of agents that need their attention while the runtime runs unattended,
+//! such as when an agent finishes a long turn or is paused after a failed turn.
+//!
+//! ```ignore
+//! let notifications = Notifications::new(WebhookNotifier::new("https://hooks.example.com/kepoki"))
+//!     .with_min_turn_duration(Duration::from_secs(300));
+//! let runtime = Runtime::builder().with_notifications(notifications).build();
+//! ```
+//!
+//! Notifications are sent in the background, agents don't wait for the notifier. Desktop
+//! notifications require the `desktop-notifications` feature, webhooks the
+//! `webhook-notifications` feature.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::runtime::AgentHandle;
+
+pub type NotifyFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Notification {
+    pub agent: AgentHandle,
+    #[serde(flatten)]
+    pub kind: NotificationKind,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// The agent ended a turn that took at least [`Notifications::min_turn_duration`].
+    TurnFinished { duration: Duration },
+    /// A turn failed and the error handler paused the agent, it waits to be unpaused.
+    Paused { error: String },
+    /// A request of the agent exceeded a quota, sent on the first failed attempt of a turn.
+    QuotaExceeded { error: String },
+}
+
+impl Display for Notification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            NotificationKind::TurnFinished { duration } => write!(
+                f,
+                "{} finished its turn after {}s",
+                self.agent,
+                duration.as_secs()
+            ),
+            NotificationKind::Paused { error } => {
+                write!(f, "{} was paused after a failed turn: {error}", self.agent)
+            }
+            NotificationKind::QuotaExceeded { error } => {
+                write!(f, "{} exceeded its quota: {error}", self.agent)
+            }
+        }
+    }
+}
+
+pub trait Notifier: Send + Sync + 'static {
+    /// Delivers the notification, reporting failures itself since nobody waits for it.
+    fn notify<'a>(&'a self, notification: &'a Notification) -> NotifyFuture<'a>;
+}
+
+impl std::fmt::Debug for dyn Notifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Notifier")
+    }
+}
+
+impl<F> Notifier for F
+where
+    F: Fn(&Notification) + Send + Sync + 'static,
+{
+    fn notify<'a>(&'a self, notification: &'a Notification) -> NotifyFuture<'a> {
+        self(notification);
+        Box::pin(async {})
+    }
+}
+
+/// The notifier of a runtime and when it is notified, see
+/// [`RuntimeBuilder::with_notifications`](crate::runtime::builder::RuntimeBuilder::with_notifications).
+#[derive(Clone, Debug)]
+pub struct Notifications {
+    notifier: Arc<dyn Notifier>,
+    /// Turns ending sooner don't notify, so that interactive turns don't.
+    pub min_turn_duration: Duration,
+}
+
+impl Notifications {
+    /// Notifies of turns that took at least a minute.
+    pub fn new(notifier: impl Notifier) -> Self {
+        Self {
+            notifier: Arc::new(notifier),
+            min_turn_duration: Duration::from_secs(60),
+        }
+    }
+
+    pub fn with_min_turn_duration(mut self, min_turn_duration: Duration) -> Self {
+        self.min_turn_duration = min_turn_duration;
+        self
+    }
+
+    pub(crate) fn send(&self, notification: Notification) {
+        let notifier = self.notifier.clone();
+        tokio::spawn(async move { notifier.notify(&notification).await });
+    }
+}
+
+/// Shows notifications on the desktop of the user running the runtime.
+#[cfg(feature = "desktop-notifications")]
+#[derive(Clone, Debug)]
+pub struct DesktopNotifier {
+    summary: String,
+}
+
+#[cfg(feature = "desktop-notifications")]
+impl Default for DesktopNotifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "desktop-notifications")]
+impl DesktopNotifier {
+    /// Titles notifications `kepoki`.
+    pub fn new() -> Self {
+        Self {
+            summary: "kepoki".to_string(),
+        }
+    }
+
+    pub fn with_summary(mut self, summary: impl Into<String>) -> Self {
+        self.summary = summary.into();
+        self
+    }
+}
+
+#[cfg(feature = "desktop-notifications")]
+impl Notifier for DesktopNotifier {
+    fn notify<'a>(&'a self, notification: &'a Notification) -> NotifyFuture<'a> {
+        let summary = self.summary.clone();
+        let body = notification.to_string();
+        Box::pin(async move {
+            // Showing a notification blocks on the notification service of the system.
+            let shown = tokio::task::spawn_blocking(move || {
+                notify_rust::Notification::new()
+                    .summary(&summary)
+                    .body(&body)
+                    .show()
+                    .map(|_| ())
+            })
+            .await;
+            match shown {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => tracing::warn!("Failed to show notification: {err}"),
+                Err(err) => tracing::warn!("Failed to show notification: {err}"),
+            }
+        })
+    }
+}
+
+/// Posts notifications as JSON to a URL, with the fields of [`Notification`] and a `text`
+/// describing it, which chat services such as Slack display.
+#[cfg(feature = "webhook-notifications")]
+#[derive(Clone, Debug)]
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[cfg(feature = "webhook-notifications")]
+impl WebhookNotifier {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.into(),
+        }
+    }
+}
+
+#[cfg(feature = "webhook-notifications")]
+impl Notifier for WebhookNotifier {
+    fn notify<'a>(&'a self, notification: &'a Notification) -> NotifyFuture<'a> {
+        Box::pin(async move {
+            let mut body = match serde_json::to_value(notification) {
+                Ok(body) => body,
+                Err(err) => {
+                    tracing::warn!("Failed to serialize notification: {err}");
+                    return;
+                }
+            };
+            body["text"] = notification.to_string().into();
+            let sent = self
+                .client
+                .post(&self.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            if let Err(err) = sent {
+                tracing::warn!("Failed to post notification to {}: {err}", self.url);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::agent::Agent;
+    use crate::error::KepokiError;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+    use crate::runtime::Runtime;
+    use crate::runtime::agent::AgentCommand;
+    use crate::runtime::agent::AgentEvent;
+    use crate::runtime::recovery::ErrorHandler;
+    use crate::runtime::recovery::ErrorHandlerFuture;
+    use crate::runtime::recovery::ErrorRecovery;
+    use crate::runtime::recovery::TurnFailure;
+
+    struct PauseHandler;
+
+    impl ErrorHandler for PauseHandler {
+        fn handle<'a>(&'a self, _failure: &'a TurnFailure) -> ErrorHandlerFuture<'a> {
+            Box::pin(async { ErrorRecovery::Pause })
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_notify_finished_paused_and_quota() {
+        let backend = MockBackend::new()
+            .with_response(MockResponse::text("Done"))
+            .with_response(MockResponse::error(KepokiError::QuotaExceeded {
+                scope: "agent".to_string(),
+                metric: "tokens".to_string(),
+                limit: 100.0,
+            }));
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut runtime = Runtime::builder()
+            .with_error_handler(PauseHandler)
+            .with_notifications(
+                Notifications::new({
+                    let sent = sent.clone();
+                    move |notification: &Notification| {
+                        sent.lock().unwrap().push(notification.to_string());
+                    }
+                })
+                .with_min_turn_duration(Duration::ZERO),
+            )
+            .build();
+        let agent = Agent {
+            name: "coder".to_string(),
+            ..Default::default()
+        };
+        let agent = runtime.spawn_agent(backend, "mock".to_string(), agent);
+
+        runtime
+            .send_as_owner(&agent, AgentCommand::UserMessage("Hi".to_string()))
+            .unwrap();
+        while !matches!(runtime.recv().await.unwrap(), AgentEvent::Message(_)) {}
+        runtime
+            .send_as_owner(&agent, AgentCommand::UserMessage("Again".to_string()))
+            .unwrap();
+        while !matches!(runtime.recv().await.unwrap(), AgentEvent::TurnFailed { .. }) {}
+
+        // Notifications are sent in the background.
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while sent.lock().unwrap().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let mut sent = sent.lock().unwrap().clone();
+        sent.sort();
+        assert_eq!(
+            sent,
+            [
+                "coder exceeded its quota: Daily tokens quota of agent exhausted, the limit is 100",
+                "coder finished its turn after 0s",
+                "coder was paused after a failed turn: Daily tokens quota of agent exhausted, the limit is 100",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_serialize_notification() {
+        let notification = Notification {
+            agent: AgentHandle {
+                name: "coder".to_string(),
+                uuid: [0; 16],
+            },
+            kind: NotificationKind::Paused {
+                error: "Overloaded".to_string(),
+            },
+        };
+        let value = serde_json::to_value(&notification).unwrap();
+        assert_eq!(value["kind"], "paused");
+        assert_eq!(value["error"], "Overloaded");
+        assert_eq!(value["agent"]["name"], "coder");
+    }
+}
@@ -0,0 +1,797 @@
+//! Orchestration patterns built from several agents.
+//!
+//! Every pattern runs its agents on runtimes of its own built by the given builder, so their
+//! events don't mix with those of other agents while the settings of the caller, such as its
+//! policy, quotas, and tools, still apply. Patterns return once they completed.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::task::JoinSet;
+
+use crate::agent::Agent;
+use crate::backend::Backend;
+use crate::backend::ContentBlock;
+use crate::error::KepokiError;
+use crate::runtime::AgentHandle;
+use crate::runtime::Runtime;
+use crate::runtime::agent::AgentCommand;
+use crate::runtime::agent::AgentEvent;
+use crate::runtime::builder::RuntimeBuilder;
+
+/// The first line of a review accepting the response.
+const APPROVED: &str = "APPROVED";
+
+/// The outcome of [`reflect`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Reflection {
+    /// The last revision of the response.
+    pub response: String,
+    /// Whether the critic approved the response, `false` if the revision limit was reached.
+    pub approved: bool,
+    /// Every review of the critic, in order.
+    pub reviews: Vec<String>,
+}
+
+/// Has a critic review the response of `agent` to `input` against the agent's review criteria,
+/// letting the agent revise its response up to `max_revisions` times until the critic approves.
+pub async fn reflect<B: Backend + Clone>(
+    runtime: &RuntimeBuilder,
+    backend: B,
+    model: B::Model,
+    agent: Agent,
+    input: String,
+    max_revisions: usize,
+) -> Result<Reflection, KepokiError> {
+    let mut runtime = runtime.build();
+    let critic = runtime.spawn_agent(backend.clone(), model.clone(), critic(&agent));
+    let actor = runtime.spawn_agent(backend, model, agent);
+
+    let result = async {
+        let mut response = respond(&mut runtime, &actor, input.clone()).await?;
+        let mut reviews = Vec::new();
+        loop {
+            let review = respond(
+                &mut runtime,
+                &critic,
+                format!("<task>\n{input}\n</task>\n\n<response>\n{response}\n</response>"),
+            )
+            .await?;
+            let approved = review.trim_start().starts_with(APPROVED);
+            reviews.push(review);
+            if approved || reviews.len() > max_revisions {
+                return Ok(Reflection {
+                    response,
+                    approved,
+                    reviews,
+                });
+            }
+
+            response = respond(
+                &mut runtime,
+                &actor,
+                format!(
+                    "A reviewer found problems with your response:\n\n{}\n\nRespond again with the complete revised response.",
+                    reviews.last().unwrap()
+                ),
+            )
+            .await?;
+        }
+    }
+    .await;
+
+    shutdown(&mut runtime, &[critic, actor]).await;
+    result
+}
+
+/// The outcome of [`map_reduce`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct MapReduce {
+    /// The response of the reducer.
+    pub output: String,
+    /// The response of a worker to each chunk, `None` for chunks whose worker failed.
+    pub mapped: Vec<Option<String>>,
+    /// The chunks whose worker failed, they are left out of the reduction.
+    pub failures: Vec<ChunkFailure>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ChunkFailure {
+    /// The position of the chunk in the input.
+    pub index: usize,
+    pub error: String,
+}
+
+/// Sends every chunk to a worker agent of its own, running up to `concurrency` workers at a
+/// time, and has the reducer combine the responses.
+///
+/// Chunks whose worker fails or panics are reported in [`MapReduce::failures`] and left out of
+/// the reduction, the pattern only fails if every worker or the reducer does.
+pub async fn map_reduce<B: Backend + Clone>(
+    runtime: &RuntimeBuilder,
+    backend: B,
+    model: B::Model,
+    chunks: impl IntoIterator<Item = String>,
+    worker: Agent,
+    reducer: Agent,
+    concurrency: usize,
+) -> Result<MapReduce, KepokiError> {
+    let mut chunks = chunks.into_iter().enumerate();
+    let mut mapped = Vec::new();
+    let mut failures = Vec::new();
+    let mut first_error = None;
+    let mut workers = JoinSet::new();
+    // The chunks in progress by the ID of their Tokio task, in case their worker panics.
+    let mut started = HashMap::new();
+    loop {
+        while workers.len() < concurrency.max(1)
+            && let Some((index, chunk)) = chunks.next()
+        {
+            mapped.push(None);
+            let (runtime, backend, model, worker) = (
+                runtime.clone(),
+                backend.clone(),
+                model.clone(),
+                worker.clone(),
+            );
+            let handle = workers.spawn(async move {
+                (
+                    index,
+                    run_once(&runtime, backend, model, worker, chunk).await,
+                )
+            });
+            started.insert(handle.id(), index);
+        }
+
+        let Some(joined) = workers.join_next_with_id().await else {
+            break;
+        };
+        let joined = match joined {
+            Ok((id, joined)) => {
+                started.remove(&id);
+                joined
+            }
+            Err(err) => {
+                let index = started.remove(&err.id()).unwrap_or_default();
+                (index, Err(KepokiError::JoinFailed(err)))
+            }
+        };
+        match joined {
+            (index, Ok(output)) => mapped[index] = Some(output),
+            (index, Err(err)) => {
+                tracing::warn!("Map-reduce worker for chunk {index} failed: {err}");
+                failures.push(ChunkFailure {
+                    index,
+                    error: err.to_string(),
+                });
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+
+    if failures.len() == mapped.len()
+        && let Some(err) = first_error
+    {
+        return Err(err);
+    }
+
+    let outputs = mapped
+        .iter()
+        .enumerate()
+        .filter_map(|(index, output)| {
+            let output = output.as_ref()?;
+            Some(format!("<output chunk=\"{index}\">\n{output}\n</output>"))
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let output = run_once(runtime, backend, model, reducer, outputs).await?;
+    failures.sort_by_key(|failure| failure.index);
+
+    Ok(MapReduce {
+        output,
+        mapped,
+        failures,
+    })
+}
+
+/// A stage of a [`pipeline`], an agent whose response must match `output_schema`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Stage {
+    pub agent: Agent,
+    /// A JSON schema for the output of the stage, which becomes the input of the next stage.
+    ///
+    /// Supports `type`, `properties`, `required`, `additionalProperties`, `items`, and `enum`.
+    pub output_schema: Value,
+}
+
+/// The progress of a [`pipeline`], serializable so that a failed run can be resumed later.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PipelineRun {
+    /// The input of the first stage.
+    pub input: Value,
+    /// The validated output of every stage that completed, in order.
+    pub outputs: Vec<Value>,
+}
+
+impl PipelineRun {
+    pub fn new(input: Value) -> Self {
+        Self {
+            input,
+            outputs: Vec::new(),
+        }
+    }
+}
+
+/// Runs the stages that `run` hasn't completed yet in order, passing the output of each stage
+/// to the next, and returns the output of the last stage.
+///
+/// Outputs that aren't JSON matching the schema of their stage fail the stage. Completed
+/// stages are kept in `run`, running it again resumes at the stage that failed.
+pub async fn pipeline<B: Backend + Clone>(
+    runtime: &RuntimeBuilder,
+    backend: B,
+    model: B::Model,
+    stages: &[Stage],
+    run: &mut PipelineRun,
+) -> Result<Value, KepokiError> {
+    for stage in stages.iter().skip(run.outputs.len()) {
+        let input = run.outputs.last().unwrap_or(&run.input);
+        let message = format!(
+            "<input>\n{input}\n</input>\n\nRespond only with JSON matching this schema:\n{}",
+            stage.output_schema
+        );
+        let response = run_once(
+            runtime,
+            backend.clone(),
+            model.clone(),
+            stage.agent.clone(),
+            message,
+        )
+        .await?;
+        let output = serde_json::from_str::<Value>(strip_code_fence(&response))
+            .map_err(|err| err.to_string())
+            .and_then(|output| {
+                validate(&output, &stage.output_schema, "$")?;
+                Ok(output)
+            })
+            .map_err(|error| KepokiError::InvalidStageOutput {
+                stage: stage.agent.name.clone(),
+                error,
+            })?;
+        run.outputs.push(output);
+    }
+
+    Ok(run.outputs.last().unwrap_or(&run.input).clone())
+}
+
+/// The contents of a response wrapped in a Markdown code block, as models tend to do with JSON.
+pub(crate) fn strip_code_fence(response: &str) -> &str {
+    let response = response.trim();
+    response
+        .strip_prefix("```")
+        .and_then(|response| response.strip_suffix("```"))
+        .map(|code| code.split_once('\n').map_or(code, |(_, code)| code))
+        .unwrap_or(response)
+}
+
+/// Checks `value` against the subset of JSON schema supported by [`Stage::output_schema`].
+pub(crate) fn validate(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+    if let Some(ty) = schema.get("type").and_then(Value::as_str) {
+        let matches = match ty {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+        if !matches {
+            return Err(format!("{path} is not of type {ty}"));
+        }
+    }
+
+    if let Some(options) = schema.get("enum").and_then(Value::as_array)
+        && !options.contains(value)
+    {
+        return Err(format!(
+            "{path} is not one of {}",
+            Value::from(options.clone())
+        ));
+    }
+
+    if let Some(object) = value.as_object() {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for required in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !object.contains_key(required) {
+                return Err(format!("{path} is missing the property {required}"));
+            }
+        }
+
+        for (name, value) in object {
+            let path = format!("{path}.{name}");
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(schema) => validate(value, schema, &path)?,
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    return Err(format!("{path} is not allowed"));
+                }
+                None => (),
+            }
+        }
+    }
+
+    if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+        for (index, value) in array.iter().enumerate() {
+            validate(value, items, &format!("{path}[{index}]"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// What the debaters of a [`debate`] see of each other.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum DebateTranscript {
+    /// Every debater sees the arguments of the others and can respond to them.
+    #[default]
+    Shared,
+    /// Debaters only refine their own position, only the arbiter sees every argument.
+    Private,
+}
+
+/// An argument made in a [`debate`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Argument {
+    /// The round the argument was made in, starting at 1.
+    pub round: usize,
+    /// The name of the debater.
+    pub debater: String,
+    pub text: String,
+}
+
+/// The outcome of [`debate`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Debate {
+    /// Every argument, in the order they were made.
+    pub arguments: Vec<Argument>,
+    /// The decision of the arbiter.
+    pub verdict: String,
+}
+
+/// Has the debaters argue `question` for `rounds` rounds, taking turns in order, before the
+/// arbiter decides it based on every argument.
+#[allow(clippy::too_many_arguments)] // The leading arguments are shared by every pattern.
+pub async fn debate<B: Backend + Clone>(
+    runtime: &RuntimeBuilder,
+    backend: B,
+    model: B::Model,
+    question: String,
+    debaters: Vec<Agent>,
+    arbiter: Agent,
+    rounds: usize,
+    transcript: DebateTranscript,
+) -> Result<Debate, KepokiError> {
+    let mut runtime = runtime.build();
+    let names = debaters
+        .iter()
+        .map(|debater| debater.name.clone())
+        .collect::<Vec<_>>();
+    let handles = debaters
+        .into_iter()
+        .map(|debater| runtime.spawn_agent(backend.clone(), model.clone(), debater))
+        .collect::<Vec<_>>();
+    let arbiter = runtime.spawn_agent(backend, model, arbiter);
+
+    let result = async {
+        let mut arguments = Vec::<Argument>::new();
+        // The number of arguments each debater has seen.
+        let mut seen = vec![0; handles.len()];
+        for round in 1..=rounds {
+            for (index, handle) in handles.iter().enumerate() {
+                let mut message = match round {
+                    1 => format!("<question>\n{question}\n</question>\n\n"),
+                    _ => String::new(),
+                };
+                if transcript == DebateTranscript::Shared {
+                    // A debater's own arguments are always seen already.
+                    for argument in &arguments[seen[index]..] {
+                        message.push_str(&render_argument(argument));
+                    }
+                }
+                message.push_str(&match (round, transcript) {
+                    (1, _) => "State your position and argue for it.".to_string(),
+                    (_, DebateTranscript::Shared) => format!(
+                        "Round {round} of {rounds}: respond to the other arguments and refine your position."
+                    ),
+                    (_, DebateTranscript::Private) => {
+                        format!("Round {round} of {rounds}: refine your position.")
+                    }
+                });
+
+                let text = respond(&mut runtime, handle, message).await?;
+                arguments.push(Argument {
+                    round,
+                    debater: names[index].clone(),
+                    text,
+                });
+                seen[index] = arguments.len();
+            }
+        }
+
+        let arguments_text = arguments.iter().map(render_argument).collect::<String>();
+        let verdict = respond(
+            &mut runtime,
+            &arbiter,
+            format!(
+                "<question>\n{question}\n</question>\n\n{arguments_text}Decide the question based on these arguments and explain your decision."
+            ),
+        )
+        .await?;
+
+        Ok(Debate { arguments, verdict })
+    }
+    .await;
+
+    shutdown(
+        &mut runtime,
+        &handles.into_iter().chain([arbiter]).collect::<Vec<_>>(),
+    )
+    .await;
+    result
+}
+
+fn render_argument(argument: &Argument) -> String {
+    format!(
+        "<argument debater=\"{}\" round=\"{}\">\n{}\n</argument>\n\n",
+        argument.debater, argument.round, argument.text
+    )
+}
+
+/// Runs an agent on a runtime of its own, built by `runtime`, until it responded to `message`.
+pub(crate) async fn run_once<B: Backend>(
+    runtime: &RuntimeBuilder,
+    backend: B,
+    model: B::Model,
+    agent: Agent,
+    message: String,
+) -> Result<String, KepokiError> {
+    let mut runtime = runtime.build();
+    let handle = runtime.spawn_agent(backend, model, agent);
+    let result = respond(&mut runtime, &handle, message).await;
+    shutdown(&mut runtime, &[handle]).await;
+    result
+}
+
+/// The definition of the critic reviewing the responses of `agent`.
+fn critic(agent: &Agent) -> Agent {
+    let criteria = match agent.review_criteria.is_empty() {
+        true => "- The response completes the task correctly and completely.".to_string(),
+        false => agent
+            .review_criteria
+            .iter()
+            .map(|criterion| format!("- {criterion}"))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    };
+
+    Agent {
+        name: format!("{}-critic", agent.name),
+        description: format!("Reviews the responses of {}.", agent.name),
+        prompt: format!(
+            "You review responses to a task against these criteria:\n\n{criteria}\n\nIf the response meets every criterion, reply with {APPROVED} on the first line. Otherwise list each problem and how to fix it."
+        ),
+        temperature: 0.0,
+        model_preferences: agent.model_preferences.clone(),
+        ..Default::default()
+    }
+}
+
+/// Sends a message to an agent and returns the text of its final response, once it stopped
+/// calling tools.
+///
+/// The agent must be the only one of the runtime that is responding.
+pub(crate) async fn respond(
+    runtime: &mut Runtime,
+    agent: &AgentHandle,
+    message: String,
+) -> Result<String, KepokiError> {
+    runtime.send_as_owner(agent, AgentCommand::UserMessage(message))?;
+    loop {
+        let event = match runtime.recv().await {
+            Ok(event) => event,
+            // The events of an agent end before its exit is reported.
+            Err(KepokiError::AgentNotFound(_)) => continue,
+            Err(err) => return Err(err),
+        };
+
+        match event {
+            AgentEvent::Message(message)
+                if !message
+                    .content
+                    .iter()
+                    .any(|block| matches!(block, ContentBlock::ToolUse { .. })) =>
+            {
+                return Ok(message
+                    .content
+                    .iter()
+                    .filter_map(|block| match block {
+                        ContentBlock::Text { text } => Some(text.as_str()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"));
+            }
+            AgentEvent::Terminated(error) => {
+                return Err(KepokiError::AgentFailed(agent.clone(), error));
+            }
+            AgentEvent::Crashed { .. } => return Err(KepokiError::AgentPanicked(agent.clone())),
+            AgentEvent::Completed(_) => {
+                return Err(KepokiError::AgentFailed(
+                    agent.clone(),
+                    "Exited before responding".to_string(),
+                ));
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Exits the agents and waits for every agent of the runtime to complete.
+pub(crate) async fn shutdown(runtime: &mut Runtime, agents: &[AgentHandle]) {
+    for agent in agents {
+        // Agents that already exited can't receive commands anymore.
+        let _ = runtime.send_as_owner(agent, AgentCommand::Exit);
+    }
+
+    while !matches!(runtime.recv().await, Err(KepokiError::NoRunningAgents)) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::mock::MockBackend;
+    use crate::mock::MockRequest;
+    use crate::mock::MockResponse;
+    use crate::policy::PolicyDecision;
+    use crate::policy::PolicyEngine;
+    use crate::policy::PolicyFuture;
+    use crate::policy::ToolRequest;
+
+    fn agent(name: &str) -> Agent {
+        Agent {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// The text of the message a request ends with.
+    fn last_text(request: &MockRequest) -> String {
+        request
+            .messages
+            .last()
+            .into_iter()
+            .flat_map(|message| &message.content)
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reflect_revises_until_approved() {
+        let backend = MockBackend::new()
+            .with_response(MockResponse::text("Draft"))
+            .with_response(MockResponse::text("Too short."))
+            .with_response(MockResponse::text("Revised"))
+            .with_response(MockResponse::text("APPROVED\nGood."));
+
+        let reflection = reflect(
+            &RuntimeBuilder::new(),
+            backend.clone(),
+            "mock".to_string(),
+            agent("writer"),
+            "Write a haiku".to_string(),
+            2,
+        )
+        .await
+        .unwrap();
+        assert_eq!(reflection.response, "Revised");
+        assert!(reflection.approved);
+        assert_eq!(reflection.reviews, ["Too short.", "APPROVED\nGood."]);
+
+        let requests = backend.requests();
+        assert!(last_text(&requests[2]).contains("Too short."));
+        assert!(last_text(&requests[3]).contains("<response>\nRevised\n</response>"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_map_reduce_leaves_out_failed_chunks() {
+        let backend = MockBackend::new()
+            .with_response(MockResponse::text("One"))
+            .with_response(MockResponse::error(KepokiError::CustomError(
+                "Unavailable".into(),
+            )))
+            .with_response(MockResponse::text("Summary"));
+
+        let result = map_reduce(
+            &RuntimeBuilder::new(),
+            backend.clone(),
+            "mock".to_string(),
+            ["first".to_string(), "second".to_string()],
+            agent("worker"),
+            agent("reducer"),
+            1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.output, "Summary");
+        assert_eq!(result.mapped, [Some("One".to_string()), None]);
+        assert!(matches!(
+            &result.failures[..],
+            [ChunkFailure { index: 1, .. }]
+        ));
+        assert_eq!(
+            last_text(&backend.requests()[2]),
+            "<output chunk=\"0\">\nOne\n</output>"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pipeline_resumes_at_failed_stage() {
+        let stages = [
+            Stage {
+                agent: agent("extract"),
+                output_schema: json!({
+                    "type": "object",
+                    "properties": { "city": { "type": "string" } },
+                    "required": ["city"],
+                }),
+            },
+            Stage {
+                agent: agent("classify"),
+                output_schema: json!({ "type": "string", "enum": ["large", "small"] }),
+            },
+        ];
+        let backend = MockBackend::new()
+            .with_response(MockResponse::text("```json\n{\"city\": \"Oslo\"}\n```"))
+            .with_response(MockResponse::text("\"medium\""));
+        let mut run = PipelineRun::new(json!("I live in Oslo."));
+
+        let runtime = RuntimeBuilder::new();
+        let result = pipeline(
+            &runtime,
+            backend.clone(),
+            "mock".to_string(),
+            &stages,
+            &mut run,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(KepokiError::InvalidStageOutput { stage, .. }) if stage == "classify"
+        ));
+        assert_eq!(run.outputs, [json!({ "city": "Oslo" })]);
+
+        backend.push_response(MockResponse::text("\"small\""));
+        let output = pipeline(
+            &runtime,
+            backend.clone(),
+            "mock".to_string(),
+            &stages,
+            &mut run,
+        )
+        .await
+        .unwrap();
+        assert_eq!(output, json!("small"));
+        assert!(last_text(&backend.requests()[2]).contains(r#"{"city":"Oslo"}"#));
+    }
+
+    struct DenyAll;
+
+    impl PolicyEngine for DenyAll {
+        fn authorize<'a>(&'a self, _request: &'a ToolRequest) -> PolicyFuture<'a> {
+            Box::pin(async { PolicyDecision::Deny("Not allowed".to_string()) })
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_run_once_applies_runtime_settings() {
+        let backend = MockBackend::new()
+            .with_response(MockResponse::tool_use("call_1", "missing", json!({})))
+            .with_response(MockResponse::text("Done"));
+        let runtime = RuntimeBuilder::new().with_policy(DenyAll);
+
+        let output = run_once(
+            &runtime,
+            backend.clone(),
+            "mock".to_string(),
+            agent("worker"),
+            "Hello".to_string(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(output, "Done");
+        assert!(matches!(
+            &backend.requests()[1].messages.last().unwrap().content[..],
+            [ContentBlock::ToolResult { content: Some(content), .. }]
+                if format!("{content:?}").contains("Denied by policy: Not allowed")
+        ));
+    }
+
+    #[test]
+    fn test_validate() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "tags": { "type": "array", "items": { "type": "string" } } },
+            "additionalProperties": false,
+        });
+        assert!(validate(&json!({ "tags": ["a"] }), &schema, "$").is_ok());
+        assert_eq!(
+            validate(&json!({ "tags": ["a", 1] }), &schema, "$"),
+            Err("$.tags[1] is not of type string".to_string())
+        );
+        assert_eq!(
+            validate(&json!({ "other": 1 }), &schema, "$"),
+            Err("$.other is not allowed".to_string())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_debate_shares_arguments() {
+        let backend = MockBackend::new()
+            .with_response(MockResponse::text("Yes"))
+            .with_response(MockResponse::text("No"))
+            .with_response(MockResponse::text("Still yes"))
+            .with_response(MockResponse::text("Still no"))
+            .with_response(MockResponse::text("Yes wins"));
+
+        let debate = debate(
+            &RuntimeBuilder::new(),
+            backend.clone(),
+            "mock".to_string(),
+            "Is it?".to_string(),
+            vec![agent("pro"), agent("con")],
+            agent("arbiter"),
+            2,
+            DebateTranscript::Shared,
+        )
+        .await
+        .unwrap();
+        assert_eq!(debate.verdict, "Yes wins");
+        assert_eq!(
+            debate
+                .arguments
+                .iter()
+                .map(|argument| (argument.round, argument.debater.as_str()))
+                .collect::<Vec<_>>(),
+            [(1, "pro"), (1, "con"), (2, "pro"), (2, "con")]
+        );
+
+        let requests = backend.requests();
+        // Pro sees the argument of con, but not its own again.
+        let message = last_text(&requests[2]);
+        assert!(message.contains("<argument debater=\"con\" round=\"1\">\nNo\n</argument>"));
+        assert!(!message.contains("debater=\"pro\""));
+        assert!(last_text(&requests[4]).contains("Still no"));
+    }
+}
@@ -0,0 +1,97 @@
+//! Command sources other than the embedder itself, such as HTTP clients, connectors, or other
+//! agents, are assigned a role that limits the commands they may send to agents.
+//!
+//! The embedder owns the runtime and sends commands with [`Runtime::send_as_owner`]. Commands
+//! of every other source go through [`Runtime::send_from`], which checks the role of the source.
+//!
+//! [`Runtime::send_as_owner`]: crate::runtime::Runtime::send_as_owner
+//! [`Runtime::send_from`]: crate::runtime::Runtime::send_from
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::runtime::agent::AgentCommand;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum CommandRole {
+    /// May send any command.
+    Owner,
+    /// May converse with and steer agents, but not stop them.
+    Operator,
+    /// May only inspect agents.
+    Observer,
+}
+
+impl CommandRole {
+    pub fn allows(&self, command: &AgentCommand) -> bool {
+        match self {
+            Self::Owner => true,
+            Self::Operator => !matches!(command, AgentCommand::Exit | AgentCommand::Terminate),
+            Self::Observer => matches!(command, AgentCommand::DumpState),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::Agent;
+    use crate::error::KepokiError;
+    use crate::mock::MockBackend;
+    use crate::runtime::Runtime;
+    use crate::runtime::agent::AgentEvent;
+
+    #[test]
+    fn test_allows() {
+        assert!(CommandRole::Owner.allows(&AgentCommand::Terminate));
+        assert!(CommandRole::Operator.allows(&AgentCommand::UserMessage("Hi".to_string())));
+        assert!(!CommandRole::Operator.allows(&AgentCommand::Exit));
+        assert!(CommandRole::Observer.allows(&AgentCommand::DumpState));
+        assert!(!CommandRole::Observer.allows(&AgentCommand::Pause));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_send_from_checks_role() {
+        let mut runtime = Runtime::builder().build();
+        runtime.set_command_role("dashboard", CommandRole::Observer);
+        runtime.set_command_role("operator", CommandRole::Operator);
+        let agent = runtime.spawn_agent(MockBackend::new(), "mock".to_string(), Agent::default());
+
+        assert!(matches!(
+            runtime.send_from("dashboard", &agent, AgentCommand::Terminate),
+            Err(KepokiError::PermissionDenied(source)) if source == "dashboard"
+        ));
+        assert!(matches!(
+            runtime.send_from("unknown", &agent, AgentCommand::DumpState),
+            Err(KepokiError::PermissionDenied(_))
+        ));
+        assert!(
+            runtime
+                .cancellation_token(&agent)
+                .is_some_and(|token| !token.is_cancelled())
+        );
+
+        runtime
+            .send_from("operator", &agent, AgentCommand::Pause)
+            .unwrap();
+        runtime
+            .send_from("dashboard", &agent, AgentCommand::DumpState)
+            .unwrap();
+        loop {
+            if let AgentEvent::StateDump(state) = runtime.recv().await.unwrap() {
+                assert!(state.paused);
+                break;
+            }
+        }
+
+        runtime.remove_command_role("operator");
+        assert!(
+            runtime
+                .send_from("operator", &agent, AgentCommand::Unpause)
+                .is_err()
+        );
+        runtime.send_as_owner(&agent, AgentCommand::Exit).unwrap();
+        while runtime.recv().await.is_ok() {}
+    }
+}
@@ -0,0 +1,308 @@
+//! Caps the daily usage of tenants and backend credentials across the agents of a runtime.
+//!
+//! Usage is counted per UTC day. Agents emit [`AgentEvent::QuotaWarning`] once usage crosses
+//! one of the warning thresholds of a quota, and their requests fail with
+//! [`KepokiError::QuotaExceeded`] once it reaches a limit, which error handlers can answer by
+//! pausing the agent until the next day.
+//!
+//! [`AgentEvent::QuotaWarning`]: crate::runtime::agent::AgentEvent::QuotaWarning
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use chrono::NaiveDate;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::backend::Usage;
+use crate::error::KepokiError;
+use crate::models::TokenPrice;
+
+/// Who usage is counted for.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaScope {
+    /// Agents running for a tenant, see [`RuntimeBuilder::with_tenant`].
+    ///
+    /// [`RuntimeBuilder::with_tenant`]: crate::runtime::builder::RuntimeBuilder::with_tenant
+    Tenant(String),
+    /// Agents using a backend credential, see [`RuntimeBuilder::with_credential`].
+    ///
+    /// [`RuntimeBuilder::with_credential`]: crate::runtime::builder::RuntimeBuilder::with_credential
+    Credential(String),
+}
+
+impl Display for QuotaScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tenant(tenant) => write!(f, "tenant {tenant}"),
+            Self::Credential(credential) => write!(f, "credential {credential}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaMetric {
+    Tokens,
+    Requests,
+    Dollars,
+}
+
+impl Display for QuotaMetric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Tokens => "tokens",
+            Self::Requests => "requests",
+            Self::Dollars => "dollars",
+        })
+    }
+}
+
+/// Daily limits of a scope, unlimited where `None`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(default)]
+pub struct Quota {
+    /// Input and output tokens.
+    pub daily_tokens: Option<u64>,
+    /// Requests sent to backends, each sample of a best-of turn counting as one.
+    pub daily_requests: Option<u64>,
+    /// Spend, computed from the token usage with `price`. Requests of models without a known
+    /// price don't count towards it.
+    pub daily_dollars: Option<f64>,
+    /// The price of tokens, `None` uses the price of the model in the
+    /// [`ModelRegistry`](crate::models::ModelRegistry).
+    pub price: Option<TokenPrice>,
+    /// Fractions of a limit at which a warning is emitted.
+    pub warn_at: Vec<f64>,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self {
+            daily_tokens: None,
+            daily_requests: None,
+            daily_dollars: None,
+            price: None,
+            warn_at: vec![0.8],
+        }
+    }
+}
+
+impl Quota {
+    fn limits(&self) -> impl Iterator<Item = (QuotaMetric, f64)> {
+        [
+            (
+                QuotaMetric::Tokens,
+                self.daily_tokens.map(|limit| limit as f64),
+            ),
+            (
+                QuotaMetric::Requests,
+                self.daily_requests.map(|limit| limit as f64),
+            ),
+            (QuotaMetric::Dollars, self.daily_dollars),
+        ]
+        .into_iter()
+        .filter_map(|(metric, limit)| Some((metric, limit?)))
+    }
+}
+
+/// The usage of a scope on the current day.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct QuotaUsage {
+    pub tokens: u64,
+    pub requests: u64,
+    pub dollars: f64,
+}
+
+impl QuotaUsage {
+    fn get(&self, metric: QuotaMetric) -> f64 {
+        match metric {
+            QuotaMetric::Tokens => self.tokens as f64,
+            QuotaMetric::Requests => self.requests as f64,
+            QuotaMetric::Dollars => self.dollars,
+        }
+    }
+}
+
+/// A threshold of a quota crossed by a request.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct QuotaWarning {
+    pub scope: QuotaScope,
+    pub metric: QuotaMetric,
+    pub used: f64,
+    pub limit: f64,
+}
+
+#[derive(Debug)]
+struct Account {
+    quota: Quota,
+    day: NaiveDate,
+    usage: QuotaUsage,
+}
+
+impl Account {
+    /// The usage of today, resetting it once the day is over.
+    fn today(&mut self) -> &mut QuotaUsage {
+        let today = Utc::now().date_naive();
+        if self.day != today {
+            self.day = today;
+            self.usage = QuotaUsage::default();
+        }
+        &mut self.usage
+    }
+}
+
+/// The quotas of a runtime and the usage counted against them.
+#[derive(Clone, Debug, Default)]
+pub struct Quotas {
+    accounts: Arc<Mutex<HashMap<QuotaScope, Account>>>,
+}
+
+impl Quotas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the quota of a scope, `None` removes it. Usage counted today is kept.
+    pub fn set(&self, scope: QuotaScope, quota: Option<Quota>) {
+        let mut accounts = self.accounts.lock().unwrap();
+        match quota {
+            Some(quota) => {
+                accounts
+                    .entry(scope)
+                    .and_modify(|account| account.quota = quota.clone())
+                    .or_insert_with(|| Account {
+                        quota,
+                        day: Utc::now().date_naive(),
+                        usage: QuotaUsage::default(),
+                    });
+            }
+            None => {
+                accounts.remove(&scope);
+            }
+        }
+    }
+
+    /// The usage of today of every scope with a quota.
+    pub fn snapshot(&self) -> HashMap<QuotaScope, QuotaUsage> {
+        self.accounts
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .map(|(scope, account)| (scope.clone(), account.today().clone()))
+            .collect()
+    }
+
+    /// Fails if any of the scopes reached one of its limits.
+    pub fn check(&self, scopes: &[QuotaScope]) -> Result<(), KepokiError> {
+        let mut accounts = self.accounts.lock().unwrap();
+        for scope in scopes {
+            let Some(account) = accounts.get_mut(scope) else {
+                continue;
+            };
+            let usage = account.today().clone();
+            if let Some((metric, limit)) = account
+                .quota
+                .limits()
+                .find(|(metric, limit)| usage.get(*metric) >= *limit)
+            {
+                return Err(KepokiError::QuotaExceeded {
+                    scope: scope.to_string(),
+                    metric: metric.to_string(),
+                    limit,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a request to a model priced at `price` against the scopes, returning the warning
+    /// thresholds it crossed.
+    pub fn record(
+        &self,
+        scopes: &[QuotaScope],
+        usage: Option<&Usage>,
+        price: Option<TokenPrice>,
+    ) -> Vec<QuotaWarning> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let mut warnings = Vec::new();
+        for scope in scopes {
+            let Some(account) = accounts.get_mut(scope) else {
+                continue;
+            };
+            let price = account.quota.price.or(price);
+            let today = account.today();
+            let before = today.clone();
+            today.requests += 1;
+            if let Some(usage) = usage {
+                today.tokens += u64::from(usage.input_tokens) + u64::from(usage.output_tokens);
+                today.dollars += price.map_or(0.0, |price| price.cost(usage));
+            }
+            let after = today.clone();
+
+            for (metric, limit) in account.quota.limits() {
+                let crossed = account.quota.warn_at.iter().any(|fraction| {
+                    let threshold = fraction * limit;
+                    before.get(metric) < threshold && after.get(metric) >= threshold
+                });
+                if crossed {
+                    warnings.push(QuotaWarning {
+                        scope: scope.clone(),
+                        metric,
+                        used: after.get(metric),
+                        limit,
+                    });
+                }
+            }
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warns_once_and_stops_at_limit() {
+        let quotas = Quotas::new();
+        let scope = QuotaScope::Tenant("acme".to_string());
+        quotas.set(
+            scope.clone(),
+            Some(Quota {
+                daily_tokens: Some(100),
+                daily_requests: Some(10),
+                ..Default::default()
+            }),
+        );
+        let scopes = [scope.clone(), QuotaScope::Credential("other".to_string())];
+        let usage = Usage {
+            input_tokens: 30,
+            output_tokens: 15,
+        };
+
+        assert!(quotas.record(&scopes, Some(&usage), None).is_empty());
+        assert!(quotas.check(&scopes).is_ok());
+        let warnings = quotas.record(&scopes, Some(&usage), None);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].metric, QuotaMetric::Tokens);
+        assert_eq!(warnings[0].used, 90.0);
+        assert!(quotas.check(&scopes).is_ok());
+
+        let warnings = quotas.record(&scopes, Some(&usage), None);
+        assert!(warnings.is_empty());
+        assert!(matches!(
+            quotas.check(&scopes),
+            Err(KepokiError::QuotaExceeded { .. })
+        ));
+        assert_eq!(quotas.snapshot()[&scope].requests, 3);
+    }
+}
@@ -0,0 +1,98 @@
+//! Error handlers decide how an agent recovers from a failed turn. Without a handler the
+//! agent terminates with the error.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use crate::error::KepokiError;
+use crate::runtime::AgentHandle;
+
+pub type ErrorHandlerFuture<'a> = Pin<Box<dyn Future<Output = ErrorRecovery> + Send + 'a>>;
+
+pub trait ErrorHandler: Send + Sync + 'static {
+    /// Inspects a failed turn and decides how the agent continues.
+    fn handle<'a>(&'a self, failure: &'a TurnFailure) -> ErrorHandlerFuture<'a>;
+}
+
+/// A request to the backend, or the response it streamed, that failed.
+#[derive(Debug)]
+pub struct TurnFailure {
+    pub agent: AgentHandle,
+    pub error: KepokiError,
+    /// The number of consecutive failures of this turn, starting at 1.
+    pub attempt: u32,
+}
+
+#[derive(Clone, Debug)]
+pub enum ErrorRecovery {
+    /// Sends the same request again.
+    Retry,
+    /// Sends the request again with some of its parameters changed.
+    RetryWith(RequestAdjustments),
+    /// Asks the model to continue the partial response of a stream that failed midway, see
+    /// [`KepokiError::StreamInterrupted`]. Sends the same request again if nothing was received.
+    Continue,
+    /// Pauses the agent and emits [`AgentEvent::TurnFailed`], unpausing retries the turn
+    /// without its partial response.
+    ///
+    /// [`AgentEvent::TurnFailed`]: crate::runtime::agent::AgentEvent::TurnFailed
+    Pause,
+    /// Stops the agent with the error.
+    Terminate,
+}
+
+/// Changes applied to the requests of a turn being retried.
+#[derive(Clone, Debug, Default)]
+pub struct RequestAdjustments {
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+}
+
+/// The error handlers of a runtime, a handler registered for an agent takes precedence over the
+/// runtime-wide handler.
+#[derive(Clone, Default)]
+pub struct ErrorHandlers {
+    inner: Arc<RwLock<ErrorHandlersInner>>,
+}
+
+#[derive(Default)]
+struct ErrorHandlersInner {
+    default: Option<Arc<dyn ErrorHandler>>,
+    agents: HashMap<AgentHandle, Arc<dyn ErrorHandler>>,
+}
+
+impl ErrorHandlers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_default(&self, handler: Option<Arc<dyn ErrorHandler>>) {
+        self.inner.write().unwrap().default = handler;
+    }
+
+    pub fn set_agent(&self, agent: &AgentHandle, handler: Option<Arc<dyn ErrorHandler>>) {
+        let agents = &mut self.inner.write().unwrap().agents;
+        match handler {
+            Some(handler) => agents.insert(agent.clone(), handler),
+            None => agents.remove(agent),
+        };
+    }
+
+    pub fn get(&self, agent: &AgentHandle) -> Option<Arc<dyn ErrorHandler>> {
+        let inner = self.inner.read().unwrap();
+        inner.agents.get(agent).or(inner.default.as_ref()).cloned()
+    }
+}
+
+impl std::fmt::Debug for ErrorHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inner = self.inner.read().unwrap();
+        f.debug_struct("ErrorHandlers")
+            .field("default", &inner.default.is_some())
+            .field("agents", &inner.agents.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
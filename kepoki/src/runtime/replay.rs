@@ -0,0 +1,193 @@
+//! Replays a recorded session against a modified agent definition or a different model.
+//!
+//! The user messages of the recording are sent in order and tool calls are answered with the
+//! recorded results instead of being executed, so only the model's behavior changes. Every
+//! response that differs from the recorded one is reported as a [`Divergence`].
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::backend::ContentBlock;
+use crate::backend::InputMessage;
+use crate::backend::Role;
+use crate::tools::ToolOutput;
+
+/// A response of the replayed agent that differs from the recorded one.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Divergence {
+    /// The replayed turn, starting at 1 for the first user message.
+    pub turn: usize,
+    /// The recorded response, `None` if the recording has no further responses in this turn.
+    pub expected: Option<Vec<ContentBlock>>,
+    pub actual: Vec<ContentBlock>,
+}
+
+#[derive(Debug)]
+pub struct Replay {
+    /// Recorded turns that weren't started yet.
+    turns: VecDeque<RecordedTurn>,
+    /// The turn being replayed.
+    current: Option<RecordedTurn>,
+    turn: usize,
+}
+
+#[derive(Debug)]
+struct RecordedTurn {
+    user_message: InputMessage,
+    responses: VecDeque<Vec<ContentBlock>>,
+    tool_results: Vec<RecordedToolResult>,
+}
+
+#[derive(Debug)]
+struct RecordedToolResult {
+    name: String,
+    input: Value,
+    output: ToolOutput,
+}
+
+impl Replay {
+    /// Splits a transcript, such as the messages of an [`AgentState`], into turns starting at
+    /// every user message that isn't a tool result.
+    ///
+    /// [`AgentState`]: crate::runtime::agent::AgentState
+    pub fn new(transcript: impl IntoIterator<Item = InputMessage>) -> Self {
+        let mut turns = VecDeque::<RecordedTurn>::new();
+        let mut tool_uses = HashMap::new();
+        for message in transcript {
+            let is_tool_result = message
+                .content
+                .iter()
+                .any(|block| matches!(block, ContentBlock::ToolResult { .. }));
+            if message.role == Role::User && !is_tool_result {
+                turns.push_back(RecordedTurn {
+                    user_message: message,
+                    responses: VecDeque::new(),
+                    tool_results: Vec::new(),
+                });
+                continue;
+            }
+
+            // Messages before the first user message have nothing to replay against.
+            let Some(turn) = turns.back_mut() else {
+                continue;
+            };
+
+            match message.role {
+                Role::Assistant => {
+                    for block in &message.content {
+                        if let ContentBlock::ToolUse { id, input, name } = block {
+                            tool_uses.insert(id.clone(), (name.clone(), parse_input(input)));
+                        }
+                    }
+                    turn.responses.push_back(message.content);
+                }
+                Role::User => {
+                    for block in message.content {
+                        if let ContentBlock::ToolResult {
+                            tool_use_id,
+                            content,
+                            is_error,
+                        } = block
+                            && let Some((name, input)) = tool_uses.remove(&tool_use_id)
+                        {
+                            turn.tool_results.push(RecordedToolResult {
+                                name,
+                                input,
+                                output: ToolOutput {
+                                    content: content.unwrap_or_default(),
+                                    is_error: is_error.unwrap_or(false),
+                                },
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        Self {
+            turns,
+            current: None,
+            turn: 0,
+        }
+    }
+
+    /// Starts the next recorded turn and returns its user message, `None` once every turn was
+    /// replayed.
+    pub fn next_user_message(&mut self) -> Option<InputMessage> {
+        self.current = self.turns.pop_front();
+        let turn = self.current.as_ref()?;
+        self.turn += 1;
+        Some(turn.user_message.clone())
+    }
+
+    /// The recorded result of a tool call with the same name and input in the current turn.
+    ///
+    /// Calls that weren't recorded return an error, the model is expected to diverge anyway.
+    pub fn tool_output(&mut self, name: &str, input: &str) -> ToolOutput {
+        let input = parse_input(input);
+        let recorded = self.current.as_mut().and_then(|turn| {
+            let position = turn
+                .tool_results
+                .iter()
+                .position(|result| result.name == name && result.input == input)?;
+            Some(turn.tool_results.remove(position).output)
+        });
+
+        recorded.unwrap_or_else(|| {
+            ToolOutput::error(format!("No recorded result for this call of `{name}`"))
+        })
+    }
+
+    /// Compares a response of the replayed agent to the next recorded response of the turn.
+    ///
+    /// Thinking is ignored, as are the ids of tool uses.
+    pub fn compare(&mut self, actual: &[ContentBlock]) -> Option<Divergence> {
+        let expected = self
+            .current
+            .as_mut()
+            .and_then(|turn| turn.responses.pop_front());
+        if let Some(expected) = &expected
+            && comparable(expected) == comparable(actual)
+        {
+            return None;
+        }
+
+        Some(Divergence {
+            turn: self.turn,
+            expected,
+            actual: actual.to_vec(),
+        })
+    }
+}
+
+#[derive(PartialEq)]
+pub(crate) enum Comparable<'a> {
+    Text(&'a str),
+    ToolUse(&'a str, Value),
+}
+
+pub(crate) fn comparable(content: &[ContentBlock]) -> Vec<Comparable<'_>> {
+    content
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::Text { text } => Some(Comparable::Text(text.trim())),
+            ContentBlock::ToolUse { input, name, .. } => {
+                Some(Comparable::ToolUse(name, parse_input(input)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parses tool input so that formatting differences don't count as divergence.
+fn parse_input(input: &str) -> Value {
+    match input.trim() {
+        "" => Value::Object(Default::default()),
+        input => serde_json::from_str(input).unwrap_or_else(|_| Value::String(input.to_string())),
+    }
+}
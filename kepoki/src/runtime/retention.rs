@@ -0,0 +1,66 @@
+//! Controls how long a runtime keeps data about its agents after the fact, for deployments with
+//! strict data-handling requirements.
+//!
+//! Kepoki never writes this data to disk or sends it anywhere, it only keeps it in memory for
+//! inspection, such as with [`Runtime::state_diff`] and [`Runtime::artifacts`].
+//!
+//! [`Runtime::state_diff`]: crate::runtime::Runtime::state_diff
+//! [`Runtime::artifacts`]: crate::runtime::Runtime::artifacts
+
+use std::time::Duration;
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::runtime::turns::TurnLog;
+
+/// How often records older than [`Retention::max_age`] are purged.
+pub const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Retention {
+    /// Whether turns are recorded in the turn log, which keeps a copy of every message.
+    pub record_turns: bool,
+    /// How long the messages of recorded turns are kept, `None` to keep them for the lifetime
+    /// of the runtime.
+    pub max_age: Option<Duration>,
+    /// Whether the artifacts and turn records of an agent are dropped once its exit was
+    /// received from [`Runtime::recv`].
+    ///
+    /// [`Runtime::recv`]: crate::runtime::Runtime::recv
+    pub drop_on_exit: bool,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            record_turns: true,
+            max_age: None,
+            drop_on_exit: false,
+        }
+    }
+}
+
+impl Retention {
+    /// Keeps nothing beyond what running agents need.
+    pub fn none() -> Self {
+        Self {
+            record_turns: false,
+            max_age: Some(Duration::ZERO),
+            drop_on_exit: true,
+        }
+    }
+}
+
+/// Purges the turn log every [`PURGE_INTERVAL`], until the task is aborted.
+pub(crate) async fn purge_job(turn_log: TurnLog, max_age: Duration) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Some(cutoff) = SystemTime::now().checked_sub(max_age) {
+            turn_log.purge(cutoff);
+        }
+    }
+}
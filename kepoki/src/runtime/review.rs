@@ -0,0 +1,126 @@
+//! Code review of git diffs by a bundled reviewer agent, producing findings structured enough
+//! to gate CI on.
+//!
+//! The reviewer has no tools, it only sees the diff, so it can't change the code it reviews.
+
+use std::path::Path;
+use std::process::Command;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::agent::Agent;
+use crate::backend::Backend;
+use crate::error::KepokiError;
+use crate::runtime::builder::RuntimeBuilder;
+use crate::runtime::patterns::run_once;
+use crate::runtime::patterns::strip_code_fence;
+
+/// The changes to review.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum DiffSource {
+    /// Unstaged changes in the working tree.
+    #[default]
+    WorkingTree,
+    /// Changes staged for the next commit.
+    Staged,
+    /// Changes between two revisions, such as `main..feature`.
+    Range(String),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Finding {
+    pub file: String,
+    /// The line in the new version of the file, if the finding is about a specific line.
+    #[serde(default)]
+    pub line: Option<u32>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Collects the diff of `source` in the git repository at `dir`.
+pub fn git_diff(dir: impl AsRef<Path>, source: &DiffSource) -> Result<String, KepokiError> {
+    let mut command = Command::new("git");
+    command.current_dir(dir).arg("diff").arg("--no-color");
+    match source {
+        DiffSource::WorkingTree => {}
+        DiffSource::Staged => {
+            command.arg("--staged");
+        }
+        DiffSource::Range(range) => {
+            command.arg(range);
+        }
+    }
+
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(KepokiError::CustomError(
+            format!(
+                "git diff failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into(),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The definition of the agent reviewing diffs.
+pub fn reviewer() -> Agent {
+    Agent {
+        name: "code-reviewer".to_string(),
+        description: "Reviews code changes for bugs, security issues, and maintainability problems.".to_string(),
+        prompt: r#"You are a meticulous code reviewer. You review diffs for bugs, security issues, performance problems, and code that is hard to maintain. Only report problems introduced or touched by the diff, not style preferences.
+
+Respond only with a JSON array of findings, an empty array if there are none. Each finding is an object with the fields:
+- "file": the path of the file, as in the diff
+- "line": the line number in the new version of the file, or null if the finding is not about a specific line
+- "severity": "error" for bugs and security issues, "warning" for likely problems, or "info" for suggestions
+- "message": what the problem is and how to fix it"#.to_string(),
+        temperature: 0.0,
+        ..Agent::default()
+    }
+}
+
+/// Has the [`reviewer`] review `diff` on a runtime built by `runtime` and returns its
+/// findings, most severe first.
+pub async fn review_diff<B: Backend>(
+    runtime: &RuntimeBuilder,
+    backend: B,
+    model: B::Model,
+    diff: &str,
+) -> Result<Vec<Finding>, KepokiError> {
+    if diff.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let response = run_once(
+        runtime,
+        backend,
+        model,
+        reviewer(),
+        format!("<diff>\n{diff}\n</diff>"),
+    )
+    .await?;
+    let mut findings = serde_json::from_str::<Vec<Finding>>(strip_code_fence(&response))
+        .map_err(|err| KepokiError::InvalidReview(err.to_string()))?;
+    findings.sort_by(|a, b| {
+        b.severity
+            .cmp(&a.severity)
+            .then_with(|| a.file.cmp(&b.file))
+            .then_with(|| a.line.cmp(&b.line))
+    });
+    Ok(findings)
+}
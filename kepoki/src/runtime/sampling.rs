@@ -0,0 +1,169 @@
+//! Best-of-N sampling generates several candidate responses for a turn and commits only the
+//! one picked by a selector to the conversation.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::backend::ContentBlock;
+use crate::backend::InputMessage;
+use crate::backend::Message;
+
+pub trait ResponseSelector: Send + Sync + 'static {
+    /// Returns the index of the best candidate response to the conversation in `messages`.
+    ///
+    /// Selectors may call a backend themselves to have a judge model compare the candidates.
+    fn select(&self, messages: &VecDeque<InputMessage>, candidates: &[Message]) -> usize;
+}
+
+#[derive(Clone)]
+pub struct BestOf {
+    /// The number of candidates sampled for every turn.
+    pub n: usize,
+    pub selector: Arc<dyn ResponseSelector>,
+}
+
+impl BestOf {
+    pub fn new(n: usize, selector: impl ResponseSelector) -> Self {
+        Self {
+            n,
+            selector: Arc::new(selector),
+        }
+    }
+}
+
+impl std::fmt::Debug for BestOf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BestOf").field("n", &self.n).finish()
+    }
+}
+
+/// Picks the candidate with the most text, preferring candidates that use tools.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MostThoroughResponse;
+
+impl ResponseSelector for MostThoroughResponse {
+    fn select(&self, _messages: &VecDeque<InputMessage>, candidates: &[Message]) -> usize {
+        candidates
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, candidate)| {
+                let tool_uses = candidate
+                    .content
+                    .iter()
+                    .filter(|block| matches!(block, ContentBlock::ToolUse { .. }))
+                    .count();
+                let text = candidate
+                    .content
+                    .iter()
+                    .map(|block| match block {
+                        ContentBlock::Text { text } => text.len(),
+                        _ => 0,
+                    })
+                    .sum::<usize>();
+                (tool_uses, text)
+            })
+            .map(|(index, _)| index)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::agent::Agent;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+    use crate::runtime::Runtime;
+    use crate::runtime::agent::AgentCommand;
+    use crate::runtime::agent::AgentEvent;
+
+    /// Picks the second candidate and keeps the text of every candidate it was offered.
+    #[derive(Default)]
+    struct SecondCandidate(Mutex<Vec<String>>);
+
+    impl ResponseSelector for Arc<SecondCandidate> {
+        fn select(&self, _messages: &VecDeque<InputMessage>, candidates: &[Message]) -> usize {
+            *self.0.lock().unwrap() = candidates.iter().map(text).collect();
+            1
+        }
+    }
+
+    fn text(message: &Message) -> String {
+        match &message.content[..] {
+            [ContentBlock::Text { text }] => text.clone(),
+            content => panic!("expected text, got {content:?}"),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commits_selected_candidate() {
+        let backend = MockBackend::new()
+            .with_response(MockResponse::text("A"))
+            .with_response(MockResponse::text("B"))
+            .with_response(MockResponse::text("C"));
+        let selector = Arc::new(SecondCandidate::default());
+        let mut runtime = Runtime::builder()
+            .with_best_of(BestOf::new(3, selector.clone()))
+            .build();
+        let agent = runtime.spawn_agent(backend.clone(), "mock".to_string(), Agent::default());
+        runtime
+            .send_as_owner(&agent, AgentCommand::UserMessage("Hello".to_string()))
+            .unwrap();
+
+        let (selected, usage) = loop {
+            if let AgentEvent::CandidatesSampled { selected, usage } = runtime.recv().await.unwrap()
+            {
+                break (selected, usage);
+            }
+        };
+        let message = loop {
+            if let AgentEvent::Message(message) = runtime.recv().await.unwrap() {
+                break message;
+            }
+        };
+        runtime.send_as_owner(&agent, AgentCommand::Exit).unwrap();
+
+        let mut candidates = selector.0.lock().unwrap().clone();
+        assert_eq!(selected, 1);
+        assert_eq!(usage.len(), 3);
+        assert!(usage.iter().all(Option::is_some));
+        assert_eq!(text(&message), candidates[1]);
+        candidates.sort();
+        assert_eq!(candidates, ["A", "B", "C"]);
+        assert_eq!(backend.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_most_thorough_prefers_tool_uses() {
+        let message = |content| Message {
+            id: String::new(),
+            content,
+            stop_reason: None,
+            stop_sequence: None,
+            usage: None,
+        };
+        let candidates = [
+            message(vec![ContentBlock::Text {
+                text: "A long answer without tools".to_string(),
+            }]),
+            message(vec![ContentBlock::ToolUse {
+                id: "call_1".to_string(),
+                input: "{}".to_string(),
+                name: "read_file".to_string(),
+            }]),
+            message(vec![ContentBlock::Text {
+                text: "Short".to_string(),
+            }]),
+        ];
+        assert_eq!(
+            MostThoroughResponse.select(&VecDeque::new(), &candidates),
+            1
+        );
+        assert_eq!(
+            MostThoroughResponse.select(&VecDeque::new(), &candidates[..1]),
+            0
+        );
+    }
+}
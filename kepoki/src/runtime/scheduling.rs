@@ -0,0 +1,162 @@
+//! Limits how many backend requests the agents of a runtime send at once.
+//!
+//! Once the limit is reached, turns wait for a slot and are admitted by the
+//! [`Priority`] of their agent, interactive agents before batch agents, and in the order they
+//! started waiting otherwise. A turn holds its slot until the response is fully received, turns
+//! sampling several responses hold a single slot for all of them.
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+use crate::agent::Priority;
+
+#[derive(Debug, Default)]
+struct State {
+    limit: Option<usize>,
+    in_flight: usize,
+    /// The waiting turns in the order they are admitted.
+    waiting: BTreeSet<(Priority, u64)>,
+    next_ticket: u64,
+}
+
+impl State {
+    fn has_slot(&self) -> bool {
+        self.limit.is_none_or(|limit| self.in_flight < limit)
+    }
+}
+
+/// Hands out request slots shared by the agents of a runtime.
+#[derive(Clone, Debug, Default)]
+pub struct RequestScheduler {
+    state: Arc<(Mutex<State>, Notify)>,
+}
+
+impl RequestScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many requests may be in flight at once, `None` doesn't limit them.
+    ///
+    /// Requests in flight keep their slots if the limit is lowered.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        let (state, admitted) = &*self.state;
+        state.lock().unwrap().limit = limit.map(|limit| limit.max(1));
+        admitted.notify_waiters();
+    }
+
+    /// Waits until a slot is free and no turn of a higher priority, or of the same priority
+    /// that waited longer, is waiting for one.
+    pub async fn acquire(&self, priority: Priority) -> RequestPermit {
+        let (state, admitted) = &*self.state;
+        let ticket = {
+            let mut state = state.lock().unwrap();
+            if state.waiting.is_empty() && state.has_slot() {
+                state.in_flight += 1;
+                return RequestPermit {
+                    scheduler: self.clone(),
+                };
+            }
+
+            let ticket = (priority, state.next_ticket);
+            state.next_ticket += 1;
+            state.waiting.insert(ticket);
+            ticket
+        };
+
+        // Leaves the line once admitted, or if the turn stops waiting.
+        let _waiting = Waiting {
+            scheduler: self,
+            ticket,
+        };
+        loop {
+            // Created before checking, so that admissions in between aren't missed.
+            let notified = admitted.notified();
+            {
+                let mut state = state.lock().unwrap();
+                if state.has_slot() && state.waiting.first() == Some(&ticket) {
+                    state.in_flight += 1;
+                    return RequestPermit {
+                        scheduler: self.clone(),
+                    };
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// The number of turns waiting for a slot.
+    pub fn waiting(&self) -> usize {
+        self.state.0.lock().unwrap().waiting.len()
+    }
+}
+
+/// A request slot, released when dropped.
+#[derive(Debug)]
+pub struct RequestPermit {
+    scheduler: RequestScheduler,
+}
+
+impl Drop for RequestPermit {
+    fn drop(&mut self) {
+        let (state, admitted) = &*self.scheduler.state;
+        state.lock().unwrap().in_flight -= 1;
+        admitted.notify_waiters();
+    }
+}
+
+/// A turn waiting for a slot.
+struct Waiting<'a> {
+    scheduler: &'a RequestScheduler,
+    ticket: (Priority, u64),
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let (state, admitted) = &*self.scheduler.state;
+        state.lock().unwrap().waiting.remove(&self.ticket);
+        // The next turn may fit into a slot as well, or is first in line now.
+        admitted.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_admits_interactive_before_batch() {
+        let scheduler = RequestScheduler::new();
+        scheduler.set_limit(Some(1));
+        let permit = scheduler.acquire(Priority::Batch).await;
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut tasks = Vec::new();
+        for (waiting, priority) in [Priority::Batch, Priority::Interactive]
+            .into_iter()
+            .enumerate()
+        {
+            let worker = scheduler.clone();
+            let sender = sender.clone();
+            tasks.push(tokio::spawn(async move {
+                let _permit = worker.acquire(priority).await;
+                sender.send(priority).unwrap();
+            }));
+            while scheduler.waiting() <= waiting {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+
+        drop(permit);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(receiver.recv().await, Some(Priority::Interactive));
+        assert_eq!(receiver.recv().await, Some(Priority::Batch));
+    }
+}
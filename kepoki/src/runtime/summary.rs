@@ -0,0 +1,205 @@
+//! Machine-readable summaries of unattended runs, such as in CI pipelines.
+//!
+//! Record every event received for the agent with [`RunSummary::record`], which also reports
+//! when the run exceeds its [`RunLimits`] so the host can stop the agent. Once the agent
+//! completed or terminated, write the summary as JSON or as a JUnit report and exit the
+//! process with [`RunSummary::exit_code`].
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::process::ExitCode;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::backend::ContentBlock;
+use crate::runtime::agent::AgentEvent;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RunLimits {
+    /// The most responses the model may generate.
+    pub max_turns: Option<u32>,
+    /// The most input and output tokens the run may use in total.
+    pub max_tokens: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum RunResult {
+    /// The run hasn't ended yet.
+    Running,
+    Succeeded,
+    Failed,
+    /// The run was stopped for exceeding its limits.
+    LimitExceeded,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RunSummary {
+    pub agent: String,
+    pub result: RunResult,
+    pub turns: u32,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// The number of calls of each tool.
+    pub tool_calls: BTreeMap<String, u32>,
+    /// Everything that went wrong during the run, such as failed turns, blocked input, and
+    /// exceeded limits.
+    pub violations: Vec<String>,
+    pub duration: Duration,
+    #[serde(skip)]
+    limits: RunLimits,
+    #[serde(skip, default = "Instant::now")]
+    started: Instant,
+}
+
+impl RunSummary {
+    pub fn new(agent: impl Into<String>, limits: RunLimits) -> Self {
+        Self {
+            agent: agent.into(),
+            result: RunResult::Running,
+            turns: 0,
+            input_tokens: 0,
+            output_tokens: 0,
+            tool_calls: BTreeMap::new(),
+            violations: Vec::new(),
+            duration: Duration::ZERO,
+            limits,
+            started: Instant::now(),
+        }
+    }
+
+    /// Records an event of the agent, returning the violation if it made the run exceed its
+    /// limits. The agent should be stopped then.
+    pub fn record(&mut self, event: &AgentEvent) -> Option<String> {
+        match event {
+            AgentEvent::Message(message) => {
+                self.turns += 1;
+                if let Some(usage) = &message.usage {
+                    self.input_tokens += u64::from(usage.input_tokens);
+                    self.output_tokens += u64::from(usage.output_tokens);
+                }
+                for block in &message.content {
+                    if let ContentBlock::ToolUse { name, .. }
+                    | ContentBlock::ServerToolUse { name, .. } = block
+                    {
+                        *self.tool_calls.entry(name.clone()).or_default() += 1;
+                    }
+                }
+
+                return self.check_limits();
+            }
+            AgentEvent::TurnFailed { error, .. } => {
+                self.violations.push(format!("Turn failed: {error}"));
+            }
+            AgentEvent::InputBlocked { kind } => {
+                self.violations
+                    .push(format!("Input blocked for containing {kind:?}"));
+            }
+            AgentEvent::Completed(_) => self.finish(RunResult::Succeeded),
+            AgentEvent::Terminated(error) => {
+                self.violations.push(format!("Terminated: {error}"));
+                self.finish(RunResult::Failed);
+            }
+            AgentEvent::Crashed { backtrace, .. } => {
+                self.violations.push(format!("Crashed: {backtrace}"));
+                self.finish(RunResult::Failed);
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    fn check_limits(&mut self) -> Option<String> {
+        if !matches!(self.result, RunResult::Running) {
+            return None;
+        }
+
+        let violation = if let Some(max_turns) = self.limits.max_turns
+            && self.turns > max_turns
+        {
+            format!("Exceeded the limit of {max_turns} turns")
+        } else if let Some(max_tokens) = self.limits.max_tokens
+            && self.input_tokens + self.output_tokens > max_tokens
+        {
+            format!("Exceeded the limit of {max_tokens} tokens")
+        } else {
+            return None;
+        };
+
+        self.violations.push(violation.clone());
+        self.finish(RunResult::LimitExceeded);
+        Some(violation)
+    }
+
+    fn finish(&mut self, result: RunResult) {
+        // The exit following an exceeded limit doesn't make the run a success.
+        if matches!(self.result, RunResult::Running) {
+            self.result = result;
+        }
+        self.duration = self.started.elapsed();
+    }
+
+    /// `0` if the run succeeded, `1` if it failed, and `2` if it exceeded its limits or hasn't
+    /// ended.
+    pub fn exit_code(&self) -> ExitCode {
+        match self.result {
+            RunResult::Succeeded => ExitCode::SUCCESS,
+            RunResult::Failed => ExitCode::FAILURE,
+            RunResult::LimitExceeded | RunResult::Running => ExitCode::from(2),
+        }
+    }
+
+    /// The summary as a JUnit XML report with a single test case for the run.
+    pub fn to_junit(&self) -> String {
+        let failures = u32::from(!matches!(self.result, RunResult::Succeeded));
+        let mut report = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            report,
+            "<testsuite name=\"kepoki\" tests=\"1\" failures=\"{failures}\" time=\"{:.3}\">",
+            self.duration.as_secs_f64()
+        );
+        let _ = writeln!(
+            report,
+            "  <testcase name=\"{}\" time=\"{:.3}\">",
+            escape_xml(&self.agent),
+            self.duration.as_secs_f64()
+        );
+        if failures > 0 {
+            let _ = writeln!(
+                report,
+                "    <failure message=\"{:?}\">{}</failure>",
+                self.result,
+                escape_xml(&self.violations.join("\n"))
+            );
+        }
+
+        let mut output = format!(
+            "turns: {}\ninput tokens: {}\noutput tokens: {}",
+            self.turns, self.input_tokens, self.output_tokens
+        );
+        for (tool, calls) in &self.tool_calls {
+            let _ = write!(output, "\ntool {tool}: {calls} calls");
+        }
+        let _ = writeln!(
+            report,
+            "    <system-out>{}</system-out>",
+            escape_xml(&output)
+        );
+        report.push_str("  </testcase>\n</testsuite>\n");
+        report
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,398 @@
+//! Generates datasets by running an agent over templated tasks, for building eval suites and
+//! fine-tuning corpora from kepoki agents.
+//!
+//! Every task template expands to one prompt per combination of its variable values. Each
+//! prompt is run `samples` times on an agent of its own, and every run is appended to the
+//! `records.jsonl` of the dataset directory with its transcript and output. Runs that succeeded
+//! before are skipped, so generating into the same directory again resumes a dataset and
+//! retries its failed runs. The last record of a run replaces earlier ones.
+
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::task::JoinSet;
+
+use crate::agent::Agent;
+use crate::backend::Backend;
+use crate::backend::InputMessage;
+use crate::error::KepokiError;
+use crate::runtime::agent::AgentCommand;
+use crate::runtime::agent::AgentEvent;
+use crate::runtime::builder::RuntimeBuilder;
+use crate::runtime::patterns::respond;
+use crate::runtime::patterns::shutdown;
+use crate::runtime::patterns::strip_code_fence;
+use crate::runtime::patterns::validate;
+
+/// A task prompt with `{{variable}}` placeholders.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct TaskTemplate {
+    /// Identifies the task in the ids of its records.
+    pub name: String,
+    pub prompt: String,
+    /// The values of each variable of the prompt.
+    #[serde(default)]
+    pub variables: BTreeMap<String, Vec<String>>,
+}
+
+impl TaskTemplate {
+    /// Every prompt the template expands to, with the variable values it was expanded with.
+    pub fn expand(&self) -> Vec<(BTreeMap<String, String>, String)> {
+        let mut combinations = vec![BTreeMap::new()];
+        for (variable, values) in &self.variables {
+            combinations = combinations
+                .into_iter()
+                .flat_map(|combination| {
+                    values.iter().map(move |value| {
+                        let mut combination = combination.clone();
+                        combination.insert(variable.clone(), value.clone());
+                        combination
+                    })
+                })
+                .collect();
+        }
+
+        combinations
+            .into_iter()
+            .map(|combination| {
+                let prompt =
+                    combination
+                        .iter()
+                        .fold(self.prompt.clone(), |prompt, (variable, value)| {
+                            prompt.replace(&format!("{{{{{variable}}}}}"), value)
+                        });
+                (combination, prompt)
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct DatasetConfig {
+    pub tasks: Vec<TaskTemplate>,
+    /// The runs of every prompt.
+    #[serde(default = "DatasetConfig::default_samples")]
+    pub samples: usize,
+    /// Runs at most this many prompts, chosen by `seed`, instead of every expanded prompt.
+    #[serde(default)]
+    pub max_prompts: Option<usize>,
+    /// Chooses the prompts when `max_prompts` is set, the same seed chooses the same prompts.
+    #[serde(default)]
+    pub seed: u64,
+    /// A JSON schema the final responses must match, recorded as the output of every run.
+    ///
+    /// Supports the schema subset of [`Stage::output_schema`].
+    ///
+    /// [`Stage::output_schema`]: crate::runtime::patterns::Stage::output_schema
+    #[serde(default)]
+    pub output_schema: Option<Value>,
+    /// The most agents running at a time.
+    #[serde(default = "DatasetConfig::default_concurrency")]
+    pub concurrency: usize,
+}
+
+impl DatasetConfig {
+    pub fn new(tasks: Vec<TaskTemplate>) -> Self {
+        Self {
+            tasks,
+            samples: Self::default_samples(),
+            max_prompts: None,
+            seed: 0,
+            output_schema: None,
+            concurrency: Self::default_concurrency(),
+        }
+    }
+
+    fn default_samples() -> usize {
+        1
+    }
+
+    fn default_concurrency() -> usize {
+        4
+    }
+}
+
+/// A run of the dataset, a line of its `records.jsonl`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct DatasetRecord {
+    /// The task, the position of the prompt among its expansions, and the sample, such as
+    /// `summarize-3-0`.
+    pub id: String,
+    pub task: String,
+    pub variables: BTreeMap<String, String>,
+    pub prompt: String,
+    pub sample: usize,
+    /// The history of the agent once it responded.
+    pub transcript: Vec<InputMessage>,
+    /// The text of the final response.
+    pub response: Option<String>,
+    /// The final response parsed as JSON, if the dataset has an output schema and it matched.
+    pub output: Option<Value>,
+    /// Why the run failed or its response didn't match the output schema.
+    pub error: Option<String>,
+}
+
+/// What [`generate`] added to the dataset.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct DatasetSummary {
+    pub generated: usize,
+    pub failed: usize,
+    /// Runs skipped because they succeeded before.
+    pub skipped: usize,
+}
+
+/// Runs `agent` over the tasks of `config`, appending a record for every run to the
+/// `records.jsonl` of `dir`.
+///
+/// Every run has a runtime of its own built by `runtime`. The config and the agent are written
+/// next to the records. Failed runs are recorded with their error and don't stop the
+/// generation.
+pub async fn generate<B: Backend + Clone>(
+    runtime: &RuntimeBuilder,
+    backend: B,
+    model: B::Model,
+    agent: Agent,
+    config: &DatasetConfig,
+    dir: impl AsRef<Path>,
+) -> Result<DatasetSummary, KepokiError> {
+    let dir = dir.as_ref();
+    tokio::fs::create_dir_all(dir).await?;
+    tokio::fs::write(
+        dir.join("dataset.json"),
+        serde_json::to_vec_pretty(&serde_json::json!({ "config": config, "agent": agent }))
+            .map_err(std::io::Error::from)?,
+    )
+    .await?;
+
+    let records_path = dir.join("records.jsonl");
+    let mut existing = HashSet::new();
+    match tokio::fs::File::open(&records_path).await {
+        Ok(file) => {
+            let mut lines = BufReader::new(file).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if let Ok(record) = serde_json::from_str::<DatasetRecord>(&line)
+                    && record.error.is_none()
+                {
+                    existing.insert(record.id);
+                }
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+    let mut records = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&records_path)
+        .await?;
+
+    let mut summary = DatasetSummary::default();
+    let mut runs = Vec::new();
+    for (task, prompt, variables, position) in prompts(config) {
+        for sample in 0..config.samples {
+            let id = format!("{}-{position}-{sample}", task.name);
+            if existing.contains(&id) {
+                summary.skipped += 1;
+                continue;
+            }
+
+            runs.push(DatasetRecord {
+                id,
+                task: task.name.clone(),
+                variables: variables.clone(),
+                prompt: prompt.clone(),
+                sample,
+                transcript: Vec::new(),
+                response: None,
+                output: None,
+                error: None,
+            });
+        }
+    }
+
+    let mut runs = runs.into_iter();
+    let mut running = JoinSet::new();
+    loop {
+        while running.len() < config.concurrency.max(1)
+            && let Some(record) = runs.next()
+        {
+            let (runtime, backend, model, agent) = (
+                runtime.clone(),
+                backend.clone(),
+                model.clone(),
+                agent.clone(),
+            );
+            let schema = config.output_schema.clone();
+            running
+                .spawn(async move { run(&runtime, backend, model, agent, schema, record).await });
+        }
+
+        let Some(joined) = running.join_next().await else {
+            break;
+        };
+        let record = joined?;
+        match record.error {
+            Some(_) => summary.failed += 1,
+            None => summary.generated += 1,
+        }
+        let mut line = serde_json::to_vec(&record).map_err(std::io::Error::from)?;
+        line.push(b'\n');
+        records.write_all(&line).await?;
+    }
+    // Writes of Tokio files may still be in flight until flushed.
+    records.flush().await?;
+
+    Ok(summary)
+}
+
+/// The prompts to run with their task, variables, and position among the expansions of the
+/// task.
+fn prompts(
+    config: &DatasetConfig,
+) -> Vec<(&TaskTemplate, String, BTreeMap<String, String>, usize)> {
+    let mut prompts = config
+        .tasks
+        .iter()
+        .flat_map(|task| {
+            task.expand()
+                .into_iter()
+                .enumerate()
+                .map(move |(position, (variables, prompt))| (task, prompt, variables, position))
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(max_prompts) = config.max_prompts
+        && max_prompts < prompts.len()
+    {
+        // A partial Fisher-Yates shuffle, ordered again so records keep the order of the tasks.
+        let mut state = config.seed;
+        for index in 0..max_prompts {
+            let chosen = index + (splitmix64(&mut state) % (prompts.len() - index) as u64) as usize;
+            prompts.swap(index, chosen);
+        }
+        prompts.truncate(max_prompts);
+        prompts.sort_by_key(|(task, _, _, position)| {
+            let task = config
+                .tasks
+                .iter()
+                .position(|candidate| std::ptr::eq(candidate, *task));
+            (task, *position)
+        });
+    }
+    prompts
+}
+
+/// Advances the state of a SplitMix64 generator, which is enough to choose prompts.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Runs the prompt of `record` on an agent of its own, filling in the rest of the record.
+async fn run<B: Backend>(
+    runtime: &RuntimeBuilder,
+    backend: B,
+    model: B::Model,
+    agent: Agent,
+    schema: Option<Value>,
+    mut record: DatasetRecord,
+) -> DatasetRecord {
+    let message = match &schema {
+        Some(schema) => format!(
+            "{}\n\nRespond only with JSON matching this schema:\n{schema}",
+            record.prompt
+        ),
+        None => record.prompt.clone(),
+    };
+
+    let mut runtime = runtime.build();
+    let handle = runtime.spawn_agent(backend, model, agent);
+    let response = respond(&mut runtime, &handle, message).await;
+    if runtime
+        .send_as_owner(&handle, AgentCommand::DumpState)
+        .is_ok()
+    {
+        while let Ok(event) = runtime.recv().await {
+            if let AgentEvent::StateDump(state) = event {
+                record.transcript = state.messages.into();
+                break;
+            }
+        }
+    }
+    shutdown(&mut runtime, &[handle]).await;
+
+    match response {
+        Ok(response) => {
+            if let Some(schema) = &schema {
+                match serde_json::from_str::<Value>(strip_code_fence(&response))
+                    .map_err(|err| err.to_string())
+                    .and_then(|output| {
+                        validate(&output, schema, "$")?;
+                        Ok(output)
+                    }) {
+                    Ok(output) => record.output = Some(output),
+                    Err(error) => record.error = Some(error),
+                }
+            }
+            record.response = Some(response);
+        }
+        Err(err) => record.error = Some(err.to_string()),
+    }
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_and_choose_prompts() {
+        let task = TaskTemplate {
+            name: "greet".to_string(),
+            prompt: "Greet {{name}} in {{language}}.".to_string(),
+            variables: BTreeMap::from([
+                (
+                    "name".to_string(),
+                    vec!["Ada".to_string(), "Alan".to_string()],
+                ),
+                (
+                    "language".to_string(),
+                    vec!["French".to_string(), "German".to_string()],
+                ),
+            ]),
+        };
+        let expanded = task.expand();
+        assert_eq!(expanded.len(), 4);
+        assert_eq!(expanded[0].1, "Greet Ada in French.");
+
+        let mut config = DatasetConfig::new(vec![task]);
+        config.max_prompts = Some(2);
+        config.seed = 7;
+        let chosen = prompts(&config)
+            .into_iter()
+            .map(|(_, prompt, ..)| prompt)
+            .collect::<Vec<_>>();
+        assert_eq!(chosen.len(), 2);
+        assert_eq!(
+            chosen,
+            prompts(&config)
+                .into_iter()
+                .map(|(_, prompt, ..)| prompt)
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! A log of what every turn of an agent changed, used to diff the state of an agent between two
+//! turns when debugging it.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::backend::ContentBlock;
+use crate::backend::InputMessage;
+use crate::backend::Role;
+use crate::backend::Usage;
+use crate::runtime::AgentHandle;
+use crate::runtime::replay::comparable;
+
+/// What a single turn, one response of the model and the results of its tool calls, changed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct TurnRecord {
+    /// When the turn ended.
+    #[serde(default = "SystemTime::now")]
+    pub at: SystemTime,
+    /// The messages added to the history since the previous turn, including the user message
+    /// that started it.
+    ///
+    /// Empty once the record was purged, see [`Retention::max_age`].
+    ///
+    /// [`Retention::max_age`]: crate::runtime::retention::Retention::max_age
+    pub messages: Vec<InputMessage>,
+    /// The version of every artifact of the agent at the end of the turn.
+    pub artifacts: BTreeMap<String, u32>,
+    /// The usage reported by the backend for the response, if any.
+    pub usage: Option<Usage>,
+}
+
+/// The changes to the state of an agent between two turns.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StateDiff {
+    pub from_turn: usize,
+    pub to_turn: usize,
+    /// Messages added to the history by the turns after `from_turn` up to `to_turn`.
+    ///
+    /// Messages removed by rewinding are not subtracted, see [`AgentState::history_edits`].
+    /// Messages of purged turns are left out.
+    ///
+    /// [`AgentState::history_edits`]: crate::runtime::agent::AgentState::history_edits
+    pub messages_added: Vec<InputMessage>,
+    pub artifacts_changed: Vec<ArtifactDiff>,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ArtifactDiff {
+    pub name: String,
+    /// The version at `from_turn`, `None` if the artifact was created afterwards.
+    pub from_version: Option<u32>,
+    pub to_version: u32,
+}
+
+/// How two agents responded to the same inputs turn by turn, such as agents spawned from the
+/// same state with different prompts or models.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Comparison {
+    pub turns: Vec<TurnComparison>,
+    /// The input tokens of every turn of the first agent, then of the second.
+    pub input_tokens: [u64; 2],
+    /// The output tokens of every turn of the first agent, then of the second.
+    pub output_tokens: [u64; 2],
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct TurnComparison {
+    /// The turn, starting at 1.
+    pub turn: usize,
+    /// The response of each agent, `None` if it didn't get this far.
+    pub responses: [Option<Vec<ContentBlock>>; 2],
+    /// Whether the responses have the same text and tool calls, ignoring thinking and the ids
+    /// of tool uses.
+    pub same: bool,
+}
+
+/// Turn records for every agent in a runtime.
+#[derive(Clone, Debug, Default)]
+pub struct TurnLog {
+    turns: Arc<Mutex<HashMap<AgentHandle, Vec<TurnRecord>>>>,
+    disabled: Arc<AtomicBool>,
+}
+
+impl TurnLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops or resumes recording turns, records already made are kept.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.disabled.store(!enabled, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, agent: &AgentHandle, record: TurnRecord) {
+        if self.disabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        self.turns
+            .lock()
+            .unwrap()
+            .entry(agent.clone())
+            .or_default()
+            .push(record);
+    }
+
+    /// The number of turns an agent completed.
+    pub fn len(&self, agent: &AgentHandle) -> usize {
+        self.turns.lock().unwrap().get(agent).map_or(0, Vec::len)
+    }
+
+    pub fn is_empty(&self, agent: &AgentHandle) -> bool {
+        self.len(agent) == 0
+    }
+
+    /// Drops the messages of every turn that ended before `cutoff`, keeping the rest of the
+    /// record so turns keep their numbers.
+    pub fn purge(&self, cutoff: SystemTime) {
+        for records in self.turns.lock().unwrap().values_mut() {
+            for record in records.iter_mut().take_while(|record| record.at < cutoff) {
+                record.messages = Vec::new();
+            }
+        }
+    }
+
+    /// Drops every record of `agent`.
+    pub fn remove_agent(&self, agent: &AgentHandle) {
+        self.turns.lock().unwrap().remove(agent);
+    }
+
+    /// Diffs the state of an agent after `from_turn` against its state after `to_turn`.
+    ///
+    /// Turns are numbered from 1, turn 0 is the state of the agent before its first turn.
+    /// Returns `None` if either turn hasn't happened yet or `from_turn` comes after `to_turn`.
+    pub fn diff(&self, agent: &AgentHandle, from_turn: usize, to_turn: usize) -> Option<StateDiff> {
+        let turns = self.turns.lock().unwrap();
+        let records = turns.get(agent).map_or(&[][..], Vec::as_slice);
+        if from_turn > to_turn || to_turn > records.len() {
+            return None;
+        }
+
+        let empty = BTreeMap::new();
+        let artifacts_at = |turn: usize| match turn {
+            0 => &empty,
+            turn => &records[turn - 1].artifacts,
+        };
+        let (from_artifacts, to_artifacts) = (artifacts_at(from_turn), artifacts_at(to_turn));
+        let artifacts_changed = to_artifacts
+            .iter()
+            .filter(|(name, version)| from_artifacts.get(*name) != Some(version))
+            .map(|(name, version)| ArtifactDiff {
+                name: name.clone(),
+                from_version: from_artifacts.get(name).copied(),
+                to_version: *version,
+            })
+            .collect();
+
+        let changed = &records[from_turn..to_turn];
+        let usage = changed.iter().filter_map(|record| record.usage.as_ref());
+        Some(StateDiff {
+            from_turn,
+            to_turn,
+            messages_added: changed
+                .iter()
+                .flat_map(|record| record.messages.iter().cloned())
+                .collect(),
+            artifacts_changed,
+            input_tokens: usage
+                .clone()
+                .map(|usage| u64::from(usage.input_tokens))
+                .sum(),
+            output_tokens: usage.map(|usage| u64::from(usage.output_tokens)).sum(),
+        })
+    }
+
+    /// Compares the responses of two agents turn by turn, aligning them by their number.
+    ///
+    /// Responses of purged turns are empty.
+    pub fn compare(&self, a: &AgentHandle, b: &AgentHandle) -> Comparison {
+        let turns = self.turns.lock().unwrap();
+        let records = [a, b].map(|agent| turns.get(agent).map_or(&[][..], Vec::as_slice));
+        let count = records[0].len().max(records[1].len());
+        let turns = (0..count)
+            .map(|index| {
+                let responses = records.map(|records| records.get(index).map(response));
+                TurnComparison {
+                    turn: index + 1,
+                    same: match &responses {
+                        [Some(a), Some(b)] => comparable(a) == comparable(b),
+                        _ => false,
+                    },
+                    responses,
+                }
+            })
+            .collect();
+
+        let usage =
+            records.map(|records| records.iter().filter_map(|record| record.usage.as_ref()));
+        Comparison {
+            turns,
+            input_tokens: usage
+                .clone()
+                .map(|usage| usage.map(|usage| u64::from(usage.input_tokens)).sum()),
+            output_tokens: usage
+                .map(|usage| usage.map(|usage| u64::from(usage.output_tokens)).sum()),
+        }
+    }
+}
+
+/// The content of the responses of a turn.
+fn response(record: &TurnRecord) -> Vec<ContentBlock> {
+    record
+        .messages
+        .iter()
+        .filter(|message| message.role == Role::Assistant)
+        .flat_map(|message| message.content.iter().cloned())
+        .collect()
+}
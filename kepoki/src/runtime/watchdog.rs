@@ -0,0 +1,262 @@
+//! Flags agents that stopped making progress in the middle of a turn, such as when a provider
+//! stops streaming without closing the connection.
+//!
+//! Agents progress whenever the backend streams an event and whenever a tool finishes. Agents
+//! waiting for a user message, paused, or queued for a request slot are never stalled.
+//! Heartbeats don't count as progress.
+//!
+//! Terminating a stalled agent cancels it, abandoning the stalled request or tool call right
+//! away, see [`crate::runtime::cancellation`]. Pausing takes effect once the stalled call
+//! returns or times out.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use tokio::sync::mpsc::WeakUnboundedSender;
+
+use crate::runtime::AgentHandle;
+use crate::runtime::agent::AgentCommand;
+use crate::runtime::agent::AgentEvent;
+use crate::runtime::cancellation::CancellationToken;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Watchdog {
+    /// How long a busy agent may go without progress before it is stalled.
+    pub window: Duration,
+    pub on_stall: StallAction,
+}
+
+impl Watchdog {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            on_stall: StallAction::default(),
+        }
+    }
+
+    pub fn with_action(mut self, on_stall: StallAction) -> Self {
+        self.on_stall = on_stall;
+        self
+    }
+}
+
+/// What the watchdog does to a stalled agent besides emitting [`AgentEvent::Stalled`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum StallAction {
+    #[default]
+    Notify,
+    /// Pauses the agent, unpausing it continues the conversation.
+    Pause,
+    /// Terminates the agent, abandoning the stalled call.
+    Terminate,
+}
+
+#[derive(Debug)]
+struct ProgressState {
+    busy: usize,
+    last: Instant,
+    stalled: bool,
+}
+
+/// When an agent last made progress.
+#[derive(Clone, Debug)]
+pub struct Progress {
+    state: Arc<Mutex<ProgressState>>,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ProgressState {
+                busy: 0,
+                last: Instant::now(),
+                stalled: false,
+            })),
+        }
+    }
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks the agent as busy until the guard is dropped.
+    pub fn busy(&self) -> BusyGuard {
+        self.state.lock().unwrap().busy += 1;
+        self.advance();
+        BusyGuard {
+            progress: self.clone(),
+        }
+    }
+
+    pub fn advance(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.last = Instant::now();
+        state.stalled = false;
+    }
+
+    /// How long the agent has been without progress if it is busy and stalled for the first
+    /// time since it last progressed.
+    fn stall(&self, window: Duration) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let idle = state.last.elapsed();
+        if state.busy == 0 || state.stalled || idle < window {
+            return None;
+        }
+
+        state.stalled = true;
+        Some(idle)
+    }
+}
+
+#[derive(Debug)]
+pub struct BusyGuard {
+    progress: Progress,
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.progress.state.lock().unwrap().busy -= 1;
+    }
+}
+
+/// An agent watched by the watchdog, until its channels are closed.
+#[derive(Clone, Debug)]
+pub(crate) struct Watched {
+    pub progress: Progress,
+    pub event_emitter: WeakUnboundedSender<AgentEvent>,
+    pub command_emitter: WeakUnboundedSender<(AgentCommand, Option<CancellationToken>)>,
+    /// Cancelled to terminate the agent, even in the middle of a call.
+    pub cancellation: CancellationToken,
+}
+
+pub(crate) type WatchedAgents = Arc<Mutex<HashMap<AgentHandle, Watched>>>;
+
+/// Checks the agents for stalls a few times per window, until the task is aborted.
+pub(crate) async fn watch(agents: WatchedAgents, watchdog: Watchdog) {
+    let period = (watchdog.window / 4).max(Duration::from_millis(10));
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        agents.lock().unwrap().retain(|_, watched| {
+            let Some(event_emitter) = watched.event_emitter.upgrade() else {
+                return false;
+            };
+            let Some(idle) = watched.progress.stall(watchdog.window) else {
+                return true;
+            };
+
+            let _ = event_emitter.send(AgentEvent::Stalled { idle });
+            match watchdog.on_stall {
+                StallAction::Notify => (),
+                StallAction::Pause => {
+                    if let Some(command_emitter) = watched.command_emitter.upgrade() {
+                        let _ = command_emitter.send((AgentCommand::Pause, None));
+                    }
+                }
+                StallAction::Terminate => watched.cancellation.cancel(),
+            }
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+    use crate::agent::Agent;
+    use crate::error::KepokiError;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+    use crate::runtime::Runtime;
+
+    #[test]
+    fn test_stalls_once_while_busy() {
+        let progress = Progress::new();
+        assert!(progress.stall(Duration::ZERO).is_none());
+
+        let busy = progress.busy();
+        assert!(progress.stall(Duration::from_secs(60)).is_none());
+        assert!(progress.stall(Duration::ZERO).is_some());
+        assert!(progress.stall(Duration::ZERO).is_none());
+
+        progress.advance();
+        assert!(progress.stall(Duration::ZERO).is_some());
+
+        drop(busy);
+        progress.advance();
+        assert!(progress.stall(Duration::ZERO).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_watch_pauses_stalled_agents() {
+        let (event_emitter, mut events) = unbounded_channel();
+        let (command_emitter, mut commands) = unbounded_channel();
+        let progress = Progress::new();
+        let _busy = progress.busy();
+        let agents = WatchedAgents::default();
+        agents.lock().unwrap().insert(
+            AgentHandle {
+                name: "agent".to_string(),
+                uuid: [0; 16],
+            },
+            Watched {
+                progress,
+                event_emitter: event_emitter.downgrade(),
+                command_emitter: command_emitter.downgrade(),
+                cancellation: CancellationToken::new(),
+            },
+        );
+
+        let watchdog = Watchdog::new(Duration::from_millis(20)).with_action(StallAction::Pause);
+        let watch = tokio::spawn(watch(agents.clone(), watchdog));
+        assert!(matches!(
+            events.recv().await,
+            Some(AgentEvent::Stalled { idle }) if idle >= Duration::from_millis(20)
+        ));
+        assert!(matches!(
+            commands.recv().await,
+            Some((AgentCommand::Pause, None))
+        ));
+
+        // Agents whose events are no longer received stop being watched.
+        drop((event_emitter, events));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(agents.lock().unwrap().is_empty());
+        watch.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_terminate_agent_stalled_in_stream() {
+        let backend = MockBackend::new().with_response(MockResponse::text("Hello").with_stall(2));
+        let mut runtime = Runtime::builder().build();
+        runtime.set_watchdog(Some(
+            Watchdog::new(Duration::from_millis(50)).with_action(StallAction::Terminate),
+        ));
+        let agent = runtime.spawn_agent(backend, "mock".to_string(), Agent::default());
+        runtime
+            .send_as_owner(&agent, AgentCommand::UserMessage("Hi".to_string()))
+            .unwrap();
+
+        let (mut stalled, mut terminated) = (false, false);
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match runtime.recv().await {
+                    Ok(AgentEvent::Stalled { .. }) => stalled = true,
+                    Ok(AgentEvent::Terminated(_)) => terminated = true,
+                    Err(KepokiError::NoRunningAgents) => break,
+                    _ => (),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(stalled && terminated);
+    }
+}
@@ -0,0 +1,353 @@
+//! Batch processing with a pool of identical worker agents pulling tasks from a [`TaskQueue`].
+//!
+//! Every task is handled in a session of its own, so tasks never see each other's
+//! conversations, and failed tasks are returned to the queue until they run out of attempts.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::task::JoinSet;
+
+use crate::agent::Agent;
+use crate::backend::Backend;
+use crate::error::KepokiError;
+use crate::runtime::builder::RuntimeBuilder;
+use crate::runtime::patterns::run_once;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Task {
+    pub id: String,
+    /// The message sent to the worker.
+    pub input: String,
+    /// The number of times a worker started the task.
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+impl Task {
+    pub fn new(id: impl Into<String>, input: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            input: input.into(),
+            attempts: 0,
+        }
+    }
+}
+
+/// A queue of tasks for a [`WorkerPool`], implement it to back the pool with a durable queue.
+pub trait TaskQueue: Send + Sync + 'static {
+    /// Takes the next task, `None` once the queue is empty.
+    fn pop(&self) -> Result<Option<Task>, KepokiError>;
+    /// Adds a task to the end of the queue, failed tasks are pushed again to be retried.
+    fn push(&self, task: Task) -> Result<(), KepokiError>;
+}
+
+/// A queue held in memory, clones share their tasks.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryQueue {
+    tasks: Arc<Mutex<VecDeque<Task>>>,
+}
+
+impl InMemoryQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.lock().unwrap().is_empty()
+    }
+}
+
+impl FromIterator<Task> for InMemoryQueue {
+    fn from_iter<T: IntoIterator<Item = Task>>(iter: T) -> Self {
+        Self {
+            tasks: Arc::new(Mutex::new(iter.into_iter().collect())),
+        }
+    }
+}
+
+impl TaskQueue for InMemoryQueue {
+    fn pop(&self) -> Result<Option<Task>, KepokiError> {
+        Ok(self.tasks.lock().unwrap().pop_front())
+    }
+
+    fn push(&self, task: Task) -> Result<(), KepokiError> {
+        self.tasks.lock().unwrap().push_back(task);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct TaskResult {
+    pub id: String,
+    /// The number of times a worker started the task, including the last attempt.
+    pub attempts: u32,
+    pub outcome: TaskOutcome,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum TaskOutcome {
+    /// The final response of the worker.
+    Completed(String),
+    /// The error of the last attempt, once the task ran out of attempts.
+    Failed(String),
+}
+
+/// The outcome of [`WorkerPool::run`].
+#[derive(Debug)]
+pub struct PoolReport {
+    /// The results of the tasks that completed or ran out of attempts, in the order they did.
+    pub results: Vec<TaskResult>,
+    /// The error of the queue that stopped the pool, `None` if the pool emptied the queue.
+    pub error: Option<KepokiError>,
+}
+
+/// Runs up to `workers` agents of the same definition at a time, each handling one task in a
+/// session of its own.
+pub struct WorkerPool<B: Backend> {
+    runtime: RuntimeBuilder,
+    backend: B,
+    model: B::Model,
+    agent: Agent,
+    workers: usize,
+    max_attempts: u32,
+}
+
+impl<B: Backend + Clone> WorkerPool<B> {
+    /// A pool whose sessions run on runtimes built by `runtime`.
+    pub fn new(runtime: &RuntimeBuilder, backend: B, model: B::Model, agent: Agent) -> Self {
+        Self {
+            runtime: runtime.clone(),
+            backend,
+            model,
+            agent,
+            workers: 4,
+            max_attempts: 3,
+        }
+    }
+
+    /// Sets the number of tasks handled at a time.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Sets the number of times a task is attempted before it is reported as failed.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Handles tasks until the queue is empty and reports their results in the order they
+    /// completed.
+    ///
+    /// Failed tasks are pushed to the end of the queue again until they reached the attempt
+    /// limit. Only errors of the queue itself stop the pool, once the tasks in progress are
+    /// done, and a task that couldn't be pushed again fails. The results of the tasks handled
+    /// before are reported along with the error.
+    pub async fn run(&self, queue: &impl TaskQueue) -> PoolReport {
+        let mut results = Vec::new();
+        let mut running = JoinSet::new();
+        // The tasks in progress by the ID of their Tokio task, in case their worker panics.
+        let mut started = HashMap::new();
+        let mut queue_error = None;
+        loop {
+            while queue_error.is_none() && running.len() < self.workers {
+                let mut task = match queue.pop() {
+                    Ok(Some(task)) => task,
+                    Ok(None) => break,
+                    Err(err) => {
+                        queue_error = Some(err);
+                        break;
+                    }
+                };
+                task.attempts += 1;
+                let (runtime, backend, model, agent) = (
+                    self.runtime.clone(),
+                    self.backend.clone(),
+                    self.model.clone(),
+                    self.agent.clone(),
+                );
+                let started_task = (task.id.clone(), task.attempts);
+                let handle = running.spawn(async move {
+                    let result =
+                        run_once(&runtime, backend, model, agent, task.input.clone()).await;
+                    (task, result)
+                });
+                started.insert(handle.id(), started_task);
+            }
+
+            let Some(joined) = running.join_next_with_id().await else {
+                break;
+            };
+            let (task, result) = match joined {
+                Ok((id, joined)) => {
+                    started.remove(&id);
+                    joined
+                }
+                Err(err) => {
+                    let (id, attempts) = started.remove(&err.id()).unwrap_or_default();
+                    tracing::error!("Worker of task {id} failed: {err}");
+                    results.push(TaskResult {
+                        id,
+                        attempts,
+                        outcome: TaskOutcome::Failed(err.to_string()),
+                    });
+                    continue;
+                }
+            };
+            match result {
+                Ok(output) => results.push(TaskResult {
+                    id: task.id,
+                    attempts: task.attempts,
+                    outcome: TaskOutcome::Completed(output),
+                }),
+                Err(err) if task.attempts < self.max_attempts => {
+                    tracing::warn!(
+                        "Task {} failed on attempt {}, retrying: {err}",
+                        task.id,
+                        task.attempts
+                    );
+                    let (id, attempts) = (task.id.clone(), task.attempts);
+                    if let Err(push_err) = queue.push(task) {
+                        tracing::error!("Failed to requeue task {id}: {push_err}");
+                        results.push(TaskResult {
+                            id,
+                            attempts,
+                            outcome: TaskOutcome::Failed(err.to_string()),
+                        });
+                        queue_error.get_or_insert(push_err);
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "Task {} failed on attempt {}: {err}",
+                        task.id,
+                        task.attempts
+                    );
+                    results.push(TaskResult {
+                        id: task.id,
+                        attempts: task.attempts,
+                        outcome: TaskOutcome::Failed(err.to_string()),
+                    });
+                }
+            }
+        }
+
+        PoolReport {
+            results,
+            error: queue_error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBackend;
+    use crate::mock::MockResponse;
+
+    /// A queue that fails once it ran out of tasks.
+    struct BrokenQueue(InMemoryQueue);
+
+    impl TaskQueue for BrokenQueue {
+        fn pop(&self) -> Result<Option<Task>, KepokiError> {
+            match self.0.pop()? {
+                Some(task) => Ok(Some(task)),
+                None => Err(KepokiError::Io(std::io::Error::other("Queue unavailable"))),
+            }
+        }
+
+        fn push(&self, _: Task) -> Result<(), KepokiError> {
+            Err(KepokiError::Io(std::io::Error::other("Queue unavailable")))
+        }
+    }
+
+    fn server_error() -> MockResponse {
+        MockResponse::error(KepokiError::Provider {
+            kind: crate::error::ProviderErrorKind::Server,
+            message: "500".to_string(),
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_failed_tasks_are_retried() {
+        let backend = MockBackend::new()
+            .with_response(server_error())
+            .with_response(MockResponse::text("B"))
+            .with_response(MockResponse::text("A"));
+        let queue = [Task::new("a", "First"), Task::new("b", "Second")]
+            .into_iter()
+            .collect::<InMemoryQueue>();
+        let pool = WorkerPool::new(
+            &RuntimeBuilder::new(),
+            backend,
+            "mock".to_string(),
+            Agent::default(),
+        )
+        .with_workers(1)
+        .with_max_attempts(2);
+
+        let report = pool.run(&queue).await;
+        assert!(report.error.is_none());
+        let results = report.results;
+        assert!(queue.is_empty());
+        assert!(matches!(
+            &results[..],
+            [
+                TaskResult { id: b, attempts: 1, outcome: TaskOutcome::Completed(first) },
+                TaskResult { id: a, attempts: 2, outcome: TaskOutcome::Completed(second) },
+            ] if (b.as_str(), first.as_str(), a.as_str(), second.as_str()) == ("b", "B", "a", "A")
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_queue_error_waits_for_running_tasks() {
+        let backend = MockBackend::new().with_response(MockResponse::text("Done"));
+        let queue = BrokenQueue([Task::new("a", "First")].into_iter().collect());
+        let pool = WorkerPool::new(
+            &RuntimeBuilder::new(),
+            backend.clone(),
+            "mock".to_string(),
+            Agent::default(),
+        );
+
+        let report = pool.run(&queue).await;
+        assert!(matches!(report.error, Some(KepokiError::Io(_))));
+        assert!(matches!(
+            &report.results[..],
+            [TaskResult { id, attempts: 1, outcome: TaskOutcome::Completed(output) }]
+                if id == "a" && output == "Done"
+        ));
+        assert_eq!(backend.requests().len(), 1);
+        assert_eq!(backend.remaining(), 0);
+
+        let backend = MockBackend::new().with_response(server_error());
+        let queue = BrokenQueue([Task::new("a", "First")].into_iter().collect());
+        let pool = WorkerPool::new(
+            &RuntimeBuilder::new(),
+            backend.clone(),
+            "mock".to_string(),
+            Agent::default(),
+        );
+        let report = pool.run(&queue).await;
+        assert!(matches!(report.error, Some(KepokiError::Io(_))));
+        // The task couldn't be pushed again to be retried.
+        assert!(matches!(
+            &report.results[..],
+            [TaskResult { id, attempts: 1, outcome: TaskOutcome::Failed(_) }] if id == "a"
+        ));
+        assert_eq!(backend.requests().len(), 1);
+    }
+}
@@ -0,0 +1,384 @@
+//! Confines the processes agents spawn, such as local MCP servers, to the working directory of
+//! the agent as defense in depth for agents acting autonomously.
+//!
+//! Processes run with a read-only view of the system, can only write to the working directory
+//! and the paths the [`Sandbox`] allows, and can't see the home directory of the user unless
+//! the working directory is inside it. On Linux they are run in namespaces with
+//! [bubblewrap](https://github.com/containers/bubblewrap), which must be installed as `bwrap`.
+//! On macOS they are run with `sandbox-exec`. Spawning fails on other platforms rather than
+//! running processes unconfined.
+//!
+//! Package managers such as `npx` and `uvx` cache in the home directory, their caches need to
+//! be allowed explicitly for servers launched through them to start quickly.
+//!
+//! Agents with `execution: container` run their processes in a [`Container`] instead, created
+//! from the image of the agent with the Docker or Podman client.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use tokio::process::Command;
+
+use crate::agent::ContainerSettings;
+use crate::agent::Execution;
+use crate::agent::Sandbox;
+use crate::error::KepokiError;
+
+/// How the processes of an agent are isolated from the host.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Isolation {
+    Jail(Jail),
+    Container(Container),
+}
+
+impl Isolation {
+    /// The isolation the agent asks for, `None` if its processes run unconfined.
+    ///
+    /// Containers take precedence over the sandbox of the agent.
+    pub fn of(agent: &crate::agent::Agent) -> Option<Self> {
+        // Bind mounts and volumes need absolute paths.
+        let root = || {
+            let root = agent
+                .roots()
+                .into_iter()
+                .next()
+                .unwrap_or_else(|| ".".into());
+            std::fs::canonicalize(&root)
+                .or_else(|_| std::path::absolute(&root))
+                .unwrap_or(root)
+        };
+        match agent.execution {
+            Execution::Container => Some(Self::Container(Container::new(
+                root(),
+                agent.container.clone().unwrap_or_default(),
+            ))),
+            Execution::Host => agent
+                .sandbox
+                .clone()
+                .map(|sandbox| Self::Jail(Jail::new(root(), sandbox))),
+        }
+    }
+
+    /// A command running `program` in isolation with the environment variables `env`.
+    pub fn command<'a>(
+        &self,
+        program: impl AsRef<str>,
+        args: impl IntoIterator<Item = impl AsRef<str>>,
+        env: impl IntoIterator<Item = (&'a String, &'a String)>,
+    ) -> Result<Command, KepokiError> {
+        match self {
+            Self::Jail(jail) => {
+                let mut command = jail.command(program, args)?;
+                command.envs(env);
+                Ok(command)
+            }
+            Self::Container(container) => container.command(program, args, env),
+        }
+    }
+}
+
+/// A sandbox rooted at the working directory of an agent.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Jail {
+    root: PathBuf,
+    sandbox: Sandbox,
+}
+
+impl Jail {
+    pub fn new(root: impl Into<PathBuf>, sandbox: Sandbox) -> Self {
+        Self {
+            root: root.into(),
+            sandbox,
+        }
+    }
+
+    /// The directory processes in the jail may write to.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// A command running `program` inside the jail, starting in its root.
+    ///
+    /// Builtin tools that run shell commands should spawn them through this.
+    pub fn command(
+        &self,
+        program: impl AsRef<str>,
+        args: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<Command, KepokiError> {
+        let program = program.as_ref();
+        let args = args
+            .into_iter()
+            .map(|arg| arg.as_ref().to_string())
+            .collect::<Vec<_>>();
+        let home = std::env::var_os("HOME").map(PathBuf::from);
+
+        let mut command = match std::env::consts::OS {
+            "linux" => self.bubblewrap(home.as_deref()),
+            "macos" => self.sandbox_exec(home.as_deref()),
+            os => {
+                return Err(KepokiError::SandboxUnsupported(format!(
+                    "Sandboxing is not supported on {os}"
+                )));
+            }
+        };
+        command.arg(program).args(args).current_dir(&self.root);
+        Ok(command)
+    }
+
+    fn bubblewrap(&self, home: Option<&Path>) -> Command {
+        let mut command = Command::new("bwrap");
+        command.args(["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc"]);
+        command.args(["--tmpfs", "/tmp"]);
+        if let Some(home) = home {
+            command.arg("--tmpfs").arg(home);
+        }
+        for path in &self.sandbox.readable_paths {
+            command.arg("--ro-bind-try").arg(path).arg(path);
+        }
+        for path in std::iter::once(&self.root).chain(&self.sandbox.writable_paths) {
+            command.arg("--bind").arg(path).arg(path);
+        }
+
+        command.args(["--unshare-all", "--die-with-parent", "--new-session"]);
+        if self.sandbox.network {
+            command.arg("--share-net");
+        }
+        command.arg("--chdir").arg(&self.root).arg("--");
+        command
+    }
+
+    fn sandbox_exec(&self, home: Option<&Path>) -> Command {
+        let subpath = |path: &Path| {
+            let path = path.display().to_string();
+            format!(
+                "(subpath \"{}\")",
+                path.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        };
+        let writable = std::iter::once(&self.root)
+            .chain(&self.sandbox.writable_paths)
+            .map(|path| subpath(path))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let readable = self
+            .sandbox
+            .readable_paths
+            .iter()
+            .map(|path| subpath(path))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        // Later rules take precedence over earlier ones.
+        let mut profile = "(version 1)\n(allow default)\n".to_string();
+        if let Some(home) = home {
+            profile.push_str(&format!("(deny file-read* {})\n", subpath(home)));
+        }
+        profile.push_str(&format!(
+            "(deny file-write*)\n(allow file-write* {writable} (subpath \"/private/tmp\") (subpath \"/private/var/folders\") (literal \"/dev/null\") (literal \"/dev/tty\"))\n(allow file-read* {writable} {readable})\n"
+        ));
+        if !self.sandbox.network {
+            profile.push_str("(deny network*)\n");
+        }
+
+        let mut command = Command::new("sandbox-exec");
+        command.arg("-p").arg(profile);
+        command
+    }
+}
+
+/// A container with the working directory of an agent mounted at the same path.
+///
+/// Containers are removed when their process exits. The working directory is the only
+/// directory shared with the host, and the network is disabled unless the settings allow it.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Container {
+    root: PathBuf,
+    settings: ContainerSettings,
+}
+
+impl Container {
+    pub fn new(root: impl Into<PathBuf>, settings: ContainerSettings) -> Self {
+        Self {
+            root: root.into(),
+            settings,
+        }
+    }
+
+    /// A command running `program` in a new container with the environment variables `env`,
+    /// starting in the working directory.
+    pub fn command<'a>(
+        &self,
+        program: impl AsRef<str>,
+        args: impl IntoIterator<Item = impl AsRef<str>>,
+        env: impl IntoIterator<Item = (&'a String, &'a String)>,
+    ) -> Result<Command, KepokiError> {
+        if self.settings.image.trim().is_empty() {
+            return Err(KepokiError::InvalidContainer(
+                "Container execution requires an image".to_string(),
+            ));
+        }
+
+        let mount = format!("{0}:{0}", self.root.display());
+        let mut command = Command::new(self.settings.engine.program());
+        command.args(["run", "--rm", "--interactive", "--init"]);
+        command.arg("--volume").arg(mount);
+        command.arg("--workdir").arg(&self.root);
+        if !self.settings.network {
+            command.args(["--network", "none"]);
+        }
+        if let Some(memory) = &self.settings.memory {
+            command.arg("--memory").arg(memory);
+        }
+        if let Some(cpus) = &self.settings.cpus {
+            command.arg("--cpus").arg(cpus);
+        }
+        // Variables named without a value are copied from the environment of the client.
+        for (key, value) in env {
+            command.arg("--env").arg(key).env(key, value);
+        }
+
+        command
+            .arg(&self.settings.image)
+            .arg(program.as_ref())
+            .args(args.into_iter().map(|arg| arg.as_ref().to_string()));
+        Ok(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::Agent;
+
+    #[test]
+    fn test_isolation_root_is_absolute() {
+        let agent = Agent {
+            working_directory: Some(".".into()),
+            execution: Execution::Container,
+            container: Some(ContainerSettings {
+                image: "node:22".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let current_dir = std::env::current_dir().unwrap().canonicalize().unwrap();
+
+        let Some(Isolation::Container(container)) = Isolation::of(&agent) else {
+            panic!("Expected a container");
+        };
+        let command = container
+            .command("ls", std::iter::empty::<&str>(), std::iter::empty())
+            .unwrap();
+        let args = command.as_std().get_args().collect::<Vec<_>>();
+        let mount = format!("{0}:{0}", current_dir.display());
+        assert!(
+            args.windows(2)
+                .any(|args| args == ["--volume", mount.as_str()])
+        );
+        assert!(
+            args.windows(2)
+                .any(|args| args == ["--workdir".as_ref(), current_dir.as_os_str()])
+        );
+
+        let agent = Agent {
+            execution: Execution::Host,
+            sandbox: Some(Sandbox::default()),
+            ..agent
+        };
+        let Some(Isolation::Jail(jail)) = Isolation::of(&agent) else {
+            panic!("Expected a jail");
+        };
+        assert_eq!(jail.root(), current_dir);
+    }
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_container_command() {
+        let container = Container::new(
+            "/work",
+            ContainerSettings {
+                image: "node:22".to_string(),
+                engine: crate::agent::ContainerEngine::Podman,
+                memory: Some("512m".to_string()),
+                ..Default::default()
+            },
+        );
+        let env = [("TOKEN".to_string(), "secret".to_string())];
+        let command = container
+            .command(
+                "npx",
+                ["server"],
+                env.iter().map(|(key, value)| (key, value)),
+            )
+            .unwrap();
+
+        assert_eq!(command.as_std().get_program(), "podman");
+        assert_eq!(
+            args(&command),
+            [
+                "run",
+                "--rm",
+                "--interactive",
+                "--init",
+                "--volume",
+                "/work:/work",
+                "--workdir",
+                "/work",
+                "--network",
+                "none",
+                "--memory",
+                "512m",
+                "--env",
+                "TOKEN",
+                "node:22",
+                "npx",
+                "server",
+            ]
+        );
+        // Values are passed through the environment of the client, not its arguments.
+        assert!(
+            command
+                .as_std()
+                .get_envs()
+                .any(|(key, value)| key == "TOKEN" && value == Some("secret".as_ref()))
+        );
+
+        let container = Container::new("/work", ContainerSettings::default());
+        assert!(matches!(
+            container.command("ls", std::iter::empty::<&str>(), std::iter::empty()),
+            Err(KepokiError::InvalidContainer(_))
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_bubblewrap_command() {
+        let jail = Jail::new(
+            "/work",
+            Sandbox {
+                readable_paths: vec!["/cache".into()],
+                writable_paths: vec!["/out".into()],
+                network: false,
+            },
+        );
+        let command = jail.command("ls", ["-a"]).unwrap();
+
+        assert_eq!(command.as_std().get_program(), "bwrap");
+        let args = args(&command);
+        let window = |expected: &[&str]| args.windows(expected.len()).any(|args| args == expected);
+        assert!(window(&["--ro-bind", "/", "/"]));
+        assert!(window(&["--ro-bind-try", "/cache", "/cache"]));
+        assert!(window(&["--bind", "/work", "/work"]));
+        assert!(window(&["--bind", "/out", "/out"]));
+        assert!(window(&["--chdir", "/work", "--", "ls", "-a"]));
+        assert!(window(&["--unshare-all"]));
+        assert!(!window(&["--share-net"]));
+    }
+}
@@ -0,0 +1,104 @@
+//! Loading of `mcpServers` configuration files as written by Claude Desktop and editors.
+//!
+//! ```json
+//! {
+//!     "mcpServers": {
+//!         "git": { "command": "uvx", "args": ["mcp-server-git"], "env": {} },
+//!         "docs": { "url": "https://example.com/mcp", "headers": { "Authorization": "Bearer ..." } }
+//!     }
+//! }
+//! ```
+//!
+//! VS Code's `servers` key is accepted as well.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::agent::LocalMcpServer;
+use crate::agent::McpServer;
+use crate::agent::RemoteMcpServer;
+use crate::error::KepokiError;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct McpConfigFile {
+    #[serde(default, alias = "servers")]
+    mcp_servers: HashMap<String, McpConfigEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum McpConfigEntry {
+    Local {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        env: HashMap<String, String>,
+    },
+    Remote {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+}
+
+impl From<McpConfigEntry> for McpServer {
+    fn from(entry: McpConfigEntry) -> Self {
+        match entry {
+            McpConfigEntry::Local { command, args, env } => {
+                McpServer::Local(LocalMcpServer { command, args, env })
+            }
+            McpConfigEntry::Remote { url, headers } => {
+                McpServer::Remote(RemoteMcpServer { url, headers })
+            }
+        }
+    }
+}
+
+/// Parses the servers of an MCP configuration file, keyed by server name.
+pub fn parse_mcp_config(json: &str) -> Result<HashMap<String, McpServer>, KepokiError> {
+    let config = serde_json::from_str::<McpConfigFile>(json)
+        .map_err(|err| KepokiError::InvalidMcpConfig(err.to_string()))?;
+
+    Ok(config
+        .mcp_servers
+        .into_iter()
+        .map(|(name, entry)| (name, entry.into()))
+        .collect())
+}
+
+/// Reads the servers of an MCP configuration file, keyed by server name.
+pub fn load_mcp_config(path: impl AsRef<Path>) -> Result<HashMap<String, McpServer>, KepokiError> {
+    parse_mcp_config(&std::fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mcp_config() {
+        let servers = parse_mcp_config(
+            r#"{
+                "mcpServers": {
+                    "git": { "command": "uvx", "args": ["mcp-server-git"] },
+                    "docs": { "type": "http", "url": "https://example.com/mcp" }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            &servers["git"],
+            McpServer::Local(LocalMcpServer { command, args, env })
+                if command == "uvx" && args == &["mcp-server-git"] && env.is_empty()
+        ));
+        assert!(matches!(
+            &servers["docs"],
+            McpServer::Remote(RemoteMcpServer { url, .. }) if url == "https://example.com/mcp"
+        ));
+    }
+}
@@ -0,0 +1,89 @@
+//! Stands in for the MCP client when the `mcp` feature is disabled, failing every tool of an MCP
+//! server.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::Map;
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use crate::agent::McpServer;
+use crate::backend::Tool;
+use crate::error::KepokiError;
+use crate::runtime::AgentHandle;
+use crate::sandbox::Isolation;
+use crate::tools::ToolOutput;
+
+#[derive(Clone, Debug)]
+pub struct McpServers {
+    tools_changed: broadcast::Sender<McpServer>,
+}
+
+impl Default for McpServers {
+    fn default() -> Self {
+        Self {
+            tools_changed: broadcast::channel(1).0,
+        }
+    }
+}
+
+impl McpServers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receives nothing, tools of MCP servers are never listed.
+    pub fn subscribe_tools_changed(&self) -> broadcast::Receiver<McpServer> {
+        self.tools_changed.subscribe()
+    }
+
+    pub fn with_idle_timeout(self, _idle_timeout: Option<Duration>) -> Self {
+        self
+    }
+
+    pub fn with_isolation(self, _isolation: Option<Isolation>) -> Self {
+        self
+    }
+
+    pub async fn list_tools(
+        &self,
+        _agent: &AgentHandle,
+        _server: &McpServer,
+    ) -> Result<Vec<Tool<'static>>, KepokiError> {
+        Err(disabled())
+    }
+
+    pub fn is_read_only(&self, _server: &McpServer, _name: &str) -> bool {
+        false
+    }
+
+    pub fn instructions(&self, _server: &McpServer) -> Option<String> {
+        None
+    }
+
+    pub fn definition(&self, _server: &McpServer, _name: &str) -> Option<Tool<'static>> {
+        None
+    }
+
+    pub async fn call_tool(
+        &self,
+        _agent: &AgentHandle,
+        _server: &McpServer,
+        _name: &str,
+        _arguments: Option<Map<String, Value>>,
+        _timeout: Duration,
+    ) -> Result<ToolOutput, KepokiError> {
+        Err(disabled())
+    }
+
+    pub async fn set_roots(&self, _agent: &AgentHandle, _roots: Vec<PathBuf>) {}
+
+    pub async fn release(&self, _agent: &AgentHandle) {}
+
+    pub async fn shutdown_idle(&self) {}
+}
+
+fn disabled() -> KepokiError {
+    KepokiError::InvalidMcpConfig("kepoki was built without the `mcp` feature".to_string())
+}
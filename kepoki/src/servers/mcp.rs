@@ -0,0 +1,602 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use rmcp::ClientHandler;
+use rmcp::ErrorData;
+use rmcp::RmcpError;
+use rmcp::RoleClient;
+use rmcp::ServiceError;
+use rmcp::ServiceExt;
+use rmcp::model::CallToolRequest;
+use rmcp::model::CallToolRequestParam;
+use rmcp::model::ClientCapabilities;
+use rmcp::model::ClientInfo;
+use rmcp::model::ClientRequest;
+use rmcp::model::JsonObject;
+use rmcp::model::ListRootsResult;
+use rmcp::model::RawContent;
+use rmcp::model::ResourceContents;
+use rmcp::model::Root;
+use rmcp::model::ServerResult;
+use rmcp::service::NotificationContext;
+use rmcp::service::PeerRequestOptions;
+use rmcp::service::RequestContext;
+use rmcp::service::RunningService;
+use rmcp::transport::TokioChildProcess;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+
+use crate::agent::LocalMcpServer;
+use crate::agent::McpServer;
+use crate::backend::ImageMediaType;
+use crate::backend::ImageSource;
+use crate::backend::Payload;
+use crate::backend::Tool;
+use crate::backend::ToolResultContentBlock;
+use crate::error::KepokiError;
+use crate::runtime::AgentHandle;
+use crate::sandbox::Isolation;
+use crate::tools::ToolOutput;
+
+/// Local MCP servers are started lazily the first time their tools are needed and shut down
+/// again after sitting idle or once no agent uses them anymore. Tool lists are cached across
+/// restarts.
+///
+/// Clones share the same server instances, which lets several agents use one process per
+/// distinct server definition.
+#[derive(Clone, Debug, Default)]
+pub struct McpServers {
+    inner: Arc<McpServersInner>,
+    idle_timeout: Option<Duration>,
+    isolation: Option<Isolation>,
+}
+
+#[derive(Debug)]
+struct McpServersInner {
+    servers: tokio::sync::Mutex<HashMap<McpServer, Arc<LocalMcpServerInstance>>>,
+    /// Shared with the client handlers, which refresh it when a server's tools change.
+    tools: Arc<Mutex<HashMap<McpServer, ListedTools>>>,
+    tools_changed: broadcast::Sender<McpServer>,
+    /// The usage instructions servers returned when they were initialized.
+    instructions: Mutex<HashMap<McpServer, String>>,
+    /// The agents that have used each server since it was last started.
+    sessions: Arc<Mutex<HashMap<McpServer, HashSet<AgentHandle>>>>,
+    /// The filesystem roots of each agent, servers see the roots of the agents using them.
+    roots: Arc<Mutex<HashMap<AgentHandle, Vec<Root>>>>,
+}
+
+impl Default for McpServersInner {
+    fn default() -> Self {
+        Self {
+            servers: Default::default(),
+            tools: Default::default(),
+            tools_changed: broadcast::channel(16).0,
+            instructions: Default::default(),
+            sessions: Default::default(),
+            roots: Default::default(),
+        }
+    }
+}
+
+impl McpServers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receives the servers whose tool lists changed after they were first listed.
+    pub fn subscribe_tools_changed(&self) -> broadcast::Receiver<McpServer> {
+        self.inner.tools_changed.subscribe()
+    }
+
+    /// Shuts down servers that haven't been used for `idle_timeout`.
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Spawns local servers in `isolation`, see [`crate::sandbox`].
+    pub fn with_isolation(mut self, isolation: Option<Isolation>) -> Self {
+        self.isolation = isolation;
+        self
+    }
+
+    async fn load(
+        &self,
+        agent: &AgentHandle,
+        server: &McpServer,
+    ) -> Result<Arc<LocalMcpServerInstance>, KepokiError> {
+        let mut servers = self.inner.servers.lock().await;
+        self.inner
+            .sessions
+            .lock()
+            .unwrap()
+            .entry(server.clone())
+            .or_default()
+            .insert(agent.clone());
+
+        if let Some(instance) = servers.get(server) {
+            return Ok(instance.clone());
+        }
+
+        let instance = match server {
+            McpServer::Remote(server) => {
+                return Err(KepokiError::RemoteMcpServerUnsupported(server.url.clone()));
+            }
+            McpServer::Local(local) => {
+                let handler = McpClientHandler {
+                    server: server.clone(),
+                    tools: self.inner.tools.clone(),
+                    tools_changed: self.inner.tools_changed.clone(),
+                    sessions: self.inner.sessions.clone(),
+                    roots: self.inner.roots.clone(),
+                };
+                Arc::new(
+                    LocalMcpServerInstance::spawn(local, self.isolation.as_ref(), handler).await?,
+                )
+            }
+        };
+
+        if let Some(instructions) = instance
+            .service
+            .peer_info()
+            .and_then(|info| info.instructions.clone())
+        {
+            self.inner
+                .instructions
+                .lock()
+                .unwrap()
+                .insert(server.clone(), instructions);
+        }
+
+        let tools = instance
+            .service
+            .list_all_tools()
+            .await
+            .map_err(RmcpError::from)?;
+        let previous = self
+            .inner
+            .tools
+            .lock()
+            .unwrap()
+            .insert(server.clone(), ListedTools::new(tools.clone()));
+        if previous.is_some_and(|previous| previous.tools != tools) {
+            let _ = self.inner.tools_changed.send(server.clone());
+        }
+
+        servers.insert(server.clone(), instance.clone());
+
+        Ok(instance)
+    }
+
+    /// The tools provided by `server`, starting it if they haven't been listed before.
+    pub async fn list_tools(
+        &self,
+        agent: &AgentHandle,
+        server: &McpServer,
+    ) -> Result<Vec<rmcp::model::Tool>, KepokiError> {
+        if !self.inner.tools.lock().unwrap().contains_key(server) {
+            self.load(agent, server).await?;
+        }
+
+        Ok(self.tools(server))
+    }
+
+    /// The cached tools of a server, empty if they haven't been listed yet.
+    pub fn tools(&self, server: &McpServer) -> Vec<rmcp::model::Tool> {
+        self.inner
+            .tools
+            .lock()
+            .unwrap()
+            .get(server)
+            .map(|listed| listed.tools.clone())
+            .unwrap_or_default()
+    }
+
+    /// Whether a cached tool of a server is hinted to be read only.
+    pub fn is_read_only(&self, server: &McpServer, name: &str) -> bool {
+        self.tools(server)
+            .into_iter()
+            .find(|tool| tool.name == name)
+            .and_then(|tool| tool.annotations)
+            .and_then(|annotations| annotations.read_only_hint)
+            .unwrap_or(false)
+    }
+
+    /// The usage instructions of a server that has been started, if it provides any.
+    pub fn instructions(&self, server: &McpServer) -> Option<String> {
+        self.inner.instructions.lock().unwrap().get(server).cloned()
+    }
+
+    /// The definition of a cached tool, as advertised to the model under its MCP name.
+    ///
+    /// Definitions are converted once when a server's tools are listed rather than every turn.
+    pub fn definition(&self, server: &McpServer, name: &str) -> Option<Tool<'static>> {
+        self.inner
+            .tools
+            .lock()
+            .unwrap()
+            .get(server)?
+            .definitions
+            .iter()
+            .find(|definition| definition.name == name)
+            .cloned()
+    }
+
+    /// Calls a tool on `server` on behalf of `agent`, starting the server first if necessary.
+    ///
+    /// Calls that exceed `timeout` are cancelled and the server is shut down, it will be
+    /// restarted by the next call.
+    pub async fn call_tool(
+        &self,
+        agent: &AgentHandle,
+        server: &McpServer,
+        name: &str,
+        arguments: Option<JsonObject>,
+        timeout: Duration,
+    ) -> Result<ToolOutput, KepokiError> {
+        let instance = self.load(agent, server).await?;
+        instance.touch();
+        let result = instance.call_tool(name, arguments, timeout).await;
+        instance.touch();
+
+        match result {
+            Err(ServiceError::Timeout { timeout }) => {
+                tracing::warn!("MCP tool {name} timed out, shutting down server");
+                self.remove(server, &instance).await;
+
+                Ok(ToolOutput::error(format!(
+                    "Tool `{name}` timed out after {} seconds and was cancelled",
+                    timeout.as_secs_f32()
+                )))
+            }
+            Err(err) => {
+                if matches!(err, ServiceError::TransportClosed) {
+                    self.remove(server, &instance).await;
+                }
+
+                Err(RmcpError::from(err).into())
+            }
+            Ok(output) => Ok(output),
+        }
+    }
+
+    /// Sets the filesystem roots of `agent`, notifying the running servers it uses if they
+    /// changed.
+    pub async fn set_roots(&self, agent: &AgentHandle, roots: Vec<PathBuf>) {
+        let roots = roots
+            .iter()
+            .map(|path| Root {
+                uri: format!("file://{}", path.display()),
+                name: path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned()),
+            })
+            .collect::<Vec<_>>();
+
+        let previous = self
+            .inner
+            .roots
+            .lock()
+            .unwrap()
+            .insert(agent.clone(), roots.clone());
+        if previous.is_some_and(|previous| previous == roots) {
+            return;
+        }
+
+        self.notify_roots_changed(agent).await;
+    }
+
+    /// Sends a roots change notification to every running server used by `agent`.
+    async fn notify_roots_changed(&self, agent: &AgentHandle) {
+        let instances = {
+            let servers = self.inner.servers.lock().await;
+            let sessions = self.inner.sessions.lock().unwrap();
+            servers
+                .iter()
+                .filter(|(server, _)| {
+                    sessions
+                        .get(*server)
+                        .is_some_and(|agents| agents.contains(agent))
+                })
+                .map(|(_, instance)| instance.clone())
+                .collect::<Vec<_>>()
+        };
+
+        for instance in instances {
+            if let Err(err) = instance.service.notify_roots_list_changed().await {
+                tracing::warn!("Failed to notify MCP server of changed roots: {err}");
+            }
+        }
+    }
+
+    /// Ends the sessions of `agent`, shutting down servers no other agent is using.
+    pub async fn release(&self, agent: &AgentHandle) {
+        self.inner.roots.lock().unwrap().remove(agent);
+        let unused = {
+            let mut sessions = self.inner.sessions.lock().unwrap();
+            for agents in sessions.values_mut() {
+                agents.remove(agent);
+            }
+
+            let unused = sessions
+                .iter()
+                .filter(|(_, agents)| agents.is_empty())
+                .map(|(server, _)| server.clone())
+                .collect::<Vec<_>>();
+            sessions.retain(|_, agents| !agents.is_empty());
+            unused
+        };
+
+        let mut servers = self.inner.servers.lock().await;
+        for server in unused {
+            if let Some(instance) = servers.remove(&server) {
+                tracing::info!("Shutting down unused MCP server: {:?}", server);
+                instance.shutdown();
+            }
+        }
+    }
+
+    /// Shuts down servers that have been idle for longer than the idle timeout.
+    pub async fn shutdown_idle(&self) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+
+        let mut servers = self.inner.servers.lock().await;
+        servers.retain(|server, instance| {
+            if instance.idle_for() < idle_timeout {
+                return true;
+            }
+
+            tracing::info!("Shutting down idle MCP server: {:?}", server);
+            instance.shutdown();
+            false
+        });
+    }
+
+    /// Removes `instance` unless it has already been replaced by a restarted server.
+    async fn remove(&self, server: &McpServer, instance: &Arc<LocalMcpServerInstance>) {
+        let mut servers = self.inner.servers.lock().await;
+        if servers
+            .get(server)
+            .is_some_and(|current| Arc::ptr_eq(current, instance))
+        {
+            servers.remove(server);
+        }
+
+        instance.shutdown();
+    }
+}
+
+/// Handles requests and notifications sent by a server to the runtime.
+#[derive(Clone, Debug)]
+struct McpClientHandler {
+    server: McpServer,
+    tools: Arc<Mutex<HashMap<McpServer, ListedTools>>>,
+    tools_changed: broadcast::Sender<McpServer>,
+    sessions: Arc<Mutex<HashMap<McpServer, HashSet<AgentHandle>>>>,
+    roots: Arc<Mutex<HashMap<AgentHandle, Vec<Root>>>>,
+}
+
+impl ClientHandler for McpClientHandler {
+    fn get_info(&self) -> ClientInfo {
+        ClientInfo {
+            capabilities: ClientCapabilities::builder()
+                .enable_roots()
+                .enable_roots_list_changed()
+                .build(),
+            ..Default::default()
+        }
+    }
+
+    async fn list_roots(
+        &self,
+        _context: RequestContext<RoleClient>,
+    ) -> Result<ListRootsResult, ErrorData> {
+        let agents = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(&self.server)
+            .cloned()
+            .unwrap_or_default();
+
+        let mut roots = Vec::new();
+        for root in agents
+            .iter()
+            .filter_map(|agent| self.roots.lock().unwrap().get(agent).cloned())
+            .flatten()
+        {
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+
+        Ok(ListRootsResult { roots })
+    }
+
+    async fn on_tool_list_changed(&self, context: NotificationContext<RoleClient>) {
+        let tools = match context.peer.list_all_tools().await {
+            Ok(tools) => tools,
+            Err(err) => {
+                tracing::error!("Failed to refresh changed MCP tools: {err}");
+                return;
+            }
+        };
+
+        tracing::info!("MCP server tools changed: {:?}", self.server);
+        self.tools
+            .lock()
+            .unwrap()
+            .insert(self.server.clone(), ListedTools::new(tools));
+        // Nobody listening just means no agent is using the server right now.
+        let _ = self.tools_changed.send(self.server.clone());
+    }
+}
+
+/// The tools of a server and their definitions for the model.
+#[derive(Debug)]
+struct ListedTools {
+    tools: Vec<rmcp::model::Tool>,
+    definitions: Vec<Tool<'static>>,
+}
+
+impl ListedTools {
+    fn new(tools: Vec<rmcp::model::Tool>) -> Self {
+        let definitions = tools
+            .iter()
+            .map(|tool| convert_tool(tool.name.to_string(), tool))
+            .collect();
+        Self { tools, definitions }
+    }
+}
+
+#[derive(Debug)]
+struct LocalMcpServerInstance {
+    service: RunningService<RoleClient, McpClientHandler>,
+    last_used: Mutex<Instant>,
+}
+
+impl LocalMcpServerInstance {
+    async fn spawn(
+        mcp_server: &LocalMcpServer,
+        isolation: Option<&Isolation>,
+        handler: McpClientHandler,
+    ) -> Result<Self, KepokiError> {
+        tracing::info!("Spawning local MCP server: {}", mcp_server.command);
+        let mut command = match isolation {
+            Some(isolation) => {
+                isolation.command(&mcp_server.command, &mcp_server.args, &mcp_server.env)?
+            }
+            None => {
+                let mut command = Command::new(&mcp_server.command);
+                command.args(&mcp_server.args).envs(&mcp_server.env);
+                command
+            }
+        };
+        command.kill_on_drop(true);
+
+        let service = handler
+            .serve(TokioChildProcess::new(command)?)
+            .await
+            .map_err(RmcpError::from)?;
+        tracing::info!("Connected to MCP server: {:?}", service.peer_info());
+
+        Ok(Self {
+            service,
+            last_used: Mutex::new(Instant::now()),
+        })
+    }
+
+    fn touch(&self) {
+        *self.last_used.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_used.lock().unwrap().elapsed()
+    }
+
+    async fn call_tool(
+        &self,
+        name: &str,
+        arguments: Option<JsonObject>,
+        timeout: Duration,
+    ) -> Result<ToolOutput, ServiceError> {
+        let request = ClientRequest::CallToolRequest(CallToolRequest::new(CallToolRequestParam {
+            name: name.to_string().into(),
+            arguments,
+        }));
+        let options = PeerRequestOptions {
+            timeout: Some(timeout),
+            ..Default::default()
+        };
+
+        // Timed out requests are cancelled with a notification before the error is returned.
+        let response = self
+            .service
+            .send_cancellable_request(request, options)
+            .await?
+            .await_response()
+            .await?;
+
+        let ServerResult::CallToolResult(result) = response else {
+            return Err(ServiceError::UnexpectedResponse);
+        };
+
+        Ok(ToolOutput {
+            content: result
+                .content
+                .into_iter()
+                .filter_map(convert_content)
+                .collect(),
+            is_error: result.is_error.unwrap_or(false),
+        })
+    }
+
+    /// Closes the connection so the server can exit, the process is killed once the
+    /// connection task has stopped.
+    fn shutdown(&self) {
+        self.service.cancellation_token().cancel();
+    }
+}
+
+/// Converts a tool advertised by an MCP server into a tool definition for the model.
+pub fn convert_tool(name: String, tool: &rmcp::model::Tool) -> Tool<'static> {
+    Tool {
+        name: name.into(),
+        input_schema: serde_json::to_string(tool.input_schema.as_ref())
+            .ok()
+            .map(Into::into),
+        description: tool
+            .description
+            .as_ref()
+            .map(|description| description.to_string().into()),
+    }
+}
+
+fn convert_content(content: rmcp::model::Content) -> Option<ToolResultContentBlock> {
+    Some(match content.raw {
+        RawContent::Text(text) => ToolResultContentBlock::Text { text: text.text },
+        RawContent::Image(image) => {
+            let media_type = match image.mime_type.as_str() {
+                "image/jpeg" => ImageMediaType::Jpeg,
+                "image/png" => ImageMediaType::Png,
+                "image/gif" => ImageMediaType::Gif,
+                "image/webp" => ImageMediaType::Webp,
+                mime_type => {
+                    tracing::warn!("Dropping MCP image with unsupported type: {mime_type}");
+                    return None;
+                }
+            };
+
+            let data = match Payload::from_base64(&image.data) {
+                Ok(data) => data,
+                Err(err) => {
+                    tracing::warn!("Dropping MCP image with invalid base64 data: {err}");
+                    return None;
+                }
+            };
+
+            ToolResultContentBlock::Image {
+                source: ImageSource::Base64 { data, media_type },
+            }
+        }
+        RawContent::Resource(resource) => match resource.resource {
+            ResourceContents::TextResourceContents { text, .. } => {
+                ToolResultContentBlock::Text { text }
+            }
+            ResourceContents::BlobResourceContents { uri, .. } => ToolResultContentBlock::Text {
+                text: format!("Binary resource: {uri}"),
+            },
+        },
+        RawContent::Audio(_) => {
+            tracing::warn!("Dropping MCP audio content, audio is not supported");
+            return None;
+        }
+    })
+}
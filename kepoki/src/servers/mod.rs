@@ -0,0 +1,15 @@
+//! The MCP servers of agents. Servers are run with the `mcp` feature, without it agents listing
+//! tools of MCP servers can't use them.
+
+pub mod config;
+#[cfg(not(feature = "mcp"))]
+mod disabled;
+#[cfg(feature = "mcp")]
+mod mcp;
+
+#[cfg(not(feature = "mcp"))]
+pub use disabled::McpServers;
+#[cfg(feature = "mcp")]
+pub use mcp::McpServers;
+#[cfg(feature = "mcp")]
+pub use mcp::convert_tool;
@@ -0,0 +1,195 @@
+//! Summarizes conversations with any backend, so that features condensing a history share one
+//! summarization pass instead of each prompting a model their own way.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::backend::Backend;
+use crate::backend::ContentBlock;
+use crate::backend::ContentBlockDelta;
+use crate::backend::InputMessage;
+use crate::backend::MessageStream;
+use crate::backend::MessagesRequest;
+use crate::backend::MessagesResponseEvent;
+use crate::backend::Role;
+use crate::backend::ToolResultContentBlock;
+use crate::error::KepokiError;
+
+/// How a summary is written.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum SummaryStyle {
+    /// A short paragraph covering the outcome of the conversation.
+    Brief,
+    /// Prose covering the goals, decisions, and open questions, detailed enough to continue
+    /// the conversation from.
+    #[default]
+    Detailed,
+    /// A bulleted list of facts, decisions, and open questions.
+    Bullets,
+}
+
+impl SummaryStyle {
+    fn instructions(self) -> &'static str {
+        match self {
+            Self::Brief => {
+                "Write a single short paragraph covering what the conversation achieved."
+            }
+            Self::Detailed => {
+                "Write a detailed summary covering the goals of the user, the decisions made, the results of tool calls that matter, and any open questions, so the conversation can be continued from the summary alone."
+            }
+            Self::Bullets => {
+                "Write a bulleted list of the facts established, the decisions made, and any open questions, one item per line."
+            }
+        }
+    }
+}
+
+pub struct Summarizer<B: Backend> {
+    backend: B,
+    model: B::Model,
+    style: SummaryStyle,
+    max_tokens: u32,
+}
+
+impl<B: Backend> Summarizer<B> {
+    pub fn new(backend: B, model: B::Model) -> Self {
+        Self {
+            backend,
+            model,
+            style: SummaryStyle::default(),
+            max_tokens: 1024,
+        }
+    }
+
+    pub fn with_style(mut self, style: SummaryStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Limits the length of summaries.
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// A definition for an agent that summarizes the conversations it is sent.
+    pub fn agent(&self) -> crate::agent::Agent {
+        crate::agent::Agent {
+            name: "summarizer".to_string(),
+            description: "Summarizes conversations.".to_string(),
+            prompt: self.prompt(),
+            temperature: 0.0,
+            ..Default::default()
+        }
+    }
+
+    /// Summarizes a conversation, such as the messages of an [`AgentState`].
+    ///
+    /// [`AgentState`]: crate::runtime::agent::AgentState
+    pub async fn summarize<'a>(
+        &self,
+        messages: impl IntoIterator<Item = &'a InputMessage>,
+    ) -> Result<String, KepokiError> {
+        let mut stream = self
+            .backend
+            .messages(MessagesRequest {
+                model: self.model.clone(),
+                messages: vec![InputMessage {
+                    id: String::new(),
+                    role: Role::User,
+                    content: vec![ContentBlock::Text {
+                        text: format!(
+                            "<conversation>\n{}</conversation>",
+                            render_transcript(messages)
+                        ),
+                    }],
+                }],
+                max_tokens: self.max_tokens,
+                system: Some(Cow::Owned(self.prompt())),
+                temperature: Some(0.0),
+                stop_sequences: None,
+                tool_choice: None,
+                tools: None,
+                reasoning: None,
+                user_id: None,
+                extensions: HashMap::new(),
+            })
+            .await?;
+
+        let mut summary = String::new();
+        while let Some(event) = stream.recv().await? {
+            match event {
+                MessagesResponseEvent::ContentBlockStart(start) => {
+                    if let ContentBlock::Text { text } = start.content_block {
+                        summary.push_str(&text);
+                    }
+                }
+                MessagesResponseEvent::ContentBlockDelta(ContentBlockDelta::Text {
+                    text, ..
+                }) => summary.push_str(&text),
+                _ => (),
+            }
+        }
+
+        Ok(summary.trim().to_string())
+    }
+
+    fn prompt(&self) -> String {
+        format!(
+            "You summarize conversations between a user and an AI assistant. {} Respond with the summary only.",
+            self.style.instructions()
+        )
+    }
+}
+
+/// Renders a conversation as plain text, leaving out thinking and binary content.
+fn render_transcript<'a>(messages: impl IntoIterator<Item = &'a InputMessage>) -> String {
+    let mut transcript = String::new();
+    for message in messages {
+        let role = match message.role {
+            Role::User => "User",
+            Role::Assistant => "Assistant",
+        };
+
+        for block in &message.content {
+            let _ = match block {
+                ContentBlock::Text { text } => writeln!(transcript, "{role}: {text}"),
+                ContentBlock::ToolUse { name, input, .. } => {
+                    writeln!(transcript, "{role} called {name}: {input}")
+                }
+                ContentBlock::ToolResult {
+                    content: Some(content),
+                    is_error,
+                    ..
+                } => {
+                    let result = content
+                        .iter()
+                        .filter_map(|block| match block {
+                            ToolResultContentBlock::Text { text } => Some(text.as_str()),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    match is_error {
+                        Some(true) => writeln!(transcript, "Tool error: {result}"),
+                        _ => writeln!(transcript, "Tool result: {result}"),
+                    }
+                }
+                ContentBlock::Image { .. } => writeln!(transcript, "{role}: [image]"),
+                ContentBlock::Document { title, .. } => writeln!(
+                    transcript,
+                    "{role}: [document {}]",
+                    title.as_deref().unwrap_or("untitled")
+                ),
+                _ => Ok(()),
+            };
+        }
+    }
+
+    transcript
+}
@@ -0,0 +1,146 @@
+//! Starting points for writing agent specifications, with a prompt skeleton and the tools and
+//! MCP servers commonly used for a kind of agent.
+//!
+//! The prompts contain `TODO` markers where they need to be adapted to the project.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::agent::Agent;
+use crate::agent::LocalMcpServer;
+use crate::agent::McpServer;
+use crate::agent::ModelMetric;
+use crate::agent::ModelPreferences;
+use crate::agent::ToolName;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum AgentTemplate {
+    /// Reads and changes code in a git repository.
+    Coding,
+    /// Researches questions on the web and reports its findings with sources.
+    Research,
+    /// Answers questions of customers.
+    Support,
+}
+
+impl AgentTemplate {
+    pub const ALL: [Self; 3] = [Self::Coding, Self::Research, Self::Support];
+
+    /// The specification of an agent named `name` created from the template.
+    pub fn agent(self, name: impl Into<String>) -> Agent {
+        match self {
+            Self::Coding => Agent {
+                name: name.into(),
+                description: "TODO: Describe what the agent works on.".to_string(),
+                prompt: "You are a software engineer working in the repository in your working directory.\n\nTODO: Describe the project, its languages and conventions, and how to build and test it.\n\nRead the relevant code before changing it, follow the conventions of the surrounding code, and keep changes focused on the task. Explain what you changed and why when you are done.".to_string(),
+                model_preferences: ModelPreferences {
+                    preferred_family: None,
+                    preferred_metrics: vec![ModelMetric::Code, ModelMetric::Quality],
+                },
+                temperature: 0.2,
+                mcp_servers: HashMap::from([
+                    local("git", "uvx", &["mcp-server-git"]),
+                    local(
+                        "filesystem",
+                        "npx",
+                        &["-y", "@modelcontextprotocol/server-filesystem", "."],
+                    ),
+                ]),
+                tools: tool_names(&[
+                    "@git/git_status",
+                    "@git/git_diff",
+                    "@git/git_log",
+                    "@git/git_add",
+                    "@git/git_commit",
+                    "@filesystem/read_file",
+                    "@filesystem/list_directory",
+                    "@filesystem/search_files",
+                    "@filesystem/write_file",
+                    "@filesystem/edit_file",
+                ]),
+                allowed_tools: tool_names(&[
+                    "@git/git_status",
+                    "@git/git_diff",
+                    "@git/git_log",
+                    "@filesystem/read_file",
+                    "@filesystem/list_directory",
+                    "@filesystem/search_files",
+                ]),
+                ..Agent::default()
+            },
+            Self::Research => Agent {
+                name: name.into(),
+                description: "TODO: Describe the topics the agent researches.".to_string(),
+                prompt: "You are a research assistant.\n\nTODO: Describe the domain, the audience of the reports, and which sources to trust.\n\nSearch broadly before drawing conclusions, prefer primary sources, and cite the source of every claim. Say so when sources disagree or when you could not find an answer.".to_string(),
+                model_preferences: ModelPreferences {
+                    preferred_family: None,
+                    preferred_metrics: vec![ModelMetric::Quality],
+                },
+                temperature: 0.3,
+                mcp_servers: HashMap::from([local("fetch", "uvx", &["mcp-server-fetch"])]),
+                tools: tool_names(&[
+                    "@fetch/fetch",
+                    "create_artifact",
+                    "update_artifact",
+                    "current_time",
+                ]),
+                allowed_tools: tool_names(&["@fetch/fetch", "current_time"]),
+                ..Agent::default()
+            },
+            Self::Support => Agent {
+                name: name.into(),
+                description: "TODO: Describe the product the agent supports.".to_string(),
+                prompt: "You are a friendly and patient support agent.\n\nTODO: Describe the product, common problems and their solutions, and when to escalate to a human.\n\nAnswer in the language of the customer, ask clarifying questions when a request is ambiguous, and never make up policies or promises. If you cannot help, say so and explain how to reach a human.".to_string(),
+                model_preferences: ModelPreferences {
+                    preferred_family: None,
+                    preferred_metrics: vec![ModelMetric::Conversational, ModelMetric::Speed],
+                },
+                temperature: 0.5,
+                tools: tool_names(&["current_time"]),
+                allowed_tools: tool_names(&["current_time"]),
+                review_criteria: vec![
+                    "The response is polite and answers the question of the customer."
+                        .to_string(),
+                    "The response makes no promises the product documentation doesn't back."
+                        .to_string(),
+                ],
+                ..Agent::default()
+            },
+        }
+    }
+}
+
+impl FromStr for AgentTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "coding" => Ok(Self::Coding),
+            "research" => Ok(Self::Research),
+            "support" => Ok(Self::Support),
+            _ => Err(format!(
+                "Unknown template `{s}`, expected coding, research, or support"
+            )),
+        }
+    }
+}
+
+fn local(name: &str, command: &str, args: &[&str]) -> (String, McpServer) {
+    (
+        name.to_string(),
+        McpServer::Local(LocalMcpServer {
+            command: command.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            env: HashMap::new(),
+        }),
+    )
+}
+
+fn tool_names(names: &[&str]) -> Vec<ToolName> {
+    names.iter().filter_map(|name| name.parse().ok()).collect()
+}
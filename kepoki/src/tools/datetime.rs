@@ -0,0 +1,85 @@
+//! Models don't know the current time, agents that need it list the `current_time` tool.
+//!
+//! Times are given in the time zone of the agent's [`Locale`]. Fixed offsets are applied
+//! exactly, kepoki doesn't ship a time zone database, so for IANA time zone names the tool
+//! reports the time in UTC alongside the name and leaves the conversion to the model.
+
+use chrono::FixedOffset;
+use chrono::Utc;
+use serde_json::Value;
+
+use crate::agent::Locale;
+use crate::backend::Tool;
+use crate::tools::BuiltinTool;
+use crate::tools::ToolContext;
+use crate::tools::ToolFuture;
+use crate::tools::ToolOutput;
+
+/// The format times are reported in, such as `2025-07-20T14:30:00+02:00 (Sunday)`.
+const FORMAT: &str = "%Y-%m-%dT%H:%M:%S%:z (%A)";
+
+pub struct CurrentTimeTool;
+
+impl BuiltinTool for CurrentTimeTool {
+    fn definition(&self) -> Tool<'static> {
+        Tool {
+            name: "current_time".into(),
+            input_schema: Some(r#"{"type":"object","properties":{}}"#.into()),
+            description: Some(
+                "Returns the current date and time in the time zone of the user.".into(),
+            ),
+        }
+    }
+
+    fn call(&self, context: ToolContext, _input: Value) -> ToolFuture<'_> {
+        Box::pin(async move { ToolOutput::text(current_time(context.locale.as_ref())) })
+    }
+
+    fn side_effecting(&self) -> bool {
+        false
+    }
+}
+
+fn current_time(locale: Option<&Locale>) -> String {
+    let now = Utc::now();
+    let Some(timezone) = locale.map(|locale| locale.timezone.as_str()) else {
+        return now.format(FORMAT).to_string();
+    };
+
+    match parse_offset(timezone) {
+        Some(offset) => now.with_timezone(&offset).format(FORMAT).to_string(),
+        None => format!(
+            "{} in UTC, convert it to the time zone of the user, {timezone}",
+            now.format(FORMAT)
+        ),
+    }
+}
+
+/// Parses fixed offsets such as `UTC`, `Z`, `+05:30`, `-0800`, and `UTC+2`.
+pub fn parse_offset(timezone: &str) -> Option<FixedOffset> {
+    let timezone = timezone.trim();
+    let offset = timezone
+        .strip_prefix("UTC")
+        .or_else(|| timezone.strip_prefix("GMT"))
+        .unwrap_or(timezone);
+    if offset.is_empty() || offset == "Z" {
+        return FixedOffset::east_opt(0);
+    }
+
+    let (sign, offset) = match offset.split_at_checked(1)? {
+        ("+", offset) => (1, offset),
+        ("-", offset) => (-1, offset),
+        _ => return None,
+    };
+    let (hours, minutes) = match offset.split_once(':') {
+        Some((hours, minutes)) => (hours, minutes),
+        None if offset.len() == 4 => offset.split_at(2),
+        None => (offset, "0"),
+    };
+    let (hours, minutes) = (hours.parse::<i32>().ok()?, minutes.parse::<i32>().ok()?);
+    if hours > 14 || minutes > 59 {
+        return None;
+    }
+
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
@@ -0,0 +1,168 @@
+//! Image generation through the `generate_image` builtin tool, available once an
+//! [`ImageGenerationBackend`] is set on the runtime.
+//!
+//! Generated images are stored as artifacts of the agent, so they can be read again later, and
+//! returned to the model as image blocks.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::artifacts::ArtifactChange;
+use crate::artifacts::ArtifactStore;
+use crate::backend::ImageMediaType;
+use crate::backend::ImageSource;
+use crate::backend::Payload;
+use crate::backend::Tool;
+use crate::backend::ToolResultContentBlock;
+use crate::error::KepokiError;
+use crate::runtime::agent::AgentEvent;
+use crate::tools::BuiltinTool;
+use crate::tools::ToolContext;
+use crate::tools::ToolFuture;
+use crate::tools::ToolOutput;
+use crate::tools::required_str;
+
+/// The most images generated by a single call of the tool.
+const MAX_IMAGES: u32 = 4;
+
+pub type ImageGenerationFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Vec<GeneratedImage>, KepokiError>> + Send + 'a>>;
+
+pub trait ImageGenerationBackend: Send + Sync + 'static {
+    fn generate<'a>(&'a self, request: &'a ImageGenerationRequest) -> ImageGenerationFuture<'a>;
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ImageGenerationRequest {
+    pub prompt: String,
+    /// What the images should not contain, for backends that support it.
+    pub negative_prompt: Option<String>,
+    /// The size of the images in pixels, backends that only support some sizes pick the
+    /// closest one.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// The number of images to generate.
+    pub count: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct GeneratedImage {
+    pub data: Payload,
+    pub media_type: ImageMediaType,
+}
+
+pub struct GenerateImageTool {
+    backend: Arc<dyn ImageGenerationBackend>,
+    artifacts: ArtifactStore,
+}
+
+impl GenerateImageTool {
+    pub fn new(backend: impl ImageGenerationBackend, artifacts: ArtifactStore) -> Self {
+        Self::with_backend(Arc::new(backend), artifacts)
+    }
+
+    pub(crate) fn with_backend(
+        backend: Arc<dyn ImageGenerationBackend>,
+        artifacts: ArtifactStore,
+    ) -> Self {
+        Self { backend, artifacts }
+    }
+}
+
+impl BuiltinTool for GenerateImageTool {
+    fn definition(&self) -> Tool<'static> {
+        Tool {
+            name: "generate_image".into(),
+            input_schema: Some(
+                format!(
+                    r#"{{"type":"object","properties":{{"prompt":{{"type":"string"}},"negative_prompt":{{"type":"string"}},"width":{{"type":"integer"}},"height":{{"type":"integer"}},"count":{{"type":"integer","minimum":1,"maximum":{MAX_IMAGES}}},"name":{{"type":"string"}}}},"required":["prompt"]}}"#
+                )
+                .into(),
+            ),
+            description: Some(
+                "Generates images from a description and stores them as artifacts named after `name`. Describe the subject, style, and composition in `prompt`.".into(),
+            ),
+        }
+    }
+
+    fn call(&self, context: ToolContext, input: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let prompt = match required_str(&input, "prompt") {
+                Ok(prompt) => prompt,
+                Err(err) => return err,
+            };
+            let dimension = |field| {
+                input
+                    .get(field)
+                    .and_then(Value::as_u64)
+                    .and_then(|value| u32::try_from(value).ok())
+            };
+            let request = ImageGenerationRequest {
+                prompt: prompt.to_string(),
+                negative_prompt: input
+                    .get("negative_prompt")
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                width: dimension("width"),
+                height: dimension("height"),
+                count: dimension("count").unwrap_or(1).clamp(1, MAX_IMAGES),
+            };
+
+            let images = match self.backend.generate(&request).await {
+                Ok(images) if !images.is_empty() => images,
+                Ok(_) => return ToolOutput::error("The backend generated no images"),
+                Err(err) => return ToolOutput::error(format!("Failed to generate image: {err}")),
+            };
+
+            let base_name = input
+                .get("name")
+                .and_then(Value::as_str)
+                .unwrap_or("generated-image");
+            let mut names = Vec::new();
+            let mut content = Vec::new();
+            for image in images {
+                let name = self.artifacts.unused_name(&context.agent, base_name);
+                let artifact = match self.artifacts.create_binary(
+                    &context.agent,
+                    &name,
+                    image.media_type.mime_type().to_string(),
+                    prompt.to_string(),
+                    image.data.clone(),
+                ) {
+                    Ok(artifact) => artifact,
+                    Err(err) => return ToolOutput::error(err),
+                };
+
+                context.emit(AgentEvent::ArtifactChanged {
+                    name: artifact.name,
+                    version: artifact.version,
+                    change: ArtifactChange::Created,
+                });
+                names.push(format!("`{name}`"));
+                content.push(ToolResultContentBlock::Image {
+                    source: ImageSource::Base64 {
+                        data: image.data,
+                        media_type: image.media_type,
+                    },
+                });
+            }
+
+            content.insert(
+                0,
+                ToolResultContentBlock::Text {
+                    text: format!("Stored as artifacts {}", names.join(", ")),
+                },
+            );
+            ToolOutput {
+                content,
+                is_error: false,
+            }
+        })
+    }
+}
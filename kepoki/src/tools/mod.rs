@@ -0,0 +1,155 @@
+//! Builtin tools are executed in-process by the runtime rather than by an MCP server.
+
+pub mod datetime;
+pub mod images;
+pub mod remote;
+#[cfg(feature = "screenshot")]
+pub mod screenshot;
+pub mod selection;
+pub mod stats;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::agent::Locale;
+use crate::backend::Tool;
+use crate::backend::ToolResultContentBlock;
+use crate::runtime::AgentHandle;
+use crate::runtime::agent::AgentEvent;
+use crate::runtime::events::CustomEvent;
+use crate::runtime::events::EventBus;
+
+pub type ToolFuture<'a> = Pin<Box<dyn Future<Output = ToolOutput> + Send + 'a>>;
+
+pub trait BuiltinTool: Send + Sync + 'static {
+    /// The definition advertised to the model.
+    fn definition(&self) -> Tool<'static>;
+
+    /// Executes the tool with the input generated by the model.
+    ///
+    /// The returned future is dropped when the call times out, so child processes spawned by
+    /// tools should be configured to be killed on drop.
+    fn call(&self, context: ToolContext, input: Value) -> ToolFuture<'_>;
+
+    /// Whether calling the tool changes anything outside of the conversation.
+    ///
+    /// Side-effecting tools are not executed while the runtime is in dry-run mode.
+    fn side_effecting(&self) -> bool {
+        true
+    }
+}
+
+/// Information about the agent a tool is being executed on behalf of.
+#[derive(Clone, Debug)]
+pub struct ToolContext {
+    pub agent: AgentHandle,
+    pub event_emitter: UnboundedSender<AgentEvent>,
+    pub event_bus: EventBus,
+    pub locale: Option<Locale>,
+}
+
+impl ToolContext {
+    /// Emits an event on the agent's event stream.
+    ///
+    /// Events are best effort, a closed receiver is not an error for the tool.
+    pub fn emit(&self, event: AgentEvent) {
+        if self.event_emitter.send(event).is_err() {
+            tracing::debug!("Event receiver closed for agent {}", self.agent);
+        }
+    }
+
+    /// Publishes a custom event on the agent's event stream and to the subscribers of `topic`.
+    pub fn publish(&self, topic: impl Into<String>, payload: Value) {
+        let topic = topic.into();
+        self.event_bus.publish(CustomEvent {
+            agent: self.agent.clone(),
+            topic: topic.clone(),
+            payload: payload.clone(),
+        });
+        self.emit(AgentEvent::Custom { topic, payload });
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ToolOutput {
+    pub content: Vec<ToolResultContentBlock>,
+    pub is_error: bool,
+}
+
+impl ToolOutput {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self {
+            content: vec![ToolResultContentBlock::Text { text: text.into() }],
+            is_error: false,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            content: vec![ToolResultContentBlock::Text {
+                text: message.into(),
+            }],
+            is_error: true,
+        }
+    }
+}
+
+/// The set of builtin tools available to agents in a runtime.
+///
+/// Agents only see the tools they list in their definition.
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: HashMap<String, Arc<dyn BuiltinTool>>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self {
+            tools: HashMap::new(),
+        }
+    }
+
+    /// Registers a tool, replacing any tool previously registered with the same name.
+    pub fn register(&mut self, tool: impl BuiltinTool) {
+        let name = tool.definition().name.into_owned();
+        self.tools.insert(name, Arc::new(tool));
+    }
+
+    /// Registers every tool of `other`, replacing tools previously registered with the same
+    /// name.
+    pub fn merge(&mut self, other: ToolRegistry) {
+        self.tools.extend(other.tools);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn BuiltinTool>> {
+        self.tools.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tools.contains_key(name)
+    }
+
+    pub fn tools(&self) -> impl Iterator<Item = &Arc<dyn BuiltinTool>> {
+        self.tools.values()
+    }
+}
+
+impl Debug for ToolRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.tools.keys()).finish()
+    }
+}
+
+/// Reads a required string field from a tool input object.
+pub(crate) fn required_str<'a>(input: &'a Value, field: &str) -> Result<&'a str, ToolOutput> {
+    input
+        .get(field)
+        .and_then(Value::as_str)
+        .ok_or_else(|| ToolOutput::error(format!("Missing required string field `{field}`")))
+}
@@ -0,0 +1,549 @@
+//! Delegates tool execution to a remote executor, such as a daemon in a locked-down VM, while
+//! agents run locally: the brain stays local and the hands are remote.
+//!
+//! The executor serves a [`ToolRegistry`] with [`ExecutorServer`]. The runtime connects with
+//! [`RemoteExecutor`] and registers the tools it lists like any builtin tool:
+//!
+//! ```ignore
+//! let executor = RemoteExecutor::connect("sandbox-vm:7400").await?;
+//! let mut builder = Runtime::builder();
+//! for tool in executor.tools().await? {
+//!     builder = builder.with_tool(tool);
+//! }
+//! let runtime = builder.build();
+//! ```
+//!
+//! Both sides exchange newline delimited JSON messages over any byte stream, usually TCP. The
+//! runtime sends [`ExecutorRequest`] envelopes, the executor answers each with
+//! [`ExecutorResponse`]s tagged with the id of the request, streaming the events tools emit
+//! before their result. Calls run concurrently, and calls the runtime gives up on, such as
+//! those that time out, are cancelled on the executor.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::net::ToSocketAddrs;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::unbounded_channel;
+
+use crate::agent::Locale;
+use crate::backend::Tool;
+use crate::backend::ToolResultContentBlock;
+use crate::error::KepokiError;
+use crate::runtime::AgentHandle;
+use crate::runtime::agent::AgentEvent;
+use crate::runtime::events::EventBus;
+use crate::tools::BuiltinTool;
+use crate::tools::ToolContext;
+use crate::tools::ToolFuture;
+use crate::tools::ToolOutput;
+use crate::tools::ToolRegistry;
+
+/// A message from the runtime to the executor.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum ExecutorRequest {
+    /// Asks for the tools the executor provides, answered with [`ExecutorResponse::Tools`].
+    ListTools { id: u64 },
+    /// Calls a tool on behalf of an agent, answered with any number of
+    /// [`ExecutorResponse::Event`]s followed by an [`ExecutorResponse::Result`].
+    Call {
+        id: u64,
+        agent: AgentHandle,
+        tool: String,
+        input: Value,
+        #[serde(default)]
+        locale: Option<Locale>,
+    },
+    /// Cancels a call, which isn't answered anymore.
+    Cancel { id: u64 },
+}
+
+/// A message from the executor to the runtime.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum ExecutorResponse {
+    Tools {
+        id: u64,
+        tools: Vec<RemoteToolDefinition>,
+    },
+    /// An event a tool emitted while running, emitted by the agent that called it.
+    Event { id: u64, event: AgentEvent },
+    Result {
+        id: u64,
+        content: Vec<ToolResultContentBlock>,
+        is_error: bool,
+    },
+    /// The request couldn't be handled, such as a call of an unknown tool.
+    Error { id: u64, message: String },
+}
+
+impl ExecutorResponse {
+    fn id(&self) -> u64 {
+        match self {
+            Self::Tools { id, .. }
+            | Self::Event { id, .. }
+            | Self::Result { id, .. }
+            | Self::Error { id, .. } => *id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RemoteToolDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// The JSON schema of the input of the tool.
+    #[serde(default)]
+    pub input_schema: Option<String>,
+    #[serde(default = "RemoteToolDefinition::default_side_effecting")]
+    pub side_effecting: bool,
+}
+
+impl RemoteToolDefinition {
+    fn default_side_effecting() -> bool {
+        true
+    }
+}
+
+/// Writes each message sent to the returned sender as a line of JSON.
+fn spawn_writer<T: Serialize + Send + 'static>(
+    mut writer: impl AsyncWrite + Send + Unpin + 'static,
+) -> UnboundedSender<T> {
+    let (sender, mut receiver) = unbounded_channel::<T>();
+    tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            let mut line = match serde_json::to_vec(&message) {
+                Ok(line) => line,
+                Err(err) => {
+                    tracing::error!("Failed to serialize executor message: {err}");
+                    continue;
+                }
+            };
+            line.push(b'\n');
+            if let Err(err) = writer.write_all(&line).await {
+                tracing::debug!("Executor connection closed: {err}");
+                break;
+            }
+            let _ = writer.flush().await;
+        }
+    });
+    sender
+}
+
+/// The senders of the responses to requests by id.
+type Pending = HashMap<u64, UnboundedSender<ExecutorResponse>>;
+
+/// A connection to a remote executor, clones share the connection.
+#[derive(Clone, Debug)]
+pub struct RemoteExecutor {
+    requests: UnboundedSender<ExecutorRequest>,
+    /// The requests waiting for responses, `None` once the connection closed.
+    pending: Arc<Mutex<Option<Pending>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl RemoteExecutor {
+    pub async fn connect(address: impl ToSocketAddrs) -> Result<Self, KepokiError> {
+        let (reader, writer) = TcpStream::connect(address).await?.into_split();
+        Ok(Self::new(reader, writer))
+    }
+
+    /// Talks to an executor over an established connection, such as a stream tunneled over
+    /// SSH or a vsock.
+    pub fn new(
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> Self {
+        let pending = Arc::new(Mutex::new(Some(Pending::new())));
+
+        let dispatch = pending.clone();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let response = match serde_json::from_str::<ExecutorResponse>(&line) {
+                    Ok(response) => response,
+                    Err(err) => {
+                        tracing::warn!("Invalid message from executor: {err}");
+                        continue;
+                    }
+                };
+                let id = response.id();
+                if let Some(sender) = dispatch
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .and_then(|pending| pending.get(&id))
+                {
+                    let _ = sender.send(response);
+                }
+            }
+            dispatch.lock().unwrap().take();
+        });
+
+        Self {
+            requests: spawn_writer(writer),
+            pending,
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Sends the request built from a fresh id, returning the responses to it.
+    fn send(
+        &self,
+        request: impl FnOnce(u64) -> ExecutorRequest,
+    ) -> Result<PendingRequest, KepokiError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, responses) = unbounded_channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .as_mut()
+            .ok_or(KepokiError::ToolExecutorDisconnected)?
+            .insert(id, sender);
+        let pending = PendingRequest {
+            id,
+            executor: self.clone(),
+            responses,
+            finished: false,
+        };
+
+        self.requests
+            .send(request(id))
+            .map_err(|_| KepokiError::ToolExecutorDisconnected)?;
+        Ok(pending)
+    }
+
+    /// The tools the executor provides, to be registered with a runtime.
+    pub async fn tools(&self) -> Result<Vec<RemoteTool>, KepokiError> {
+        let mut pending = self.send(|id| ExecutorRequest::ListTools { id })?;
+        pending.finished = true;
+        match pending.next().await {
+            Some(ExecutorResponse::Tools { tools, .. }) => Ok(tools
+                .into_iter()
+                .map(|definition| RemoteTool {
+                    executor: self.clone(),
+                    definition,
+                })
+                .collect()),
+            Some(ExecutorResponse::Error { message, .. }) => {
+                Err(KepokiError::ToolExecutorError(message))
+            }
+            Some(response) => Err(KepokiError::ToolExecutorError(format!(
+                "Unexpected response to listing tools: {response:?}"
+            ))),
+            None => Err(KepokiError::ToolExecutorDisconnected),
+        }
+    }
+
+    async fn call(&self, context: ToolContext, tool: String, input: Value) -> ToolOutput {
+        let locale = context.locale.clone();
+        let agent = context.agent.clone();
+        let mut pending = match self.send(|id| ExecutorRequest::Call {
+            id,
+            agent,
+            tool,
+            input,
+            locale,
+        }) {
+            Ok(pending) => pending,
+            Err(err) => return ToolOutput::error(err.to_string()),
+        };
+
+        loop {
+            match pending.next().await {
+                Some(ExecutorResponse::Event {
+                    event: AgentEvent::Custom { topic, payload },
+                    ..
+                }) => context.publish(topic, payload),
+                Some(ExecutorResponse::Event { event, .. }) => context.emit(event),
+                Some(ExecutorResponse::Result {
+                    content, is_error, ..
+                }) => {
+                    pending.finished = true;
+                    return ToolOutput { content, is_error };
+                }
+                Some(ExecutorResponse::Error { message, .. }) => {
+                    pending.finished = true;
+                    return ToolOutput::error(message);
+                }
+                Some(response) => {
+                    tracing::warn!("Unexpected response to a tool call: {response:?}");
+                }
+                None => {
+                    return ToolOutput::error(KepokiError::ToolExecutorDisconnected.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// The responses to a request, cancelling the request when dropped before it finished.
+struct PendingRequest {
+    id: u64,
+    executor: RemoteExecutor,
+    responses: UnboundedReceiver<ExecutorResponse>,
+    finished: bool,
+}
+
+impl PendingRequest {
+    async fn next(&mut self) -> Option<ExecutorResponse> {
+        self.responses.recv().await
+    }
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        if let Some(pending) = self.executor.pending.lock().unwrap().as_mut() {
+            pending.remove(&self.id);
+        }
+        if !self.finished {
+            let _ = self
+                .executor
+                .requests
+                .send(ExecutorRequest::Cancel { id: self.id });
+        }
+    }
+}
+
+/// A tool executed by a remote executor.
+#[derive(Clone, Debug)]
+pub struct RemoteTool {
+    executor: RemoteExecutor,
+    definition: RemoteToolDefinition,
+}
+
+impl BuiltinTool for RemoteTool {
+    fn definition(&self) -> Tool<'static> {
+        Tool {
+            name: self.definition.name.clone().into(),
+            input_schema: self.definition.input_schema.clone().map(Into::into),
+            description: self.definition.description.clone().map(Into::into),
+        }
+    }
+
+    fn call(&self, context: ToolContext, input: Value) -> ToolFuture<'_> {
+        Box::pin(
+            self.executor
+                .call(context, self.definition.name.clone(), input),
+        )
+    }
+
+    fn side_effecting(&self) -> bool {
+        self.definition.side_effecting
+    }
+}
+
+/// Serves the tools of a registry to remote runtimes.
+#[derive(Clone, Debug)]
+pub struct ExecutorServer {
+    tools: ToolRegistry,
+}
+
+impl ExecutorServer {
+    pub fn new(tools: ToolRegistry) -> Self {
+        Self { tools }
+    }
+
+    /// Accepts connections on `address` until the listener fails.
+    pub async fn listen(self, address: impl ToSocketAddrs) -> std::io::Result<()> {
+        let listener = TcpListener::bind(address).await?;
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            tracing::info!("Tool executor connection from {peer}");
+            let server = self.clone();
+            tokio::spawn(async move {
+                let (reader, writer) = stream.into_split();
+                server.serve(reader, writer).await;
+            });
+        }
+    }
+
+    /// Serves a single connection until it closes, cancelling the calls still running.
+    pub async fn serve(
+        &self,
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+    ) {
+        let responses = spawn_writer::<ExecutorResponse>(writer);
+        let mut calls = HashMap::<u64, tokio::task::JoinHandle<()>>::new();
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+            calls.retain(|_, call| !call.is_finished());
+
+            match serde_json::from_str::<ExecutorRequest>(&line) {
+                Ok(ExecutorRequest::ListTools { id }) => {
+                    let tools = self
+                        .tools
+                        .tools()
+                        .map(|tool| {
+                            let definition = tool.definition();
+                            RemoteToolDefinition {
+                                name: definition.name.into_owned(),
+                                description: definition.description.map(|d| d.into_owned()),
+                                input_schema: definition.input_schema.map(|s| s.into_owned()),
+                                side_effecting: tool.side_effecting(),
+                            }
+                        })
+                        .collect();
+                    let _ = responses.send(ExecutorResponse::Tools { id, tools });
+                }
+                Ok(ExecutorRequest::Call {
+                    id,
+                    agent,
+                    tool,
+                    input,
+                    locale,
+                }) => {
+                    let Some(tool) = self.tools.get(&tool).cloned() else {
+                        let _ = responses.send(ExecutorResponse::Error {
+                            id,
+                            message: format!("Unknown tool: {tool}"),
+                        });
+                        continue;
+                    };
+                    calls.insert(
+                        id,
+                        tokio::spawn(execute(id, tool, agent, input, locale, responses.clone())),
+                    );
+                }
+                Ok(ExecutorRequest::Cancel { id }) => {
+                    if let Some(call) = calls.remove(&id) {
+                        call.abort();
+                    }
+                }
+                Err(err) => tracing::warn!("Invalid message from runtime: {err}"),
+            }
+        }
+
+        for call in calls.into_values() {
+            call.abort();
+        }
+    }
+}
+
+/// Runs a call, forwarding the events the tool emits before its result.
+async fn execute(
+    id: u64,
+    tool: Arc<dyn BuiltinTool>,
+    agent: AgentHandle,
+    input: Value,
+    locale: Option<Locale>,
+    responses: UnboundedSender<ExecutorResponse>,
+) {
+    let (event_emitter, mut events) = unbounded_channel();
+    let context = ToolContext {
+        agent,
+        event_emitter,
+        event_bus: EventBus::new(),
+        locale,
+    };
+
+    let call = tool.call(context, input);
+    tokio::pin!(call);
+    let output = loop {
+        tokio::select! {
+            output = &mut call => break output,
+            Some(event) = events.recv() => {
+                let _ = responses.send(ExecutorResponse::Event { id, event });
+            }
+        }
+    };
+    while let Ok(event) = events.try_recv() {
+        let _ = responses.send(ExecutorResponse::Event { id, event });
+    }
+
+    let _ = responses.send(ExecutorResponse::Result {
+        id,
+        content: output.content,
+        is_error: output.is_error,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct EchoTool;
+
+    impl BuiltinTool for EchoTool {
+        fn definition(&self) -> Tool<'static> {
+            Tool {
+                name: "echo".into(),
+                input_schema: None,
+                description: None,
+            }
+        }
+
+        fn call(&self, context: ToolContext, input: Value) -> ToolFuture<'_> {
+            Box::pin(async move {
+                context.publish("echo", input.clone());
+                ToolOutput::text(input.to_string())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remote_call() {
+        let (runtime_side, executor_side) = tokio::io::duplex(4096);
+        let mut tools = ToolRegistry::new();
+        tools.register(EchoTool);
+        let server = ExecutorServer::new(tools);
+        tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(executor_side);
+            server.serve(reader, writer).await;
+        });
+
+        let (reader, writer) = tokio::io::split(runtime_side);
+        let executor = RemoteExecutor::new(reader, writer);
+        let tools = executor.tools().await.unwrap();
+        assert_eq!(tools.len(), 1);
+        assert_eq!(tools[0].definition().name, "echo");
+
+        let (event_emitter, mut events) = unbounded_channel();
+        let context = ToolContext {
+            agent: serde_json::from_value(serde_json::json!({
+                "name": "agent",
+                "uuid": vec![0; 16],
+            }))
+            .unwrap(),
+            event_emitter,
+            event_bus: EventBus::new(),
+            locale: None,
+        };
+        let output = tools[0]
+            .call(context, serde_json::json!({ "text": "hi" }))
+            .await;
+        assert!(!output.is_error);
+        assert!(matches!(
+            &output.content[..],
+            [ToolResultContentBlock::Text { text }] if text == r#"{"text":"hi"}"#
+        ));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(AgentEvent::Custom { topic, .. }) if topic == "echo"
+        ));
+    }
+}
@@ -0,0 +1,118 @@
+//! Screen capture through the `screenshot` builtin tool, groundwork for agents that operate a
+//! desktop. Only available with the `screenshot` feature.
+//!
+//! A screenshot can show anything the user has open, so every capture first goes through the
+//! approval gate the tool was created with, and is only taken if it approves.
+
+use std::io::Cursor;
+use std::sync::Arc;
+
+use serde_json::Value;
+use xcap::Monitor;
+use xcap::Window;
+use xcap::image::ImageFormat;
+
+use crate::backend::ImageMediaType;
+use crate::backend::ImageSource;
+use crate::backend::Payload;
+use crate::backend::Tool;
+use crate::backend::ToolResultContentBlock;
+use crate::runtime::AgentHandle;
+use crate::tools::BuiltinTool;
+use crate::tools::ToolContext;
+use crate::tools::ToolFuture;
+use crate::tools::ToolOutput;
+
+/// What a screenshot captures.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CaptureTarget {
+    /// The primary monitor.
+    Screen,
+    /// The first window whose title contains the text.
+    Window(String),
+}
+
+/// Decides whether an agent may take a screenshot of a target.
+pub type ScreenshotApproval = Arc<dyn Fn(&AgentHandle, &CaptureTarget) -> bool + Send + Sync>;
+
+pub struct ScreenshotTool {
+    approval: ScreenshotApproval,
+}
+
+impl ScreenshotTool {
+    pub fn new(
+        approval: impl Fn(&AgentHandle, &CaptureTarget) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            approval: Arc::new(approval),
+        }
+    }
+}
+
+impl BuiltinTool for ScreenshotTool {
+    fn definition(&self) -> Tool<'static> {
+        Tool {
+            name: "screenshot".into(),
+            input_schema: Some(
+                r#"{"type":"object","properties":{"window":{"type":"string"}}}"#.into(),
+            ),
+            description: Some(
+                "Takes a screenshot of the primary screen, or of the window whose title contains `window`. The user must approve every screenshot.".into(),
+            ),
+        }
+    }
+
+    fn call(&self, context: ToolContext, input: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let target = match input.get("window").and_then(Value::as_str) {
+                Some(title) => CaptureTarget::Window(title.to_string()),
+                None => CaptureTarget::Screen,
+            };
+            if !(self.approval)(&context.agent, &target) {
+                return ToolOutput::error("The user declined the screenshot");
+            }
+
+            match capture(&target) {
+                Ok(data) => ToolOutput {
+                    content: vec![ToolResultContentBlock::Image {
+                        source: ImageSource::Base64 {
+                            data,
+                            media_type: ImageMediaType::Png,
+                        },
+                    }],
+                    is_error: false,
+                },
+                Err(err) => ToolOutput::error(format!("Failed to take screenshot: {err}")),
+            }
+        })
+    }
+
+    fn side_effecting(&self) -> bool {
+        false
+    }
+}
+
+/// Captures the target as a PNG image.
+fn capture(target: &CaptureTarget) -> Result<Payload, Box<dyn std::error::Error>> {
+    let image = match target {
+        CaptureTarget::Screen => Monitor::all()?
+            .into_iter()
+            .find(|monitor| monitor.is_primary().unwrap_or(false))
+            .ok_or("No primary monitor found")?
+            .capture_image()?,
+        CaptureTarget::Window(title) => Window::all()?
+            .into_iter()
+            .find(|window| {
+                window
+                    .title()
+                    .is_ok_and(|window_title| window_title.contains(title.as_str()))
+                    && !window.is_minimized().unwrap_or(false)
+            })
+            .ok_or_else(|| format!("No visible window with a title containing `{title}`"))?
+            .capture_image()?,
+    };
+
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageFormat::Png)?;
+    Ok(png.into_inner().into())
+}
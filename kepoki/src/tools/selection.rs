@@ -0,0 +1,262 @@
+//! Advertising every tool of every MCP server an agent uses can take up a large part of the
+//! context. Agents with a [`ToolSelection`] only advertise the tools most relevant to the
+//! latest user message each turn.
+//!
+//! Tools are ranked by the words they share with the message, or by embedding similarity if
+//! [`ToolEmbeddings`] are set on the runtime.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::agent::ToolName;
+use crate::agent::ToolSelection;
+use crate::backend::EmbeddingBackend;
+use crate::backend::Tool;
+use crate::error::KepokiError;
+
+/// Words too common to tell tools apart.
+const STOP_WORDS: &[&str] = &[
+    "and", "are", "can", "for", "from", "how", "into", "not", "please", "that", "the", "this",
+    "what", "when", "with", "you", "your",
+];
+
+/// The embedding model tools are ranked with, see
+/// [`RuntimeBuilder::with_tool_embeddings`](crate::runtime::builder::RuntimeBuilder::with_tool_embeddings).
+#[derive(Clone)]
+pub struct ToolEmbeddings {
+    backend: Arc<dyn EmbeddingBackend>,
+    model: String,
+}
+
+impl ToolEmbeddings {
+    /// Embeds tools and messages with the embedding model of `backend` named `model`.
+    pub fn new(backend: impl EmbeddingBackend, model: impl Into<String>) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            model: model.into(),
+        }
+    }
+}
+
+impl std::fmt::Debug for ToolEmbeddings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ToolEmbeddings")
+            .field("model", &self.model)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Selects the tools advertised to agents with a [`ToolSelection`].
+///
+/// Clones share the embeddings of tool descriptions, which are computed once per description.
+#[derive(Clone, Debug, Default)]
+pub struct ToolSelector {
+    model: Option<ToolEmbeddings>,
+    embeddings: Arc<Mutex<HashMap<String, Arc<[f32]>>>>,
+}
+
+impl ToolSelector {
+    pub fn new(model: Option<ToolEmbeddings>) -> Self {
+        Self {
+            model,
+            embeddings: Default::default(),
+        }
+    }
+
+    /// Keeps the tools listed in `selection.always` and the most relevant of the remaining
+    /// tools to `query`, up to `selection.max_tools` in total, in their original order.
+    pub async fn select(
+        &self,
+        query: &str,
+        tools: Vec<(&ToolName, Tool<'static>)>,
+        selection: &ToolSelection,
+    ) -> Vec<Tool<'static>> {
+        if tools.len() <= selection.max_tools {
+            return tools.into_iter().map(|(_, tool)| tool).collect();
+        }
+
+        let (always, candidates): (Vec<_>, Vec<_>) = tools
+            .iter()
+            .enumerate()
+            .partition(|(_, (name, _))| selection.always.contains(name));
+        let texts = candidates
+            .iter()
+            .map(|(_, (_, tool))| describe(tool))
+            .collect::<Vec<_>>();
+        let scores = self
+            .embedding_scores(query, &texts)
+            .await
+            .unwrap_or_else(|| keyword_scores(query, &texts));
+
+        let mut ranked = candidates
+            .iter()
+            .map(|(index, _)| *index)
+            .zip(scores)
+            .collect::<Vec<_>>();
+        ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        let remaining = selection.max_tools.saturating_sub(always.len());
+        let selected = always
+            .iter()
+            .map(|(index, _)| *index)
+            .chain(ranked.into_iter().take(remaining).map(|(index, _)| index))
+            .collect::<HashSet<_>>();
+
+        tools
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| selected.contains(index))
+            .map(|(_, (_, tool))| tool)
+            .collect()
+    }
+
+    /// Cosine similarities of the texts to the query, `None` without an embedding model or if
+    /// embedding failed.
+    async fn embedding_scores(&self, query: &str, texts: &[String]) -> Option<Vec<f32>> {
+        let model = self.model.as_ref()?;
+        let result = async {
+            let missing = {
+                let embeddings = self.embeddings.lock().unwrap();
+                texts
+                    .iter()
+                    .filter(|text| !embeddings.contains_key(*text))
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+
+            let mut inputs = vec![query.to_string()];
+            inputs.extend(missing.iter().cloned());
+            let mut embedded = model
+                .backend
+                .embed(&model.model, &inputs)
+                .await?
+                .into_iter();
+            let query = embedded.next().unwrap_or_default();
+
+            let mut embeddings = self.embeddings.lock().unwrap();
+            for (text, embedding) in missing.into_iter().zip(embedded) {
+                embeddings.insert(text, embedding.into());
+            }
+
+            Ok::<_, KepokiError>(
+                texts
+                    .iter()
+                    .map(|text| {
+                        embeddings
+                            .get(text)
+                            .map_or(0.0, |embedding| cosine_similarity(&query, embedding))
+                    })
+                    .collect(),
+            )
+        }
+        .await;
+
+        result
+            .map_err(|err| tracing::warn!("Failed to embed tools, ranking by keywords: {err}"))
+            .ok()
+    }
+}
+
+/// The text a tool is ranked by.
+fn describe(tool: &Tool<'_>) -> String {
+    match &tool.description {
+        Some(description) => format!("{}: {description}", tool.name),
+        None => tool.name.to_string(),
+    }
+}
+
+/// The share of the query's words that occur in each text.
+fn keyword_scores(query: &str, texts: &[String]) -> Vec<f32> {
+    let query = words(query);
+    texts
+        .iter()
+        .map(|text| {
+            let text = words(text);
+            query.intersection(&text).count() as f32 / query.len().max(1) as f32
+        })
+        .collect()
+}
+
+/// Lowercase words of at least three characters, splitting tool names like `read_file` too.
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.len() >= 3)
+        .map(str::to_lowercase)
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+        .collect()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    match norm(a) * norm(b) {
+        0.0 => 0.0,
+        norms => dot / norms,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::EmbedFuture;
+
+    /// Embeds texts by whether they are about the weather.
+    struct WeatherEmbeddings;
+
+    impl EmbeddingBackend for WeatherEmbeddings {
+        fn embed<'a>(&'a self, model: &'a str, texts: &'a [String]) -> EmbedFuture<'a> {
+            assert_eq!(model, "weather-embed");
+            Box::pin(async move {
+                Ok(texts
+                    .iter()
+                    .map(|text| {
+                        let weather = ["weather", "rain"].iter().any(|word| text.contains(word));
+                        vec![weather as u8 as f32, 1.0 - weather as u8 as f32]
+                    })
+                    .collect())
+            })
+        }
+    }
+
+    fn tool(name: &'static str, description: &'static str) -> Tool<'static> {
+        Tool {
+            name: name.into(),
+            input_schema: None,
+            description: Some(description.into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_select_ranks_by_keywords_or_embeddings() {
+        let names = ["read_file", "forecast"].map(|name| name.parse::<ToolName>().unwrap());
+        let tools = || {
+            vec![
+                (&names[0], tool("read_file", "Reads a file from the disk")),
+                (&names[1], tool("forecast", "Gets the weather of a city")),
+            ]
+        };
+        let selection = ToolSelection {
+            max_tools: 1,
+            always: Vec::new(),
+        };
+
+        let keywords = ToolSelector::default();
+        let selected = keywords
+            .select("Read the file notes.txt", tools(), &selection)
+            .await;
+        assert!(matches!(&selected[..], [tool] if tool.name == "read_file"));
+
+        let embeddings = ToolSelector::new(Some(ToolEmbeddings::new(
+            WeatherEmbeddings,
+            "weather-embed",
+        )));
+        let selected = embeddings
+            .select("Will it rain tomorrow?", tools(), &selection)
+            .await;
+        assert!(matches!(&selected[..], [tool] if tool.name == "forecast"));
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::agent::ToolName;
+use crate::backend::ToolResultContentBlock;
+use crate::backend::estimate_tokens;
+use crate::tools::ToolOutput;
+
+/// Upper bounds of the latency histogram buckets, the last bucket is unbounded.
+pub const LATENCY_BUCKETS: [Duration; 6] = [
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+    Duration::from_secs(300),
+];
+
+/// Rough token cost of an image in a tool result.
+const IMAGE_TOKENS: u64 = 1600;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ToolStat {
+    /// Number of times the tool was called, including dry runs.
+    pub calls: u64,
+    /// Number of calls that returned an error result.
+    pub errors: u64,
+    /// Number of calls skipped because the runtime was in dry-run mode.
+    pub dry_runs: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+    /// Call counts per [`LATENCY_BUCKETS`] entry, with one extra bucket for slower calls.
+    pub latency_histogram: [u64; LATENCY_BUCKETS.len() + 1],
+    /// Estimated number of tokens the tool's results added to the context.
+    pub result_tokens: u64,
+}
+
+impl ToolStat {
+    pub fn error_rate(&self) -> f64 {
+        match self.calls {
+            0 => 0.0,
+            calls => self.errors as f64 / calls as f64,
+        }
+    }
+
+    pub fn mean_latency(&self) -> Duration {
+        match u32::try_from(self.calls) {
+            Ok(0) | Err(_) => Duration::ZERO,
+            Ok(calls) => self.total_latency / calls,
+        }
+    }
+
+    /// The upper bound of the histogram bucket containing the given percentile, in `0.0..=1.0`.
+    pub fn latency_percentile(&self, percentile: f64) -> Duration {
+        let target = (self.calls as f64 * percentile).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.latency_histogram.iter().enumerate() {
+            seen += count;
+            if seen >= target && *count > 0 {
+                return LATENCY_BUCKETS
+                    .get(bucket)
+                    .copied()
+                    .unwrap_or(self.max_latency);
+            }
+        }
+
+        self.max_latency
+    }
+
+    fn record(&mut self, latency: Duration, output: &ToolOutput, dry_run: bool) {
+        self.calls += 1;
+        self.errors += output.is_error as u64;
+        self.dry_runs += dry_run as u64;
+        self.total_latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_histogram[bucket] += 1;
+        self.result_tokens += output
+            .content
+            .iter()
+            .map(|block| match block {
+                ToolResultContentBlock::Text { text } => estimate_tokens(text) as u64,
+                ToolResultContentBlock::Image { .. } => IMAGE_TOKENS,
+                ToolResultContentBlock::Unknown { data } => {
+                    estimate_tokens(&data.to_string()) as u64
+                }
+            })
+            .sum::<u64>();
+    }
+}
+
+/// Usage statistics for every tool called in a runtime.
+#[derive(Clone, Debug, Default)]
+pub struct ToolStats {
+    stats: Arc<Mutex<HashMap<ToolName, ToolStat>>>,
+}
+
+impl ToolStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, tool: &ToolName, latency: Duration, output: &ToolOutput, dry_run: bool) {
+        self.stats
+            .lock()
+            .unwrap()
+            .entry(tool.clone())
+            .or_default()
+            .record(latency, output, dry_run);
+    }
+
+    pub fn snapshot(&self) -> HashMap<ToolName, ToolStat> {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_percentile() {
+        let mut stat = ToolStat::default();
+        stat.record(Duration::from_millis(5), &ToolOutput::text("ok"), false);
+        stat.record(Duration::from_millis(500), &ToolOutput::error("no"), false);
+        stat.record(Duration::from_millis(700), &ToolOutput::text("ok"), false);
+
+        assert_eq!(stat.calls, 3);
+        assert_eq!(stat.errors, 1);
+        assert_eq!(stat.latency_percentile(0.3), Duration::from_millis(10));
+        assert_eq!(stat.latency_percentile(0.5), Duration::from_secs(1));
+        assert_eq!(stat.max_latency, Duration::from_millis(700));
+    }
+}
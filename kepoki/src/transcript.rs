@@ -0,0 +1,409 @@
+//! Anonymizes transcripts, so that real agent sessions can be shared as bug reports or eval
+//! corpora.
+//!
+//! Personal information found by [`crate::pii::detect`] and configured entities, such as the
+//! names of people and companies, are replaced with numbered placeholders like `[PERSON_1]`.
+//! The same value gets the same placeholder across the whole transcript, so the conversation
+//! stays coherent. Names aren't detected, configure the ones to replace as entities.
+//!
+//! [`FineTuneExport`] converts transcripts into the fine-tuning formats of providers.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+
+use regress::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::backend::ContentBlock;
+use crate::backend::DocumentSource;
+use crate::backend::InputMessage;
+use crate::backend::Role;
+use crate::backend::ToolResultContentBlock;
+
+/// Replaces personal information with placeholders, consistently across every text it
+/// anonymizes.
+#[derive(Debug, Default)]
+pub struct Anonymizer {
+    /// Entities as their kind and text, matched case-insensitively.
+    entities: Vec<(String, String)>,
+    pattern: Option<Regex>,
+    /// The placeholder of every value replaced so far.
+    placeholders: HashMap<String, String>,
+    /// The number of distinct values replaced so far, by kind.
+    counts: HashMap<String, usize>,
+}
+
+impl Anonymizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces `text` wherever it appears as a whole word, with a placeholder of `kind` such as
+    /// `PERSON` or `COMPANY`.
+    pub fn with_entity(mut self, kind: impl Into<String>, text: impl Into<String>) -> Self {
+        let text = text.into();
+        if text.is_empty() {
+            return self;
+        }
+
+        self.entities.push((kind.into().to_uppercase(), text));
+        // Longer entities first, so that `Jane Doe` is replaced as a whole before `Jane`.
+        self.entities
+            .sort_by_key(|(_, text)| std::cmp::Reverse(text.len()));
+        let alternatives = self
+            .entities
+            .iter()
+            .map(|(_, text)| format!("({})", escape(text)))
+            .collect::<Vec<_>>()
+            .join("|");
+        self.pattern = Some(
+            Regex::with_flags(&format!(r"(?<!\w)(?:{alternatives})(?!\w)"), "i")
+                .expect("Escaped entities form a valid pattern"),
+        );
+        self
+    }
+
+    /// Replaces each of `names` as an entity of kind `PERSON`.
+    pub fn with_names(self, names: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        names.into_iter().fold(self, |anonymizer, name| {
+            anonymizer.with_entity("PERSON", name)
+        })
+    }
+
+    /// The placeholder of every value replaced so far, keep it private to map placeholders back.
+    pub fn placeholders(&self) -> &HashMap<String, String> {
+        &self.placeholders
+    }
+
+    /// Anonymizes the text of every message, including tool calls, tool results, thinking, and
+    /// plain text documents.
+    ///
+    /// Thinking loses its signature, providers reject the anonymized transcript if it is sent
+    /// back to them as it is. Images are left alone.
+    pub fn anonymize<'a>(&mut self, messages: impl IntoIterator<Item = &'a mut InputMessage>) {
+        for message in messages {
+            for block in &mut message.content {
+                self.anonymize_block(block);
+            }
+        }
+    }
+
+    fn anonymize_block(&mut self, block: &mut ContentBlock) {
+        match block {
+            ContentBlock::Text { text } => *text = self.anonymize_text(text),
+            ContentBlock::ToolUse { input, .. } | ContentBlock::ServerToolUse { input, .. } => {
+                *input = self.anonymize_text(input);
+            }
+            ContentBlock::ToolResult {
+                content: Some(content),
+                ..
+            } => {
+                for block in content {
+                    if let ToolResultContentBlock::Text { text } = block {
+                        *text = self.anonymize_text(text);
+                    }
+                }
+            }
+            ContentBlock::Thinking {
+                thinking,
+                signature,
+            } => {
+                *thinking = self.anonymize_text(thinking);
+                *signature = None;
+            }
+            ContentBlock::Document {
+                source: DocumentSource::Base64 { data, .. },
+                title,
+            } => {
+                if let Ok(text) = std::str::from_utf8(data) {
+                    *data = self.anonymize_text(text).into_bytes().into();
+                }
+                if let Some(title) = title {
+                    *title = self.anonymize_text(title);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Replaces the personal information and entities in `text` with their placeholders.
+    pub fn anonymize_text(&mut self, text: &str) -> String {
+        // Detected information contains entities rather than the other way around, such as a
+        // name in an email address, and takes precedence over those it overlaps with.
+        let mut found = crate::pii::detect(text)
+            .into_iter()
+            .map(|pii| {
+                let kind = pii.kind.placeholder().trim_matches(['[', ']']).to_string();
+                let value = text[pii.range.clone()].to_lowercase();
+                (pii.range, kind, value)
+            })
+            .collect::<Vec<_>>();
+        if let Some(pattern) = &self.pattern {
+            for entity in pattern.find_iter(text) {
+                let range = entity.range();
+                if found
+                    .iter()
+                    .any(|(found, ..)| range.start < found.end && found.start < range.end)
+                {
+                    continue;
+                }
+
+                let index = (1..=self.entities.len())
+                    .find(|&group| entity.group(group).is_some())
+                    .expect("One alternative matched");
+                let (kind, value) = &self.entities[index - 1];
+                found.push((range, kind.clone(), value.to_lowercase()));
+            }
+        }
+        found.sort_by_key(|(range, ..)| range.start);
+
+        let mut anonymized = String::with_capacity(text.len());
+        let mut end = 0;
+        for (range, kind, value) in found {
+            anonymized.push_str(&text[end..range.start]);
+            anonymized.push_str(self.placeholder(kind, value));
+            end = range.end;
+        }
+        anonymized.push_str(&text[end..]);
+        anonymized
+    }
+
+    fn placeholder(&mut self, kind: String, value: String) -> &str {
+        self.placeholders.entry(value).or_insert_with(|| {
+            let count = self.counts.entry(kind.clone()).or_default();
+            *count += 1;
+            format!("[{kind}_{count}]")
+        })
+    }
+}
+
+/// Anonymizes the personal information [`crate::pii::detect`] finds in the messages.
+///
+/// Use an [`Anonymizer`] to replace names and other entities as well.
+pub fn anonymize<'a>(messages: impl IntoIterator<Item = &'a mut InputMessage>) {
+    Anonymizer::new().anonymize(messages);
+}
+
+/// A fine-tuning format of a provider, one example per line of JSON.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportFormat {
+    /// `{"messages": [...]}` with the system prompt as the first message.
+    OpenaiJsonl,
+    /// `{"system": "...", "messages": [...]}`, as Claude models are fine-tuned on Bedrock.
+    AnthropicJsonl,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "openai-jsonl" => Ok(Self::OpenaiJsonl),
+            "anthropic-jsonl" => Ok(Self::AnthropicJsonl),
+            _ => Err(format!(
+                "Unknown format `{s}`, expected openai-jsonl or anthropic-jsonl"
+            )),
+        }
+    }
+}
+
+/// Converts transcripts into fine-tuning examples.
+///
+/// Examples only keep the text of the conversation, tool calls, tool results, thinking, and
+/// attachments are left out. Messages of the same role that end up next to each other are
+/// merged, and every example ends with a response of the model.
+#[derive(Debug)]
+pub struct FineTuneExport {
+    format: ExportFormat,
+    anonymizer: Option<Anonymizer>,
+}
+
+impl FineTuneExport {
+    pub fn new(format: ExportFormat) -> Self {
+        Self {
+            format,
+            anonymizer: None,
+        }
+    }
+
+    /// Anonymizes the examples, placeholders stay consistent across all of them.
+    pub fn with_anonymizer(mut self, anonymizer: Anonymizer) -> Self {
+        self.anonymizer = Some(anonymizer);
+        self
+    }
+
+    /// The example of a transcript as a line of JSON, `None` if the model never responded with
+    /// text in it.
+    pub fn example(&mut self, system: Option<&str>, messages: &[InputMessage]) -> Option<String> {
+        let mut turns = Vec::<(Role, String)>::new();
+        for message in messages {
+            let text = message
+                .content
+                .iter()
+                .filter_map(|block| match block {
+                    ContentBlock::Text { text } if !text.trim().is_empty() => Some(text.trim()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            if text.is_empty() {
+                continue;
+            }
+
+            match turns.last_mut() {
+                Some((role, last)) if *role == message.role => {
+                    last.push_str("\n\n");
+                    last.push_str(&text);
+                }
+                _ => turns.push((message.role, text)),
+            }
+        }
+
+        // Examples start with the user and end with the model.
+        let start = turns.iter().position(|(role, _)| *role == Role::User)?;
+        let end = turns
+            .iter()
+            .rposition(|(role, _)| *role == Role::Assistant)?;
+        if end < start {
+            return None;
+        }
+
+        let mut system = system
+            .filter(|system| !system.trim().is_empty())
+            .map(str::to_string);
+        let mut turns = turns.drain(start..=end).collect::<Vec<_>>();
+        if let Some(anonymizer) = &mut self.anonymizer {
+            for (_, text) in &mut turns {
+                *text = anonymizer.anonymize_text(text);
+            }
+            if let Some(system) = &mut system {
+                *system = anonymizer.anonymize_text(system);
+            }
+        }
+
+        let messages = turns
+            .into_iter()
+            .map(|(role, text)| {
+                let role = match role {
+                    Role::User => "user",
+                    Role::Assistant => "assistant",
+                };
+                json!({ "role": role, "content": text })
+            })
+            .collect::<Vec<_>>();
+        let example = match self.format {
+            ExportFormat::OpenaiJsonl => {
+                let system = system.map(|system| json!({ "role": "system", "content": system }));
+                json!({ "messages": system.into_iter().chain(messages).collect::<Vec<_>>() })
+            }
+            ExportFormat::AnthropicJsonl => match system {
+                Some(system) => json!({ "system": system, "messages": messages }),
+                None => json!({ "messages": messages }),
+            },
+        };
+        Some(example.to_string())
+    }
+
+    /// Writes the examples of transcripts and their system prompts, returning how many were
+    /// written.
+    pub fn write<'a>(
+        &mut self,
+        mut writer: impl Write,
+        transcripts: impl IntoIterator<Item = (Option<&'a str>, &'a [InputMessage])>,
+    ) -> std::io::Result<usize> {
+        let mut written = 0;
+        for (system, messages) in transcripts {
+            if let Some(example) = self.example(system, messages) {
+                writeln!(writer, "{example}")?;
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// Escapes the characters that have a meaning in patterns.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if r"\^$.|?*+()[]{}/-".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consistent_placeholders() {
+        let mut anonymizer = Anonymizer::new()
+            .with_names(["Jane Doe", "Jane"])
+            .with_entity("company", "Acme Corp.");
+        assert_eq!(
+            anonymizer.anonymize_text("Jane Doe (jane@acme.com) of Acme Corp. asked Bob."),
+            "[PERSON_1] ([EMAIL_1]) of [COMPANY_1] asked Bob."
+        );
+        assert_eq!(
+            anonymizer.anonymize_text("Mail JANE@acme.com, Jane will reply. Janet won't."),
+            "Mail [EMAIL_1], [PERSON_2] will reply. Janet won't."
+        );
+    }
+
+    #[test]
+    fn test_export_drops_tool_noise() {
+        let message = |role, content| InputMessage {
+            id: String::new(),
+            role,
+            content,
+        };
+        let text = |text: &str| ContentBlock::Text {
+            text: text.to_string(),
+        };
+        let messages = [
+            message(Role::User, vec![text("What time is it?")]),
+            message(
+                Role::Assistant,
+                vec![
+                    text("Let me check."),
+                    ContentBlock::ToolUse {
+                        id: "call".to_string(),
+                        input: "{}".to_string(),
+                        name: "clock".to_string(),
+                    },
+                ],
+            ),
+            message(
+                Role::User,
+                vec![ContentBlock::ToolResult {
+                    tool_use_id: "call".to_string(),
+                    content: None,
+                    is_error: None,
+                }],
+            ),
+            message(Role::Assistant, vec![text("It is noon.")]),
+        ];
+
+        let mut export = FineTuneExport::new(ExportFormat::OpenaiJsonl);
+        let example = export.example(Some("Be brief."), &messages).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&example).unwrap(),
+            json!({
+                "messages": [
+                    { "role": "system", "content": "Be brief." },
+                    { "role": "user", "content": "What time is it?" },
+                    { "role": "assistant", "content": "Let me check.\n\nIt is noon." },
+                ]
+            })
+        );
+        assert_eq!(export.example(None, &messages[..1]), None);
+    }
+}